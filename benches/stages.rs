@@ -0,0 +1,90 @@
+use criterion::BatchSize;
+use criterion::Criterion;
+use criterion::Throughput;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use language::codegen::generate_nasm_x86_string;
+use language::codegen::generate_program;
+use language::instr::AsmProgram;
+use language::lexer::Lexer;
+use language::opt::OptLevel;
+use language::parser::ParseTree;
+
+// Functions in the generated program, each with `STATEMENTS` statements, so 100k statements in total.
+const FUNCTIONS: usize = 1000;
+const STATEMENTS: usize = 100;
+
+// A valid program cycling through declarations, assignments, conditionals and dumps.
+fn generate_source(functions: usize) -> Vec<u8> {
+    let mut src: String = String::new();
+    for f in 0..functions {
+        src.push_str(&format!("func f{} {{\n    let a = {};\n", f, f));
+        for s in 0..STATEMENTS {
+            match s % 4 {
+                0 => src.push_str(&format!("    let v{} = a * {} + (a - 1) / 3;\n", s, s)),
+                1 => src.push_str(&format!("    a = a + v{};\n", s - 1)),
+                2 => src.push_str(&format!("    if a > {} && a ~= 7 {{\n        dump a;\n    }} else {{\n        a = 0 - a;\n    }}\n", s)),
+                _ => src.push_str(&format!("    dump a - {};\n", s)),
+            }
+        }
+        src.push_str("}\n\n");
+    }
+    src.push_str("func main {\n    f0();\n}\n");
+    src.into_bytes()
+}
+
+fn lexed(src: &[u8]) -> Lexer {
+    let mut lexer: Lexer = Lexer::new(src.to_vec());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Benchmark source failed to lex");
+    lexer
+}
+
+fn parsed(src: &[u8]) -> ParseTree {
+    let mut ast: ParseTree = ParseTree::new("bench".to_string());
+    ast.construct(&mut lexed(src)).expect("Error: Benchmark source failed to parse");
+    ast
+}
+
+// Each stage is timed on its own, with the stages before it run outside the measurement.
+fn stages(c: &mut Criterion) {
+    let src: Vec<u8> = generate_source(FUNCTIONS);
+    let mut group = c.benchmark_group("stages");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(src.len() as u64));
+
+    group.bench_function("tokenize", |b| b.iter_batched(
+        || Lexer::new(src.clone()),
+        |mut lexer| lexer.tokenize(),
+        BatchSize::LargeInput,
+    ));
+    group.bench_function("lex", |b| b.iter_batched(
+        || {
+            let mut lexer: Lexer = Lexer::new(src.clone());
+            lexer.tokenize();
+            lexer
+        },
+        |mut lexer| lexer.lex().expect("Error: Benchmark source failed to lex"),
+        BatchSize::LargeInput,
+    ));
+    group.bench_function("parse", |b| b.iter_batched(
+        || lexed(&src),
+        |mut lexer| ParseTree::new("bench".to_string()).construct(&mut lexer).expect("Error: Benchmark source failed to parse"),
+        BatchSize::LargeInput,
+    ));
+
+    let ast: ParseTree = parsed(&src);
+    group.bench_function("codegen -O0", |b| b.iter(|| {
+        let prog: AsmProgram = generate_program(&ast, OptLevel::O0).expect("Error: Benchmark source failed to compile");
+        generate_nasm_x86_string(&prog)
+    }));
+
+    group.bench_function("codegen -O2", |b| b.iter(|| {
+        let prog: AsmProgram = generate_program(&ast, OptLevel::O2).expect("Error: Benchmark source failed to compile");
+        generate_nasm_x86_string(&prog)
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, stages);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+#![no_main]
+
+use language::lexer::Lexer;
+use libfuzzer_sys::fuzz_target;
+
+// Any bytes must either lex or be rejected with a diagnostic, never panic.
+fuzz_target!(|data: &[u8]| {
+    let mut lexer: Lexer = Lexer::new(data.to_vec());
+    lexer.tokenize();
+    let _ = lexer.lex();
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use language::limits::Limits;
+use language::session::Session;
+use language::source::FileId;
+use libfuzzer_sys::fuzz_target;
+
+// Whatever lexes must either parse or be rejected with a diagnostic, never panic.
+// NOTE: Small limits keep the fuzzer on inputs that are interesting rather than only big, and check they hold.
+fuzz_target!(|data: &[u8]| {
+    let mut session: Session = Session::new();
+    session.limits = Limits { max_ident_len: 64, max_depth: 32, max_functions: 64, max_locals: 64 };
+    let file: FileId = session.add("fuzz", data.to_vec());
+    let Ok(mut lexer) = session.lex(file) else {
+        return;
+    };
+    let _ = session.parse(file, &mut lexer);
+});
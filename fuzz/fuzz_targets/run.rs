@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::io;
+use std::time::Duration;
+use language::diagnostic::CollectorSink;
+use language::limits::Limits;
+use language::limits::RunLimits;
+use language::session::Session;
+use language::source::FileId;
+use libfuzzer_sys::fuzz_target;
+
+// Whatever compiles must run to its end or be stopped with a diagnostic, never panic, hang or run out of memory.
+// NOTE: The run limits keep endless loops and deep recursion within the time libFuzzer gives one input.
+fuzz_target!(|data: &[u8]| {
+    let mut session: Session = Session::new();
+    session.limits = Limits { max_ident_len: 64, max_depth: 32, max_functions: 64, max_locals: 64 };
+    session.run_limits = RunLimits { max_steps: Some(100_000), max_memory: Some(64 * 1024), timeout: Some(Duration::from_millis(100)), max_call_depth: Some(1024) };
+    let file: FileId = session.add("fuzz", data.to_vec());
+    let _ = session.run(file, &mut io::sink(), &mut CollectorSink::default());
+});
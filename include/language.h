@@ -0,0 +1,40 @@
+/* C API of the compiler, available when built with `--features capi`. */
+#ifndef LANGUAGE_H
+#define LANGUAGE_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum {
+    LANG_OUTPUT_ASM = 0,
+    LANG_OUTPUT_TOKENS_JSON = 1,
+    LANG_OUTPUT_AST_JSON = 2,
+} LangOutput;
+
+typedef enum {
+    LANG_OPT_O0 = 0,
+    LANG_OPT_O1 = 1,
+    LANG_OPT_O2 = 2,
+    LANG_OPT_OS = 3,
+} LangOptLevel;
+
+/* The fields are plain ints so any value can be checked, unknown ones fail with a diagnostic. */
+typedef struct {
+    int output; /* A LangOutput */
+    int opt_level; /* A LangOptLevel, only used for assembly output */
+} LangOptions;
+
+/* Compiles `source` and returns the requested output, or NULL if compilation failed.
+ * If `out_diag` is not NULL it receives a JSON array of diagnostics on failure and NULL on success.
+ * `options` may be NULL, selecting assembly output at -O0. Unknown options fail with a diagnostic.
+ * Every returned string must be released with `lang_free`. */
+char *lang_compile(const char *source, const LangOptions *options, char **out_diag);
+
+void lang_free(char *text);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
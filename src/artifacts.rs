@@ -0,0 +1,45 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Names, writes and cleans up the intermediate files of one compilation, so every stage puts them in the same place.
+pub struct Artifacts {
+    base: PathBuf, // Every artifact is this path with its extension added
+    pub keep: bool, // Keeps every intermediate rather than only writing the ones later stages read
+}
+impl Artifacts {
+    // Intermediates go next to the output, like `out.asm` for `out`, and are removed once linked.
+    pub fn beside(out_path: &str) -> Self {
+        Artifacts { base: PathBuf::from(out_path), keep: false }
+    }
+
+    // Intermediates of every stage are kept under `<out-dir>/<name>.d/`, like `build/app.d/app.asm` for `build/app`.
+    pub fn saved(out_path: &str) -> io::Result<Self> {
+        let out: &Path = Path::new(out_path);
+        let name: String = out.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| "output".to_string());
+        let dir: PathBuf = out.with_file_name(format!("{}.d", name));
+        fs::create_dir_all(&dir)?;
+        Ok(Artifacts { base: dir.join(name), keep: true })
+    }
+
+    pub fn path(&self, ext: &str) -> String {
+        format!("{}.{}", self.base.to_string_lossy(), ext)
+    }
+
+    // Writes an artifact only a person reads, like the tokens or the AST. Does nothing unless they are kept.
+    pub fn save(&self, ext: &str, contents: &str) -> io::Result<()> {
+        if !self.keep {
+            return Ok(());
+        }
+        fs::write(self.path(ext), contents)
+    }
+
+    // Removes an artifact a later stage has finished reading, unless they are kept.
+    pub fn discard(&self, ext: &str) -> io::Result<()> {
+        if self.keep {
+            return Ok(());
+        }
+        fs::remove_file(self.path(ext))
+    }
+}
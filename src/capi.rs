@@ -0,0 +1,149 @@
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::panic;
+use crate::Inspection;
+use crate::json::Json;
+use crate::opt::OptLevel;
+
+// C entry points for driving the compiler in-process, declared in `include/language.h`.
+// Every returned string is owned by the caller and must be released with `lang_free`.
+
+#[repr(C)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum LangOutput {
+    Asm = 0,
+    TokensJson = 1,
+    AstJson = 2,
+}
+impl TryFrom<c_int> for LangOutput {
+    type Error = String;
+
+    fn try_from(value: c_int) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(LangOutput::Asm),
+            1 => Ok(LangOutput::TokensJson),
+            2 => Ok(LangOutput::AstJson),
+            _ => Err(format!("Unknown output {}", value)),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum LangOptLevel {
+    O0 = 0,
+    O1 = 1,
+    O2 = 2,
+    Os = 3,
+}
+impl TryFrom<c_int> for LangOptLevel {
+    type Error = String;
+
+    fn try_from(value: c_int) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(LangOptLevel::O0),
+            1 => Ok(LangOptLevel::O1),
+            2 => Ok(LangOptLevel::O2),
+            3 => Ok(LangOptLevel::Os),
+            _ => Err(format!("Unknown optimisation level {}", value)),
+        }
+    }
+}
+impl From<LangOptLevel> for OptLevel {
+    fn from(level: LangOptLevel) -> Self {
+        match level {
+            LangOptLevel::O0 => OptLevel::O0,
+            LangOptLevel::O1 => OptLevel::O1,
+            LangOptLevel::O2 => OptLevel::O2,
+            LangOptLevel::Os => OptLevel::Os,
+        }
+    }
+}
+
+// NOTE: C may store any value in an enum, so the fields are plain integers and checked before they become enums.
+#[repr(C)]
+pub struct LangOptions {
+    pub output: c_int, // A `LangOutput`
+    pub opt_level: c_int, // A `LangOptLevel`, only used for assembly output
+}
+
+// A diagnostic about the call rather than the source, which has no position.
+fn message(msg: String) -> Json {
+    Json::object(vec![("message", Json::Str(msg))])
+}
+
+fn into_c(text: String) -> *mut c_char {
+    // NOTE: Output is built from valid source text and JSON, interior NULs are dropped rather than failing.
+    CString::new(text.replace('\0', "")).expect("Error: String still contains NUL").into_raw()
+}
+
+fn compile(src: String, output: LangOutput, opt_level: LangOptLevel) -> Result<String, Vec<Json>> {
+    // Assembly goes through every stage a `Session` runs, desugaring and checks included.
+    if output == LangOutput::Asm {
+        return crate::compile_to_asm(&src, opt_level.into()).map_err(|diags| diags.iter().map(|d| d.to_json()).collect());
+    }
+    let res: Inspection = crate::inspect(src, "capi".to_string());
+    if !res.diagnostics.is_empty() {
+        return Err(res.diagnostics.iter().map(|d| d.to_json()).collect());
+    }
+    if output == LangOutput::TokensJson {
+        let toks: Vec<Json> = res.tokens.unwrap_or_default().iter().map(|tok| tok.to_json()).collect();
+        return Ok(Json::Array(toks).to_string());
+    }
+    Ok(res.ast.map(|ast| ast.root.to_json()).unwrap_or(Json::Null).to_string())
+}
+
+/// Compiles a NUL-terminated source and returns the requested output, or NULL if compilation failed.
+/// If `out_diag` is not NULL it receives a JSON array of diagnostics on failure and NULL on success.
+/// `options` may be NULL, selecting assembly output at `-O0`. Unknown options fail with a diagnostic.
+///
+/// # Safety
+/// `source` must be a valid NUL-terminated string, `options` and `out_diag` must be NULL or valid pointers.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lang_compile(source: *const c_char, options: *const LangOptions, out_diag: *mut *mut c_char) -> *mut c_char {
+    if !out_diag.is_null() {
+        unsafe { *out_diag = std::ptr::null_mut() };
+    }
+    if source.is_null() {
+        return std::ptr::null_mut();
+    }
+    let src: String = unsafe { CStr::from_ptr(source) }.to_string_lossy().to_string();
+    let (output, opt_level): (c_int, c_int) = if options.is_null() {
+        (LangOutput::Asm as c_int, LangOptLevel::O0 as c_int)
+    } else {
+        unsafe { ((*options).output, (*options).opt_level) }
+    };
+
+    let res: Result<String, Vec<Json>> = match (LangOutput::try_from(output), LangOptLevel::try_from(opt_level)) {
+        // Unwinding across the C boundary aborts, so any internal panic is reported as a diagnostic.
+        (Ok(output), Ok(opt_level)) => panic::catch_unwind(move || compile(src, output, opt_level))
+            .unwrap_or_else(|_| Err(vec![message("Internal compiler error".to_string())])),
+        (output, opt_level) => Err(output.err().into_iter().chain(opt_level.err()).map(message).collect()),
+    };
+    match res {
+        Ok(text) => into_c(text),
+        Err(diags) => {
+            if !out_diag.is_null() {
+                unsafe { *out_diag = into_c(Json::Array(diags).to_string()) };
+            }
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Releases a string returned by `lang_compile`.
+///
+/// # Safety
+/// `text` must be NULL or a string returned by this library that was not freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lang_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(unsafe { CString::from_raw(text) });
+    }
+}
@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufWriter;
+use std::io::Write;
+use crate::diagnostic::Diagnostic;
+use crate::hir;
+use crate::hir::BinOp;
+use crate::hir::Block;
+use crate::hir::Callee;
+use crate::hir::Expr;
+use crate::hir::ExprKind;
+use crate::hir::Function;
+use crate::hir::Intrinsic;
+use crate::hir::Local;
+use crate::hir::LocalId;
+use crate::hir::Stmt;
+use crate::hir::UnOp;
+use crate::instr::AsmFunction;
+use crate::instr::AsmProgram;
+use crate::instr::Cond;
+use crate::instr::Instr;
+use crate::instr::Operand;
+use crate::instr::Reg;
+use crate::instr::imm;
+use crate::instr::mem;
+use crate::instr::reg;
+use crate::nasm;
+use crate::opt::OptLevel;
+use crate::opt::eliminate_common_subexpressions;
+use crate::opt::inline_functions;
+use crate::opt::propagate_copies;
+use crate::opt::thread_jumps;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+use crate::regalloc;
+use crate::regalloc::Allocation;
+use crate::symbols::mangle;
+use crate::symbols::qualify;
+
+// Labels are numbered across the whole program, since the same source can be emitted more than once once it is inlined.
+fn next_label(prefix: &str, labels: &mut usize) -> String {
+    *labels += 1;
+    format!("{}_{}", prefix, *labels - 1)
+}
+
+// The condition a comparison operator tests, None for the other operators.
+fn comparison(op: BinOp) -> Option<Cond> {
+    match op {
+        BinOp::Less => Some(Cond::L),
+        BinOp::LessEqual => Some(Cond::Le),
+        BinOp::Greater => Some(Cond::G),
+        BinOp::GreaterEqual => Some(Cond::Ge),
+        BinOp::Equal => Some(Cond::E),
+        BinOp::NotEqual => Some(Cond::Ne),
+        _ => None,
+    }
+}
+
+fn generate_comparison(out: &mut Vec<Instr>, name: &str, cond: Cond) {
+    out.push(Instr::Comment(format!("BinOp::{}", name)));
+    out.push(Instr::Pop(reg(Reg::Rbx)));
+    out.push(Instr::Pop(reg(Reg::Rax)));
+    out.push(Instr::Cmp(reg(Reg::Rax), reg(Reg::Rbx)));
+    out.push(Instr::Mov(reg(Reg::Rax), imm(0)));
+    out.push(Instr::Set(cond, reg(Reg::Al)));
+    out.push(Instr::Push(reg(Reg::Rax)));
+}
+
+// Turns the value atop the stack into 0 or 1 in `rax`, keeping the flags of comparing it with 0 for a jump after.
+fn generate_truth(out: &mut Vec<Instr>) {
+    out.push(Instr::Pop(reg(Reg::Rax)));
+    out.push(Instr::Cmp(reg(Reg::Rax), imm(0)));
+    out.push(Instr::Mov(reg(Reg::Rax), imm(0)));
+    out.push(Instr::Set(Cond::Ne, reg(Reg::Al)));
+}
+
+// What is left of an expression being generated, the next to generate last.
+enum ExprWork<'e> {
+    Operands(&'e Expr),
+    Op(&'e Expr), // Its operands are atop the stack
+    Instrs(Vec<Instr>),
+}
+
+// Operands are generated from a worklist rather than by recursion, so how deeply an expression nests, like a long chain of
+// `+`, is bounded by memory rather than by the compiler's own stack.
+fn generate_expr(out: &mut Vec<Instr>, homes: &[Option<Operand>], locals: &[Local], labels: &mut usize, expr: &Expr) {
+    let mut work: Vec<ExprWork> = vec![ExprWork::Operands(expr)];
+    while let Some(next) = work.pop() {
+        let expr: &Expr = match next {
+            ExprWork::Operands(expr) => expr,
+            ExprWork::Op(expr) => {
+                generate_op(out, homes, locals, expr);
+                continue;
+            },
+            ExprWork::Instrs(mut instrs) => {
+                out.append(&mut instrs);
+                continue;
+            },
+        };
+        match &expr.kind {
+            // The rhs of `&&` and `||` is only computed if the lhs does not decide the result already, which is then 0 or 1
+            // in `rax` at the join.
+            ExprKind::Binary(op @ (BinOp::And | BinOp::Or), lhs, rhs) => {
+                let (name, prefix, decided): (&str, &str, Cond) = match op {
+                    BinOp::And => ("OpLogicalAnd", "_and_end", Cond::E),     // A false lhs makes the result 0
+                    _ => ("OpLogicalOr", "_or_end", Cond::Ne),               // A true lhs makes the result 1
+                };
+                let end: String = next_label(prefix, labels);
+                let mut test: Vec<Instr> = vec![Instr::Comment(format!("BinOp::{}", name))];
+                generate_truth(&mut test);
+                test.push(Instr::Jcc(decided, end.clone()));
+                let mut join: Vec<Instr> = Vec::new();
+                generate_truth(&mut join);
+                join.push(Instr::Label(end));
+                join.push(Instr::Push(reg(Reg::Rax)));
+                work.extend([ExprWork::Instrs(join), ExprWork::Operands(rhs), ExprWork::Instrs(test), ExprWork::Operands(lhs)]);
+            },
+            ExprKind::Unary(_, operand) => work.extend([ExprWork::Op(expr), ExprWork::Operands(operand)]),
+            ExprKind::Binary(_, lhs, rhs) => work.extend([ExprWork::Op(expr), ExprWork::Operands(rhs), ExprWork::Operands(lhs)]),
+            ExprKind::Intrinsic(_, args) => {
+                work.push(ExprWork::Op(expr));
+                work.extend(args.iter().rev().map(ExprWork::Operands));
+            },
+            _ => generate_op(out, homes, locals, expr),
+        }
+    }
+}
+
+// Combines the values of an expression's operands, which are atop the stack with the last one pushed last.
+fn generate_op(out: &mut Vec<Instr>, homes: &[Option<Operand>], locals: &[Local], expr: &Expr) {
+    match &expr.kind {
+        ExprKind::FuncAddr(label) => {
+            out.push(Instr::Comment(format!("FuncAddr {}", label)));
+            out.push(Instr::Mov(reg(Reg::Rax), Operand::Label(label.clone())));
+            out.push(Instr::Push(reg(Reg::Rax)));
+        },
+        ExprKind::Literal(val) => {
+            out.push(Instr::Comment(format!("Literal {}", val)));
+            out.push(Instr::Mov(reg(Reg::Rax), imm(*val)));
+            out.push(Instr::Push(reg(Reg::Rax)));
+        },
+        ExprKind::Local(local) => {
+            out.push(Instr::Comment(format!("Var {}", locals[*local].name)));
+            out.push(Instr::Mov(reg(Reg::Rax), home(homes, *local)));
+            out.push(Instr::Push(reg(Reg::Rax)));
+        },
+        // `min`, `max` and `abs` are branchless, the result is picked with a conditional move rather than a jump. `rand` and
+        // `time_ns` call their routine in the prelude, which returns in `rax`.
+        ExprKind::Intrinsic(intrinsic, _) => {
+            out.push(Instr::Comment(format!("Intrinsic {}", intrinsic.name())));
+            match intrinsic {
+                Intrinsic::Min | Intrinsic::Max => {
+                    let cond: Cond = if *intrinsic == Intrinsic::Min { Cond::G } else { Cond::L };
+                    out.push(Instr::Pop(reg(Reg::Rbx)));
+                    out.push(Instr::Pop(reg(Reg::Rax)));
+                    out.push(Instr::Cmp(reg(Reg::Rax), reg(Reg::Rbx)));
+                    out.push(Instr::Cmov(cond, reg(Reg::Rax), reg(Reg::Rbx)));
+                },
+                Intrinsic::Abs => {
+                    // `neg` compares 0 with the value, so if the value was greater keep it rather than its negation.
+                    out.push(Instr::Pop(reg(Reg::Rax)));
+                    out.push(Instr::Mov(reg(Reg::Rbx), reg(Reg::Rax)));
+                    out.push(Instr::Neg(reg(Reg::Rax)));
+                    out.push(Instr::Cmov(Cond::L, reg(Reg::Rax), reg(Reg::Rbx)));
+                },
+                Intrinsic::Rand | Intrinsic::TimeNs => out.push(Instr::Call(intrinsic.name().to_string())),
+            }
+            out.push(Instr::Push(reg(Reg::Rax)));
+        },
+        // Functions return in `rax`, and save every register a caller's locals may live in.
+        ExprKind::Call(Callee::Label(label)) => {
+            out.push(Instr::Comment(format!("FuncCall {}", label)));
+            out.push(Instr::Call(label.clone()));
+            out.push(Instr::Push(reg(Reg::Rax)));
+        },
+        ExprKind::Call(Callee::Local(local)) => {
+            out.push(Instr::Comment(format!("FuncCall {}", locals[*local].name)));
+            out.push(Instr::Mov(reg(Reg::Rax), home(homes, *local)));
+            out.push(Instr::CallIndirect(reg(Reg::Rax)));
+            out.push(Instr::Push(reg(Reg::Rax)));
+        },
+        ExprKind::Unary(UnOp::Neg, _) => {
+            out.push(Instr::Comment("UnOp::OpMinus".to_string()));
+            out.push(Instr::Pop(reg(Reg::Rax)));
+            out.push(Instr::Neg(reg(Reg::Rax)));
+            out.push(Instr::Push(reg(Reg::Rax)));
+        },
+        ExprKind::Binary(op, _, _) => {
+            match op {
+                BinOp::Add => {
+                    out.push(Instr::Comment("BinOp::OpPlus".to_string()));
+                    out.push(Instr::Pop(reg(Reg::Rax)));
+                    out.push(Instr::Pop(reg(Reg::Rbx)));
+                    out.push(Instr::Add(reg(Reg::Rax), reg(Reg::Rbx)));
+                    out.push(Instr::Push(reg(Reg::Rax)));
+                },
+                BinOp::Sub => {
+                    out.push(Instr::Comment("BinOp::OpMinus".to_string()));
+                    out.push(Instr::Pop(reg(Reg::Rbx)));
+                    out.push(Instr::Pop(reg(Reg::Rax)));
+                    out.push(Instr::Sub(reg(Reg::Rax), reg(Reg::Rbx)));
+                    out.push(Instr::Push(reg(Reg::Rax)));
+                },
+                BinOp::Mul => {
+                    out.push(Instr::Comment("BinOp::OpMul".to_string()));
+                    out.push(Instr::Pop(reg(Reg::Rax)));
+                    out.push(Instr::Pop(reg(Reg::Rbx)));
+                    out.push(Instr::Imul(reg(Reg::Rax), reg(Reg::Rbx)));
+                    out.push(Instr::Push(reg(Reg::Rax)));
+                },
+                BinOp::Div => {
+                    out.push(Instr::Comment("BinOp::OpDiv".to_string()));
+                    out.push(Instr::Pop(reg(Reg::Rcx)));
+                    out.push(Instr::Pop(reg(Reg::Rax)));
+                    out.push(Instr::Cqo);
+                    out.push(Instr::Idiv(reg(Reg::Rcx)));
+                    out.push(Instr::Push(reg(Reg::Rax)));
+                },
+                // `idiv` leaves the remainder in `rdx`, with the sign of the dividend.
+                BinOp::Mod => {
+                    out.push(Instr::Comment("BinOp::OpMod".to_string()));
+                    out.push(Instr::Pop(reg(Reg::Rcx)));
+                    out.push(Instr::Pop(reg(Reg::Rax)));
+                    out.push(Instr::Cqo);
+                    out.push(Instr::Idiv(reg(Reg::Rcx)));
+                    out.push(Instr::Push(reg(Reg::Rdx)));
+                },
+                BinOp::Less => generate_comparison(out, "OpLessThan", Cond::L),
+                BinOp::LessEqual => generate_comparison(out, "OpLessEqual", Cond::Le),
+                BinOp::Greater => generate_comparison(out, "OpGreaterThan", Cond::G),
+                BinOp::GreaterEqual => generate_comparison(out, "OpGreaterEqual", Cond::Ge),
+                BinOp::Equal => generate_comparison(out, "OpEqual", Cond::E),
+                BinOp::NotEqual => generate_comparison(out, "OpNotEqual", Cond::Ne),
+                BinOp::Or | BinOp::And => panic!("Error: `{:?}` is generated along with its operands", op),
+            }
+        },
+    }
+}
+
+// Where a local lives. Lowering resolved every use to a declaration before it, so it always has a home by now.
+fn home(homes: &[Option<Operand>], local: LocalId) -> Operand {
+    homes[local].clone().expect("Error: Local is used before it is declared")
+}
+
+// Jumps to `target` when the guard `FunctionGen::guard` generated is false.
+fn skip_unless(out: &mut Vec<Instr>, compared: Option<Cond>, target: &str) {
+    match compared {
+        Some(cond) => {
+            out.push(Instr::Pop(reg(Reg::Rbx)));
+            out.push(Instr::Pop(reg(Reg::Rax)));
+            out.push(Instr::Cmp(reg(Reg::Rax), reg(Reg::Rbx)));
+            out.push(Instr::Jcc(cond.inverse(), target.to_string()));
+        },
+        None => {
+            out.push(Instr::Pop(reg(Reg::Rax)));
+            out.push(Instr::Cmp(reg(Reg::Rax), imm(0)));
+            out.push(Instr::Jcc(Cond::E, target.to_string()));
+        },
+    }
+}
+
+// What is left of the statements being generated, the next to generate last.
+enum Work<'b> {
+    Block(&'b Block),
+    Stmt(&'b Stmt),
+    Instr(Instr),
+    Leave(i64), // The end of a block, and the stack index of the block around it
+}
+
+// The state of one function while its statements are generated.
+struct FunctionGen<'a> {
+    out: Vec<Instr>,
+    homes: Vec<Option<Operand>>, // Register or stack slot of each local in scope
+    locals: &'a [Local],
+    labels: &'a mut usize,
+    opt: OptLevel,
+    alloc: &'a Allocation,
+    rodata: &'a mut Vec<(String, Vec<i64>)>,
+    main: bool, // Exits rather than returning
+}
+impl FunctionGen<'_> {
+    fn expr(&mut self, expr: &Expr) {
+        generate_expr(&mut self.out, &self.homes, self.locals, self.labels, expr);
+    }
+
+    // A comparison at the root of a guard is left as its two operands for `skip_unless` to compare, so the branch is taken
+    // on the flags it sets rather than on a 0 or 1 compared with 0 again.
+    fn guard(&mut self, guard: &Expr) -> Option<Cond> {
+        match &guard.kind {
+            ExprKind::Binary(op, lhs, rhs) if self.opt >= OptLevel::O1 && let Some(cond) = comparison(*op) => {
+                self.expr(lhs);
+                self.expr(rhs);
+                Some(cond)
+            },
+            _ => {
+                self.expr(guard);
+                None
+            },
+        }
+    }
+
+    // Brings a local into scope, in its register if it was given one and otherwise in the stack slot its value was pushed to.
+    fn var_decl(&mut self, stack_ix: &mut i64, local: LocalId) {
+        self.out.push(Instr::Comment(format!("VarDecl {}", self.locals[local].name)));
+        match self.alloc.get(local) {
+            Some(r) => {
+                self.out.push(Instr::Pop(reg(Reg::Rax)));
+                self.out.push(Instr::Mov(reg(r), reg(Reg::Rax)));
+                self.homes[local] = Some(reg(r));
+            },
+            None => {
+                // NOTE: This relies on the variable value being atop the stack already.
+                self.homes[local] = Some(mem(Reg::Rbp, *stack_ix));
+                *stack_ix -= 8;
+            },
+        }
+    }
+
+    // Statements are generated from a worklist rather than by recursion, like expressions are, so nested conditionals and
+    // inlined bodies cannot overflow the compiler's stack either.
+    fn body(&mut self, mut stack_ix: i64, body: &Block) {
+        let mut work: Vec<Work> = vec![Work::Block(body)];
+        while let Some(next) = work.pop() {
+            match next {
+                Work::Block(block) => {
+                    work.push(Work::Leave(stack_ix));
+                    work.extend(block.iter().rev().map(Work::Stmt));
+                },
+                Work::Stmt(stmt) => self.stmt(&mut work, &mut stack_ix, stmt),
+                Work::Instr(instr) => self.out.push(instr),
+                // Block locals go out of scope here, the slots they used are handed back to the enclosing block.
+                // NOTE: Measured from the stack index rather than the number of names, so the next declaration reuses exactly the freed slots.
+                Work::Leave(outer_ix) => {
+                    self.out.push(Instr::Comment("Deallocate block locals".to_string()));
+                    self.out.push(Instr::Add(reg(Reg::Rsp), imm(outer_ix - stack_ix)));
+                    stack_ix = outer_ix;
+                },
+            }
+        }
+    }
+
+    fn stmt<'b>(&mut self, work: &mut Vec<Work<'b>>, stack_ix: &mut i64, stmt: &'b Stmt) {
+        match stmt {
+            Stmt::If { guard, known, then, els } => {
+                // A guard that is known at compile time only needs the branch it selects.
+                if self.opt >= OptLevel::O1 && let Some(val) = known {
+                    self.out.push(Instr::Comment(format!("Conditional (always {})", *val != 0)));
+                    match (*val != 0, els) {
+                        (true, _) => work.push(Work::Block(then)),
+                        (false, Some(els)) => work.push(Work::Block(els)),
+                        (false, None) => {},
+                    }
+                    return;
+                }
+
+                self.out.push(Instr::Comment("Conditional".to_string()));
+                let compared: Option<Cond> = self.guard(guard);
+                let id: usize = *self.labels;
+                self.out.push(Instr::Label(next_label("_if", self.labels)));
+                let end_label: String = format!("_end_{}", id);
+                match els {
+                    None => {
+                        self.out.push(Instr::Comment("If (No Else)".to_string()));
+                        skip_unless(&mut self.out, compared, &end_label);
+                        work.extend([Work::Instr(Instr::Label(end_label)), Work::Block(then)]);
+                    },
+                    Some(els) => {
+                        let false_label: String = format!("_false_{}", id);
+                        self.out.push(Instr::Comment("If".to_string()));
+                        skip_unless(&mut self.out, compared, &false_label);
+                        // Pushed in reverse, the last is generated first.
+                        work.extend([
+                            Work::Instr(Instr::Label(end_label.clone())),
+                            Work::Block(els),
+                            Work::Instr(Instr::Label(false_label)),
+                            Work::Instr(Instr::Comment("Else".to_string())),
+                            Work::Instr(Instr::Jmp(end_label)),
+                            Work::Block(then),
+                        ]);
+                    }
+                }
+            },
+            // The guard is tested before every run of the body, which jumps back to it.
+            Stmt::While { guard, body } => {
+                let id: usize = *self.labels;
+                let start_label: String = next_label("_while", self.labels);
+                let end_label: String = format!("_done_{}", id);
+                self.out.push(Instr::Comment("While".to_string()));
+                self.out.push(Instr::Label(start_label.clone()));
+                let compared: Option<Cond> = self.guard(guard);
+                skip_unless(&mut self.out, compared, &end_label);
+                work.extend([Work::Instr(Instr::Label(end_label)), Work::Instr(Instr::Jmp(start_label)), Work::Block(body)]);
+            },
+            Stmt::Block(block) => work.push(Work::Block(block)),
+            Stmt::Let(local, init) => {
+                self.expr(init);
+                self.var_decl(stack_ix, *local);
+            },
+            // Values that are all known at compile time are printed ahead of time into `.rodata`, which one `write` outputs.
+            // NOTE: `dump` writes straight to stdout without buffering, so the output stays in order with the others.
+            Stmt::Dump { known: Some(known), .. } if self.opt >= OptLevel::O2 => {
+                let label: String = next_label("_dump", self.labels);
+                let len: usize = known.iter().map(|value| (*value as u64).to_string().len() + 1).sum();
+                self.out.push(Instr::Comment(format!("DebugDump (always {:?})", known)));
+                self.out.push(Instr::Mov(reg(Reg::Rax), imm(1)));
+                self.out.push(Instr::Mov(reg(Reg::Rdi), imm(1)));
+                self.out.push(Instr::Mov(reg(Reg::Rsi), Operand::Label(label.clone())));
+                self.out.push(Instr::Mov(reg(Reg::Rdx), imm(len as i64)));
+                self.out.push(Instr::Syscall);
+                self.rodata.push((label, known.clone()));
+            },
+            // Each value is dumped once it is computed. All but the last go through `dump_item`, which ends them with the
+            // program's separator rather than a newline.
+            Stmt::Dump { values, .. } => {
+                for (ix, value) in values.iter().enumerate() {
+                    self.expr(value);
+                    let routine: &str = if ix + 1 == values.len() { "dump" } else { "dump_item" };
+                    self.out.push(Instr::Comment("DebugDump".to_string()));
+                    self.out.push(Instr::Pop(reg(Reg::Rdi)));
+                    self.out.push(Instr::Call(routine.to_string()));
+                }
+            },
+            Stmt::Assign(local, value) => {
+                self.expr(value);
+                self.out.push(Instr::Comment(format!("Assign {}", self.locals[*local].name)));
+                self.out.push(Instr::Pop(reg(Reg::Rax)));
+                self.out.push(Instr::Mov(home(&self.homes, *local), reg(Reg::Rax)));
+            },
+            Stmt::Exit(value) => {
+                self.expr(value);
+                self.out.push(Instr::Comment("Exit".to_string()));
+                self.out.push(Instr::Pop(reg(Reg::Rdi)));
+                self.out.push(Instr::Mov(reg(Reg::Rax), imm(60)));
+                self.out.push(Instr::Syscall);
+            },
+            // `main` has nothing to return to, so the value is its exit status.
+            Stmt::Return(value) if self.main => {
+                self.expr(value);
+                self.out.push(Instr::Comment("Return main".to_string()));
+                self.out.push(Instr::Pop(reg(Reg::Rdi)));
+                self.out.push(Instr::Mov(reg(Reg::Rax), imm(60)));
+                self.out.push(Instr::Syscall);
+            },
+            Stmt::Return(value) => {
+                self.expr(value);
+                self.out.push(Instr::Comment("Return".to_string()));
+                self.out.push(Instr::Pop(reg(Reg::Rax)));
+                self.out.extend(epilogue(self.alloc));
+            },
+            Stmt::Call(Callee::Label(label)) => {
+                self.out.push(Instr::Comment(format!("FuncCall {}", label)));
+                self.out.push(Instr::Call(label.clone()));
+            },
+            Stmt::Call(Callee::Local(local)) => {
+                self.out.push(Instr::Comment(format!("FuncCall {}", self.locals[*local].name)));
+                self.out.push(Instr::Mov(reg(Reg::Rax), home(&self.homes, *local)));
+                self.out.push(Instr::CallIndirect(reg(Reg::Rax)));
+            },
+        }
+    }
+}
+
+// The deepest the stack slots of a block's locals go, counting nested blocks on top of the locals declared before them.
+fn slots_needed(block: &Block, alloc: &Allocation) -> i64 {
+    let mut work: Vec<(&Block, i64)> = vec![(block, 0)]; // Each block to measure, and the depth of the slots below it
+    let mut max: i64 = 0;
+    while let Some((block, mut depth)) = work.pop() {
+        for stmt in block {
+            match stmt {
+                Stmt::Let(local, _) if alloc.get(*local).is_none() => depth += 8,
+                Stmt::Block(inner) => work.push((inner, depth)),
+                Stmt::If { then, els, .. } => work.extend([Some(then), els.as_ref()].into_iter().flatten().map(|body| (body, depth))),
+                Stmt::While { body, .. } => work.push((body, depth)),
+                _ => {},
+            }
+            max = max.max(depth);
+        }
+    }
+    max
+}
+
+// Frees the frame and gives back the caller's registers, leaving `rax` as it is for the value returned.
+fn epilogue(alloc: &Allocation) -> Vec<Instr> {
+    let mut body: Vec<Instr> = vec![Instr::Mov(reg(Reg::Rsp), reg(Reg::Rbp))];
+    let saved: i64 = alloc.used.len() as i64 * 8;
+    if saved > 0 {
+        body.push(Instr::Sub(reg(Reg::Rsp), imm(saved)));
+        for r in alloc.used.iter().rev() {
+            body.push(Instr::Pop(reg(*r)));
+        }
+    }
+    body.push(Instr::Pop(reg(Reg::Rbp)));
+    body.push(Instr::Ret);
+    body
+}
+
+// Constant dumps are added to `rodata`, to be printed with the rest of the program.
+pub fn generate_function(func: &Function, labels: &mut usize, opt: OptLevel, rodata: &mut Vec<(String, Vec<i64>)>) -> AsmFunction {
+    let mut body: Vec<Instr> = Vec::new();
+    body.push(Instr::Comment(format!("Prologue {}", func.label)));
+    body.push(Instr::Push(reg(Reg::Rbp)));
+    body.push(Instr::Mov(reg(Reg::Rbp), reg(Reg::Rsp)));
+
+    // Registers given to locals belong to the caller too, so they are saved below the frame pointer.
+    let alloc: Allocation = if opt >= OptLevel::O2 { regalloc::allocate(func) } else { Allocation::default() };
+    for r in &alloc.used {
+        body.push(Instr::Push(reg(*r)));
+    }
+    let saved: i64 = alloc.used.len() as i64 * 8;
+
+    let stack_ix: i64 = -8 - saved; // after function prologue, first slot is at stack pointer - 8
+    let main: bool = func.label == mangle("main");
+    let mut state: FunctionGen = FunctionGen { out: body, homes: vec![None; func.locals.len()], locals: &func.locals, labels, opt, alloc: &alloc, rodata, main };
+    state.body(stack_ix, &func.body);
+    let mut body: Vec<Instr> = state.out;
+    let frame_size: i64 = saved + slots_needed(&func.body, &alloc);
+    let spills: usize = (0..func.locals.len()).filter(|local| alloc.get(*local).is_none()).count();
+
+    // Running off the end of `main` ends the program with status 0, there is nothing to return to.
+    if main {
+        body.push(Instr::Comment("Implicit exit main".to_string()));
+        body.push(Instr::Mov(reg(Reg::Rdi), imm(0)));
+        body.push(Instr::Mov(reg(Reg::Rax), imm(60)));
+        body.push(Instr::Syscall);
+        return AsmFunction { name: func.label.clone(), body, frame_size, spills };
+    }
+
+    // Running off the end of any other function returns 0.
+    body.push(Instr::Comment(format!("Epilogue {}", func.label)));
+    body.push(Instr::Mov(reg(Reg::Rax), imm(0)));
+    body.extend(epilogue(&alloc));
+
+    AsmFunction { name: func.label.clone(), body, frame_size, spills }
+}
+
+fn function_label(name: &str, func: &ParseNode) -> String {
+    if func.kind == NodeType::ExportFuncDecl { func.tok.val_str() } else { mangle(name) }
+}
+
+// The label of the function with the given qualified name, None if there is no such function.
+pub fn find_label(tree: &ParseTree, name: &str) -> Option<String> {
+    tree.functions().into_iter().find(|(qualified, _)| qualified == name).map(|(name, func)| function_label(&name, func))
+}
+
+// An entry point calling the function at `label` and exiting with status 0 once it returns.
+pub fn entry_function(label: &str) -> AsmFunction {
+    AsmFunction {
+        name: "_start".to_string(),
+        body: vec![
+            Instr::Call(label.to_string()),
+            Instr::Mov(reg(Reg::Rdi), imm(0)),
+            Instr::Mov(reg(Reg::Rax), imm(60)),
+            Instr::Syscall,
+        ],
+        frame_size: 0,
+        spills: 0,
+    }
+}
+
+fn label_calls(node: &mut ParseNode, module: Option<&str>, labels: &HashMap<String, String>) {
+    if matches!(node.kind, NodeType::FuncCall | NodeType::FuncAddr) {
+        let name: String = qualify(&node.tok.val_str(), module, |name| labels.contains_key(name));
+        if let Some(label) = labels.get(&name) {
+            node.tok.val = label.clone().into_bytes();
+        }
+    }
+    for child in &mut node.children {
+        label_calls(child, module, labels);
+    }
+}
+
+// Lifts the functions of modules to the top level and names every function and call by its label, so later stages
+// only see plain functions. Labels are the mangled qualified names, except for `export` functions which keep their own.
+// NOTE: Calls through a variable keep its name, sema makes sure no function visible from there or label shares it.
+pub fn label_functions(tree: &ParseTree) -> ParseTree {
+    let labels: HashMap<String, String> = tree.functions().into_iter().map(|(name, func)| (name.clone(), function_label(&name, func))).collect();
+    let mut res: ParseTree = tree.clone();
+    res.root.children = tree.functions().into_iter().map(|(name, func)| {
+        let mut func: ParseNode = func.clone();
+        label_calls(&mut func, name.rsplit_once('.').map(|(module, _)| module), &labels);
+        func.tok.val = labels[&name].clone().into_bytes();
+        func
+    }).collect();
+    res
+}
+
+// Lowers the parse tree through the HIR into per-function instruction lists, ready to be optimised or printed.
+pub fn generate_program(ast: &ParseTree, opt: OptLevel) -> Result<AsmProgram, Diagnostic> {
+    let labelled: ParseTree = label_functions(ast);
+    let ast: &ParseTree = &labelled;
+    let optimised: ParseTree;
+    let ast: &ParseTree = if opt >= OptLevel::O2 {
+        // NOTE: Inlining copies a body into every caller, which `-Os` does not trade size for.
+        let inlined: ParseTree = if opt == OptLevel::Os { ast.clone() } else { inline_functions(ast) };
+        optimised = eliminate_common_subexpressions(&inlined);
+        &optimised
+    } else {
+        ast
+    };
+    let hir: Vec<Function> = hir::lower(ast)?;
+    let mut labels: usize = 0;
+    let mut rodata: Vec<(String, Vec<i64>)> = Vec::new();
+    let mut funcs: Vec<AsmFunction> = hir.iter().map(|func| generate_function(func, &mut labels, opt, &mut rodata)).collect();
+    if opt >= OptLevel::O1 {
+        // NOTE: Threading first drops labels that would otherwise split the patterns copy propagation looks for.
+        funcs.iter_mut().for_each(thread_jumps);
+        funcs.iter_mut().for_each(propagate_copies);
+    }
+    // NOTE: `main` never returns, it exits at its end, so `call` is only used to align the stack like any other call.
+    let entry: AsmFunction = AsmFunction {
+        name: "_start".to_string(),
+        body: vec![
+            Instr::Call(mangle("main")),
+        ],
+        frame_size: 0,
+        spills: 0,
+    };
+    let exports: Vec<String> = hir.iter().filter(|func| func.public).map(|func| func.label.clone()).collect();
+    Ok(AsmProgram { funcs, entry, exports, rodata, dump_same_line: false, note: None })
+}
+
+// Writes the assembly for a whole program to any writer, e.g. a file, a socket or an in-memory buffer.
+// NOTE: Lowering errors are reported by `generate_program`, so only writing can fail here.
+pub fn generate_nasm_x86(f: &mut impl Write, prog: &AsmProgram) -> std::io::Result<()> {
+    nasm::print_program(f, prog)
+}
+
+pub fn generate_nasm_x86_file(out_path: &str, prog: &AsmProgram) -> std::io::Result<()> {
+    let mut f: BufWriter<fs::File> = BufWriter::new(fs::File::create(out_path)?);
+    generate_nasm_x86(&mut f, prog)?;
+    f.flush()
+}
+
+pub fn generate_nasm_x86_string(prog: &AsmProgram) -> String {
+    let mut buf: Vec<u8> = Vec::new();
+    generate_nasm_x86(&mut buf, prog).expect("Error: Writing assembly to memory cannot fail");
+    String::from_utf8(buf).expect("Error: Generated assembly is not valid UTF-8")
+}
@@ -0,0 +1,104 @@
+// Evaluates expressions made only of literals, for folding and for anything else that needs a value at compile time.
+use crate::lexer::Pos;
+use crate::lexer::TokenType;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum ConstError {
+    NotConstant, // Reads a variable or calls something whose value is only known at runtime
+    Overflow(Pos, String), // Position and spelling of the operator whose result does not fit in an i64
+    DivideByZero(Pos),
+}
+impl ConstError {
+    pub fn message(&self) -> Option<String> {
+        match self {
+            ConstError::NotConstant => None,
+            ConstError::Overflow(_, op) => Some(format!("`{}` overflows a 64 bit integer", op)),
+            ConstError::DivideByZero(_) => Some("Division by zero".to_string()),
+        }
+    }
+
+    pub fn pos(&self) -> Option<&Pos> {
+        match self {
+            ConstError::NotConstant => None,
+            ConstError::Overflow(pos, _) | ConstError::DivideByZero(pos) => Some(pos),
+        }
+    }
+}
+
+fn checked(val: Option<i64>, node: &ParseNode) -> Result<i64, ConstError> {
+    val.ok_or_else(|| ConstError::Overflow(node.tok.pos.clone(), node.tok.val_str()))
+}
+
+// Evaluates an expression over i64. A fault anywhere inside it is reported even when the whole is not constant, so
+// `x + 1 / 0` still gives `DivideByZero`.
+pub fn evaluate(node: &ParseNode) -> Result<i64, ConstError> {
+    match node.kind {
+        NodeType::Literal => node.tok.value.ok_or(ConstError::NotConstant),
+        NodeType::UnOp => {
+            let val: i64 = evaluate(node.children.first().ok_or(ConstError::NotConstant)?)?;
+            match node.tok.kind {
+                TokenType::OpMinus => checked(val.checked_neg(), node),
+                _ => Err(ConstError::NotConstant),
+            }
+        },
+        NodeType::Intrinsic => {
+            let args: Vec<i64> = operands(node.children.iter().map(evaluate).collect())?;
+            match (node.tok.val.as_slice(), &args[..]) {
+                (b"min", [a, b]) => Ok(*a.min(b)),
+                (b"max", [a, b]) => Ok(*a.max(b)),
+                (b"abs", [a]) => checked(a.checked_abs(), node),
+                _ => Err(ConstError::NotConstant),
+            }
+        },
+        NodeType::BinOp => {
+            let lhs: Result<i64, ConstError> = evaluate(node.children.first().ok_or(ConstError::NotConstant)?);
+            // The rhs of `&&` and `||` does not run once the lhs decides the result, so it need not be known or even valid.
+            match (&node.tok.kind, &lhs) {
+                (TokenType::OpLogicalAnd, Ok(0)) => return Ok(0),
+                (TokenType::OpLogicalOr, Ok(val)) if *val != 0 => return Ok(1),
+                _ => {},
+            }
+            let rhs: Result<i64, ConstError> = evaluate(node.children.get(1).ok_or(ConstError::NotConstant)?);
+            if matches!(node.tok.kind, TokenType::OpDiv | TokenType::OpMod) && matches!(rhs, Ok(0)) {
+                return Err(ConstError::DivideByZero(node.tok.pos.clone()));
+            }
+            let vals: Vec<i64> = operands(vec![lhs, rhs])?;
+            let (lhs, rhs) = (vals[0], vals[1]);
+            match node.tok.kind {
+                TokenType::OpPlus => checked(lhs.checked_add(rhs), node),
+                TokenType::OpMinus => checked(lhs.checked_sub(rhs), node),
+                TokenType::OpMul => checked(lhs.checked_mul(rhs), node),
+                TokenType::OpDiv => checked(lhs.checked_div(rhs), node),
+                TokenType::OpMod => checked(lhs.checked_rem(rhs), node),
+                TokenType::OpLessThan => Ok((lhs < rhs) as i64),
+                TokenType::OpLessEqual => Ok((lhs <= rhs) as i64),
+                TokenType::OpGreaterThan => Ok((lhs > rhs) as i64),
+                TokenType::OpGreaterEqual => Ok((lhs >= rhs) as i64),
+                TokenType::OpEqual => Ok((lhs == rhs) as i64),
+                TokenType::OpNotEqual => Ok((lhs != rhs) as i64),
+                TokenType::OpLogicalOr => Ok((lhs != 0 || rhs != 0) as i64),
+                TokenType::OpLogicalAnd => Ok((lhs != 0 && rhs != 0) as i64),
+                _ => Err(ConstError::NotConstant),
+            }
+        },
+        _ => Err(ConstError::NotConstant),
+    }
+}
+
+// The values of all operands, or the first fault among them, or `NotConstant` if one only has a value at runtime.
+fn operands(vals: Vec<Result<i64, ConstError>>) -> Result<Vec<i64>, ConstError> {
+    if let Some(fault) = vals.iter().find_map(|val| val.as_ref().err().filter(|err| !matches!(err, ConstError::NotConstant))) {
+        return Err(fault.clone());
+    }
+    vals.into_iter().collect()
+}
+
+// The value of an expression if it is known at compile time and computing it cannot fault or overflow.
+// NOTE: Overflowing expressions are left to wrap at runtime like the emitted `add`/`imul` do, so folding never
+// changes program output.
+pub fn eval_const(node: &ParseNode) -> Option<i64> {
+    evaluate(node).ok()
+}
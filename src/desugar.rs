@@ -0,0 +1,107 @@
+// Rewrites the surface syntax of a program into the smaller core node set the checks and backends handle.
+// NOTE: The formatter and the language server work on the tree as parsed, so shorthand is kept until this runs.
+use crate::lexer::Pos;
+use crate::lexer::Token;
+use crate::lexer::TokenType;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+
+// Rewrites one surface construct in place. Nodes it does not rewrite are left as they are.
+type Rule = fn(&mut ParseNode);
+
+// Applied to every node after its children, so a rule only ever sees core nodes below it.
+const RULES: [Rule; 4] = [
+    compound_assign,
+    else_if,
+    assert,
+    for_loop,
+];
+
+// The status a program exits with when an `assert` fails, which the `test` subcommand reports as a failed assertion.
+pub const ASSERT_FAILED_STATUS: u8 = 134;
+
+pub fn desugar(tree: &mut ParseTree) {
+    lower(&mut tree.root);
+}
+
+fn lower(node: &mut ParseNode) {
+    node.children.iter_mut().for_each(lower);
+    for rule in RULES {
+        rule(node);
+    }
+}
+
+// `a += b` already holds `a + b`, so only the kind changes.
+fn compound_assign(node: &mut ParseNode) {
+    if node.kind == NodeType::CompoundAssign {
+        node.kind = NodeType::Assign;
+    }
+}
+
+// `else if c { .. }` becomes `else { if c { .. } }`.
+fn else_if(node: &mut ParseNode) {
+    if node.kind == NodeType::Conditional && node.children.get(2).is_some_and(|els| els.kind == NodeType::Conditional) {
+        let cond: ParseNode = node.children.pop().expect("Error: Conditional has no else branch");
+        node.children.push(ParseNode::new_block(vec![cond]));
+    }
+}
+
+// `assert c;` becomes `if c == 0 { exit 134; }`, positioned at the `assert` so the checks can tell it apart.
+fn assert(node: &mut ParseNode) {
+    if node.kind != NodeType::Assert {
+        return;
+    }
+    let cond: ParseNode = node.children.pop().expect("Error: Assert has no condition");
+    let eq: Token = synthetic(TokenType::OpEqual, "==", None);
+    let guard: ParseNode = ParseNode::new_bin_op(eq, cond, ParseNode::new_literal(synthetic(TokenType::LiteralInt, "0", Some(0))));
+    let status: ParseNode = ParseNode::new_literal(synthetic(TokenType::LiteralInt, &ASSERT_FAILED_STATUS.to_string(), Some(ASSERT_FAILED_STATUS as i64)));
+    let fail: ParseNode = ParseNode::new_block(vec![ParseNode::new_exit(synthetic(TokenType::KeywordExit, "exit", None), Some(status))]);
+    *node = ParseNode::new_conditional(node.tok.clone(), guard, fail, None);
+}
+
+// `for i = a to b { .. }` becomes
+// `{ let i = a; let for.i = b; let for.i.more = i <= for.i; while for.i.more { { .. } for.i.more = i < for.i; i = i + for.i.more; } }`,
+// so the last value is computed once and the loop variable is a local of a block around the loop, gone after it.
+// The test comes before the step, so a loop up to the largest integer ends there rather than overflowing and going on.
+// NOTE: `for.i` cannot be spelled as an identifier, and loops nested in each other count different variables, so it is unique
+// within its scope. Its uses are positioned nowhere, as the source never names it.
+fn for_loop(node: &mut ParseNode) {
+    if node.kind != NodeType::For {
+        return;
+    }
+    let mut parts = std::mem::take(&mut node.children).into_iter();
+    let (Some(first), Some(last), Some(body)) = (parts.next(), parts.next(), parts.next()) else {
+        panic!("{} Error: For loop is missing its bounds or body", node.tok.pos);
+    };
+    let var = |kind: TokenType, val: &str| synthetic(kind, val, None);
+    let name: String = node.tok.val_str();
+    let bound: String = format!("for.{}", name);
+    let more: String = format!("for.{}.more", name);
+    let compare = |kind: TokenType, op: &str| ParseNode::new_bin_op(
+        var(kind, op),
+        ParseNode::new_var(var(TokenType::Identifier, &name)),
+        ParseNode::new_var(var(TokenType::Identifier, &bound)),
+    );
+    let step: ParseNode = ParseNode::new_bin_op(
+        var(TokenType::OpPlus, "+"),
+        ParseNode::new_var(var(TokenType::Identifier, &name)),
+        ParseNode::new_var(var(TokenType::Identifier, &more)),
+    );
+    let looped: ParseNode = ParseNode::new_while(var(TokenType::KeywordFor, "for"), ParseNode::new_var(var(TokenType::Identifier, &more)), ParseNode::new_block(vec![
+        body,
+        ParseNode::new_assign(var(TokenType::Identifier, &more), compare(TokenType::OpLessThan, "<")),
+        ParseNode::new_assign(var(TokenType::Identifier, &name), step),
+    ]));
+    *node = ParseNode::new_block(vec![
+        ParseNode::new_var_decl(node.tok.clone(), Some(first)),
+        ParseNode::new_var_decl(var(TokenType::Identifier, &bound), Some(last)),
+        ParseNode::new_var_decl(var(TokenType::Identifier, &more), Some(compare(TokenType::OpLessEqual, "<="))),
+        looped,
+    ]);
+}
+
+// A token the source does not hold, positioned nowhere.
+fn synthetic(kind: TokenType, val: &str, value: Option<i64>) -> Token {
+    Token { kind, val: val.as_bytes().to_vec(), pos: Pos { col: usize::MAX - 1, row: usize::MAX - 1, offset: usize::MAX - 1 }, value }
+}
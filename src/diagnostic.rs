@@ -0,0 +1,177 @@
+use std::any::Any;
+use std::fmt;
+use std::io::Write;
+use std::panic;
+use crate::json::Json;
+use crate::lexer::Pos;
+use crate::source::FileId;
+use crate::source::SourceFile;
+use crate::source::SourceMap;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: Option<FileId>,
+    pub pos: Pos,
+    pub msg: String,
+}
+impl Diagnostic {
+    pub fn error(pos: Pos, msg: String) -> Self {
+        Diagnostic { severity: Severity::Error, file: None, pos, msg }
+    }
+
+    pub fn warning(pos: Pos, msg: String) -> Self {
+        Diagnostic { severity: Severity::Warning, file: None, pos, msg }
+    }
+
+    pub fn in_file(mut self, file: FileId) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    // Splits a compiler panic message of the form `[row:col] Error: msg` into its position and message.
+    pub fn from_message(msg: &str) -> Self {
+        let unknown: Pos = Pos { row: 0, col: 0, offset: 0 };
+        let fallback = || Diagnostic::error(unknown.clone(), msg.trim_start_matches("Error:").trim().to_string());
+        let Some((loc, text)) = msg.strip_prefix('[').and_then(|rest| rest.split_once(']')) else {
+            return fallback();
+        };
+        let text: String = text.trim_start().trim_start_matches("Error:").trim().to_string();
+        match loc.split_once(':').map(|(r, c)| (r.parse::<usize>(), c.parse::<usize>())) {
+            Some((Ok(row), Ok(col))) => Diagnostic::error(Pos { row: row.saturating_sub(1), col: col.saturating_sub(1), offset: 0 }, text),
+            _ => fallback(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("severity", Json::Str(self.label().to_lowercase())),
+            ("row", Json::Int(self.pos.row as i64 + 1)),
+            ("col", Json::Int(self.pos.col as i64 + 1)),
+            ("message", Json::Str(self.msg.clone())),
+        ])
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.pos, self.label(), self.msg)
+    }
+}
+impl fmt::Debug for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// Receives every diagnostic the pipeline reports, so embedders decide how errors are shown.
+pub trait DiagnosticSink {
+    fn emit(&mut self, diag: Diagnostic);
+}
+
+// Renders diagnostics for a person at a terminal, quoting the offending source line.
+pub struct TerminalSink<'a> {
+    sources: &'a SourceMap,
+}
+impl<'a> TerminalSink<'a> {
+    pub fn new(sources: &'a SourceMap) -> Self {
+        TerminalSink { sources }
+    }
+}
+impl DiagnosticSink for TerminalSink<'_> {
+    fn emit(&mut self, diag: Diagnostic) {
+        let colour: &str = match diag.severity {
+            Severity::Error => "\x1b[31m",
+            Severity::Warning => "\x1b[33m",
+        };
+        let file: Option<&SourceFile> = diag.file.map(|id| self.sources.get(id));
+        let name: &str = file.map(|f| f.name.as_str()).unwrap_or("");
+        eprintln!("{}{}{} {}:\x1b[0m {}", colour, name, diag.pos, diag.label(), diag.msg);
+        if let Some(line) = file.and_then(|f| f.line(diag.pos.row)) {
+            let gutter: String = format!("{}", diag.pos.row + 1);
+            eprintln!("    {} | {}", gutter, line);
+            eprintln!("    {:w$} | {:c$}{}^\x1b[0m", "", "", colour, w = gutter.len(), c = diag.pos.col);
+        }
+    }
+}
+
+// Writes one JSON object per diagnostic and line, for tools consuming compiler output.
+pub struct JsonSink<'a, W: Write> {
+    out: W,
+    sources: &'a SourceMap,
+}
+impl<'a, W: Write> JsonSink<'a, W> {
+    pub fn new(out: W, sources: &'a SourceMap) -> Self {
+        JsonSink { out, sources }
+    }
+}
+impl<W: Write> DiagnosticSink for JsonSink<'_, W> {
+    fn emit(&mut self, diag: Diagnostic) {
+        let mut json: Json = diag.to_json();
+        if let (Some(id), Json::Object(fields)) = (diag.file, &mut json) {
+            fields.insert(0, ("file".to_string(), Json::Str(self.sources.get(id).name.clone())));
+        }
+        let _ = writeln!(self.out, "{}", json);
+    }
+}
+
+// Keeps diagnostics in memory, for tests and library users.
+#[derive(Default)]
+pub struct CollectorSink {
+    pub diagnostics: Vec<Diagnostic>,
+}
+impl CollectorSink {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.is_error())
+    }
+}
+impl DiagnosticSink for CollectorSink {
+    fn emit(&mut self, diag: Diagnostic) {
+        self.diagnostics.push(diag);
+    }
+}
+
+// Passes a stage's result on, reporting its error against the file into the sink instead.
+pub fn report<T>(res: Result<T, Diagnostic>, file: FileId, sink: &mut dyn DiagnosticSink) -> Option<T> {
+    match res {
+        Ok(val) => Some(val),
+        Err(diag) => {
+            sink.emit(diag.in_file(file));
+            None
+        }
+    }
+}
+
+// Runs a stage and turns an internal compiler panic into a diagnostic, so long-running hosts survive bugs.
+// NOTE: Callers should install a silent panic hook if the default stderr report is unwanted.
+pub fn catch<T>(stage: impl FnOnce() -> Result<T, Diagnostic> + panic::UnwindSafe) -> Result<T, Diagnostic> {
+    panic::catch_unwind(stage).unwrap_or_else(|payload| Err(Diagnostic::from_message(&panic_message(payload.as_ref()))))
+}
+
+// The message a panic was raised with, from `panic!` with or without format arguments.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<String>() {
+        Some(s) => s.clone(),
+        None => payload.downcast_ref::<&str>().map(|s| s.to_string()).unwrap_or_else(|| "Unknown error".to_string()),
+    }
+}
@@ -0,0 +1,81 @@
+use crate::lexer::Lexer;
+use crate::lexer::Pos;
+use crate::parser::ParseTree;
+
+pub enum DocFormat {
+    Markdown,
+    Html,
+}
+
+static DOC_COMMENT: &str = "///";
+
+pub struct FuncDoc {
+    pub name: String,
+    pub pos: Pos,
+    pub lines: Vec<String>,
+}
+
+// Attaches runs of `///` comments, each alone on its line, to the function declared directly below them.
+pub fn collect(lexer: &Lexer, tree: &ParseTree) -> Vec<FuncDoc> {
+    let mut res: Vec<FuncDoc> = Vec::new();
+    for (name, func) in tree.functions() {
+        let mut lines: Vec<String> = Vec::new();
+        let mut row: usize = func.tok.pos.row;
+        while row > 0 {
+            row -= 1;
+            let Some(comment) = lexer.comments.iter().find(|c| c.pos.row == row) else {
+                break;
+            };
+            let text: String = comment.val_str();
+            let Some(text) = text.strip_prefix(DOC_COMMENT) else {
+                break;
+            };
+            if lexer.toks.iter().any(|tok| tok.pos.row == row) {
+                break;
+            }
+            lines.push(text.strip_prefix(' ').unwrap_or(text).trim_end().to_string());
+        }
+        lines.reverse();
+        res.push(FuncDoc { name, pos: func.tok.pos.clone(), lines });
+    }
+    res
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn render(title: &str, docs: &[FuncDoc], format: DocFormat) -> String {
+    let mut out: String = String::new();
+    match format {
+        DocFormat::Markdown => {
+            out.push_str(&format!("# {}\n", title));
+            for doc in docs {
+                out.push_str(&format!("\n## `{}`\n\n```\nfunc {}\n```\n\n", doc.name, doc.name));
+                if doc.lines.is_empty() {
+                    out.push_str("*Undocumented*\n");
+                }
+                for line in &doc.lines {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+                out.push_str(&format!("\nDefined at {}\n", doc.pos));
+            }
+        },
+        DocFormat::Html => {
+            out.push_str(&format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>\n", html_escape(title), html_escape(title)));
+            for doc in docs {
+                let name: String = html_escape(&doc.name);
+                out.push_str(&format!("<h2 id=\"{}\"><code>{}</code></h2>\n<pre>func {}</pre>\n", name, name, name));
+                if doc.lines.is_empty() {
+                    out.push_str("<p><em>Undocumented</em></p>\n");
+                } else {
+                    out.push_str(&format!("<p>{}</p>\n", doc.lines.iter().map(|l| html_escape(l)).collect::<Vec<String>>().join("\n")));
+                }
+                out.push_str(&format!("<p>Defined at {}</p>\n", doc.pos));
+            }
+            out.push_str("</body>\n</html>\n");
+        }
+    }
+    out
+}
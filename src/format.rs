@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+use crate::lexer::Lexer;
+use crate::lexer::Token;
+use crate::lexer::TokenType;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+
+static INDENT: &str = "    ";
+
+// Writes a parse tree back out as source in the canonical layout: four space indents, one item per line and
+// only the parentheses the grammar needs.
+// Comments are put back on their own line above the item that followed them, or after it if they ended its line,
+// and a single blank line is kept wherever the source had one or more.
+pub fn format_program(lexer: &Lexer, tree: &ParseTree) -> String {
+    let mut f: Formatter = Formatter {
+        out: String::new(),
+        comments: &lexer.comments,
+        next: 0,
+        rows: lexer.toks.iter().chain(lexer.comments.iter()).map(|tok| tok.pos.row).collect(),
+        closes: lexer.toks.iter().filter(|tok| tok.kind == TokenType::CloseScope).map(|tok| tok.pos.offset).collect(),
+    };
+    for (i, item) in tree.root.children.iter().enumerate() {
+        if i > 0 {
+            f.out.push('\n');
+        }
+        match item.kind {
+            NodeType::Module => f.module(item),
+            _ => {
+                f.function(item, 0);
+            },
+        }
+    }
+    f.comments_before(usize::MAX, 0);
+    f.out
+}
+
+struct Formatter<'a> {
+    out: String,
+    comments: &'a [Token],
+    next: usize, // The first comment not written yet
+    rows: HashSet<usize>, // Source lines holding a token or comment
+    closes: Vec<usize>, // Offsets of every `}`, in order
+}
+impl Formatter<'_> {
+    // Keeps an empty line of the source, unless it would open a block or double an empty line already written.
+    fn blank_line_before(&mut self, row: usize) {
+        if row > 0 && !self.rows.contains(&(row - 1)) && !self.out.is_empty() && !self.out.ends_with("\n\n") && !self.out.ends_with("{\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn comments_before(&mut self, row: usize, depth: usize) {
+        while let Some(comment) = self.comments.get(self.next) && comment.pos.row < row {
+            self.blank_line_before(comment.pos.row);
+            self.out.push_str(&INDENT.repeat(depth));
+            self.out.push_str(&comment.val_str());
+            self.out.push('\n');
+            self.next += 1;
+        }
+    }
+
+    fn trailing_comment(&mut self, row: usize) {
+        if let Some(comment) = self.comments.get(self.next) && comment.pos.row == row {
+            self.out.push(' ');
+            self.out.push_str(&comment.val_str());
+            self.next += 1;
+        }
+    }
+
+    // Writes a function and returns the offset of its closing brace.
+    fn function(&mut self, func: &ParseNode, depth: usize) -> usize {
+        let indent: String = INDENT.repeat(depth);
+        self.comments_before(func.tok.pos.row, depth);
+        self.blank_line_before(func.tok.pos.row);
+        let modifier: &str = match func.kind {
+            NodeType::PubFuncDecl => "pub ",
+            NodeType::ExportFuncDecl => "export ",
+            NodeType::TestFuncDecl => "test ",
+            _ => "",
+        };
+        self.out.push_str(&format!("{}{}func {} {{", indent, modifier, func.tok.val_str()));
+        self.trailing_comment(func.tok.pos.row);
+        self.out.push('\n');
+        let close: usize = self.block(&func.children[0], depth + 1, func.tok.pos.offset);
+        self.out.push_str(&format!("{}}}\n", indent));
+        close
+    }
+
+    fn module(&mut self, module: &ParseNode) {
+        self.comments_before(module.tok.pos.row, 0);
+        self.blank_line_before(module.tok.pos.row);
+        self.out.push_str(&format!("module {} {{", module.tok.val_str()));
+        self.trailing_comment(module.tok.pos.row);
+        self.out.push('\n');
+        let mut end: usize = module.tok.pos.offset;
+        for (i, func) in module.children.iter().enumerate() {
+            if i > 0 {
+                self.out.push('\n');
+            }
+            end = self.function(func, 1);
+        }
+        // Comments after the last function stay inside the module.
+        let close: usize = self.closes.iter().copied().find(|close| *close > end).unwrap_or(usize::MAX);
+        while let Some(comment) = self.comments.get(self.next) && comment.pos.offset < close {
+            self.blank_line_before(comment.pos.row);
+            self.out.push_str(INDENT);
+            self.out.push_str(&comment.val_str());
+            self.out.push('\n');
+            self.next += 1;
+        }
+        self.out.push_str("}\n");
+    }
+
+    // Writes the items of a block opened after `start` and returns the offset of its closing brace.
+    // Comments before that brace stay inside the block.
+    fn block(&mut self, block: &ParseNode, depth: usize, start: usize) -> usize {
+        let mut end: usize = start;
+        for item in &block.children {
+            end = end.max(self.block_item(item, depth));
+        }
+        let close: usize = self.closes.iter().copied().find(|close| *close > end).unwrap_or(usize::MAX);
+        while let Some(comment) = self.comments.get(self.next) && comment.pos.offset < close {
+            self.blank_line_before(comment.pos.row);
+            self.out.push_str(&INDENT.repeat(depth));
+            self.out.push_str(&comment.val_str());
+            self.out.push('\n');
+            self.next += 1;
+        }
+        close
+    }
+
+    // Writes one item and returns the offset of the last token it was parsed from.
+    fn block_item(&mut self, item: &ParseNode, depth: usize) -> usize {
+        let indent: String = INDENT.repeat(depth);
+        let row: usize = item.tok.pos.row;
+        self.comments_before(row, depth);
+        self.blank_line_before(row);
+        self.out.push_str(&indent);
+        match item.kind {
+            NodeType::VarDecl => {
+                // Declarations without an initialiser get a `0` positioned nowhere, which is left implicit again.
+                let init: &ParseNode = &item.children[0];
+                if is_implicit(init) {
+                    self.out.push_str(&format!("let {};", item.tok.val_str()));
+                } else {
+                    self.out.push_str(&format!("let {} = {};", item.tok.val_str(), expr(init)));
+                }
+            },
+            NodeType::Assign => self.out.push_str(&format!("{} = {};", item.tok.val_str(), expr(&item.children[0]))),
+            NodeType::CompoundAssign => {
+                let op: &ParseNode = &item.children[0];
+                self.out.push_str(&format!("{} {}= {};", item.tok.val_str(), op.tok.val_str(), expr(&op.children[1])));
+            },
+            NodeType::DebugDump => self.out.push_str(&format!("dump {};", item.children.iter().map(expr).collect::<Vec<String>>().join(", "))),
+            NodeType::Exit if is_implicit(&item.children[0]) => self.out.push_str("exit;"),
+            NodeType::Exit => self.out.push_str(&format!("exit {};", expr(&item.children[0]))),
+            NodeType::Return if is_implicit(&item.children[0]) => self.out.push_str("return;"),
+            NodeType::Return => self.out.push_str(&format!("return {};", expr(&item.children[0]))),
+            NodeType::FuncCall => self.out.push_str(&format!("{}();", item.tok.val_str())),
+            NodeType::Assert => self.out.push_str(&format!("assert {};", expr(&item.children[0]))),
+            NodeType::Conditional => {
+                self.out.push_str(&format!("if {} {{", expr(&item.children[0])));
+                self.trailing_comment(row);
+                self.out.push('\n');
+                let mut end: usize = self.block(&item.children[1], depth + 1, last_offset(&item.children[0]));
+                // Each `else if` continues on the closing brace of the branch before it.
+                let mut cond: &ParseNode = item;
+                while let Some(next) = cond.children.get(2) && next.kind == NodeType::Conditional {
+                    self.out.push_str(&format!("{}}} else if {} {{", indent, expr(&next.children[0])));
+                    self.trailing_comment(next.tok.pos.row);
+                    self.out.push('\n');
+                    end = self.block(&next.children[1], depth + 1, last_offset(&next.children[0]));
+                    cond = next;
+                }
+                if let Some(else_block) = cond.children.get(2) {
+                    self.out.push_str(&format!("{}}} else {{\n", indent));
+                    end = self.block(else_block, depth + 1, end);
+                }
+                self.out.push_str(&format!("{}}}\n", indent));
+                return end;
+            },
+            NodeType::For => {
+                let (first, last) = (&item.children[0], &item.children[1]);
+                self.out.push_str(&format!("for {} = {} to {} {{", item.tok.val_str(), expr(first), expr(last)));
+                self.trailing_comment(row);
+                self.out.push('\n');
+                let end: usize = self.block(&item.children[2], depth + 1, last_offset(last));
+                self.out.push_str(&format!("{}}}\n", indent));
+                return end;
+            },
+            _ => panic!("{} Error: Cannot format {:?} as a block item", item.tok.pos, item.kind),
+        }
+        self.trailing_comment(row);
+        self.out.push('\n');
+        last_offset(item)
+    }
+}
+
+fn is_implicit(node: &ParseNode) -> bool {
+    node.tok.pos.row == usize::MAX - 1
+}
+
+// Nodes the parser synthesizes are positioned at `usize::MAX - 1` and skipped.
+fn last_offset(node: &ParseNode) -> usize {
+    let own: usize = if node.tok.pos.offset == usize::MAX - 1 { 0 } else { node.tok.pos.offset };
+    node.children.iter().map(last_offset).fold(own, usize::max)
+}
+
+// How tightly an expression binds, following the production rules of the parser.
+fn precedence(node: &ParseNode) -> u8 {
+    match (&node.kind, &node.tok.kind) {
+        (NodeType::BinOp, TokenType::OpLogicalOr) => 1,
+        (NodeType::BinOp, TokenType::OpLogicalAnd) => 2,
+        (NodeType::BinOp, TokenType::OpEqual | TokenType::OpNotEqual) => 3,
+        (NodeType::BinOp, TokenType::OpGreaterThan | TokenType::OpGreaterEqual | TokenType::OpLessThan | TokenType::OpLessEqual) => 4,
+        (NodeType::BinOp, TokenType::OpPlus | TokenType::OpMinus) => 5,
+        (NodeType::BinOp, _) => 6,
+        (NodeType::UnOp, _) => 7,
+        _ => 8,
+    }
+}
+
+fn expr_at_least(node: &ParseNode, min: u8) -> String {
+    if precedence(node) < min {
+        format!("({})", expr(node))
+    } else {
+        expr(node)
+    }
+}
+
+fn expr(node: &ParseNode) -> String {
+    match node.kind {
+        NodeType::BinOp => {
+            // Operators associate to the left, so an equally tight right operand needs parentheses.
+            let prec: u8 = precedence(node);
+            let lhs: String = expr_at_least(&node.children[0], prec);
+            let rhs: String = expr_at_least(&node.children[1], prec + 1);
+            format!("{} {} {}", lhs, node.tok.val_str(), rhs)
+        },
+        NodeType::UnOp => format!("{}{}", node.tok.val_str(), expr_at_least(&node.children[0], 7)),
+        NodeType::Intrinsic => format!("{}({})", node.tok.val_str(), node.children.iter().map(expr).collect::<Vec<String>>().join(", ")),
+        NodeType::FuncAddr => format!("&{}", node.tok.val_str()),
+        NodeType::FuncCall => format!("{}()", node.tok.val_str()),
+        _ => node.tok.val_str(),
+    }
+}
+
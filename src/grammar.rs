@@ -0,0 +1,182 @@
+use crate::json::Json;
+use crate::lexer::KEYWORDS;
+use crate::lexer::LINE_COMMENT;
+use crate::lexer::OPERATORS;
+use crate::lexer::TokenType;
+use crate::lexer::token_text;
+
+pub enum GrammarFormat {
+    TmLanguage,
+    TreeSitter,
+}
+
+// Binary operators from loosest to tightest binding, mirroring the expression rules of the parser.
+const PRECEDENCE: [&[TokenType]; 6] = [
+    &[TokenType::OpLogicalOr],
+    &[TokenType::OpLogicalAnd],
+    &[TokenType::OpEqual, TokenType::OpNotEqual],
+    &[TokenType::OpLessThan, TokenType::OpGreaterThan, TokenType::OpLessEqual, TokenType::OpGreaterEqual],
+    &[TokenType::OpPlus, TokenType::OpMinus],
+    &[TokenType::OpMul, TokenType::OpDiv, TokenType::OpMod],
+];
+
+fn text(kind: &TokenType) -> &'static str {
+    token_text(kind).unwrap_or_else(|| panic!("Error: No fixed spelling for token `{:?}`", kind))
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut res: String = String::new();
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+fn tm_pattern(name: &str, regex: String) -> Json {
+    Json::object(vec![("name", Json::Str(name.to_string())), ("match", Json::Str(regex))])
+}
+
+fn generate_tmlanguage() -> String {
+    let keywords: Vec<&str> = KEYWORDS.iter().map(|(text, _)| *text).collect();
+    // Longest first so that e.g. `<=` is not highlighted as `<` followed by `=`.
+    let mut operators: Vec<&str> = OPERATORS.iter().map(|(text, _)| *text).collect();
+    operators.sort_by_key(|op| std::cmp::Reverse(op.len()));
+    let operators: Vec<String> = operators.iter().map(|op| regex_escape(op)).collect();
+
+    let patterns: Vec<Json> = vec![
+        tm_pattern("comment.line.double-slash.language", format!("{}.*$", regex_escape(LINE_COMMENT))),
+        tm_pattern("keyword.control.language", format!("\\b({})\\b", keywords.join("|"))),
+        tm_pattern("constant.numeric.integer.language", "\\b[0-9]+\\b".to_string()),
+        tm_pattern("entity.name.function.language", "\\b[A-Za-z][A-Za-z0-9_]*(?=\\s*\\()".to_string()),
+        tm_pattern("variable.other.language", "\\b[A-Za-z][A-Za-z0-9_]*\\b".to_string()),
+        tm_pattern("keyword.operator.language", operators.join("|")),
+    ];
+
+    Json::object(vec![
+        ("$schema", Json::Str("https://raw.githubusercontent.com/martinring/tmlanguage/master/tmlanguage.json".to_string())),
+        ("name", Json::Str("Language".to_string())),
+        ("scopeName", Json::Str("source.language".to_string())),
+        ("fileTypes", Json::Array(vec![Json::Str("lang".to_string())])),
+        ("patterns", Json::Array(patterns)),
+    ]).to_string()
+}
+
+fn js_str(s: &str) -> String {
+    Json::Str(s.to_string()).to_string()
+}
+
+fn generate_tree_sitter() -> String {
+    let kw = |kind: TokenType| js_str(text(&kind));
+    let mut binary_rules: Vec<String> = Vec::new();
+    for (level, ops) in PRECEDENCE.iter().enumerate() {
+        for op in ops.iter() {
+            binary_rules.push(format!("      prec.left({}, seq(field('lhs', $._expression), field('op', {}), field('rhs', $._expression))),", level + 1, js_str(text(op))));
+        }
+    }
+
+    format!("\
+// Generated by `lang grammar --format tree-sitter`, do not edit by hand.
+module.exports = grammar({{
+  name: 'language',
+
+  extras: $ => [/\\s/, $.comment],
+
+  word: $ => $.identifier,
+
+  rules: {{
+    program: $ => repeat(choice($.function, $.module)),
+
+    module: $ => seq({module_kw}, field('name', $.identifier), {open}, repeat($.function), {close}),
+
+    function: $ => seq(optional(choice({pub_kw}, {export_kw}, {test_kw})), {func}, field('name', $.identifier), $.block),
+
+    block: $ => seq({open}, repeat($._block_item), {close}),
+
+    _block_item: $ => choice($.declaration, $._statement),
+
+    declaration: $ => seq({let_kw}, field('name', $.identifier), optional(seq({assign}, field('value', $._expression))), {end}),
+
+    _statement: $ => choice($.dump, $.exit, $.return, $.call, $.assignment, $.conditional, $.assertion, $.for_loop),
+
+    dump: $ => seq({dump}, $._expression, repeat(seq({comma}, $._expression)), {end}),
+
+    exit: $ => seq({exit}, optional($._expression), {end}),
+
+    return: $ => seq({return_kw}, optional($._expression), {end}),
+
+    call: $ => seq($.call_expression, {end}),
+
+    assignment: $ => seq(field('name', $.identifier), choice({assign}, {compound}), field('value', $._expression), {end}),
+
+    conditional: $ => seq({if_kw}, field('guard', $._expression), $.block, optional(seq({else_kw}, choice($.block, $.conditional)))),
+
+    assertion: $ => seq({assert_kw}, $._expression, {end}),
+
+    for_loop: $ => seq({for_kw}, field('name', $.identifier), {assign}, field('first', $._expression), {to_kw}, field('last', $._expression), $.block),
+
+    _expression: $ => choice($.binary_expression, $.unary_expression, $.parenthesized, $.call_expression, $.function_address, $.integer, $.identifier),
+
+    binary_expression: $ => choice(
+{binary}
+    ),
+
+    unary_expression: $ => prec({unary_prec}, seq({minus}, $._expression)),
+
+    parenthesized: $ => seq({lparen}, $._expression, {rparen}),
+
+    // Intrinsics take arguments, other functions none.
+    call_expression: $ => seq(field('name', $._function_name), {lparen}, optional(seq($._expression, repeat(seq({comma}, $._expression)))), {rparen}),
+
+    function_address: $ => seq({amp}, field('name', $._function_name)),
+
+    _function_name: $ => seq($.identifier, optional(seq({dot}, $.identifier))),
+
+    integer: $ => /[0-9]+/,
+
+    identifier: $ => /[A-Za-z][A-Za-z0-9_]*/,
+
+    comment: $ => token(seq({comment}, /.*/)),
+  }}
+}});
+",
+        func = kw(TokenType::KeywordFunctionDecl),
+        module_kw = kw(TokenType::KeywordModule),
+        pub_kw = kw(TokenType::KeywordPub),
+        export_kw = kw(TokenType::KeywordExport),
+        test_kw = kw(TokenType::KeywordTest),
+        assert_kw = kw(TokenType::KeywordAssert),
+        for_kw = kw(TokenType::KeywordFor),
+        to_kw = kw(TokenType::KeywordTo),
+        open = kw(TokenType::OpenScope),
+        close = kw(TokenType::CloseScope),
+        let_kw = kw(TokenType::KeywordVariableDecl),
+        assign = kw(TokenType::OpAssign),
+        compound = [TokenType::OpPlusAssign, TokenType::OpMinusAssign, TokenType::OpMulAssign, TokenType::OpDivAssign].into_iter().map(kw).collect::<Vec<String>>().join(", "),
+        end = kw(TokenType::End),
+        dump = kw(TokenType::KeywordDebugDump),
+        exit = kw(TokenType::KeywordExit),
+        return_kw = kw(TokenType::KeywordReturn),
+        lparen = kw(TokenType::OpenParen),
+        rparen = kw(TokenType::CloseParen),
+        comma = kw(TokenType::Comma),
+        dot = kw(TokenType::Dot),
+        if_kw = kw(TokenType::KeywordIf),
+        else_kw = kw(TokenType::KeywordElse),
+        minus = kw(TokenType::OpMinus),
+        amp = kw(TokenType::OpAddressOf),
+        binary = binary_rules.join("\n"),
+        unary_prec = PRECEDENCE.len() + 1,
+        comment = js_str(LINE_COMMENT),
+    )
+}
+
+// Generates an editor grammar from the token tables of the lexer so the two never drift apart.
+pub fn generate(format: GrammarFormat) -> String {
+    match format {
+        GrammarFormat::TmLanguage => generate_tmlanguage(),
+        GrammarFormat::TreeSitter => generate_tree_sitter(),
+    }
+}
@@ -0,0 +1,288 @@
+// The typed form of a program the backend consumes. Every name is resolved to the local or label it refers to and
+// every expression carries its type, so codegen never goes back to the tokens.
+use crate::consteval::eval_const;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Pos;
+use crate::lexer::TokenType;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Type {
+    Int,
+    Func, // The address of a function, which can be called through
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Or,
+    And,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum UnOp {
+    Neg,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Intrinsic {
+    Min,
+    Max,
+    Abs,
+    Rand,
+    TimeNs,
+}
+impl Intrinsic {
+    // As it is written in the source, which is also the label of the prelude routine behind `rand` and `time_ns`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Intrinsic::Min => "min",
+            Intrinsic::Max => "max",
+            Intrinsic::Abs => "abs",
+            Intrinsic::Rand => "rand",
+            Intrinsic::TimeNs => "time_ns",
+        }
+    }
+}
+
+// Index of a local in `Function::locals`. Every declaration gets its own, so shadowed names never share one.
+pub type LocalId = usize;
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Local {
+    pub name: String,
+    pub ty: Type, // The type of its initialiser
+    pub pos: Pos,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum ExprKind {
+    Literal(i64),
+    Local(LocalId),
+    FuncAddr(String), // Label of the function
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Intrinsic(Intrinsic, Vec<Expr>),
+    Call(Callee), // The value the function returns
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub ty: Type,
+    pub pos: Pos,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Callee {
+    Label(String),
+    Local(LocalId), // A local holding a function address
+}
+
+pub type Block = Vec<Stmt>;
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Stmt {
+    Let(LocalId, Expr),
+    Assign(LocalId, Expr),
+    Dump {
+        values: Vec<Expr>,
+        known: Option<Vec<i64>>, // Every value, if they are all known at compile time
+    },
+    Exit(Expr),
+    Return(Expr),
+    Call(Callee),
+    If {
+        guard: Expr,
+        known: Option<i64>, // Value of the guard if it is known at compile time
+        then: Block,
+        els: Option<Block>,
+    },
+    While {
+        guard: Expr,
+        body: Block,
+    },
+    Block(Block), // The body of an inlined function, or the scope of a loop variable
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Function {
+    pub label: String,
+    pub public: bool, // Made `global` so other object files can call it
+    pub locals: Vec<Local>,
+    pub body: Block,
+}
+
+// Resolves names to the locals declared in the function so far, innermost block last.
+struct Lowering {
+    locals: Vec<Local>,
+    scopes: Vec<Vec<(String, LocalId)>>,
+}
+impl Lowering {
+    fn lookup(&self, name: &str) -> Option<LocalId> {
+        self.scopes.iter().rev().flat_map(|scope| scope.iter().rev()).find(|(n, _)| n == name).map(|(_, id)| *id)
+    }
+
+    fn local(&self, node: &ParseNode) -> Result<LocalId, Diagnostic> {
+        self.lookup(&node.tok.val_str())
+            .ok_or_else(|| Diagnostic::error(node.tok.pos.clone(), format!("No such variable `{}` in local scope", node.tok.val_str())))
+    }
+
+    // Sema made sure a local of the same name holds a function address.
+    fn callee(&self, node: &ParseNode) -> Callee {
+        match self.lookup(&node.tok.val_str()) {
+            Some(id) => Callee::Local(id),
+            None => Callee::Label(node.tok.val_str()),
+        }
+    }
+
+    fn block(&mut self, block: &ParseNode) -> Result<Block, Diagnostic> {
+        self.scopes.push(Vec::new());
+        let res: Result<Block, Diagnostic> = block.children.iter().map(|item| self.stmt(item)).collect();
+        self.scopes.pop();
+        res
+    }
+
+    fn stmt(&mut self, node: &ParseNode) -> Result<Stmt, Diagnostic> {
+        match node.kind {
+            NodeType::VarDecl => {
+                let init: Expr = self.expr(&node.children[0])?;
+                let name: String = node.tok.val_str();
+                // NOTE: Locals cannot shadow ones of an enclosing block, even though they are told apart here.
+                if self.lookup(&name).is_some() {
+                    return Err(Diagnostic::error(node.tok.pos.clone(), format!("Variable with this name is already declared `{}`", name)));
+                }
+                self.locals.push(Local { name: name.clone(), ty: init.ty, pos: node.tok.pos.clone() });
+                let id: LocalId = self.locals.len() - 1;
+                self.scopes.last_mut().expect("Error: Lowering has no open scope").push((name, id));
+                Ok(Stmt::Let(id, init))
+            },
+            NodeType::Assign => {
+                let value: Expr = self.expr(&node.children[0])?;
+                Ok(Stmt::Assign(self.local(node)?, value))
+            },
+            NodeType::DebugDump => Ok(Stmt::Dump {
+                values: node.children.iter().map(|value| self.expr(value)).collect::<Result<_, _>>()?,
+                known: node.children.iter().map(eval_const).collect(),
+            }),
+            NodeType::Exit => Ok(Stmt::Exit(self.expr(&node.children[0])?)),
+            NodeType::Return => Ok(Stmt::Return(self.expr(&node.children[0])?)),
+            NodeType::FuncCall => Ok(Stmt::Call(self.callee(node))),
+            NodeType::Conditional => {
+                let guard: Expr = self.expr(&node.children[0])?;
+                let then: Block = self.block(&node.children[1])?;
+                let els: Option<Block> = node.children.get(2).map(|els| self.block(els)).transpose()?;
+                Ok(Stmt::If { guard, known: eval_const(&node.children[0]), then, els })
+            },
+            NodeType::While => {
+                let guard: Expr = self.expr(&node.children[0])?;
+                Ok(Stmt::While { guard, body: self.block(&node.children[1])? })
+            },
+            NodeType::Block => Ok(Stmt::Block(self.block(node)?)),
+            _ => panic!("{} Error: Expected block item but got `{}`", node.tok.pos, node.tok.val_str()),
+        }
+    }
+
+    fn expr(&mut self, node: &ParseNode) -> Result<Expr, Diagnostic> {
+        let pos: Pos = node.tok.pos.clone();
+        let int = |kind: ExprKind| Ok(Expr { kind, ty: Type::Int, pos: pos.clone() });
+        match node.kind {
+            NodeType::Literal => {
+                let Some(val) = node.tok.value else {
+                    return Err(Diagnostic::error(pos, format!("Integer literal `{}` has no value", node.tok.val_str())));
+                };
+                int(ExprKind::Literal(val))
+            },
+            NodeType::Var => {
+                let id: LocalId = self.local(node)?;
+                Ok(Expr { kind: ExprKind::Local(id), ty: self.locals[id].ty, pos })
+            },
+            NodeType::FuncAddr => Ok(Expr { kind: ExprKind::FuncAddr(node.tok.val_str()), ty: Type::Func, pos }),
+            NodeType::FuncCall => int(ExprKind::Call(self.callee(node))),
+            NodeType::UnOp => {
+                let operand: Expr = self.expr(&node.children[0])?;
+                match node.tok.kind {
+                    TokenType::OpMinus => int(ExprKind::Unary(UnOp::Neg, Box::new(operand))),
+                    _ => panic!("Error: Unknown unary operator kind `{:?}`", node.tok.kind),
+                }
+            },
+            NodeType::BinOp => {
+                let lhs: Expr = self.expr(&node.children[0])?;
+                let rhs: Expr = self.expr(&node.children[1])?;
+                let op: BinOp = match node.tok.kind {
+                    TokenType::OpPlus => BinOp::Add,
+                    TokenType::OpMinus => BinOp::Sub,
+                    TokenType::OpMul => BinOp::Mul,
+                    TokenType::OpDiv => BinOp::Div,
+                    TokenType::OpMod => BinOp::Mod,
+                    TokenType::OpLessThan => BinOp::Less,
+                    TokenType::OpLessEqual => BinOp::LessEqual,
+                    TokenType::OpGreaterThan => BinOp::Greater,
+                    TokenType::OpGreaterEqual => BinOp::GreaterEqual,
+                    TokenType::OpEqual => BinOp::Equal,
+                    TokenType::OpNotEqual => BinOp::NotEqual,
+                    TokenType::OpLogicalOr => BinOp::Or,
+                    TokenType::OpLogicalAnd => BinOp::And,
+                    _ => panic!("Error: Unknown binary operator kind `{:?}`", node.tok.kind),
+                };
+                int(ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)))
+            },
+            NodeType::Intrinsic => {
+                let args: Vec<Expr> = node.children.iter().map(|arg| self.expr(arg)).collect::<Result<_, _>>()?;
+                let intrinsic: Intrinsic = match node.tok.val.as_slice() {
+                    b"min" => Intrinsic::Min,
+                    b"max" => Intrinsic::Max,
+                    b"abs" => Intrinsic::Abs,
+                    b"rand" => Intrinsic::Rand,
+                    b"time_ns" => Intrinsic::TimeNs,
+                    _ => panic!("{} Error: Unknown intrinsic `{}`", node.tok.pos, node.tok.val_str()),
+                };
+                int(ExprKind::Intrinsic(intrinsic, args))
+            },
+            _ => panic!("{} Error: Invalid node in expression ({:?}) `{}`", node.tok.pos, node.kind, node.tok.val_str()),
+        }
+    }
+}
+
+// Lowers a function whose name and calls are labels already, as `codegen` leaves them.
+pub fn lower_function(func: &ParseNode) -> Result<Function, Diagnostic> {
+    let mut lowering: Lowering = Lowering { locals: Vec::new(), scopes: Vec::new() };
+    let body: &ParseNode = func.children.first().unwrap_or_else(|| panic!("{} Error: Function has no body", func.tok.pos));
+    let body: Block = lowering.block(body)?;
+    let public: bool = matches!(func.kind, NodeType::PubFuncDecl | NodeType::ExportFuncDecl);
+    Ok(Function { label: func.tok.val_str(), public, locals: lowering.locals, body })
+}
+
+pub fn lower(tree: &ParseTree) -> Result<Vec<Function>, Diagnostic> {
+    tree.root.children.iter().map(lower_function).collect()
+}
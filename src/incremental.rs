@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Lexer;
+use crate::lexer::Pos;
+use crate::lexer::Token;
+use crate::lexer::TokenType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+
+// How much of the previous parse was reused by the last update.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct ReparseStats {
+    pub reused_tokens: usize,
+    pub relexed_tokens: usize,
+    pub reused_funcs: usize,
+    pub parsed_funcs: usize,
+}
+
+// Re-parses successive versions of one source, reusing the tokens before the first edit and the subtrees of
+// functions whose text did not change. Functions are the reparse boundary as nothing in one function's tree
+// depends on another function.
+pub struct IncrementalParser {
+    name: String,
+    text: Vec<u8>,
+    toks: Vec<Token>,
+    comments: Vec<Token>,
+    funcs: HashMap<(Vec<u8>, usize), (ParseNode, Vec<Token>)>, // Keyed by the function's source text and starting column
+    pub stats: ReparseStats,
+}
+
+fn is_synthetic(pos: &Pos) -> bool {
+    pos.row == usize::MAX - 1
+}
+
+fn shift(node: &mut ParseNode, rows: isize, offset: isize) {
+    if !is_synthetic(&node.tok.pos) {
+        node.tok.pos.row = node.tok.pos.row.wrapping_add_signed(rows);
+        node.tok.pos.offset = node.tok.pos.offset.wrapping_add_signed(offset);
+    }
+    for child in &mut node.children {
+        shift(child, rows, offset);
+    }
+}
+
+impl IncrementalParser {
+    pub fn new(name: String) -> Self {
+        IncrementalParser {
+            name,
+            text: Vec::new(),
+            toks: Vec::new(),
+            comments: Vec::new(),
+            funcs: HashMap::new(),
+            stats: ReparseStats::default(),
+        }
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.toks
+    }
+
+    pub fn comments(&self) -> &[Token] {
+        &self.comments
+    }
+
+    fn lex(&self, text: &[u8]) -> Result<(Vec<Token>, Vec<Token>, usize), Diagnostic> {
+        let prefix: usize = self.text.iter().zip(text.iter()).take_while(|(a, b)| a == b).count();
+
+        // Restart at the last function or module declaration that starts before the first changed byte.
+        let restart: Option<&Token> = self.toks.iter()
+            .rfind(|tok| matches!(tok.kind, TokenType::KeywordFunctionDecl | TokenType::KeywordModule | TokenType::KeywordPub | TokenType::KeywordExport | TokenType::KeywordTest) && tok.pos.offset < prefix);
+        let mut lexer: Lexer = match restart {
+            None => Lexer::new(text.to_vec()),
+            Some(tok) => {
+                let toks: Vec<Token> = self.toks.iter().filter(|t| t.pos.offset < tok.pos.offset).cloned().collect();
+                let comments: Vec<Token> = self.comments.iter().filter(|c| c.pos.offset < tok.pos.offset).cloned().collect();
+                Lexer::resume(text.to_vec(), tok.pos.clone(), toks, comments)
+            }
+        };
+        let reused: usize = lexer.toks.len();
+        lexer.tokenize();
+        lexer.lex()?;
+        Ok((lexer.toks, lexer.comments, reused))
+    }
+
+    // Parses the new version of the source, the previous state is only replaced once it parsed successfully.
+    pub fn update(&mut self, text: &str) -> Result<ParseTree, Diagnostic> {
+        let text: Vec<u8> = text.as_bytes().to_vec();
+        let (toks, comments, reused_tokens) = self.lex(&text)?;
+        let mut stats: ReparseStats = ReparseStats { reused_tokens, relexed_tokens: toks.len() - reused_tokens, ..Default::default() };
+
+        // Split the token stream into functions and modules at `pub`, `export`, `test`, `func` and `module` keywords outside of any braces.
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        let mut depth: usize = 0;
+        for (ix, tok) in toks.iter().enumerate() {
+            let after_modifier: bool = ix > 0 && matches!(toks[ix - 1].kind, TokenType::KeywordPub | TokenType::KeywordExport | TokenType::KeywordTest);
+            match tok.kind {
+                TokenType::Eof => break,
+                TokenType::KeywordFunctionDecl if depth == 0 && !after_modifier => ranges.push((ix, ix + 1)),
+                TokenType::KeywordModule | TokenType::KeywordPub | TokenType::KeywordExport | TokenType::KeywordTest if depth == 0 => ranges.push((ix, ix + 1)),
+                TokenType::OpenScope => depth += 1,
+                TokenType::CloseScope => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            // Tokens before the first function belong to none, they are parsed on their own to fail as a full parse does.
+            if ranges.is_empty() {
+                ranges.push((ix, ix + 1));
+            }
+            if let Some(range) = ranges.last_mut() {
+                range.1 = ix + 1;
+            }
+        }
+
+        let mut funcs: HashMap<(Vec<u8>, usize), (ParseNode, Vec<Token>)> = HashMap::new();
+        let mut children: Vec<ParseNode> = Vec::new();
+        let mut chained: Vec<Token> = Vec::new();
+        for (start, end) in ranges {
+            let first: &Token = &toks[start];
+            let last: &Token = &toks[end - 1];
+            let key: (Vec<u8>, usize) = (text[first.pos.offset..last.pos.offset + last.val.len()].to_vec(), first.pos.col);
+            let (func, func_chained) = match self.funcs.get(&key) {
+                Some((cached, cached_chained)) => {
+                    stats.reused_funcs += 1;
+                    let mut func: ParseNode = cached.clone();
+                    let mut func_chained: Vec<Token> = cached_chained.clone();
+                    // The keywords are not stored in the tree, so the first identifier after them is the anchor.
+                    let old: &Pos = &func.tok.pos;
+                    let new: &Pos = &toks[start..end].iter().find(|tok| tok.kind == TokenType::Identifier).expect("Error: Function range has no name").pos;
+                    let (rows, offset) = (new.row as isize - old.row as isize, new.offset as isize - old.offset as isize);
+                    shift(&mut func, rows, offset);
+                    for tok in &mut func_chained {
+                        tok.pos.row = tok.pos.row.wrapping_add_signed(rows);
+                        tok.pos.offset = tok.pos.offset.wrapping_add_signed(offset);
+                    }
+                    (func, func_chained)
+                },
+                None => {
+                    stats.parsed_funcs += 1;
+                    // Keep the real end of file, so a function left open reports it at the same place as a full parse.
+                    let mut range: Vec<Token> = toks[start..end].to_vec();
+                    range.extend(toks.last().cloned());
+                    let mut lexer: Lexer = Lexer::from_tokens(range);
+                    let mut tree: ParseTree = ParseTree::new(self.name.clone());
+                    tree.construct(&mut lexer)?;
+                    (tree.root.children.pop().expect("Error: Function range produced no function"), tree.chained)
+                }
+            };
+            funcs.insert(key, (func.clone(), func_chained.clone()));
+            children.push(func);
+            chained.extend(func_chained);
+        }
+
+        let mut tree: ParseTree = ParseTree::new(self.name.clone());
+        tree.root.children = children;
+        tree.chained = chained;
+        self.text = text;
+        self.toks = toks;
+        self.comments = comments;
+        self.funcs = funcs;
+        self.stats = stats;
+        Ok(tree)
+    }
+}
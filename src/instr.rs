@@ -0,0 +1,137 @@
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Reg {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    Rbp,
+    Rsp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    Al, // Low byte of rax, the target of `set<cc>`
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    Mem(Reg, i64), // [reg + offset]
+    Label(String), // The address of a label
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Cond {
+    E,
+    Ne,
+    L,
+    Le,
+    G,
+    Ge,
+}
+impl Cond {
+    // The condition that holds exactly when this one does not.
+    pub fn inverse(self) -> Cond {
+        match self {
+            Cond::E => Cond::Ne,
+            Cond::Ne => Cond::E,
+            Cond::L => Cond::Ge,
+            Cond::Le => Cond::G,
+            Cond::G => Cond::Le,
+            Cond::Ge => Cond::L,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Instr {
+    Comment(String),
+    Label(String),
+    Mov(Operand, Operand),
+    Push(Operand),
+    Pop(Operand),
+    Add(Operand, Operand),
+    Sub(Operand, Operand),
+    Imul(Operand, Operand),
+    Cqo, // Sign extends rax into rdx:rax for `idiv`
+    Idiv(Operand),
+    Neg(Operand),
+    Xor(Operand, Operand),
+    Cmp(Operand, Operand),
+    Set(Cond, Operand),
+    Cmov(Cond, Operand, Operand),
+    Jmp(String),
+    Jcc(Cond, String),
+    Call(String),
+    CallIndirect(Operand),
+    Ret,
+    Syscall,
+}
+
+// The instructions of one emitted routine, printed under a single global label.
+#[derive(Clone)]
+pub struct AsmFunction {
+    pub name: String,
+    pub body: Vec<Instr>,
+    pub frame_size: i64, // Bytes below the frame pointer, saved registers and stack slots of locals
+    pub spills: usize, // Locals kept on the stack rather than in a register
+}
+impl AsmFunction {
+    // Instructions that end up in the output, leaving out comments and labels.
+    pub fn instr_count(&self) -> usize {
+        self.body.iter().filter(|instr| !matches!(instr, Instr::Comment(_) | Instr::Label(_))).count()
+    }
+}
+
+#[derive(Clone)]
+pub struct AsmProgram {
+    pub funcs: Vec<AsmFunction>,
+    pub entry: AsmFunction,
+    pub exports: Vec<String>, // Labels of `pub` functions, made global so other object files can link against them
+    pub rodata: Vec<(String, Vec<i64>)>, // Values of constant dumps by their label, printed like `dump` would print them
+    pub dump_same_line: bool, // Ends all but the last value of a `dump` with a space instead of a newline
+    pub note: Option<String>, // How the program was built, recorded in the `.note.language` section
+}
+
+impl AsmProgram {
+    // Every instruction before it is printed as assembly, one per line under the label of its function.
+    pub fn ir(&self) -> String {
+        let mut res: String = String::new();
+        for func in self.funcs.iter().chain([&self.entry]) {
+            res.push_str(&format!("{}:\n", func.name));
+            for instr in &func.body {
+                res.push_str(&format!("    {:?}\n", instr));
+            }
+        }
+        res
+    }
+}
+
+pub fn reg(r: Reg) -> Operand {
+    Operand::Reg(r)
+}
+
+pub fn imm(i: i64) -> Operand {
+    Operand::Imm(i)
+}
+
+pub fn mem(r: Reg, offset: i64) -> Operand {
+    Operand::Mem(r, offset)
+}
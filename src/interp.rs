@@ -0,0 +1,376 @@
+// Runs a program by walking its HIR, without an assembler or linker. Programs behave as their native build does: values
+// wrap on overflow, `dump` prints them unsigned and the exit status is the low byte of the value given to `exit`.
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::Write;
+use std::time::Instant;
+use crate::diagnostic::Diagnostic;
+use crate::hir::BinOp;
+use crate::hir::Block;
+use crate::hir::Callee;
+use crate::hir::Expr;
+use crate::hir::ExprKind;
+use crate::hir::Function;
+use crate::hir::Intrinsic;
+use crate::hir::Stmt;
+use crate::hir::UnOp;
+use crate::json::Json;
+use crate::lexer::Pos;
+use crate::limits::RunLimits;
+use crate::symbols::MANGLE_PREFIX;
+use crate::symbols::mangle;
+
+// Calls that may be nested before the program is stopped, as many as fit the 8MB stack of a native program when each
+// takes no more than its return address and frame pointer. Native programs would crash instead.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = (8 << 20) / 16;
+
+// NOTE: Every call recurses on the host stack, which is grown by this much whenever less than `STACK_RED_ZONE` is left.
+// The red zone covers the deepest a call can nest blocks and expressions before its next call, in a debug build.
+const STACK_SEGMENT: usize = 8 << 20;
+const STACK_RED_ZONE: usize = 1 << 20;
+
+// Steps between readings of the clock for `RunLimits::timeout`, which costs more than a step.
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+// Where `rand` starts while tracing, so a traced run gives the same values every time.
+const TRACE_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+// How each statement is logged while tracing.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum TraceFormat {
+    Text, // `[3:5] main: let b | a = 1`
+    Json, // One object per line with `row`, `col`, `function`, `statement` and `variables`
+}
+
+// Logs every statement before it runs, with the values of the variables in scope at it.
+struct Tracer<'a> {
+    format: TraceFormat,
+    out: &'a mut dyn Write,
+    frames: Vec<(usize, Vec<bool>)>, // The function of each call in progress, with which of its locals are in scope
+    rand: u64,
+}
+
+fn unknown() -> Pos {
+    Pos { row: 0, col: 0, offset: 0 }
+}
+
+// Where a statement leaves its function.
+enum Flow {
+    Next,
+    Return(i64),
+}
+
+// Why the program stopped before its entry function returned. Both unwind every call, even from inside an expression.
+enum Halt {
+    Exit(i64),
+    Fault(Diagnostic),
+}
+impl From<Diagnostic> for Halt {
+    fn from(d: Diagnostic) -> Self {
+        Halt::Fault(d)
+    }
+}
+
+pub struct Interpreter<'a> {
+    funcs: Vec<&'a Function>,
+    labels: HashMap<&'a str, usize>, // Index of each function, which is also the value of its address
+    out: &'a mut dyn Write,
+    dump_same_line: bool,
+    depth: usize,
+    start: Instant, // When the program started, `time_ns` counts from it on the monotonic clock as native programs do
+    limits: RunLimits,
+    steps: u64,
+    memory: usize, // Bytes of locals in the calls in progress
+    at: Pos, // The last statement run that the source holds, where going over a limit is reported
+    tracer: Option<Tracer<'a>>,
+}
+impl<'a> Interpreter<'a> {
+    pub fn new(funcs: &'a [Function], out: &'a mut dyn Write) -> Self {
+        let labels: HashMap<&str, usize> = funcs.iter().enumerate().map(|(ix, func)| (func.label.as_str(), ix)).collect();
+        Interpreter {
+            funcs: funcs.iter().collect(), labels, out, dump_same_line: false, depth: 0, start: Instant::now(),
+            limits: RunLimits::default(), steps: 0, memory: 0, at: unknown(), tracer: None,
+        }
+    }
+
+    pub fn dump_same_line(mut self, same_line: bool) -> Self {
+        self.dump_same_line = same_line;
+        self
+    }
+
+    pub fn limits(mut self, limits: RunLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Logs each statement to `out` as it runs. `rand` and `time_ns` become deterministic, so the log is the same on every
+    // run: `rand` draws from a fixed seed and `time_ns` gives the number of statements run so far.
+    pub fn trace(mut self, format: TraceFormat, out: &'a mut dyn Write) -> Self {
+        self.tracer = Some(Tracer { format, out, frames: Vec::new(), rand: TRACE_SEED });
+        self
+    }
+
+    // Counts a statement about to run, stopping the program at it once it has run too many or for too long.
+    // NOTE: Blocks only group the statements in them, which are counted themselves.
+    fn step(&mut self, stmt: &Stmt) -> Result<(), Halt> {
+        if matches!(stmt, Stmt::Block(_)) {
+            return Ok(());
+        }
+        self.steps += 1;
+        // NOTE: Statements made up by desugaring, like the step of a `for` loop, are positioned nowhere.
+        if let Some(pos) = stmt_pos(stmt) && pos.row != usize::MAX - 1 {
+            self.at = pos.clone();
+        }
+        if let Some(max) = self.limits.max_steps && self.steps > max {
+            return Err(Diagnostic::error(self.at.clone(), format!("Step limit exceeded (more than {} statements run)", max)).into());
+        }
+        if let Some(timeout) = self.limits.timeout && self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) && self.start.elapsed() > timeout {
+            return Err(Diagnostic::error(self.at.clone(), format!("Time limit exceeded (more than {} ms)", timeout.as_millis())).into());
+        }
+        Ok(())
+    }
+
+    // Calls the function at `label` and returns the exit status, which is 0 if it returns. A value returned from `main`
+    // is its exit status, as it exits rather than returning.
+    pub fn run(&mut self, label: &str) -> Result<u8, Diagnostic> {
+        let Some(&func) = self.labels.get(label) else {
+            return Err(Diagnostic::error(unknown(), format!("No function `{}` to run", label)));
+        };
+        match self.call(func) {
+            Ok(val) if label == mangle("main") => Ok(val as u8),
+            Ok(_) => Ok(0),
+            Err(Halt::Exit(status)) => Ok(status as u8),
+            Err(Halt::Fault(d)) => Err(d),
+        }
+    }
+
+    // Gives the value the function returns, 0 if it runs off its end.
+    // NOTE: Calls carry no position, so errors about them are reported at the start of the file.
+    fn call(&mut self, func: usize) -> Result<i64, Halt> {
+        if let Some(max) = self.limits.max_call_depth && self.depth == max {
+            return Err(Diagnostic::error(unknown(), format!("Call stack overflow (more than {} nested calls)", max)).into());
+        }
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, || self.enter(func))
+    }
+
+    // The call itself, once the host stack has room for it.
+    fn enter(&mut self, func: usize) -> Result<i64, Halt> {
+        let func: &Function = self.funcs[func];
+        let frame: usize = func.locals.len() * 8;
+        if let Some(max) = self.limits.max_memory && self.memory + frame > max {
+            return Err(Diagnostic::error(self.at.clone(), format!("Memory limit exceeded (more than {} bytes of locals)", max)).into());
+        }
+        self.depth += 1;
+        self.memory += frame;
+        let mut locals: Vec<i64> = vec![0; func.locals.len()];
+        if let Some(tracer) = &mut self.tracer {
+            tracer.frames.push((self.labels[func.label.as_str()], vec![false; func.locals.len()]));
+        }
+        let res: Result<Flow, Halt> = self.block(&mut locals, &func.body);
+        self.depth -= 1;
+        self.memory -= frame;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.frames.pop();
+        }
+        match res? {
+            Flow::Next => Ok(0),
+            Flow::Return(val) => Ok(val),
+        }
+    }
+
+    fn callee(&self, locals: &[i64], callee: &Callee) -> usize {
+        match callee {
+            Callee::Label(label) => *self.labels.get(label.as_str()).unwrap_or_else(|| panic!("Error: Call to `{}`, which has no function", label)),
+            Callee::Local(local) => locals[*local] as usize,
+        }
+    }
+
+    fn block(&mut self, locals: &mut [i64], block: &Block) -> Result<Flow, Halt> {
+        let mut flow: Flow = Flow::Next;
+        for stmt in block {
+            if let Flow::Return(val) = self.stmt(locals, stmt)? {
+                flow = Flow::Return(val);
+                break;
+            }
+        }
+        // The locals declared in the block go out of scope with it.
+        if let Some((_, live)) = self.tracer.as_mut().and_then(|tracer| tracer.frames.last_mut()) {
+            for stmt in block {
+                if let Stmt::Let(local, _) = stmt {
+                    live[*local] = false;
+                }
+            }
+        }
+        Ok(flow)
+    }
+
+    fn stmt(&mut self, locals: &mut [i64], stmt: &Stmt) -> Result<Flow, Halt> {
+        self.step(stmt)?;
+        if self.tracer.is_some() {
+            self.trace_stmt(locals, stmt)?;
+        }
+        match stmt {
+            Stmt::Let(local, value) => {
+                locals[*local] = self.expr(locals, value)?;
+                if let Some((_, live)) = self.tracer.as_mut().and_then(|tracer| tracer.frames.last_mut()) {
+                    live[*local] = true;
+                }
+            },
+            Stmt::Assign(local, value) => locals[*local] = self.expr(locals, value)?,
+            Stmt::Dump { values, .. } => {
+                for (ix, value) in values.iter().enumerate() {
+                    let val: i64 = self.expr(locals, value)?;
+                    let end: char = if ix + 1 < values.len() && self.dump_same_line { ' ' } else { '\n' };
+                    write!(self.out, "{}{}", val as u64, end)
+                        .map_err(|e| Diagnostic::error(value.pos.clone(), format!("Failed to write output: {}", e)))?;
+                }
+            },
+            Stmt::Exit(value) => return Err(Halt::Exit(self.expr(locals, value)?)),
+            Stmt::Return(value) => return Ok(Flow::Return(self.expr(locals, value)?)),
+            Stmt::Call(callee) => {
+                self.call(self.callee(locals, callee))?;
+            },
+            Stmt::If { guard, then, els, .. } => {
+                if self.expr(locals, guard)? != 0 {
+                    return self.block(locals, then);
+                }
+                if let Some(els) = els {
+                    return self.block(locals, els);
+                }
+            },
+            Stmt::While { guard, body } => {
+                while self.expr(locals, guard)? != 0 {
+                    if let Flow::Return(val) = self.block(locals, body)? {
+                        return Ok(Flow::Return(val));
+                    }
+                }
+            },
+            Stmt::Block(block) => return self.block(locals, block),
+        }
+        Ok(Flow::Next)
+    }
+
+    fn expr(&mut self, locals: &[i64], expr: &Expr) -> Result<i64, Halt> {
+        match &expr.kind {
+            ExprKind::Literal(val) => Ok(*val),
+            ExprKind::Local(local) => Ok(locals[*local]),
+            ExprKind::FuncAddr(label) => Ok(self.labels[label.as_str()] as i64),
+            ExprKind::Unary(UnOp::Neg, operand) => Ok(self.expr(locals, operand)?.wrapping_neg()),
+            // The rhs of `&&` and `||` is only evaluated if the lhs does not decide the result, as in native code.
+            ExprKind::Binary(BinOp::And, lhs, rhs) => Ok((self.expr(locals, lhs)? != 0 && self.expr(locals, rhs)? != 0) as i64),
+            ExprKind::Binary(BinOp::Or, lhs, rhs) => Ok((self.expr(locals, lhs)? != 0 || self.expr(locals, rhs)? != 0) as i64),
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs: i64 = self.expr(locals, lhs)?;
+                let rhs: i64 = self.expr(locals, rhs)?;
+                match op {
+                    BinOp::Add => Ok(lhs.wrapping_add(rhs)),
+                    BinOp::Sub => Ok(lhs.wrapping_sub(rhs)),
+                    BinOp::Mul => Ok(lhs.wrapping_mul(rhs)),
+                    BinOp::Div | BinOp::Mod if rhs == 0 => Err(Diagnostic::error(expr.pos.clone(), "Division by zero".to_string()).into()),
+                    BinOp::Div => lhs.checked_div(rhs).ok_or_else(|| Diagnostic::error(expr.pos.clone(), "`/` overflows a 64 bit integer".to_string()).into()),
+                    BinOp::Mod => lhs.checked_rem(rhs).ok_or_else(|| Diagnostic::error(expr.pos.clone(), "`%` overflows a 64 bit integer".to_string()).into()),
+                    BinOp::Less => Ok((lhs < rhs) as i64),
+                    BinOp::LessEqual => Ok((lhs <= rhs) as i64),
+                    BinOp::Greater => Ok((lhs > rhs) as i64),
+                    BinOp::GreaterEqual => Ok((lhs >= rhs) as i64),
+                    BinOp::Equal => Ok((lhs == rhs) as i64),
+                    BinOp::NotEqual => Ok((lhs != rhs) as i64),
+                    BinOp::Or | BinOp::And => panic!("Error: `{:?}` is evaluated along with its operands", op),
+                }
+            },
+            ExprKind::Intrinsic(intrinsic, args) => {
+                let args: Vec<i64> = args.iter().map(|arg| self.expr(locals, arg)).collect::<Result<_, _>>()?;
+                match intrinsic {
+                    Intrinsic::Min => Ok(args[0].min(args[1])),
+                    Intrinsic::Max => Ok(args[0].max(args[1])),
+                    Intrinsic::Abs => Ok(args[0].wrapping_abs()),
+                    Intrinsic::Rand if let Some(tracer) = &mut self.tracer => {
+                        // xorshift64*
+                        tracer.rand ^= tracer.rand >> 12;
+                        tracer.rand ^= tracer.rand << 25;
+                        tracer.rand ^= tracer.rand >> 27;
+                        Ok(tracer.rand.wrapping_mul(0x2545_f491_4f6c_dd1d) as i64)
+                    },
+                    // Every `RandomState` is keyed differently, so hashing nothing with a new one gives a fresh value.
+                    Intrinsic::Rand => Ok(RandomState::new().hash_one(()) as i64),
+                    Intrinsic::TimeNs if self.tracer.is_some() => Ok(self.steps as i64),
+                    Intrinsic::TimeNs => Ok(self.start.elapsed().as_nanos() as i64),
+                }
+            },
+            ExprKind::Call(callee) => self.call(self.callee(locals, callee)),
+        }
+    }
+
+    // NOTE: Statements and locals made up by desugaring are left out, a `for` loop shows as its variable and the body it
+    // runs. Calls have no position of their own, so are logged without one.
+    fn trace_stmt(&mut self, locals: &[i64], stmt: &Stmt) -> Result<(), Halt> {
+        let pos: Option<&Pos> = stmt_pos(stmt);
+        if pos.is_some_and(|pos| pos.row == usize::MAX - 1) {
+            return Ok(());
+        }
+        let Some(tracer) = &mut self.tracer else {
+            return Ok(());
+        };
+        let &(func, ref live) = tracer.frames.last().unwrap_or_else(|| panic!("Error: Statement traced outside of a call"));
+        let func: &Function = self.funcs[func];
+        let func_name: &str = func.label.strip_prefix(MANGLE_PREFIX).unwrap_or(&func.label);
+        let name = |local: &usize| func.locals[*local].name.as_str();
+        let what: String = match stmt {
+            Stmt::Let(local, _) | Stmt::Assign(local, _) if name(local).contains('.') => return Ok(()),
+            Stmt::Let(local, _) => format!("let {}", name(local)),
+            Stmt::Assign(local, _) => format!("assign {}", name(local)),
+            Stmt::Dump { .. } => "dump".to_string(),
+            Stmt::Exit(_) => "exit".to_string(),
+            Stmt::Return(_) => "return".to_string(),
+            Stmt::Call(Callee::Label(label)) => format!("call {}", label.strip_prefix(MANGLE_PREFIX).unwrap_or(label)),
+            Stmt::Call(Callee::Local(local)) => format!("call {}", name(local)),
+            Stmt::If { .. } => "if".to_string(),
+            Stmt::While { .. } => "while".to_string(),
+            Stmt::Block(_) => return Ok(()),
+        };
+        // A shadowing local hides the earlier one of its name, made up locals like the bound of a `for` are left out.
+        let mut vars: Vec<(&str, i64)> = Vec::new();
+        for (local, _) in live.iter().enumerate().filter(|(_, live)| **live) {
+            if name(&local).contains('.') {
+                continue;
+            }
+            match vars.iter_mut().find(|(var, _)| *var == name(&local)) {
+                Some(var) => var.1 = locals[local],
+                None => vars.push((name(&local), locals[local])),
+            }
+        }
+        let line: String = match tracer.format {
+            TraceFormat::Text => {
+                let mut line: String = pos.map_or(String::new(), |pos| format!("{} ", pos));
+                line += &format!("{}: {}", func_name, what);
+                if !vars.is_empty() {
+                    let vars: Vec<String> = vars.iter().map(|(var, val)| format!("{} = {}", var, val)).collect();
+                    line += &format!(" | {}", vars.join(", "));
+                }
+                line
+            },
+            TraceFormat::Json => Json::object(vec![
+                ("row", pos.map_or(Json::Null, |pos| Json::Int(pos.row as i64 + 1))),
+                ("col", pos.map_or(Json::Null, |pos| Json::Int(pos.col as i64 + 1))),
+                ("function", Json::Str(func_name.to_string())),
+                ("statement", Json::Str(what)),
+                ("variables", Json::Object(vars.iter().map(|(var, val)| (var.to_string(), Json::Int(*val))).collect())),
+            ]).to_string(),
+        };
+        writeln!(tracer.out, "{}", line).map_err(|e| Diagnostic::error(self.at.clone(), format!("Failed to write trace: {}", e)))?;
+        Ok(())
+    }
+}
+
+// Where a statement is reported, at its first expression. Calls have none.
+fn stmt_pos(stmt: &Stmt) -> Option<&Pos> {
+    match stmt {
+        Stmt::Let(_, value) | Stmt::Assign(_, value) | Stmt::Exit(value) | Stmt::Return(value) => Some(&value.pos),
+        Stmt::Dump { values, .. } => values.first().map(|value| &value.pos),
+        Stmt::If { guard, .. } | Stmt::While { guard, .. } => Some(&guard.pos),
+        Stmt::Call(_) | Stmt::Block(_) => None,
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt;
+
+// Minimal JSON value used for the machine-readable outputs of the compiler.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Self {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Int(i) => Some(*i),
+            Json::Float(x) => Some(*x as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn parse(src: &str) -> Result<Json, String> {
+        let mut parser: JsonParser = JsonParser { src: src.as_bytes(), cur: 0 };
+        let val: Json = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.cur != parser.src.len() {
+            return Err(format!("Error: Trailing characters in JSON at offset {}", parser.cur));
+        }
+        Ok(val)
+    }
+}
+
+struct JsonParser<'a> {
+    src: &'a [u8],
+    cur: usize,
+}
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.cur < self.src.len() && matches!(self.src[self.cur], b' ' | b'\t' | b'\n' | b'\r') {
+            self.cur += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.src.get(self.cur) != Some(&c) {
+            return Err(format!("Error: Expected `{}` in JSON at offset {}", c as char, self.cur));
+        }
+        self.cur += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.src.get(self.cur) {
+            None => Err("Error: Unexpected end of JSON".to_string()),
+            Some(b'{') => {
+                self.cur += 1;
+                let mut fields: Vec<(String, Json)> = Vec::new();
+                self.skip_whitespace();
+                if self.src.get(self.cur) == Some(&b'}') {
+                    self.cur += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key: String = self.parse_string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.parse_value()?));
+                    self.skip_whitespace();
+                    match self.src.get(self.cur) {
+                        Some(b',') => self.cur += 1,
+                        Some(b'}') => {
+                            self.cur += 1;
+                            return Ok(Json::Object(fields));
+                        },
+                        _ => return Err(format!("Error: Expected `,` or `}}` in JSON at offset {}", self.cur)),
+                    }
+                }
+            },
+            Some(b'[') => {
+                self.cur += 1;
+                let mut items: Vec<Json> = Vec::new();
+                self.skip_whitespace();
+                if self.src.get(self.cur) == Some(&b']') {
+                    self.cur += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value()?);
+                    self.skip_whitespace();
+                    match self.src.get(self.cur) {
+                        Some(b',') => self.cur += 1,
+                        Some(b']') => {
+                            self.cur += 1;
+                            return Ok(Json::Array(items));
+                        },
+                        _ => return Err(format!("Error: Expected `,` or `]` in JSON at offset {}", self.cur)),
+                    }
+                }
+            },
+            Some(b'"') => Ok(Json::Str(self.parse_string()?)),
+            Some(b't') => self.parse_word("true", Json::Bool(true)),
+            Some(b'f') => self.parse_word("false", Json::Bool(false)),
+            Some(b'n') => self.parse_word("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(c) => Err(format!("Error: Unexpected `{}` in JSON at offset {}", *c as char, self.cur)),
+        }
+    }
+
+    fn parse_word(&mut self, word: &str, val: Json) -> Result<Json, String> {
+        if !self.src[self.cur..].starts_with(word.as_bytes()) {
+            return Err(format!("Error: Invalid literal in JSON at offset {}", self.cur));
+        }
+        self.cur += word.len();
+        Ok(val)
+    }
+
+    fn parse_number(&mut self) -> Result<Json, String> {
+        let start: usize = self.cur;
+        let mut is_float: bool = false;
+        while let Some(c) = self.src.get(self.cur) {
+            match c {
+                b'0'..=b'9' | b'-' | b'+' => {},
+                b'.' | b'e' | b'E' => is_float = true,
+                _ => break,
+            }
+            self.cur += 1;
+        }
+        let text: &str = std::str::from_utf8(&self.src[start..self.cur]).map_err(|e| e.to_string())?;
+        if is_float {
+            text.parse::<f64>().map(Json::Float).map_err(|_| format!("Error: Invalid number `{}` in JSON", text))
+        } else {
+            text.parse::<i64>().map(Json::Int).map_err(|_| format!("Error: Invalid number `{}` in JSON", text))
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let hex: &[u8] = self.src.get(self.cur..self.cur + 4).ok_or("Error: Unexpected end of JSON escape")?;
+        let hex: &str = std::str::from_utf8(hex).map_err(|e| e.to_string())?;
+        self.cur += 4;
+        u32::from_str_radix(hex, 16).map_err(|_| format!("Error: Invalid unicode escape `{}` in JSON", hex))
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut res: Vec<u8> = Vec::new();
+        loop {
+            let c: u8 = *self.src.get(self.cur).ok_or("Error: Unterminated string in JSON")?;
+            self.cur += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let esc: u8 = *self.src.get(self.cur).ok_or("Error: Unterminated string in JSON")?;
+                    self.cur += 1;
+                    match esc {
+                        b'"' => res.push(b'"'),
+                        b'\\' => res.push(b'\\'),
+                        b'/' => res.push(b'/'),
+                        b'b' => res.push(0x08),
+                        b'f' => res.push(0x0c),
+                        b'n' => res.push(b'\n'),
+                        b'r' => res.push(b'\r'),
+                        b't' => res.push(b'\t'),
+                        b'u' => {
+                            let mut code: u32 = self.parse_hex4()?;
+                            if (0xD800..0xDC00).contains(&code) && self.src[self.cur..].starts_with(b"\\u") {
+                                self.cur += 2;
+                                let low: u32 = self.parse_hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            let ch: char = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            let mut buf: [u8; 4] = [0; 4];
+                            res.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                        },
+                        _ => return Err(format!("Error: Invalid escape in JSON at offset {}", self.cur)),
+                    }
+                },
+                _ => res.push(c),
+            }
+        }
+        String::from_utf8(res).map_err(|e| e.to_string())
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::Float(x) => write!(f, "{}", x),
+            Json::Str(s) => write_escaped(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_escaped(f, key)?;
+                    write!(f, ":{}", val)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
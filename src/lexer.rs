@@ -1,8 +1,12 @@
 use std::fmt;
+use crate::diagnostic::Diagnostic;
+use crate::json::Json;
 
 #[derive(Debug)]
 #[derive(PartialEq)]
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 pub enum TokenType {
     None,
     End,
@@ -10,7 +14,12 @@ pub enum TokenType {
     OpMinus,
     OpMul,
     OpDiv,
+    OpMod,
     OpAssign,
+    OpPlusAssign,
+    OpMinusAssign,
+    OpMulAssign,
+    OpDivAssign,
     OpEqual,
     OpNotEqual,
     OpGreaterThan,
@@ -19,24 +28,118 @@ pub enum TokenType {
     OpLessEqual,
     OpLogicalOr,
     OpLogicalAnd,
+    OpAddressOf,
     OpenParen,
     CloseParen,
     OpenScope,
     CloseScope,
+    Comma,
+    Dot,
     KeywordFunctionDecl,
     KeywordExit,
     KeywordDebugDump,
     KeywordVariableDecl,
     KeywordIf,
     KeywordElse,
+    KeywordModule,
+    KeywordPub,
+    KeywordExport,
+    KeywordTest,
+    KeywordAssert,
+    KeywordFor,
+    KeywordTo,
+    KeywordReturn,
     Identifier,
     LiteralInt,
+    Comment,
+    Eof, // Always the last token, positioned at the end of the source
 }
 
+// The spellings of every fixed token, shared by the lexer and the editor grammar generators.
+pub const KEYWORDS: [(&str, TokenType); 14] = [
+    ("func", TokenType::KeywordFunctionDecl),
+    ("exit", TokenType::KeywordExit),
+    ("dump", TokenType::KeywordDebugDump),
+    ("let",  TokenType::KeywordVariableDecl),
+    ("if",   TokenType::KeywordIf),
+    ("else", TokenType::KeywordElse),
+    ("module", TokenType::KeywordModule),
+    ("pub",  TokenType::KeywordPub),
+    ("export", TokenType::KeywordExport),
+    ("test", TokenType::KeywordTest),
+    ("assert", TokenType::KeywordAssert),
+    ("for", TokenType::KeywordFor),
+    ("to", TokenType::KeywordTo),
+    ("return", TokenType::KeywordReturn),
+];
+
+pub const OPERATORS: [(&str, TokenType); 19] = [
+    ("+",  TokenType::OpPlus),
+    ("-",  TokenType::OpMinus),
+    ("*",  TokenType::OpMul),
+    ("/",  TokenType::OpDiv),
+    ("%",  TokenType::OpMod),
+    ("=",  TokenType::OpAssign),
+    ("+=", TokenType::OpPlusAssign),
+    ("-=", TokenType::OpMinusAssign),
+    ("*=", TokenType::OpMulAssign),
+    ("/=", TokenType::OpDivAssign),
+    ("==", TokenType::OpEqual),
+    ("~=", TokenType::OpNotEqual),
+    (">",  TokenType::OpGreaterThan),
+    ("<",  TokenType::OpLessThan),
+    (">=", TokenType::OpGreaterEqual),
+    ("<=", TokenType::OpLessEqual),
+    ("||", TokenType::OpLogicalOr),
+    ("&&", TokenType::OpLogicalAnd),
+    ("&",  TokenType::OpAddressOf),
+];
+
+pub const PUNCTUATION: [(&str, TokenType); 7] = [
+    ("(", TokenType::OpenParen),
+    (")", TokenType::CloseParen),
+    ("{", TokenType::OpenScope),
+    ("}", TokenType::CloseScope),
+    (",", TokenType::Comma),
+    (".", TokenType::Dot),
+    (";", TokenType::End),
+];
+
+pub const LINE_COMMENT: &str = "//";
+pub const SHEBANG: &str = "#!";
+
+// Looks up the fixed spelling of a keyword, operator or punctuation token.
+pub fn token_text(kind: &TokenType) -> Option<&'static str> {
+    KEYWORDS.iter().chain(OPERATORS.iter()).chain(PUNCTUATION.iter()).find(|(_, k)| k == kind).map(|(text, _)| *text)
+}
+
+// Names a kind of token for diagnostics, quoting fixed spellings.
+pub fn describe(kind: &TokenType) -> String {
+    match (kind, token_text(kind)) {
+        (_, Some(text)) => format!("`{}`", text),
+        (TokenType::Identifier, None) => "identifier".to_string(),
+        (TokenType::LiteralInt, None) => "integer literal".to_string(),
+        (TokenType::Eof, None) => "end of file".to_string(),
+        (kind, None) => format!("{:?}", kind),
+    }
+}
+
+// Reports that something else was expected than the token found, which may be the end of the file.
+pub fn unexpected(expected: &str, tok: &Token) -> Diagnostic {
+    match tok.kind {
+        TokenType::Eof => Diagnostic::error(tok.pos.clone(), format!("Expected {} but file ended", expected)),
+        _ => Diagnostic::error(tok.pos.clone(), format!("Expected {} but got `{}`", expected, tok.val_str())),
+    }
+}
+
+#[derive(Debug)]
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 pub struct Pos {
     pub row: usize,
     pub col: usize,
+    pub offset: usize,
 }
 impl fmt::Display for Pos {
     // NOTE: Stored row and column are indices starting from 0, whereas in files, we count from 1.
@@ -45,20 +148,56 @@ impl fmt::Display for Pos {
     }
 }
 
+#[derive(Debug)]
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 pub struct Token {
     pub kind: TokenType,
+    #[cfg_attr(feature = "serde", serde(with = "text"))]
     pub val: Vec<u8>,
     pub pos: Pos,
+    // The value of an integer literal, parsed once by the lexer so later stages never read it from the text.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub value: Option<i64>,
 }
 impl Token {
     pub fn val_str(&self) -> String {
-        String::from_utf8(self.val.clone()).expect("Error: Failed to convert token value to string")
+        // NOTE: Sources are not checked to be UTF-8, so invalid bytes are shown as replacement characters.
+        String::from_utf8_lossy(&self.val).to_string()
+    }
+
+    // NOTE: Rows and columns are emitted counting from 1 (as in diagnostics), the offset is a byte index from 0.
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("kind", Json::Str(format!("{:?}", self.kind))),
+            ("text", Json::Str(self.val_str())),
+            ("row", Json::Int(self.pos.row as i64 + 1)),
+            ("col", Json::Int(self.pos.col as i64 + 1)),
+            ("offset", Json::Int(self.pos.offset as i64)),
+        ])
+    }
+}
+
+// Serializes token text as a string rather than an array of bytes.
+#[cfg(feature = "serde")]
+mod text {
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(val: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&String::from_utf8_lossy(val))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        Ok(String::deserialize(d)?.into_bytes())
     }
 }
 
 pub struct Lexer {
     pub toks: Vec<Token>,
+    pub comments: Vec<Token>, // Kept out of `toks` so the parser never sees them
     pub pos: Pos,
     src: Vec<u8>,
     cur: usize,
@@ -66,31 +205,90 @@ pub struct Lexer {
 }
 impl Lexer {
     pub fn new(src: Vec<u8>) -> Self {
-        let first = *src.first().expect("Error: Provided source file is empty");
-        Lexer { 
+        let first: u8 = src.first().copied().unwrap_or(0);
+        Lexer {
             toks: Vec::new(),
-            pos: Pos { row: 0, col: 0 },
+            comments: Vec::new(),
+            pos: Pos { row: 0, col: 0, offset: 0 },
             src,
             cur: 0,
             rune: first,
         }
     }
 
+    // Continues lexing `src` from `pos`, keeping already lexed tokens that lie before it.
+    pub fn resume(src: Vec<u8>, pos: Pos, toks: Vec<Token>, comments: Vec<Token>) -> Self {
+        let rune: u8 = *src.get(pos.offset).expect("Error: Cannot resume lexing past the end of the source");
+        Lexer {
+            toks,
+            comments,
+            cur: pos.offset,
+            pos,
+            src,
+            rune,
+        }
+    }
+
+    // Wraps already lexed tokens so that they can be handed to the parser, ending them with `Eof` if needed.
+    pub fn from_tokens(mut toks: Vec<Token>) -> Self {
+        if toks.last().is_none_or(|tok| tok.kind != TokenType::Eof) {
+            let pos: Pos = match toks.last() {
+                None => Pos { row: 0, col: 0, offset: 0 },
+                Some(tok) => Pos { row: tok.pos.row, col: tok.pos.col + tok.val.len(), offset: tok.pos.offset + tok.val.len() },
+            };
+            toks.push(Token { kind: TokenType::Eof, val: Vec::new(), pos, value: None });
+        }
+        Lexer {
+            toks,
+            comments: Vec::new(),
+            pos: Pos { row: 0, col: 0, offset: 0 },
+            src: Vec::new(),
+            cur: 0,
+            rune: 0,
+        }
+    }
+
     pub fn has_token(&self) -> bool {
-        self.cur < self.toks.len() - 1
+        self.peek_n(0).kind != TokenType::Eof
     }
 
+    // NOTE: Consuming never moves past `Eof`, so reading on at the end keeps returning it.
     pub fn consume_token(&mut self) -> Token {
-        let tok = self.toks.get(self.cur).expect("Error: Lexer failed to consume next token");
-        self.cur += 1;
-        tok.clone()
+        let tok: Token = self.peek_n(0);
+        if tok.kind != TokenType::Eof {
+            self.cur += 1;
+        }
+        tok
     }
 
     pub fn peek_token(&mut self) -> Token {
-        let tok = self.toks.get(self.cur).expect("Error: Lexer failed to peek next token");
+        self.peek_n(0)
+    }
+
+    // Looks `n` tokens ahead without consuming anything, `peek_n(0)` is the next token.
+    pub fn peek_n(&self, n: usize) -> Token {
+        let tok = self.toks.get(self.cur + n).or(self.toks.last()).expect("Error: Lexer has no tokens to peek");
         tok.clone()
     }
 
+    // Consumes the next token if it has the given kind.
+    pub fn eat_if(&mut self, kind: TokenType) -> bool {
+        if self.peek_n(0).kind != kind {
+            return false;
+        }
+        self.consume_token();
+        true
+    }
+
+    // Consumes the next token, which must have the given kind.
+    pub fn expect(&mut self, kind: TokenType) -> Result<Token, Diagnostic> {
+        let tok: Token = self.consume_token();
+        if tok.kind != kind {
+            return Err(unexpected(&describe(&kind), &tok));
+        }
+        Ok(tok)
+    }
+
     pub fn previous_token(&mut self) -> Token {
         let tok = self.toks.get(self.cur - 1).expect("Error: Lexer failed to peek previous token");
         tok.clone()
@@ -104,111 +302,78 @@ impl Lexer {
         }
     }
 
+    // Ends the lexeme being read as a token positioned where it started, if there is one.
+    fn end_lexeme(&mut self, lexeme: &mut Vec<u8>, start: &Pos) {
+        if lexeme.is_empty() {
+            return;
+        }
+        self.toks.push(Token { kind: TokenType::None, val: std::mem::take(lexeme), pos: start.clone(), value: None });
+    }
+
+    // Adds the current byte to the lexeme, which starts here if it was empty.
+    fn extend_lexeme(&self, lexeme: &mut Vec<u8>, start: &mut Pos) {
+        if lexeme.is_empty() {
+            *start = self.pos.clone();
+        }
+        lexeme.push(self.rune);
+    }
+
+    // Length of the longest operator or punctuation spelled from the current byte on, 0 if none is.
+    fn symbol_len(&self) -> usize {
+        let rest: &[u8] = &self.src[self.cur..];
+        OPERATORS.iter().chain(PUNCTUATION.iter()).map(|(text, _)| text.as_bytes()).filter(|text| rest.starts_with(text)).map(|text| text.len()).max().unwrap_or(0)
+    }
+
+    // NOTE: Every token is positioned where its first byte was read, rather than worked out back from where it ended.
     pub fn tokenize(&mut self) {
         let mut lexeme: Vec<u8> = Vec::new();
-        loop {
-            match self.rune {
-                b' ' | b'\n' => {
-                    if !lexeme.is_empty() {
-                        self.toks.push(Token {
-                            kind: TokenType::None,
-                            val: lexeme.clone(),
-                            pos: Pos { row: self.pos.row, col: self.pos.col - lexeme.len() },
-                        });
-                        lexeme.clear();
-                    }
-                },
-                b';' | b'+' | b'-' | b'*' | b'/' | b'(' | b')' | b'{' | b'}' => {
-                    if !lexeme.is_empty() {
-                        self.toks.push(Token {
-                            kind: TokenType::None,
-                            val: lexeme.clone(),
-                            pos: Pos { row: self.pos.row, col: self.pos.col - lexeme.len() },
-                        });
-                        lexeme.clear();
-                    }
+        let mut start: Pos = self.pos.clone();
+        while self.cur < self.src.len() {
+            // Line comments run until the end of the line and are stored separately from the tokens.
+            // A `#!` line starting the file is kept as one too, so scripts can name the compiler to run them.
+            if self.src[self.cur..].starts_with(LINE_COMMENT.as_bytes()) || (self.cur == 0 && self.src.starts_with(SHEBANG.as_bytes())) {
+                self.end_lexeme(&mut lexeme, &start);
 
-                    lexeme.push(self.rune);
-                    self.toks.push(Token {
-                        kind: TokenType::None,
-                        val: lexeme.clone(),
-                        pos: Pos { row: self.pos.row, col: self.pos.col },
-                    });
-                    lexeme.clear();
-                },
-                b'>' | b'<' | b'~' => {
-                    if !lexeme.is_empty() {
-                        self.toks.push(Token {
-                            kind: TokenType::None,
-                            val: lexeme.clone(),
-                            pos: Pos { row: self.pos.row, col: self.pos.col - lexeme.len() },
-                        });
-                        lexeme.clear();
-                    }
-                    lexeme.push(self.rune);
-                },
-                b'=' => {
-                    if !lexeme.is_empty() {
-                        let last: &u8 = lexeme.last().expect("Error: Failed to get last char in lexeme");
-                        if matches!(last, b'>' | b'<' | b'=' | b'~') {
-                            lexeme.push(self.rune);
-                        } else {
-                            self.toks.push(Token {
-                                kind: TokenType::None,
-                                val: lexeme.clone(),
-                                pos: Pos { row: self.pos.row, col: self.pos.col - lexeme.len() },
-                            });
-                            lexeme.clear();
-                            lexeme.push(self.rune);
-                        }
-                    } else {
-                        lexeme.push(self.rune);
-                    }
-                },
-                b'&' | b'|' => {
-                    if !lexeme.is_empty() {
-                        let last: &u8 = lexeme.last().expect("Error: Failed to get last char in lexeme");
-                        if matches!(last, b'&' | b'|') {
-                            lexeme.push(self.rune);
-                        }
-                        self.toks.push(Token {
-                            kind: TokenType::None,
-                            val: lexeme.clone(),
-                            pos: Pos { row: self.pos.row, col: self.pos.col - lexeme.len() },
-                        });
-                        lexeme.clear();
-                    } else {
-                        lexeme.push(self.rune);
-                    }
-                },
-                b'A'..=b'z' | b'0'..=b'9' => {
-                    if !lexeme.is_empty() {
-                        let last: &u8 = lexeme.last().expect("Error: Failed to get last char in lexeme");
-                        if matches!(last, b'A'..=b'z' | b'0'..=b'9') {
-                            lexeme.push(self.rune);
-                        } else {
-                            self.toks.push(Token {
-                                kind: TokenType::None,
-                                val: lexeme.clone(),
-                                pos: Pos { row: self.pos.row, col: self.pos.col - lexeme.len() },
-                            });
-                            lexeme.clear();
-                            lexeme.push(self.rune);
+                let pos: Pos = self.pos.clone();
+                let mut has_char: bool = true;
+                while has_char && self.rune != b'\n' {
+                    has_char = self.advance_char();
+                }
+                let end: usize = if has_char { self.cur } else { self.src.len() };
+                self.comments.push(Token { kind: TokenType::Comment, val: self.src[pos.offset..end].to_vec(), pos, value: None });
+                if !has_char {
+                    break;
+                }
+                continue;
+            }
+
+            // Operators and punctuation end any word before them and take as many bytes as the longest spelling that fits.
+            let symbol: usize = self.symbol_len();
+            if symbol > 0 {
+                self.end_lexeme(&mut lexeme, &start);
+                start = self.pos.clone();
+                lexeme.extend_from_slice(&self.src[self.cur..self.cur + symbol]);
+                self.end_lexeme(&mut lexeme, &start);
+                for _ in 1..symbol {
+                    self.advance_char();
+                }
+            } else {
+                match self.rune {
+                    b' ' | b'\n' => self.end_lexeme(&mut lexeme, &start),
+                    b'A'..=b'z' | b'0'..=b'9' => {
+                        if lexeme.last().is_some_and(|last| !matches!(last, b'A'..=b'z' | b'0'..=b'9')) {
+                            self.end_lexeme(&mut lexeme, &start);
                         }
-                    } else {
+                        self.extend_lexeme(&mut lexeme, &mut start);
+                    },
+                    // Continuation bytes stay with the start of their character, so it is reported whole.
+                    0x80..=0xBF if lexeme.first().is_some_and(|c| *c >= 0xC0) => {
                         lexeme.push(self.rune);
+                    },
+                    _ => {
+                        self.end_lexeme(&mut lexeme, &start);
+                        self.extend_lexeme(&mut lexeme, &mut start);
                     }
-                },
-                _ => {
-                    if !lexeme.is_empty() {
-                        self.toks.push(Token {
-                            kind: TokenType::None,
-                            val: lexeme.clone(),
-                            pos: Pos { row: self.pos.row, col: self.pos.col - lexeme.len() },
-                        });
-                        lexeme.clear();
-                    }
-                    lexeme.push(self.rune);
                 }
             }
 
@@ -217,12 +382,18 @@ impl Lexer {
             }
         }
 
+        self.end_lexeme(&mut lexeme, &start);
+        let row: usize = self.src.iter().filter(|c| **c == b'\n').count();
+        let col: usize = self.src.iter().rev().take_while(|c| **c != b'\n').count();
+        self.toks.push(Token { kind: TokenType::Eof, val: Vec::new(), pos: Pos { row, col, offset: self.src.len() }, value: None });
+
         self.cur = 0;
     }
 
     fn advance_char(&mut self) -> bool {
         self.cur += 1;
         self.pos.col += 1;
+        self.pos.offset = self.cur;
         if self.cur >= self.src.len() {
             self.rune = 0;
             false
@@ -236,56 +407,33 @@ impl Lexer {
         }
     }
 
-    pub fn lex(&mut self) {
+    pub fn lex(&mut self) -> Result<(), Diagnostic> {
         for tok in &mut self.toks {
+            if tok.kind == TokenType::Eof {
+                continue;
+            }
             let len: usize = tok.val.len();
-            let first: &u8 = tok.val.first().unwrap_or_else(|| panic!("{} Error: Failed to get first char in token", tok.pos));
-
-            if len == 0 {
-                panic!("{} Error: Cannot have an empty token", tok.pos);
-            } else if len == 1 {
-                match first { // First try to match using first byte
-                    b'+' => tok.kind = TokenType::OpPlus,
-                    b'-' => tok.kind = TokenType::OpMinus,
-                    b'*' => tok.kind = TokenType::OpMul,
-                    b'/' => tok.kind = TokenType::OpDiv,
-                    b'=' => tok.kind = TokenType::OpAssign,
-                    b'(' => tok.kind = TokenType::OpenParen,
-                    b')' => tok.kind = TokenType::CloseParen,
-                    b'>' => tok.kind = TokenType::OpGreaterThan,
-                    b'<' => tok.kind = TokenType::OpLessThan,
-                    b'{' => tok.kind = TokenType::OpenScope,
-                    b'}' => tok.kind = TokenType::CloseScope,
-                    b';' => tok.kind = TokenType::End,
-                    b'0'..=b'9' => tok.kind = TokenType::LiteralInt,
-                    b'A'..=b'z' => tok.kind = TokenType::Identifier,
-                    _ => panic!("{} Error: Invalid token `{}`", tok.pos, tok.val_str()),
-                }
+            let Some(first) = tok.val.first() else {
+                return Err(Diagnostic::error(tok.pos.clone(), "Cannot have an empty token".to_string()));
+            };
+
+            if let Some((_, kind)) = OPERATORS.iter().chain(PUNCTUATION.iter()).chain(KEYWORDS.iter()).find(|(text, _)| text.as_bytes() == tok.val.as_slice()) {
+                tok.kind = kind.clone();
+            } else if tok.val.iter().all(|c| c.is_ascii_digit()) { // Then match variable contents of words
+                tok.kind = TokenType::LiteralInt;
+                let Ok(value) = tok.val_str().parse::<i64>() else {
+                    return Err(Diagnostic::error(tok.pos.clone(), format!("Integer literal `{}` is out of range", tok.val_str())));
+                };
+                tok.value = Some(value);
+            } else if first.is_ascii_alphabetic() || (len == 1 && matches!(first, b'A'..=b'z')) {
+                tok.kind = TokenType::Identifier;
+            } else if tok.val == b"|" {
+                // NOTE: There are no bitwise operators, so a single `|` is a mistyped `||`.
+                return Err(Diagnostic::error(tok.pos.clone(), "Invalid token `|`, did you mean `||`?".to_string()));
             } else {
-                match tok.val_str().as_str() {
-                    "=="   => tok.kind = TokenType::OpEqual,
-                    "~="   => tok.kind = TokenType::OpNotEqual,
-                    ">="   => tok.kind = TokenType::OpGreaterEqual,
-                    "<="   => tok.kind = TokenType::OpLessEqual,
-                    "&&"   => tok.kind = TokenType::OpLogicalAnd,
-                    "||"   => tok.kind = TokenType::OpLogicalOr,
-                    "exit" => tok.kind = TokenType::KeywordExit,
-                    "func" => tok.kind = TokenType::KeywordFunctionDecl,
-                    "dump" => tok.kind = TokenType::KeywordDebugDump,
-                    "if"   => tok.kind = TokenType::KeywordIf,
-                    "else" => tok.kind = TokenType::KeywordElse,
-                    "let"  => tok.kind = TokenType::KeywordVariableDecl,
-                    _ => { // Then match variable contents of words
-                        if tok.val.iter().all(|c| c.is_ascii_digit()) {
-                            tok.kind = TokenType::LiteralInt;
-                        } else if first.is_ascii_alphabetic() {
-                            tok.kind = TokenType::Identifier;
-                        } else {
-                            panic!("{} Error: Invalid token `{}`", tok.pos, tok.val_str());
-                        }
-                    }
-                }
+                return Err(Diagnostic::error(tok.pos.clone(), format!("Invalid token `{}`", tok.val_str())));
             }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,130 @@
+use std::io::Write;
+use std::panic;
+use crate::diagnostic::CollectorSink;
+use crate::diagnostic::Diagnostic;
+use crate::json::Json;
+use crate::limits::RunLimits;
+use crate::lexer::Lexer;
+use crate::lexer::Token;
+use crate::opt::OptLevel;
+use crate::parser::ParseTree;
+use crate::session::Session;
+use crate::source::FileId;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod artifacts;
+pub mod codegen;
+pub mod consteval;
+pub mod desugar;
+pub mod diagnostic;
+pub mod doc;
+pub mod format;
+pub mod grammar;
+pub mod hir;
+pub mod incremental;
+pub mod interp;
+pub mod instr;
+pub mod json;
+pub mod lexer;
+pub mod limits;
+pub mod nasm;
+pub mod opt;
+pub mod parser;
+pub mod regalloc;
+pub mod sema;
+pub mod semantic;
+pub mod session;
+pub mod source;
+pub mod stress;
+pub mod symbols;
+pub mod target;
+pub mod trace;
+pub mod validate;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// Everything the front end produced for a source, as far as it got before the first error.
+pub struct Inspection {
+    pub tokens: Option<Vec<Token>>,
+    pub ast: Option<ParseTree>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+impl Inspection {
+    pub fn to_json(&self) -> Json {
+        let mut res: Vec<(&str, Json)> = Vec::new();
+        if let Some(toks) = &self.tokens {
+            res.push(("tokens", Json::Array(toks.iter().map(|tok| tok.to_json()).collect())));
+        }
+        if let Some(ast) = &self.ast {
+            res.push(("ast", ast.root.to_json()));
+        }
+        res.push(("diagnostics", Json::Array(self.diagnostics.iter().map(|d| d.to_json()).collect())));
+        Json::object(res)
+    }
+}
+
+// Runs the lexer and parser entirely in memory, without touching the file system or spawning processes.
+// NOTE: The tree is the one written, before desugaring or any checks, compile it with `compile_to_asm`.
+pub fn inspect(src: String, name: String) -> Inspection {
+    let mut res: Inspection = Inspection { tokens: None, ast: None, diagnostics: Vec::new() };
+    let lexed = diagnostic::catch(move || {
+        let mut lexer: Lexer = Lexer::new(src.into_bytes());
+        lexer.tokenize();
+        lexer.lex()?;
+        Ok(lexer)
+    });
+    let lexer: Lexer = match lexed {
+        Err(diag) => {
+            res.diagnostics.push(diag);
+            return res;
+        },
+        Ok(lexer) => lexer,
+    };
+
+    res.tokens = Some(lexer.toks.clone());
+    let parsed = diagnostic::catch(move || {
+        let mut lexer: Lexer = lexer;
+        let mut ast: ParseTree = ParseTree::new(name);
+        ast.construct(&mut lexer)?;
+        Ok(ast)
+    });
+    match parsed {
+        Err(diag) => res.diagnostics.push(diag),
+        Ok(ast) => res.ast = Some(ast),
+    }
+    res
+}
+
+// Compiles a source to NASM assembly entirely in memory, returning every diagnostic if it does not compile.
+// NOTE: Warnings of a source that compiles are dropped, use a `Session` to see them.
+pub fn compile_to_asm(src: &str, opt_level: OptLevel) -> Result<String, Vec<Diagnostic>> {
+    let src: Vec<u8> = src.as_bytes().to_vec();
+    let compiled = diagnostic::catch(move || {
+        let mut session: Session = Session::new();
+        session.opt_level = opt_level;
+        let file: FileId = session.add("source", src);
+        let mut sink: CollectorSink = CollectorSink::default();
+        Ok(session.compile(file, &mut sink).ok_or(sink.diagnostics))
+    });
+    compiled.unwrap_or_else(|diag| Err(vec![diag]))
+}
+
+// Runs a source with the interpreter, writing what it dumps to `out` and returning its exit status, or every diagnostic
+// if it does not compile or fails while running.
+pub fn run_source(src: &str, out: &mut impl Write) -> Result<u8, Vec<Diagnostic>> {
+    run_source_limited(src, out, RunLimits::default())
+}
+
+// Like `run_source`, stopping the program with an error once it goes over the limits. For running untrusted programs.
+pub fn run_source_limited(src: &str, out: &mut impl Write, limits: RunLimits) -> Result<u8, Vec<Diagnostic>> {
+    let src: Vec<u8> = src.as_bytes().to_vec();
+    let ran = diagnostic::catch(panic::AssertUnwindSafe(move || {
+        let mut session: Session = Session::new();
+        session.run_limits = limits;
+        let file: FileId = session.add("source", src);
+        let mut sink: CollectorSink = CollectorSink::default();
+        Ok(session.run(file, out, &mut sink).ok_or(sink.diagnostics))
+    }));
+    ran.unwrap_or_else(|diag| Err(vec![diag]))
+}
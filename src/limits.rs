@@ -0,0 +1,86 @@
+use std::time::Duration;
+use crate::diagnostic::Diagnostic;
+use crate::interp::DEFAULT_MAX_CALL_DEPTH;
+use crate::lexer::Token;
+use crate::lexer::TokenType;
+use crate::parser::DEFAULT_MAX_DEPTH;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+
+// NOTE: Labels are built from identifiers, so this keeps them well within what the assembler accepts.
+pub const DEFAULT_MAX_IDENT_LEN: usize = 256;
+pub const DEFAULT_MAX_FUNCTIONS: usize = 65536;
+pub const DEFAULT_MAX_LOCALS: usize = 4096;
+
+// How large a program may get before it is rejected with a diagnostic rather than slowing every later stage down.
+// Fuzzers lower them to spend their time on inputs that are interesting rather than only big.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Limits {
+    pub max_ident_len: usize, // In bytes
+    pub max_depth: usize, // Levels of parentheses, operators and blocks, checked by the parser
+    pub max_functions: usize, // Counting those in modules
+    pub max_locals: usize, // Declarations in one function, including those of nested blocks
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_ident_len: DEFAULT_MAX_IDENT_LEN,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_functions: DEFAULT_MAX_FUNCTIONS,
+            max_locals: DEFAULT_MAX_LOCALS,
+        }
+    }
+}
+impl Limits {
+    pub fn check_tokens(&self, toks: &[Token]) -> Result<(), Diagnostic> {
+        match toks.iter().find(|tok| tok.kind == TokenType::Identifier && tok.val.len() > self.max_ident_len) {
+            Some(tok) => Err(Diagnostic::error(tok.pos.clone(), format!("Identifier too long (more than {} bytes)", self.max_ident_len))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_tree(&self, ast: &ParseTree) -> Result<(), Diagnostic> {
+        let funcs: Vec<(String, &ParseNode)> = ast.functions();
+        if let Some((_, func)) = funcs.get(self.max_functions) {
+            return Err(Diagnostic::error(func.tok.pos.clone(), format!("Too many functions (more than {})", self.max_functions)));
+        }
+        for (name, func) in funcs {
+            let mut decls: Vec<&ParseNode> = Vec::new();
+            collect_decls(func, &mut decls);
+            if let Some(decl) = decls.get(self.max_locals) {
+                return Err(Diagnostic::error(decl.tok.pos.clone(), format!("Too many locals in `{}` (more than {})", name, self.max_locals)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// How much a program run by the interpreter may do before it is stopped with a diagnostic, so untrusted programs can be
+// run by servers and fuzzers. None leaves that unlimited.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct RunLimits {
+    pub max_steps: Option<u64>, // Statements run, counting every time round a loop
+    pub max_memory: Option<usize>, // Bytes of locals in the calls in progress, 8 for each
+    pub timeout: Option<Duration>, // Wall clock time from the start of the run
+    pub max_call_depth: Option<usize>, // Calls in progress at once
+}
+impl Default for RunLimits {
+    fn default() -> Self {
+        RunLimits { max_steps: None, max_memory: None, timeout: None, max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH) }
+    }
+}
+
+// Declarations in source order.
+fn collect_decls<'a>(node: &'a ParseNode, out: &mut Vec<&'a ParseNode>) {
+    if node.kind == NodeType::VarDecl {
+        out.push(node);
+    }
+    for child in &node.children {
+        collect_decls(child, out);
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use language::diagnostic;
+use language::json::Json;
+use language::lexer::Lexer;
+use language::lexer::Pos;
+use language::incremental::IncrementalParser;
+use language::sema;
+use language::semantic::SemanticKind;
+use language::semantic::SemanticToken;
+use language::semantic::semantic_tokens;
+use language::symbols::Symbol;
+use language::symbols::SymbolKind;
+use language::symbols::SymbolTable;
+
+// LSP constants, see the specification for `DiagnosticSeverity` and `SymbolKind`.
+const SEVERITY_ERROR: i64 = 1;
+const SYMBOL_KIND_FUNCTION: i64 = 12;
+const SYMBOL_KIND_VARIABLE: i64 = 13;
+const ERROR_METHOD_NOT_FOUND: i64 = -32601;
+
+struct LspDiagnostic {
+    pos: Pos,
+    len: usize,
+    msg: String,
+}
+
+struct Document {
+    text: String,
+    parser: IncrementalParser,
+    symbols: Option<SymbolTable>,
+}
+impl Document {
+    fn new(uri: &str) -> Self {
+        Document { text: String::new(), parser: IncrementalParser::new(uri.to_string()), symbols: None }
+    }
+}
+
+// Re-parses the document, reusing whatever the edit did not touch, and reports the resulting diagnostics.
+// NOTE: A panic is an internal compiler error, guard against it so the server keeps running.
+fn analyse(doc: &mut Document, text: &str) -> Vec<LspDiagnostic> {
+    doc.text = text.to_string();
+    let parser: &mut IncrementalParser = &mut doc.parser;
+    let res = diagnostic::catch(AssertUnwindSafe(|| parser.update(text)));
+
+    let mut diags: Vec<LspDiagnostic> = Vec::new();
+    match res {
+        Ok(tree) => {
+            let symbols: SymbolTable = SymbolTable::build(&tree);
+            for diag in sema::check_symbols(&symbols) {
+                let len: usize = symbols.refs.iter().find(|r| r.pos.offset == diag.pos.offset).map_or(1, |r| r.name.len());
+                diags.push(LspDiagnostic { pos: diag.pos, len, msg: diag.msg });
+            }
+            doc.symbols = Some(symbols);
+        },
+        Err(diag) => {
+            doc.symbols = None;
+            diags.push(LspDiagnostic { pos: diag.pos, len: 1, msg: diag.msg });
+        }
+    }
+
+    diags
+}
+
+// Encodes the tokens as the relative (line, start, length, type, modifiers) quintuples LSP expects.
+fn encode_semantic_tokens(text: &str) -> Json {
+    let src: Vec<u8> = text.as_bytes().to_vec();
+    let toks: Vec<SemanticToken> = diagnostic::catch(move || {
+        let mut lexer: Lexer = Lexer::new(src);
+        lexer.tokenize();
+        lexer.lex()?;
+        Ok(semantic_tokens(&lexer))
+    }).unwrap_or_default();
+
+    let lines: Vec<&str> = text.split('\n').collect();
+    let mut data: Vec<Json> = Vec::new();
+    let (mut prev_row, mut prev_col): (usize, usize) = (0, 0);
+    for tok in toks {
+        let line: &str = lines.get(tok.pos.row).copied().unwrap_or("");
+        let col: usize = utf16_col(line, tok.pos.col);
+        let delta_col: usize = if tok.pos.row == prev_row { col - prev_col } else { col };
+        data.push(Json::Int((tok.pos.row - prev_row) as i64));
+        data.push(Json::Int(delta_col as i64));
+        data.push(Json::Int((utf16_col(line, tok.pos.col + tok.len) - col) as i64));
+        data.push(Json::Int(tok.kind.index() as i64));
+        data.push(Json::Int(0));
+        (prev_row, prev_col) = (tok.pos.row, col);
+    }
+    Json::object(vec![("data", Json::Array(data))])
+}
+
+// LSP counts columns in UTF-16 code units where the compiler counts bytes. A column inside a character counts all of it.
+fn utf16_col(line: &str, col: usize) -> usize {
+    let units: usize = line.char_indices().take_while(|(ix, _)| *ix < col).map(|(_, c)| c.len_utf16()).sum();
+    units + col.saturating_sub(line.len())
+}
+
+// The byte column of a column counted in UTF-16 code units, from a client.
+fn byte_col(line: &str, col: usize) -> usize {
+    let mut units: usize = 0;
+    for (ix, c) in line.char_indices() {
+        if units >= col {
+            return ix;
+        }
+        units += c.len_utf16();
+    }
+    line.len() + col.saturating_sub(units)
+}
+
+// `len` bytes from `pos` in the text split into `lines`.
+fn range_json(lines: &[&str], pos: &Pos, len: usize) -> Json {
+    let line: &str = lines.get(pos.row).copied().unwrap_or("");
+    let point = |col: usize| Json::object(vec![("line", Json::Int(pos.row as i64)), ("character", Json::Int(utf16_col(line, col) as i64))]);
+    Json::object(vec![("start", point(pos.col)), ("end", point(pos.col + len))])
+}
+
+fn symbol_json(lines: &[&str], sym: &Symbol, children: Vec<Json>) -> Json {
+    let kind: i64 = match sym.kind {
+        SymbolKind::Function => SYMBOL_KIND_FUNCTION,
+        SymbolKind::Variable => SYMBOL_KIND_VARIABLE,
+    };
+    Json::object(vec![
+        ("name", Json::Str(sym.name.clone())),
+        ("kind", Json::Int(kind)),
+        ("range", range_json(lines, &sym.pos, sym.name.len())),
+        ("selectionRange", range_json(lines, &sym.pos, sym.name.len())),
+        ("children", Json::Array(children)),
+    ])
+}
+
+fn read_message(input: &mut impl BufRead) -> Option<Json> {
+    let mut len: Option<usize> = None;
+    loop {
+        let mut line: String = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line: &str = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(val) = line.strip_prefix("Content-Length:") {
+            len = val.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body: Vec<u8> = vec![0; len?];
+    input.read_exact(&mut body).ok()?;
+    Json::parse(&String::from_utf8_lossy(&body)).ok()
+}
+
+fn send_message(out: &mut impl Write, msg: Json) -> std::io::Result<()> {
+    let body: String = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn respond(out: &mut impl Write, id: &Json, result: Json) -> std::io::Result<()> {
+    send_message(out, Json::object(vec![("jsonrpc", Json::Str("2.0".to_string())), ("id", id.clone()), ("result", result)]))
+}
+
+fn publish_diagnostics(out: &mut impl Write, uri: &str, text: &str, diags: &[LspDiagnostic]) -> std::io::Result<()> {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let diags: Vec<Json> = diags.iter().map(|d| Json::object(vec![
+        ("range", range_json(&lines, &d.pos, d.len)),
+        ("severity", Json::Int(SEVERITY_ERROR)),
+        ("source", Json::Str("language".to_string())),
+        ("message", Json::Str(d.msg.clone())),
+    ])).collect();
+    send_message(out, Json::object(vec![
+        ("jsonrpc", Json::Str("2.0".to_string())),
+        ("method", Json::Str("textDocument/publishDiagnostics".to_string())),
+        ("params", Json::object(vec![("uri", Json::Str(uri.to_string())), ("diagnostics", Json::Array(diags))])),
+    ]))
+}
+
+// Runs a language server speaking LSP over stdin/stdout until the client sends `exit`.
+pub fn run() -> std::io::Result<()> {
+    // NOTE: Compiler errors are caught and reported as diagnostics, so keep them off stderr.
+    panic::set_hook(Box::new(|_| {}));
+
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    serve(stdin.lock(), stdout.lock())
+}
+
+// Answers the messages read from `input` on `out` until the client sends `exit` or `input` ends.
+pub fn serve(mut input: impl BufRead, mut out: impl Write) -> std::io::Result<()> {
+    let mut docs: HashMap<String, Document> = HashMap::new();
+
+    while let Some(msg) = read_message(&mut input) {
+        let method: &str = msg.get("method").and_then(Json::as_str).unwrap_or("");
+        let id: Option<&Json> = msg.get("id");
+        let params: Json = msg.get("params").cloned().unwrap_or(Json::Null);
+        let uri: String = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Json::as_str).unwrap_or("").to_string();
+
+        match method {
+            "initialize" => {
+                let caps: Json = Json::object(vec![
+                    ("textDocumentSync", Json::Int(1)), // Full document sync
+                    ("definitionProvider", Json::Bool(true)),
+                    ("documentSymbolProvider", Json::Bool(true)),
+                    ("semanticTokensProvider", Json::object(vec![
+                        ("legend", Json::object(vec![
+                            ("tokenTypes", Json::Array(SemanticKind::ALL.iter().map(|k| Json::Str(k.lsp_name().to_string())).collect())),
+                            ("tokenModifiers", Json::Array(Vec::new())),
+                        ])),
+                        ("full", Json::Bool(true)),
+                    ])),
+                ]);
+                respond(&mut out, id.unwrap_or(&Json::Null), Json::object(vec![("capabilities", caps)]))?;
+            },
+            "shutdown" => respond(&mut out, id.unwrap_or(&Json::Null), Json::Null)?,
+            "exit" => break,
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text: Option<&str> = if method == "textDocument/didOpen" {
+                    params.get("textDocument").and_then(|d| d.get("text")).and_then(Json::as_str)
+                } else {
+                    params.get("contentChanges").and_then(Json::as_array).and_then(|c| c.last()).and_then(|c| c.get("text")).and_then(Json::as_str)
+                };
+                if let Some(text) = text {
+                    let doc: &mut Document = docs.entry(uri.clone()).or_insert_with(|| Document::new(&uri));
+                    let diags: Vec<LspDiagnostic> = analyse(doc, text);
+                    publish_diagnostics(&mut out, &uri, text, &diags)?;
+                }
+            },
+            "textDocument/didClose" => {
+                docs.remove(&uri);
+                publish_diagnostics(&mut out, &uri, "", &[])?;
+            },
+            "textDocument/definition" => {
+                let row: usize = params.get("position").and_then(|p| p.get("line")).and_then(Json::as_i64).unwrap_or(0) as usize;
+                let col: usize = params.get("position").and_then(|p| p.get("character")).and_then(Json::as_i64).unwrap_or(0) as usize;
+                let result: Json = match docs.get(&uri) {
+                    None => Json::Null,
+                    Some(doc) => {
+                        let lines: Vec<&str> = doc.text.split('\n').collect();
+                        let col: usize = byte_col(lines.get(row).copied().unwrap_or(""), col);
+                        match doc.symbols.as_ref().and_then(|s| s.definition_at(row, col)) {
+                            None => Json::Null,
+                            Some(sym) => Json::object(vec![("uri", Json::Str(uri.clone())), ("range", range_json(&lines, &sym.pos, sym.name.len()))]),
+                        }
+                    },
+                };
+                respond(&mut out, id.unwrap_or(&Json::Null), result)?;
+            },
+            "textDocument/documentSymbol" => {
+                let mut result: Vec<Json> = Vec::new();
+                if let Some(doc) = docs.get(&uri) && let Some(symbols) = &doc.symbols {
+                    let lines: Vec<&str> = doc.text.split('\n').collect();
+                    for (ix, func) in symbols.symbols.iter().enumerate().filter(|(_, s)| s.kind == SymbolKind::Function) {
+                        let vars: Vec<Json> = symbols.symbols.iter()
+                            .filter(|s| s.parent == Some(ix))
+                            .map(|s| symbol_json(&lines, s, Vec::new()))
+                            .collect();
+                        result.push(symbol_json(&lines, func, vars));
+                    }
+                }
+                respond(&mut out, id.unwrap_or(&Json::Null), Json::Array(result))?;
+            },
+            "textDocument/semanticTokens/full" => {
+                let result: Json = match docs.get(&uri) {
+                    None => Json::Null,
+                    Some(doc) => encode_semantic_tokens(&doc.text),
+                };
+                respond(&mut out, id.unwrap_or(&Json::Null), result)?;
+            },
+            _ => {
+                // Unknown notifications are ignored, unknown requests must still be answered.
+                if let Some(id) = id {
+                    send_message(&mut out, Json::object(vec![
+                        ("jsonrpc", Json::Str("2.0".to_string())),
+                        ("id", id.clone()),
+                        ("error", Json::object(vec![("code", Json::Int(ERROR_METHOD_NOT_FOUND)), ("message", Json::Str(format!("Unsupported method `{}`", method)))])),
+                    ]))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
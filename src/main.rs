@@ -1,18 +1,53 @@
-use std::collections::HashMap;
+use std::any::Any;
 use std::env;
 use std::fs;
+use std::io;
 use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
 use std::process::Command;
-use std::slice::Iter;
-use crate::lexer::Lexer;
-use crate::lexer::Token;
-use crate::lexer::TokenType;
-use crate::parser::NodeType;
-use crate::parser::ParseNode;
-use crate::parser::ParseTree;
-
-pub mod lexer;
-pub mod parser;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Instant;
+use language::artifacts::Artifacts;
+use language::codegen::find_label;
+use language::codegen::generate_nasm_x86_file;
+use language::desugar::ASSERT_FAILED_STATUS;
+use language::diagnostic;
+use language::diagnostic::CollectorSink;
+use language::diagnostic::Diagnostic;
+use language::diagnostic::DiagnosticSink;
+use language::diagnostic::JsonSink;
+use language::diagnostic::TerminalSink;
+use language::limits::Limits;
+use language::nasm;
+use language::opt::OptLevel;
+use language::source::DiskProvider;
+use language::source::FileId;
+use language::stress;
+use language::stress::StressConfig;
+use language::target::Target;
+use language::trace;
+use language::session::Session;
+use language::doc;
+use language::doc::DocFormat;
+use language::format::format_program;
+use language::grammar;
+use language::grammar::GrammarFormat;
+use language::instr::AsmProgram;
+use language::interp::TraceFormat;
+use language::json::Json;
+use language::lexer::Lexer;
+use language::lexer::Pos;
+use language::parser::NodeType;
+use language::parser::ParseTree;
+
+pub mod lsp;
+#[cfg(feature = "serve")]
+pub mod playground;
 
 #[cfg(test)]
 pub mod tests;
@@ -20,355 +55,139 @@ pub mod tests;
 #[derive(PartialEq)]
 enum Flag {
     EmitTokens,
+    EmitTokensJson,
+    EmitDiagnosticsJson,
     EmitParseTree,
+    ParseTreeDepth(usize),
+    EmitTokensTo(String), // Writes the printed tokens to this path instead of stderr
+    EmitParseTreeTo(String), // Writes the printed parse tree to this path instead of stderr
     EmitAsm,
+    EmitAsmListing,
+    OnlyFunc(String), // Qualified name of the one function `--emit asm` prints
+    EmitStats,
+    EmitMap,
+    SaveTemps,
+    ObjectOnly, // Stops once the object is assembled, writing it to the output path
+    LinkerScript(String), // Lays out the executable with this script, given to `ld -T`
+    LinkerArgs(Vec<String>), // Passed to `ld` as they are, after the object
+    Strip, // Leaves the symbol table and debug sections out of the executable
+    Check,
     Run
 }
 
-fn generate_node_nasm_x86(f: &mut fs::File, local_vars: &mut HashMap<Vec<u8>, i64>, stack_ix: &mut i64, node: &ParseNode) -> std::io::Result<()> {
-    match node.kind {
-        NodeType::FuncCall => {
-            writeln!(f, "; --- FuncCall {} ---", node.tok.val_str())?;
-            writeln!(f, "    call {}", node.tok.val_str())?;
-        },
-        NodeType::Literal => {
-            writeln!(f, "; --- Literal {} ---", node.tok.val_str())?;
-            writeln!(f, "    mov rax, {}", node.tok.val_str())?;
-            writeln!(f, "    push rax")?;
-        },
-        NodeType::Assign => {
-            match local_vars.get(&node.tok.val) {
-                None => panic!("{} Error: No such variable `{}` in local scope", node.tok.pos, node.tok.val_str()),
-                Some(ofst) => {
-                    writeln!(f, "; --- Assign {} ---", node.tok.val_str())?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    mov [rbp {}], rax", ofst)?;
-                }
-            }
-        },
-        NodeType::VarDecl => {
-            if local_vars.contains_key(&node.tok.val) {
-                panic!("{} Error: Variable with this name is already declared `{}`", node.tok.pos, node.tok.val_str());
-            }
-            writeln!(f, "; --- VarDecl {} ---", node.tok.val_str())?;
-            // NOTE: This relies on the variable value being atop the stack already.
-            local_vars.insert(node.tok.val.clone(), *stack_ix);
-            *stack_ix -= 8;
-        },
-        NodeType::Var => {
-            match local_vars.get(&node.tok.val) {
-                None => panic!("{} Error: No such variable `{}` in local scope", node.tok.pos, node.tok.val_str()),
-                Some(ofst) => {
-                    writeln!(f, "; --- Var {} ---", node.tok.val_str())?;
-                    writeln!(f, "    mov rax, [rbp {}]", ofst)?;
-                    writeln!(f, "    push rax")?;
-                }
-            }
-        },
-        NodeType::Exit => {
-            writeln!(f, "; --- Exit ---")?;
-            writeln!(f, "    pop rdi")?;
-            writeln!(f, "    mov rax, 60")?;
-            writeln!(f, "    syscall")?;
-        },
-        NodeType::DebugDump => {
-            writeln!(f, "; --- DebugDump ---")?;
-            writeln!(f, "    pop rdi")?;
-            writeln!(f, "    call dump")?;
-        },
-        NodeType::UnOp => {
-            match node.tok.kind {
-                TokenType::OpMinus => {
-                    writeln!(f, "; --- UnOp::OpMinus ---")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    neg rax")?;
-                    writeln!(f, "    push rax")?;
-                },
-                _ => panic!("Error: Unknown unary operator kind `{:?}`", node.tok.kind)
-            }
-        },
-        NodeType::BinOp => {
-            match node.tok.kind {
-                TokenType::OpPlus => {
-                    writeln!(f, "; --- BinOp::OpPlus ---")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    add rax, rbx")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpMinus => {
-                    writeln!(f, "; --- BinOp::OpMinus---")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    sub rax, rbx")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpMul => {
-                    writeln!(f, "; --- BinOp::OpMul ---")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    imul rax, rbx")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpDiv => {
-                    writeln!(f, "; --- BinOp::OpDiv ---")?;
-                    writeln!(f, "    pop rcx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    xor rdx, rdx")?;
-                    writeln!(f, "    idiv rcx")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpLessThan => {
-                    writeln!(f, "; --- BinOp::OpLessThan ---")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, rbx")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    setl al")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpLessEqual => {
-                    writeln!(f, "; --- BinOp::OpLessEqual ---")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, rbx")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    setle al")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpGreaterThan => {
-                    writeln!(f, "; --- BinOp::OpGreaterThan ---")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, rbx")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    setg al")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpGreaterEqual => {
-                    writeln!(f, "; --- BinOp::OpGreaterEqual ---")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, rbx")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    setge al")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpEqual => {
-                    writeln!(f, "; --- BinOp::OpEqual ---")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, rbx")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    sete al")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpNotEqual => {
-                    writeln!(f, "; --- BinOp::OpNotEqual ---")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, rbx")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    setne al")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpLogicalOr => {
-                    writeln!(f, "; --- BinOp::OpLogicalOr ---")?;
-                    writeln!(f, "_or_{}_{}:", node.tok.pos.col, node.tok.pos.row)?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    cmp rax, 0")?;
-                    writeln!(f, "    je ._rhs")?;    // If lhs is false, check rhs
-                    writeln!(f, "    mov rax, 1")?;
-                    writeln!(f, "    jmp ._end")?;   // If lhs is true, short circuit
-                    writeln!(f, "._rhs:")?;
-                    writeln!(f, "    cmp rbx, 0")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    setne al")?;   // If rhs is true, set al to 1
-                    writeln!(f, "._end:")?;
-                    writeln!(f, "    push rax")?;
-                },
-                TokenType::OpLogicalAnd => {
-                    writeln!(f, "; --- BinOp::OpLogicalAnd ---")?;
-                    writeln!(f, "_and_{}_{}:", node.tok.pos.col, node.tok.pos.row)?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    pop rbx")?;
-                    writeln!(f, "    cmp rax, 0")?;
-                    writeln!(f, "    jne ._rhs")?;  // If lhs is true, check rhs
-                    writeln!(f, "    jmp ._end")?;  // If lhs is false, short circuit
-                    writeln!(f, "._rhs:")?;
-                    writeln!(f, "    cmp rbx, 0")?;
-                    writeln!(f, "    mov rax, 0")?;
-                    writeln!(f, "    setne al")?;   // If rhs is true, set al to 1
-                    writeln!(f, "._end:")?;
-                    writeln!(f, "    push rax")?;
-                },
-                _ => unimplemented!("Generating assembly for other bin ops"),
-            }
-        },
-        _ => {
-            panic!("{} Error: Invalid node in statement ({:?}) `{}`", node.tok.pos, node.kind, node.tok.val_str())
-        }
-    }
-
-    Ok(())
+// The stage a panic was raised in and its message, kept by the hook `install_ice_hook` sets.
+static ICE: Mutex<Option<(&'static str, String)>> = Mutex::new(None);
+
+// Panics inside a stage are bugs in the compiler rather than in the program, so they are kept to be reported by
+// `report_ice` instead of printed by Rust. Others, like usage errors, are printed as before.
+fn install_ice_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| match trace::current_stage() {
+        Some(stage) => *ICE.lock().unwrap_or_else(|e| e.into_inner()) = Some((stage, diagnostic::panic_message(info.payload()))),
+        None => default(info),
+    }));
 }
 
-fn generate_block_nasm_x86(f: &mut fs::File, local_vars: &mut HashMap<Vec<u8>, i64>, stack_ix: &mut i64, block: &ParseNode) -> std::io::Result<()> {
-    let mut block_stack_ix = *stack_ix;
-    let mut block_local_vars: HashMap<Vec<u8>, i64> = HashMap::new();
-    for var in local_vars.clone() {
-        block_local_vars.insert(var.0, var.1);
-    }
-
-    for block_item in &block.children {
-        generate_block_item_nasm_x86(f, &mut block_local_vars, &mut block_stack_ix, block_item)?;
-    }
-
-    let block_var_cnt: usize = block_local_vars.len() - local_vars.len();
-    writeln!(f, "; --- Deallocate block locals ---")?;
-    writeln!(f, "    add rsp, {}", block_var_cnt * 8)?;
-
-    Ok(())
+// Reports a panic from compiling `path` and exits with the status Rust gives panics.
+fn report_ice(path: &str, payload: Box<dyn Any + Send>) -> ! {
+    let Some((stage, msg)) = ICE.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        panic::resume_unwind(payload);
+    };
+    eprintln!("Error: Internal compiler error in stage `{}` while compiling `{}`: {}", stage, path, msg);
+    eprintln!("Note: This is a bug in the compiler, not in your program. Please file a bug report with the source file and the flags used attached.");
+    eprintln!("\n\x1b[31mCOMPILATION FAILED (internal compiler error)\x1b[0m");
+    process::exit(101);
 }
 
-fn generate_block_item_nasm_x86(f: &mut fs::File, local_vars: &mut HashMap<Vec<u8>, i64>, stack_ix: &mut i64, block_item: &ParseNode) -> std::io::Result<()> {
-    match block_item.kind {
-        NodeType::Conditional => {
-            let tok: &Token = &block_item.tok;
-            let mut it: Iter<ParseNode> = block_item.children.iter();
-            let guard: &ParseNode = it.next().unwrap_or_else(|| panic!("{} Error: Failed to get condition in `if`", block_item.tok.pos));
-            let if_body: &ParseNode = it.next().unwrap_or_else(|| panic!("{} Error: Failed to get true branch in `if`", block_item.tok.pos));
-            let else_body: Option<&ParseNode> = it.next();
-
-            writeln!(f, "; --- Conditional ---")?;
-            for node in &guard.post_order() {
-                generate_node_nasm_x86(f, local_vars, stack_ix, node)?;
-            }
-            writeln!(f, "_if_{}_{}:", tok.pos.row, tok.pos.col)?;
-            match else_body {
-                None => {
-                    writeln!(f, "; --- If (No Else) ---")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, 0")?;
-                    writeln!(f, "    je _end_{}_{}", tok.pos.row, tok.pos.col)?;
-                    generate_block_nasm_x86(f, local_vars, stack_ix, if_body)?;
-                    // for block_item in &if_body.children {
-                    //     generate_block_item_nasm_x86(f, local_vars, stack_ix, block_item)?;
-                    // }
-                    writeln!(f, "_end_{}_{}:", tok.pos.row, tok.pos.col)?;
-                },
-                Some(else_body) => {
-                    writeln!(f, "; --- If ---")?;
-                    writeln!(f, "    pop rax")?;
-                    writeln!(f, "    cmp rax, 0")?;
-                    writeln!(f, "    je _false_{}_{}", tok.pos.row, tok.pos.col)?;
-                    generate_block_nasm_x86(f, local_vars, stack_ix, if_body)?;
-                    // for block_item in &if_body.children {
-                    //     generate_block_item_nasm_x86(f, local_vars, stack_ix, block_item)?;
-                    // }
-                    writeln!(f, "    jmp _end_{}_{}", tok.pos.row, tok.pos.col)?;
-                    writeln!(f, "; --- Else ---")?;
-                    writeln!(f, "_false_{}_{}:", tok.pos.row, tok.pos.col)?;
-                    generate_block_nasm_x86(f, local_vars, stack_ix, else_body)?;
-                    // for block_item in &else_body.children {
-                    //     generate_block_item_nasm_x86(f, local_vars, stack_ix, block_item)?;
-                    // }
-                    writeln!(f, "_end_{}_{}:", tok.pos.row, tok.pos.col)?;
-                }
-            }
-        },
-        NodeType::Assign | NodeType::Exit | NodeType::DebugDump | NodeType::VarDecl | NodeType::FuncCall => {
-            for node in &block_item.post_order() {
-                generate_node_nasm_x86(f, local_vars, stack_ix, node)?;
-            }
+// The assembly `--emit asm` prints, the whole program or only the function named by `only`.
+fn asm_listing(prog: &AsmProgram, ast: &ParseTree, only: Option<&str>) -> Result<String, Diagnostic> {
+    let mut listing: Vec<u8> = Vec::new();
+    let printed = match only {
+        None => nasm::print_program(&mut listing, prog),
+        Some(name) => {
+            let start: Pos = Pos { row: 0, col: 0, offset: 0 };
+            let Some(label) = find_label(ast, name) else {
+                return Err(Diagnostic::error(start, format!("No function `{}` to emit", name)));
+            };
+            let Some(func) = prog.funcs.iter().find(|func| func.name == label) else {
+                return Err(Diagnostic::error(start, format!("`{}` is never called, so it has no assembly", name)));
+            };
+            nasm::print_function(&mut listing, func)
         },
-        _ => panic!("{} Error: Expected block item but got `{}`", block_item.tok.pos, block_item.tok.val_str()),
-    }
-
-    Ok(())
+    };
+    printed.unwrap_or_else(|e| panic!("Error: Failed to print assembly: {e}"));
+    Ok(String::from_utf8(listing).expect("Error: Assembly is not valid UTF-8"))
 }
 
-fn generate_nasm_x86(out_path: &String, ast: &mut ParseTree) -> std::io::Result<()> {
-    let mut f = fs::File::create(out_path)?;
-    writeln!(f, "; --- Header ---")?;
-    writeln!(f, "global _start")?;
-    writeln!(f, "section .text")?;
-    writeln!(f, "; --- Debug Dump ---")?;
-    writeln!(f, "dump:")?;
-    writeln!(f, "    sub rsp, 40")?;
-    writeln!(f, "    lea rsi, [rsp + 31]")?;
-    writeln!(f, "    mov byte [rsp + 31], 10")?;
-    writeln!(f, "    mov ecx, 1")?;
-    writeln!(f, "    mov r8, -3689348814741910323")?;
-    writeln!(f, ".LBB0_1:")?;
-    writeln!(f, "    mov rax, rdi")?;
-    writeln!(f, "    mul r8")?;
-    writeln!(f, "    shr rdx, 3")?;
-    writeln!(f, "    lea eax, [rdx + rdx]")?;
-    writeln!(f, "    lea eax, [rax + 4*rax]")?;
-    writeln!(f, "    mov r9d, edi")?;
-    writeln!(f, "    sub r9d, eax")?;
-    writeln!(f, "    or r9b, 48")?;
-    writeln!(f, "    mov byte [rsi - 1], r9b")?;
-    writeln!(f, "    dec rsi")?;
-    writeln!(f, "    inc rcx")?;
-    writeln!(f, "    cmp rdi, 9")?;
-    writeln!(f, "    mov rdi, rdx")?;
-    writeln!(f, "    ja .LBB0_1")?;
-    writeln!(f, "    mov edi, 1")?;
-    writeln!(f, "    mov rdx, rcx")?;
-    writeln!(f, "    mov rax, 1")?;
-    writeln!(f, "    syscall")?;
-    writeln!(f, "    add rsp, 40")?;
-    writeln!(f, "    ret")?;
-
-    for func in &ast.root.children {
-        assert!(func.kind == NodeType::FuncDecl, "{} Error: Children of root must be functions", func.tok.pos);
-
-        writeln!(f, "; --- FuncDecl {} ---", func.tok.val_str())?;
-        writeln!(f, "{}:", func.tok.val_str())?;
-        writeln!(f, "; --- Prologue {} ---", func.tok.val_str())?;
-        writeln!(f, "    push rbp")?;
-        writeln!(f, "    mov rbp, rsp")?;
-
-        let mut stack_ix: i64 = -8; // after function prologue, first slot is at stack pointer - 4
-        let mut local_vars: HashMap<Vec<u8>, i64> = HashMap::new();
-
-        for block_item in &func.children {
-            generate_block_item_nasm_x86(&mut f, &mut local_vars, &mut stack_ix, block_item)?;
+// What the build note of a program records: its source, the compiler's version and the options it was built with, one
+// `key=value` per line. A reproducible build only records the file names of the source and of every path in the options.
+// NOTE: The output path is left out, where the program was put says nothing about how it was built.
+fn provenance(source: &str, args: &[String], reproducible: bool) -> String {
+    let path: &Path = Path::new(source);
+    let source: String = match reproducible {
+        true => path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+        false => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()).to_string_lossy().to_string(),
+    };
+    let mut options: Vec<String> = Vec::new();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                it.next();
+            },
+            _ if arg == path.as_os_str().to_string_lossy().as_ref() => {},
+            _ if reproducible => options.push(strip_dirs(arg)),
+            _ => options.push(arg.clone()),
         }
-
-        writeln!(f, "; --- Epilogue {} ---", func.tok.val_str())?;
-        writeln!(f, "    mov rsp, rbp")?;
-        writeln!(f, "    pop rbp")?;
-        writeln!(f, "    ret")?;
     }
-
-    writeln!(f, "; --- Footer ---")?;
-    writeln!(f, "_start:")?;
-    writeln!(f, "    call main")?;
-    writeln!(f, "    mov rdi, 0")?;
-    writeln!(f, "    mov rax, 60")?;
-    writeln!(f, "    syscall")?;
-
-    Ok(())
+    format!("source={}\nversion={}\noptions={}", source, env!("CARGO_PKG_VERSION"), options.join(" "))
 }
 
-fn compile(src_code: Vec<u8>, src_path: String, _res_path: String, flags: Vec<Flag>) {
-    eprintln!("\nInfo: Compiling program");
-    let mut obj_path: String = _res_path.clone();
-    obj_path.push_str(".o");
+// Cuts every path in an option down to its file name, like `/opt/cross` to `cross`. Linker arguments are paths of
+// their own between the commas, after a `key=` or a one letter flag like `-L`.
+fn strip_dirs(arg: &str) -> String {
+    let pieces: Vec<String> = arg.split(',').map(|piece| {
+        if !piece.contains('/') {
+            return piece.to_string();
+        }
+        let start: usize = match piece.rfind('=') {
+            Some(ix) => ix + 1,
+            None if piece.starts_with('-') => 2.min(piece.len()),
+            None => 0,
+        };
+        let name: String = Path::new(&piece[start..]).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        format!("{}{}", &piece[..start], name)
+    }).collect();
+    pieces.join(",")
+}
 
-    let mut asm_path: String = _res_path.clone();
-    asm_path.push_str(".asm");
+// What `--emit tokens-json` prints, every token as an object in one array.
+fn tokens_json(lexer: &Lexer) -> String {
+    Json::Array(lexer.toks.iter().map(|tok| tok.to_json()).collect()).to_string()
+}
 
-    let mut res_path = _res_path.clone();
-    res_path.insert_str(0, "./");
+// Compiles a source into an executable, reporting compile errors into the sink. Returns false if any were found.
+fn compile(session: &Session, file: FileId, _res_path: String, flags: Vec<Flag>, sink: &mut dyn DiagnosticSink) -> bool {
+    eprintln!();
+    trace::info("Compiling program");
+    let artifacts: Artifacts = if flags.contains(&Flag::SaveTemps) {
+        Artifacts::saved(&_res_path).unwrap_or_else(|e| panic!("Error: Failed to create artifact directory: {e}"))
+    } else {
+        Artifacts::beside(&_res_path)
+    };
+    let object_only: bool = flags.contains(&Flag::ObjectOnly);
+    let obj_path: String = if object_only { _res_path.clone() } else { artifacts.path("o") };
+    let asm_path: String = artifacts.path("asm");
+
+    // A bare name would be looked up on PATH when run, so point it at the working directory.
+    let mut res_path: String = _res_path.clone();
+    if !res_path.contains('/') {
+        res_path.insert_str(0, "./");
+    }
 
-    let mut lexer: Lexer = Lexer::new(src_code);
-    lexer.tokenize();
-    lexer.lex();
+    let Some(mut lexer) = diagnostic::report(session.lex(file), file, sink) else {
+        return false;
+    };
     if flags.contains(&Flag::EmitTokens) {
         eprintln!("Info: Emitting Tokens:");
         for tok in &lexer.toks {
@@ -376,55 +195,330 @@ fn compile(src_code: Vec<u8>, src_path: String, _res_path: String, flags: Vec<Fl
         }
         eprintln!();
     }
-    let ast = &mut parser::ParseTree::new(src_path.clone());
-    ast.construct(&mut lexer);
+    let toks: String = lexer.toks.iter().map(|tok| format!("{}: {:?} `{}`\n", tok.pos, tok.kind, tok.val_str())).collect();
+    artifacts.save("tokens", &toks).unwrap_or_else(|e| panic!("Error: Failed to save tokens: {e}"));
+    if let Some(path) = flags.iter().find_map(|flag| if let Flag::EmitTokensTo(path) = flag { Some(path) } else { None }) {
+        fs::write(path, &toks).unwrap_or_else(|e| panic!("Error: Failed to write tokens to `{path}`: {e}"));
+    }
+    if flags.contains(&Flag::EmitTokensJson) {
+        println!("{}", tokens_json(&lexer));
+    }
+    let Some(ast) = diagnostic::report(session.parse(file, &mut lexer), file, sink) else {
+        return false;
+    };
+    artifacts.save("ast.json", &ast.root.to_json().to_string()).unwrap_or_else(|e| panic!("Error: Failed to save parse tree: {e}"));
+    let depth: Option<usize> = flags.iter().find_map(|flag| if let Flag::ParseTreeDepth(depth) = flag { Some(*depth) } else { None });
     if flags.contains(&Flag::EmitParseTree) {
         eprintln!("Info: Emitting Parse Tree:");
-        ast.dump();
+        eprint!("{}", ast.root.tree_string(depth));
         eprintln!();
     }
+    if let Some(path) = flags.iter().find_map(|flag| if let Flag::EmitParseTreeTo(path) = flag { Some(path) } else { None }) {
+        fs::write(path, ast.root.tree_string(depth)).unwrap_or_else(|e| panic!("Error: Failed to write parse tree to `{path}`: {e}"));
+    }
 
-    let generate = generate_nasm_x86(&asm_path, ast);
+    let diags: Vec<Diagnostic> = session.check(file, &ast);
+    let failed: bool = diags.iter().any(|d| d.is_error());
+    diags.into_iter().for_each(|d| sink.emit(d));
+    if failed {
+        return false;
+    }
+    let Some(prog) = diagnostic::report(session.lower(file, &ast), file, sink) else {
+        return false;
+    };
+    artifacts.save("ir", &prog.ir()).unwrap_or_else(|e| panic!("Error: Failed to save instructions: {e}"));
+    if flags.contains(&Flag::EmitStats) {
+        eprintln!("Info: Emitting Stats:");
+        for func in &prog.funcs {
+            eprintln!("    Function: {}: frame {} bytes, {} instructions, {} spills", func.name, func.frame_size, func.instr_count(), func.spills);
+        }
+        eprintln!();
+    }
+    if flags.contains(&Flag::EmitAsmListing) {
+        let only: Option<&str> = flags.iter().find_map(|flag| if let Flag::OnlyFunc(name) = flag { Some(name.as_str()) } else { None });
+        let Some(listing) = diagnostic::report(asm_listing(&prog, &ast, only), file, sink) else {
+            return false;
+        };
+        eprintln!("Info: Emitting Assembly:");
+        eprint!("{}", listing);
+        eprintln!();
+    }
+    if flags.contains(&Flag::EmitMap) {
+        eprintln!("Info: Emitting Map:");
+        let mut offset: usize = 0;
+        for (label, size) in nasm::symbol_map(&prog) {
+            eprintln!("    {:08x} {:>6} {}", offset, size, label);
+            offset += size;
+        }
+        eprintln!("    Total: {} bytes", offset);
+        eprintln!();
+    }
+    if flags.contains(&Flag::Check) {
+        eprintln!("\n\x1b[92mCHECK COMPLETE\x1b[0m");
+        return true;
+    }
+    let generate = trace::stage("emit", || generate_nasm_x86_file(&asm_path, &prog));
     let _ = generate.inspect_err(|e| panic!("Error: Failed to generate assembly: {e}"));
 
-    eprintln!("Info: Calling `nasm -f elf64 -o {} {}`", &obj_path, &asm_path);
-    let assemble = Command::new("nasm").arg("-f").arg("elf64").arg("-o").arg(&obj_path).arg(&asm_path).output();
-    let assemble_err: String = String::from_utf8(assemble.ok().unwrap().stderr).expect("");
+    let (nasm_path, ld_path): (PathBuf, PathBuf) = (session.target.tool(session.sysroot.as_deref(), "nasm"), session.target.tool(session.sysroot.as_deref(), "ld"));
+    trace::info(&format!("Calling `{} -f {} -o {} {}`", nasm_path.display(), session.target.object_format(), &obj_path, &asm_path));
+    let mut nasm: Command = Command::new(&nasm_path);
+    // nasm records the path of its input in the object, so a reproducible build gives it the bare file name of the
+    // assembly from the directory it is in. The object is named in full, as `-c` may put it somewhere else.
+    let (obj_arg, asm_arg): (String, String) = match Path::new(&asm_path).parent() {
+        Some(dir) if session.reproducible && !dir.as_os_str().is_empty() => {
+            nasm.current_dir(dir);
+            let obj: PathBuf = std::path::absolute(&obj_path).unwrap_or_else(|e| panic!("Error: Failed to resolve `{}`: {e}", &obj_path));
+            let asm: String = Path::new(&asm_path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            (obj.to_string_lossy().to_string(), asm)
+        },
+        _ => (obj_path.clone(), asm_path.clone()),
+    };
+    let assemble = trace::stage("assemble", || nasm.arg("-f").arg(session.target.object_format()).arg("-o").arg(&obj_arg).arg(&asm_arg).output());
+    let assemble_err: String = String::from_utf8(assemble.unwrap_or_else(|e| panic!("Error: Failed to run nasm: {e}")).stderr).expect("");
     if !assemble_err.is_empty() {
         panic!("\n\x1b[31mCOMPILATION FAILED (assembler) \n{}\x1b[0m", assemble_err);
     }
 
-    eprintln!("Info: Calling `ld -o {} {}`", &res_path, &obj_path);
-    let link = Command::new("ld").arg("-o").arg(&res_path).arg(&obj_path).output();
-    let link_err: String = String::from_utf8(link.ok().unwrap().stderr).expect("");
-    if !link_err.is_empty() {
-        panic!("\n\x1b[31mCOMPILATION FAILED (linker) \n{}\x1b[0m", link_err);
+    // With `-c` the object is the output, left for another program to link.
+    if !object_only {
+        let mut ld: Command = Command::new(&ld_path);
+        ld.arg("-m").arg(session.target.linker_emulation());
+        if flags.contains(&Flag::Strip) {
+            ld.arg("-s");
+        }
+        if let Some(root) = &session.sysroot {
+            ld.arg(format!("--sysroot={}", root.display()));
+        }
+        for flag in &flags {
+            if let Flag::LinkerScript(script) = flag {
+                ld.arg("-T").arg(script);
+            }
+        }
+        ld.arg("-o").arg(&res_path).arg(&obj_path);
+        for flag in &flags {
+            if let Flag::LinkerArgs(args) = flag {
+                ld.args(args);
+            }
+        }
+        let shown: Vec<String> = ld.get_args().map(|arg| arg.to_string_lossy().to_string()).collect();
+        trace::info(&format!("Calling `{} {}`", ld_path.display(), shown.join(" ")));
+        let link = trace::stage("link", || ld.output());
+        let link_err: String = String::from_utf8(link.unwrap_or_else(|e| panic!("Error: Failed to run ld: {e}")).stderr).expect("");
+        if !link_err.is_empty() {
+            panic!("\n\x1b[31mCOMPILATION FAILED (linker) \n{}\x1b[0m", link_err);
+        }
     }
 
-    if !flags.contains(&Flag::EmitAsm) {
-        eprintln!("Info: Calling `rm {}`", &asm_path);
-        let rm_asm = Command::new("rm").arg(&asm_path).output();
-        let rm_asm_err: String = String::from_utf8(rm_asm.expect("Error: Failed to retrieve output of assembling").stderr).expect("Error: Failed to convert stderr to string");
-        if !rm_asm_err.is_empty() {
-            panic!("\n\x1b[31mCOMPILATION FAILED (delete intermediate .asm) \n{}\x1b[0m", rm_asm_err);
+    if artifacts.keep {
+        trace::info(&format!("Keeping `{}` along with the other intermediates", &asm_path));
+    } else {
+        if !flags.contains(&Flag::EmitAsm) {
+            trace::info(&format!("Deleting `{}`", &asm_path));
+            artifacts.discard("asm").unwrap_or_else(|e| panic!("\n\x1b[31mCOMPILATION FAILED (delete intermediate .asm) \n{}\x1b[0m", e));
+        }
+        if !object_only {
+            trace::info(&format!("Deleting `{}`", &obj_path));
+            artifacts.discard("o").unwrap_or_else(|e| panic!("\n\x1b[31mCOMPILATION FAILED (delete intermediate .o) \n{}\x1b[0m", e));
         }
     }
 
-    eprintln!("Info: Calling `rm {}`", &obj_path);
-    let rm_o = Command::new("rm").arg(&obj_path).output();
-    let rm_o_err: String = String::from_utf8(rm_o.expect("Error: Failed to retrieve result of linking").stderr).expect("Error: Failed to convert stderr to string");
-    if !rm_o_err.is_empty() {
-        panic!("\n\x1b[31mCOMPILATION FAILED (delete intermediate .o) \n{}\x1b[0m", rm_o_err);
+    // Builds made to be small say how small they came out.
+    if session.opt_level == OptLevel::Os || flags.contains(&Flag::Strip) {
+        let code: usize = nasm::symbol_map(&prog).iter().map(|(_, size)| size).sum();
+        let file: u64 = fs::metadata(&res_path).map(|meta| meta.len()).unwrap_or_else(|e| panic!("Error: Failed to read the size of `{}`: {e}", &res_path));
+        trace::info(&format!("Size: {} bytes of code, {} bytes in `{}`", code, file, &res_path));
     }
 
     eprintln!("\n\x1b[92mCOMPILATION COMPLETE\x1b[0m");
 
     if flags.contains(&Flag::Run) {
-        eprintln!("Info: Calling `{}`", &res_path);
+        trace::info(&format!("Calling `{}`", &res_path));
         let run = Command::new(&res_path).spawn().expect("Error: Failed to run executable").wait_with_output();
         let status = run.expect("Error: Failed to retrieve output of running").status;
-        eprintln!("Info: Exit code {}", status.code().expect("Error: Failed to retrieve exit code of executable"));
+        trace::info(&format!("Exit code {}", status.code().expect("Error: Failed to retrieve exit code of executable")));
+    }
+    true
+}
+
+// Sources in `dir`, in name order. Those with a `.expected` file of the same name beside them are run as a whole, and
+// the test functions of any of them are run one by one.
+fn discover_tests(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut tests: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path: PathBuf = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "lang") {
+            tests.push(path);
+        }
+    }
+    tests.sort();
+    Ok(tests)
+}
+
+// Qualified names of the functions marked `test` in a source, in source order.
+fn test_functions(path: &Path) -> Result<Vec<String>, String> {
+    let mut session: Session = Session::new();
+    let file: FileId = session.sources.load(&DiskProvider, &path.to_string_lossy()).map_err(|e| format!("Failed to read source: {}", e))?;
+    let ast: ParseTree = diagnostic::catch(AssertUnwindSafe(|| {
+        let mut lexer: Lexer = session.lex(file)?;
+        session.parse_surface(file, &mut lexer)
+    })).map_err(|d| d.to_string())?;
+    Ok(ast.functions().into_iter().filter(|(_, func)| func.kind == NodeType::TestFuncDecl).map(|(name, _)| name).collect())
+}
+
+// Compiles and runs one source from `main` or the given function, natively or in the interpreter, giving what it
+// printed and its exit status. Compile errors and failures to build it are given as the reason it could not run.
+// NOTE: The executable is built in `scratch`, so the test's directory is left as it was.
+fn run_lang_test(path: &Path, entry: Option<&str>, interp: bool, scratch: &Path) -> Result<(Vec<u8>, i32), String> {
+    let mut session: Session = Session::new();
+    session.entry = entry.map(str::to_string);
+    let file: FileId = session.sources.load(&DiskProvider, &path.to_string_lossy()).map_err(|e| format!("Failed to read source: {}", e))?;
+    let mut sink: CollectorSink = CollectorSink::default();
+    let errors = |sink: &CollectorSink| sink.diagnostics.iter().map(|d| d.to_string()).collect::<Vec<String>>().join("\n");
+    if interp {
+        let mut out: Vec<u8> = Vec::new();
+        let status: Option<u8> = diagnostic::catch(AssertUnwindSafe(|| Ok(session.run(file, &mut out, &mut sink)))).map_err(|d| d.to_string())?;
+        return status.map(|status| (out, status as i32)).ok_or_else(|| errors(&sink));
+    }
+    let asm: String = diagnostic::catch(AssertUnwindSafe(|| Ok(session.compile(file, &mut sink)))).map_err(|d| d.to_string())?
+        .ok_or_else(|| errors(&sink))?;
+    let mut name: String = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    if let Some(entry) = entry {
+        name = format!("{}-{}", name, entry);
+    }
+    let exe_path: PathBuf = build_executable(&asm, scratch, &name)?;
+    let run = Command::new(&exe_path).output().map_err(|e| format!("Failed to run executable: {}", e))?;
+    Ok((run.stdout, run.status.code().unwrap_or(-1)))
+}
+
+// Assembles and links a program into `scratch`, giving the path of the executable.
+fn build_executable(asm: &str, scratch: &Path, name: &str) -> Result<PathBuf, String> {
+    let (asm_path, obj_path, exe_path) = (scratch.join(format!("{}.asm", name)), scratch.join(format!("{}.o", name)), scratch.join(name));
+    fs::write(&asm_path, asm).map_err(|e| format!("Failed to write assembly: {}", e))?;
+    let build = |command: &mut Command, tool: &str| -> Result<(), String> {
+        let built = command.output().map_err(|e| format!("Failed to run {}: {}", tool, e))?;
+        match built.status.success() {
+            true => Ok(()),
+            false => Err(format!("{} failed: {}", tool, String::from_utf8_lossy(&built.stderr).trim())),
+        }
+    };
+    let target: Target = Target::default();
+    build(Command::new("nasm").arg("-f").arg(target.object_format()).arg("-o").arg(&obj_path).arg(&asm_path), "nasm")?;
+    build(Command::new("ld").arg("-m").arg(target.linker_emulation()).arg("-o").arg(&exe_path).arg(&obj_path), "ld")?;
+    Ok(exe_path)
+}
+
+// Compiles a source at `-O2` and runs it `runs` times with its output thrown away, giving the wall time of each run
+// in seconds and the exit status of the last.
+fn bench(path: &Path, runs: usize, scratch: &Path) -> Result<(Vec<f64>, i32), String> {
+    let mut session: Session = Session::new();
+    session.opt_level = OptLevel::O2;
+    let file: FileId = session.sources.load(&DiskProvider, &path.to_string_lossy()).map_err(|e| format!("Failed to read source: {}", e))?;
+    let mut sink: CollectorSink = CollectorSink::default();
+    let asm: String = diagnostic::catch(AssertUnwindSafe(|| Ok(session.compile(file, &mut sink)))).map_err(|d| d.to_string())?
+        .ok_or_else(|| sink.diagnostics.iter().map(|d| d.to_string()).collect::<Vec<String>>().join("\n"))?;
+    let name: String = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let exe_path: PathBuf = build_executable(&asm, scratch, &name)?;
+    let mut times: Vec<f64> = Vec::new();
+    let mut code: i32 = 0;
+    for _ in 0..runs {
+        let start: Instant = Instant::now();
+        let status = Command::new(&exe_path).stdout(Stdio::null()).status().map_err(|e| format!("Failed to run executable: {}", e))?;
+        times.push(start.elapsed().as_secs_f64());
+        code = status.code().unwrap_or(-1);
+    }
+    Ok((times, code))
+}
+
+// The fastest, mean and standard deviation of some run times in seconds, in milliseconds.
+fn bench_summary(times: &[f64]) -> String {
+    let n: f64 = times.len() as f64;
+    let min: f64 = times.iter().copied().fold(f64::INFINITY, f64::min);
+    let mean: f64 = times.iter().sum::<f64>() / n;
+    // NOTE: The sample deviation, as the runs stand in for every run the program could make.
+    let var: f64 = if times.len() > 1 { times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (n - 1.0) } else { 0.0 };
+    format!("{} runs: min {:.3} ms, mean {:.3} ms, stddev {:.3} ms", times.len(), min * 1e3, mean * 1e3, var.sqrt() * 1e3)
+}
+
+// The lines where `got` differs from `exp`, numbered from 1.
+fn output_diff(exp: &str, got: &str) -> String {
+    let (exp, got): (Vec<&str>, Vec<&str>) = (exp.lines().collect(), got.lines().collect());
+    let mut res: String = String::new();
+    for row in 0..exp.len().max(got.len()) {
+        let show = |line: Option<&&str>| line.map_or("nothing".to_string(), |line| format!("`{}`", line));
+        if exp.get(row) != got.get(row) {
+            res.push_str(&format!("    Line {}: expected {}, got {}\n", row + 1, show(exp.get(row)), show(got.get(row))));
+        }
+    }
+    res
+}
+
+// Runs every test in `dir` the way `src/tests.rs` runs the compiler's own: the output must match `name.expected` and the
+// exit status `name.exitcode`, or 0 if there is none. Each test function then runs on its own and passes if it returns,
+// failing if an `assert` in it fails or it exits with another status. Returns how many passed and failed.
+fn run_lang_tests(dir: &Path, interp: bool, out: &mut dyn Write) -> io::Result<(usize, usize)> {
+    let scratch: PathBuf = env::temp_dir().join(format!("language-test-runner-{}", process::id()));
+    fs::create_dir_all(&scratch)?;
+    let (mut passed, mut failed): (usize, usize) = (0, 0);
+    let mut report = |out: &mut dyn Write, name: &str, problems: String| -> io::Result<()> {
+        if problems.is_empty() {
+            passed += 1;
+            writeln!(out, "\x1b[92mPASS\x1b[0m {}", name)
+        } else {
+            failed += 1;
+            write!(out, "\x1b[31mFAIL\x1b[0m {}\n{}", name, problems)
+        }
+    };
+    let indent = |reason: String| -> String { reason.lines().map(|line| format!("    {}\n", line)).collect() };
+    for path in discover_tests(dir)? {
+        let name: String = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+        let fixture: bool = path.with_extension("expected").is_file();
+        if fixture {
+            let problems: String = run_fixture(&path, &name, interp, &scratch)?;
+            report(out, &name, problems)?;
+        }
+        // A source that does not parse has failed as a fixture already.
+        let tests: Vec<String> = match test_functions(&path) {
+            Ok(tests) => tests,
+            Err(_) if fixture => Vec::new(),
+            Err(reason) => {
+                report(out, &name, indent(reason))?;
+                continue;
+            },
+        };
+        for test in tests {
+            let problems: String = match run_lang_test(&path, Some(&test), interp, &scratch) {
+                Ok((_, 0)) => String::new(),
+                Ok((_, code)) if code == ASSERT_FAILED_STATUS as i32 => "    Assertion failed\n".to_string(),
+                Ok((_, code)) => format!("    Exit status {}, expected 0\n", code),
+                Err(reason) => indent(reason),
+            };
+            report(out, &format!("{}::{}", name, test), problems)?;
+        }
+    }
+    fs::remove_dir_all(&scratch)?;
+    writeln!(out, "\n{} passed, {} failed", passed, failed)?;
+    Ok((passed, failed))
+}
+
+// Runs a source from `main`, giving where its output and exit status differ from `name.expected` and `name.exitcode`.
+fn run_fixture(path: &Path, name: &str, interp: bool, scratch: &Path) -> io::Result<String> {
+    let exp: String = fs::read_to_string(path.with_extension("expected"))?;
+    let exp_code: i32 = match fs::read_to_string(path.with_extension("exitcode")) {
+        Ok(code) => code.trim().parse::<i32>().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("`{}.exitcode` does not hold an integer", name)))?,
+        Err(_) => 0,
+    };
+    let mut problems: String = String::new();
+    match run_lang_test(path, None, interp, scratch) {
+        Ok((got, code)) => {
+            problems.push_str(&output_diff(&exp, &String::from_utf8_lossy(&got)));
+            if code != exp_code {
+                problems.push_str(&format!("    Exit status {}, expected {}\n", code, exp_code));
+            }
+        },
+        Err(reason) => problems = reason.lines().map(|line| format!("    {}\n", line)).collect(),
     }
+    Ok(problems)
 }
 
 pub fn usage(com: &str) -> String {
@@ -433,13 +527,52 @@ pub fn usage(com: &str) -> String {
 
 \x1b[92mUSAGE:\x1b[0m
   {} \x1b[33m<input-file> <flags>\x1b[0m 
+  {} \x1b[33mlsp\x1b[0m                   Run the language server over stdio
+  {} \x1b[33mgrammar --format <fmt>\x1b[0m  Print an editor grammar (tmlanguage | tree-sitter)
+  {} \x1b[33mdoc <input-file> [--format <fmt>]\x1b[0m  Print function docs (md | html)
+  {} \x1b[33mfmt <input-file>\x1b[0m      Print the program in the canonical layout
+  {} \x1b[33mgen-test [--stmts N] [--depth D] [--seed S]\x1b[0m  Print a large generated program
+  {} \x1b[33mbench <input-file> [--runs N]\x1b[0m  Time N runs (10 by default) of the program built at -O2
+  {} \x1b[33minterp <input-file> [--trace | --trace-json]\x1b[0m  Run the program in the interpreter, logging each statement with --trace
+  {} \x1b[33mtest <dir> [--interp]\x1b[0m   Run every `.lang` file in <dir> with a `.expected` file beside it, and every `test func`
 
 \x1b[92mFLAGS:\x1b[0m
   \x1b[33m-r     --run\x1b[0m:          Run after compiling
+  \x1b[33m--check\x1b[0m:               Report errors without generating output
+  \x1b[33m-c\x1b[0m:                    Only assemble, writing the object to the output path (default output.o)
   \x1b[33m-pt    --parse-tree\x1b[0m:   Print parse tree
+  \x1b[33m--parse-tree-depth <n>\x1b[0m: Only print the parse tree <n> levels deep
   \x1b[33m-t     --tokens\x1b[0m:       Print tokens
   \x1b[33m-a     --assembly\x1b[0m:     Keep intermediate assembly
-", com)
+  \x1b[33m--save-temps\x1b[0m:          Keep tokens, AST, instructions, assembly and object under <output>.d/
+  \x1b[33m-o     --output\x1b[0m:       Specify output path
+  \x1b[33m-O0 -O1 -O2 -Os\x1b[0m:       Optimisation level, -Os for the smallest code (default -O0)
+  \x1b[33m--strip\x1b[0m:               Leave symbols out of the executable
+  \x1b[33m--no-reorder-blocks\x1b[0m:   Keep blocks in source order when optimising
+  \x1b[33m--max-depth <n>\x1b[0m:       Nesting limit for parentheses, operators and blocks (default 128)
+  \x1b[33m--max-ident-len <n>\x1b[0m:   Longest identifier in bytes (default 256)
+  \x1b[33m--max-functions <n>\x1b[0m:   Most functions in a program (default 65536)
+  \x1b[33m--max-locals <n>\x1b[0m:      Most locals declared in one function (default 4096)
+  \x1b[33m--script\x1b[0m:              Run statements outside of functions as the body of `main`
+  \x1b[33m--dump-same-line\x1b[0m:      Print the values of one `dump` on one line, separated by spaces
+  \x1b[33m--entry <name>\x1b[0m:        Function to start the program at in place of `main`
+  \x1b[33m--no-prelude\x1b[0m:          Leave out the routines behind `dump`, `rand` and `time_ns`
+  \x1b[33m--ast-validate\x1b[0m:        Check the parse tree after every stage (always on in debug builds)
+  \x1b[33m--reproducible\x1b[0m:        Keep the directories of the source and output out of the build
+  \x1b[33m--no-provenance\x1b[0m:       Leave the source, compiler version and options out of the output
+  \x1b[33m--target <triple>\x1b[0m:     Target to build for (default x86_64-unknown-linux-gnu)
+  \x1b[33m--linker-script <file>\x1b[0m: Lay out the executable with this `ld` script
+  \x1b[33m-Wl,<arg>[,<arg>...]\x1b[0m:  Pass the arguments on to `ld`
+  \x1b[33m--sysroot <dir>\x1b[0m:       Look for the target's assembler and linker under <dir>/bin and <dir>/usr/bin first
+  \x1b[33m--emit tokens-json\x1b[0m:    Print tokens as JSON to stdout
+  \x1b[33m--emit diagnostics-json\x1b[0m: Print errors as JSON lines to stderr
+  \x1b[33m--emit stats\x1b[0m:          Print frame size, instruction and spill counts per function
+  \x1b[33m--emit map\x1b[0m:            Print every label with its offset and size in bytes
+  \x1b[33m--emit asm\x1b[0m:            Print the generated assembly
+  \x1b[33m--only-func <name>\x1b[0m:    Only print the assembly of this function with `--emit asm`
+  \x1b[33m--emit tokens=<path>\x1b[0m:  Write the tokens to a file rather than stderr
+  \x1b[33m--emit ast=<path>\x1b[0m:     Write the parse tree to a file rather than stderr
+", com, com, com, com, com, com, com, com, com)
 }                  
                    
 pub fn main() {
@@ -447,20 +580,243 @@ pub fn main() {
 
     // TODO: make the args come in any order then make sure tests run with new name
 
+    // Driver messages are events with the `tracing` feature, print them like the plain build unless the host says otherwise.
+    #[cfg(feature = "tracing")]
+    let _ = tracing::subscriber::set_global_default(trace::StderrSubscriber::default());
+
     let mut flags: Vec<Flag> = Vec::new();
 
     let mut it = env::args();
     let com: String = it.next().unwrap_or_else(|| panic!("Error: Failed to get command name from args"));
+    match env::args().nth(1).as_deref() {
+        Some("lsp") => {
+            lsp::run().unwrap_or_else(|e| panic!("Error: Language server failed: {e}"));
+            return;
+        },
+        Some("grammar") => {
+            it.next();
+            let format: GrammarFormat = match (it.next().as_deref(), it.next().as_deref()) {
+                (Some("--format"), Some("tmlanguage")) => GrammarFormat::TmLanguage,
+                (Some("--format"), Some("tree-sitter")) => GrammarFormat::TreeSitter,
+                _ => panic!("{}", usage(&com)),
+            };
+            println!("{}", grammar::generate(format));
+            return;
+        },
+        #[cfg(feature = "serve")]
+        Some("serve") => {
+            it.next();
+            let mut addr: String = "127.0.0.1:8080".to_string();
+            while let Some(arg) = it.next() {
+                match arg.as_str() {
+                    "--addr" => addr = it.next().unwrap_or_else(|| panic!("{}", usage(&com))),
+                    _ => panic!("{}", usage(&com)),
+                }
+            }
+            playground::run(&addr).unwrap_or_else(|e| panic!("Error: Playground server failed: {e}"));
+            return;
+        },
+        Some("doc") => {
+            it.next();
+            let mut format: DocFormat = DocFormat::Markdown;
+            let mut path: Option<String> = None;
+            while let Some(arg) = it.next() {
+                match arg.as_str() {
+                    "--format" => {
+                        match it.next().as_deref() {
+                            Some("md") => format = DocFormat::Markdown,
+                            Some("html") => format = DocFormat::Html,
+                            _ => panic!("{}", usage(&com)),
+                        }
+                    },
+                    _ => path = Some(arg),
+                }
+            }
+            let path: String = path.unwrap_or_else(|| panic!("{}", usage(&com)));
+            let mut session: Session = Session::new();
+            let file: FileId = session.sources.load(&DiskProvider, &path).unwrap_or_else(|_| panic!("{}", usage(&com)));
+            let mut sink: TerminalSink = TerminalSink::new(&session.sources);
+            let Some(mut lexer) = diagnostic::report(session.lex(file), file, &mut sink) else {
+                process::exit(1);
+            };
+            let Some(ast) = diagnostic::report(session.parse(file, &mut lexer), file, &mut sink) else {
+                process::exit(1);
+            };
+            print!("{}", doc::render(&path, &doc::collect(&lexer, &ast), format));
+            return;
+        },
+        Some("test") => {
+            it.next();
+            let mut dir: Option<String> = None;
+            let mut interp: bool = false;
+            for arg in it.by_ref() {
+                match arg.as_str() {
+                    "--interp" => interp = true,
+                    _ if dir.is_none() => dir = Some(arg),
+                    _ => panic!("{}", usage(&com)),
+                }
+            }
+            let dir: String = dir.unwrap_or_else(|| panic!("{}", usage(&com)));
+            let (_, failed) = run_lang_tests(Path::new(&dir), interp, &mut io::stdout())
+                .unwrap_or_else(|e| panic!("Error: Failed to run the tests in `{dir}`: {e}"));
+            process::exit(if failed == 0 { 0 } else { 1 });
+        },
+        Some("interp") => {
+            it.next();
+            let mut path: Option<String> = None;
+            let mut format: Option<TraceFormat> = None;
+            for arg in it.by_ref() {
+                match arg.as_str() {
+                    "--trace" => format = Some(TraceFormat::Text),
+                    "--trace-json" => format = Some(TraceFormat::Json),
+                    _ if path.is_none() => path = Some(arg),
+                    _ => panic!("{}", usage(&com)),
+                }
+            }
+            let path: String = path.unwrap_or_else(|| panic!("{}", usage(&com)));
+            let mut session: Session = Session::new();
+            let file: FileId = session.sources.load(&DiskProvider, &path).unwrap_or_else(|_| panic!("{}", usage(&com)));
+            let mut sink: TerminalSink = TerminalSink::new(&session.sources);
+            let mut out = io::stdout();
+            let status: Option<u8> = match format {
+                // NOTE: The trace goes to stderr, so what the program dumps can still be piped on its own.
+                Some(format) => session.run_traced(file, &mut out, format, &mut io::stderr(), &mut sink),
+                None => session.run(file, &mut out, &mut sink),
+            };
+            let _ = out.flush();
+            process::exit(status.map_or(1, i32::from));
+        },
+        Some("bench") => {
+            it.next();
+            let mut path: Option<String> = None;
+            let mut runs: usize = 10;
+            while let Some(arg) = it.next() {
+                match arg.as_str() {
+                    "--runs" => runs = it.next().and_then(|n| n.parse::<usize>().ok()).filter(|n| *n > 0).unwrap_or_else(|| panic!("{}", usage(&com))),
+                    _ if path.is_none() => path = Some(arg),
+                    _ => panic!("{}", usage(&com)),
+                }
+            }
+            let path: String = path.unwrap_or_else(|| panic!("{}", usage(&com)));
+            let scratch: PathBuf = env::temp_dir().join(format!("language-bench-{}", process::id()));
+            fs::create_dir_all(&scratch).unwrap_or_else(|e| panic!("Error: Failed to create `{}`: {e}", scratch.display()));
+            let res = bench(Path::new(&path), runs, &scratch);
+            let _ = fs::remove_dir_all(&scratch);
+            match res {
+                Ok((times, code)) => {
+                    if code != 0 {
+                        eprintln!("Warning: `{}` exited with status {}", path, code);
+                    }
+                    println!("{}", bench_summary(&times));
+                },
+                Err(reason) => {
+                    eprintln!("{}", reason);
+                    process::exit(1);
+                },
+            }
+            return;
+        },
+        Some("gen-test") => {
+            it.next();
+            let mut config: StressConfig = StressConfig::default();
+            while let Some(arg) = it.next() {
+                let val: Option<u64> = it.next().and_then(|v| v.parse::<u64>().ok());
+                match (arg.as_str(), val) {
+                    ("--stmts", Some(n)) => config.stmts = n as usize,
+                    ("--depth", Some(d)) => config.depth = d as usize,
+                    ("--seed", Some(s)) => config.seed = s,
+                    _ => panic!("{}", usage(&com)),
+                }
+            }
+            print!("{}", stress::generate(&config));
+            return;
+        },
+        Some("fmt") => {
+            it.next();
+            let path: String = it.next().unwrap_or_else(|| panic!("{}", usage(&com)));
+            let mut session: Session = Session::new();
+            let file: FileId = session.sources.load(&DiskProvider, &path).unwrap_or_else(|_| panic!("{}", usage(&com)));
+            let mut sink: TerminalSink = TerminalSink::new(&session.sources);
+            let Some(mut lexer) = diagnostic::report(session.lex(file), file, &mut sink) else {
+                process::exit(1);
+            };
+            let Some(ast) = diagnostic::report(session.parse_surface(file, &mut lexer), file, &mut sink) else {
+                process::exit(1);
+            };
+            print!("{}", format_program(&lexer, &ast));
+            return;
+        },
+        _ => {}
+    }
     let mut out_path: Option<String> = None;
+    let mut opt_level: OptLevel = OptLevel::O0;
+    let mut no_reorder_blocks: bool = false;
+    let mut limits: Limits = Limits::default();
+    let mut script: bool = false;
+    let mut dump_same_line: bool = false;
+    let mut entry: Option<String> = None;
+    let mut no_prelude: bool = false;
+    let mut ast_validate: bool = false;
+    let mut reproducible: bool = false;
+    let mut target: Target = Target::default();
+    let mut sysroot: Option<PathBuf> = None;
+    let mut no_provenance: bool = false;
     let mut in_path: Option<String> = None;
     // for arg in it {
     while let Some(arg) = it.next() {
         match arg.as_str() {
             "-r" | "--run" => flags.push(Flag::Run),
+            "-c" => flags.push(Flag::ObjectOnly),
+            "--check" => flags.push(Flag::Check),
             "-a" | "--assembly" => flags.push(Flag::EmitAsm),
+            "--save-temps" => flags.push(Flag::SaveTemps),
             "-pt" | "--parse-tree" => flags.push(Flag::EmitParseTree),
+            "--parse-tree-depth" => flags.push(Flag::ParseTreeDepth(it.next().and_then(|d| d.parse::<usize>().ok()).unwrap_or_else(|| panic!("{}", usage(&com))))),
             "-t" | "--tokens" => flags.push(Flag::EmitTokens),
             "-o" | "--output" => out_path = it.next(),
+            "--no-reorder-blocks" => no_reorder_blocks = true,
+            "--script" => script = true,
+            "--dump-same-line" => dump_same_line = true,
+            "--entry" => entry = Some(it.next().unwrap_or_else(|| panic!("{}", usage(&com)))),
+            "--no-prelude" => no_prelude = true,
+            "--ast-validate" => ast_validate = true,
+            "--reproducible" => reproducible = true,
+            "--no-provenance" => no_provenance = true,
+            "--target" => {
+                let triple: String = it.next().unwrap_or_else(|| panic!("{}", usage(&com)));
+                target = Target::parse(&triple).unwrap_or_else(|| {
+                    let known: Vec<&str> = Target::ALL.iter().map(|target| target.triple()).collect();
+                    panic!("Error: Unsupported target `{}`, expected one of: {}", triple, known.join(", "))
+                });
+            },
+            "--sysroot" => {
+                let root: PathBuf = PathBuf::from(it.next().unwrap_or_else(|| panic!("{}", usage(&com))));
+                if !root.is_dir() {
+                    panic!("Error: Sysroot `{}` is not a directory", root.display());
+                }
+                sysroot = Some(root);
+            },
+            "--strip" => flags.push(Flag::Strip),
+            "--linker-script" => flags.push(Flag::LinkerScript(it.next().unwrap_or_else(|| panic!("{}", usage(&com))))),
+            _ if arg.starts_with("-Wl,") => flags.push(Flag::LinkerArgs(arg["-Wl,".len()..].split(',').filter(|a| !a.is_empty()).map(str::to_string).collect())),
+            "--only-func" => flags.push(Flag::OnlyFunc(it.next().unwrap_or_else(|| panic!("{}", usage(&com))))),
+            "--max-depth" => limits.max_depth = it.next().and_then(|d| d.parse::<usize>().ok()).unwrap_or_else(|| panic!("{}", usage(&com))),
+            "--max-ident-len" => limits.max_ident_len = it.next().and_then(|n| n.parse::<usize>().ok()).unwrap_or_else(|| panic!("{}", usage(&com))),
+            "--max-functions" => limits.max_functions = it.next().and_then(|n| n.parse::<usize>().ok()).unwrap_or_else(|| panic!("{}", usage(&com))),
+            "--max-locals" => limits.max_locals = it.next().and_then(|n| n.parse::<usize>().ok()).unwrap_or_else(|| panic!("{}", usage(&com))),
+            "-O0" | "-O1" | "-O2" | "-Os" => opt_level = OptLevel::parse(&arg).expect("Error: Optimisation flag was matched but not parsed"),
+            "--emit" => {
+                match it.next().as_deref() {
+                    Some("tokens-json") => flags.push(Flag::EmitTokensJson),
+                    Some("diagnostics-json") => flags.push(Flag::EmitDiagnosticsJson),
+                    Some("stats") => flags.push(Flag::EmitStats),
+                    Some("map") => flags.push(Flag::EmitMap),
+                    Some("asm") => flags.push(Flag::EmitAsmListing),
+                    Some(kind) if kind.starts_with("tokens=") => flags.push(Flag::EmitTokensTo(kind["tokens=".len()..].to_string())),
+                    Some(kind) if kind.starts_with("ast=") => flags.push(Flag::EmitParseTreeTo(kind["ast=".len()..].to_string())),
+                    _ => panic!("{}", usage(&com)),
+                }
+            },
             _ => {
                 match in_path {
                     None => in_path = Some(arg),
@@ -470,7 +826,18 @@ pub fn main() {
         }
     }
 
-    let mut out: String = "output".to_string();
+    // Picking a function only means something for the assembly that is printed.
+    if flags.iter().any(|flag| matches!(flag, Flag::OnlyFunc(_))) && !flags.contains(&Flag::EmitAsmListing) {
+        panic!("{}", usage(&com));
+    }
+
+    // An object cannot be run, and nothing is linked to take linker options or be stripped.
+    let links: bool = flags.iter().any(|flag| matches!(flag, Flag::LinkerScript(_) | Flag::LinkerArgs(_) | Flag::Strip));
+    if flags.contains(&Flag::ObjectOnly) && (flags.contains(&Flag::Run) || links) {
+        panic!("{}", usage(&com));
+    }
+
+    let mut out: String = if flags.contains(&Flag::ObjectOnly) { "output.o" } else { "output" }.to_string();
     if let Some(path) = out_path {
         out = path;
     }
@@ -478,8 +845,33 @@ pub fn main() {
     match in_path {
         None => panic!("{}", usage(&com)),
         Some(path) => {
-            let src: Vec<u8> = fs::read(&path).unwrap_or_else(|_| panic!("{}", usage(&com)));
-            compile(src, path.to_string(), out, flags);
+            let mut session: Session = Session::new();
+            session.opt_level = opt_level;
+            session.no_reorder_blocks = no_reorder_blocks;
+            session.limits = limits;
+            session.script = script;
+            session.dump_same_line = dump_same_line;
+            session.entry = entry;
+            session.no_prelude = no_prelude;
+            session.ast_validate = ast_validate;
+            session.reproducible = reproducible;
+            session.target = target;
+            session.sysroot = sysroot;
+            if !no_provenance {
+                session.provenance = Some(provenance(&path, &env::args().skip(1).collect::<Vec<String>>(), reproducible));
+            }
+            let file: FileId = session.sources.load(&DiskProvider, &path).unwrap_or_else(|_| panic!("{}", usage(&com)));
+            install_ice_hook();
+            let compiled = panic::catch_unwind(AssertUnwindSafe(|| if flags.contains(&Flag::EmitDiagnosticsJson) {
+                compile(&session, file, out, flags, &mut JsonSink::new(io::stderr(), &session.sources))
+            } else {
+                compile(&session, file, out, flags, &mut TerminalSink::new(&session.sources))
+            }));
+            let ok: bool = compiled.unwrap_or_else(|payload| report_ice(&path, payload));
+            if !ok {
+                eprintln!("\n\x1b[31mCOMPILATION FAILED\x1b[0m");
+                process::exit(1);
+            }
         }
     }
 
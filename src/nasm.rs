@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use crate::instr::AsmFunction;
+use crate::instr::AsmProgram;
+use crate::instr::Cond;
+use crate::instr::Instr;
+use crate::instr::Operand;
+use crate::instr::Reg;
+
+// Labels of the prelude and names the assembler reads as registers or keywords, which `export` functions cannot take.
+pub const RESERVED_LABELS: [&str; 46] = [
+    "_start", "dump", "dump_item", "rand", "time_ns",
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    "eax", "ebx", "ecx", "edx", "esi", "edi", "ebp", "esp",
+    "al", "bl", "cl", "dl", "ah", "bh", "ch", "dh",
+    "byte", "word", "dword", "qword", "offset", "section", "global", "extern", "rel",
+];
+
+// Prints a value in an unsigned base 10 representation followed by a newline to stdout.
+static DUMP_ROUTINE: [&str; 27] = [
+    "dump:",
+    "    sub rsp, 40",
+    "    lea rsi, [rsp + 31]",
+    "    mov byte [rsp + 31], 10",
+    "    mov ecx, 1",
+    "    mov r8, -3689348814741910323",
+    ".LBB0_1:",
+    "    mov rax, rdi",
+    "    mul r8",
+    "    shr rdx, 3",
+    "    lea eax, [rdx + rdx]",
+    "    lea eax, [rax + 4*rax]",
+    "    mov r9d, edi",
+    "    sub r9d, eax",
+    "    or r9b, 48",
+    "    mov byte [rsi - 1], r9b",
+    "    dec rsi",
+    "    inc rcx",
+    "    cmp rdi, 9",
+    "    mov rdi, rdx",
+    "    ja .LBB0_1",
+    "    mov edi, 1",
+    "    mov rdx, rcx",
+    "    mov rax, 1",
+    "    syscall",
+    "    add rsp, 40",
+    "    ret",
+];
+
+// Returns a random number in `rax`. Uses `rdrand` when the CPU has it, retrying a few times as it can run dry, and
+// otherwise asks the kernel with `getrandom`.
+// NOTE: `cpuid` also writes `rbx`, so it is saved around it. The registers locals live in are never touched.
+static RAND_ROUTINE: [&str; 23] = [
+    "rand:",
+    "    push rbx",
+    "    mov eax, 1",
+    "    cpuid",
+    "    pop rbx",
+    "    bt ecx, 30",
+    "    jnc .getrandom",
+    "    mov ecx, 10",
+    ".retry:",
+    "    rdrand rax",
+    "    jc .done",
+    "    dec ecx",
+    "    jnz .retry",
+    ".getrandom:",
+    "    push rax",
+    "    mov rdi, rsp",
+    "    mov esi, 8",
+    "    xor edx, edx",
+    "    mov eax, 318",
+    "    syscall",
+    "    pop rax",
+    ".done:",
+    "    ret",
+];
+
+// Returns the nanoseconds since boot in `rax`, read with `clock_gettime` on the monotonic clock so it never runs
+// backwards.
+static TIME_ROUTINE: [&str; 11] = [
+    "time_ns:",
+    "    sub rsp, 16",
+    "    mov edi, 1",
+    "    mov rsi, rsp",
+    "    mov eax, 228",
+    "    syscall",
+    "    mov rax, [rsp]",
+    "    imul rax, rax, 1000000000",
+    "    add rax, [rsp + 8]",
+    "    add rsp, 16",
+    "    ret",
+];
+
+// Owner and type of the note a program's build details are recorded in, as `readelf -n` shows them.
+pub const NOTE_OWNER: &str = "Language";
+pub const NOTE_TYPE: u32 = 1;
+
+// Encoded sizes of the routines above in bytes, as NASM assembles them.
+const DUMP_SIZE: usize = 94;
+
+// Routines a program only gets when it calls them, by the label they are called with, along with their size in bytes.
+static ON_DEMAND: [(&str, &str, &[&str], usize); 2] = [
+    ("rand", "Random", &RAND_ROUTINE, 50),
+    ("time_ns", "Time", &TIME_ROUTINE, 40),
+];
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: &str = match self {
+            Reg::Rax => "rax",
+            Reg::Rbx => "rbx",
+            Reg::Rcx => "rcx",
+            Reg::Rdx => "rdx",
+            Reg::Rsi => "rsi",
+            Reg::Rdi => "rdi",
+            Reg::Rbp => "rbp",
+            Reg::Rsp => "rsp",
+            Reg::R8 => "r8",
+            Reg::R9 => "r9",
+            Reg::R10 => "r10",
+            Reg::R11 => "r11",
+            Reg::R12 => "r12",
+            Reg::R13 => "r13",
+            Reg::R14 => "r14",
+            Reg::R15 => "r15",
+            Reg::Al => "al",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "{}", r),
+            Operand::Imm(i) => write!(f, "{}", i),
+            Operand::Label(name) => write!(f, "{}", name),
+            Operand::Mem(r, 0) => write!(f, "[{}]", r),
+            Operand::Mem(r, ofst) if *ofst < 0 => write!(f, "[{} - {}]", r, ofst.unsigned_abs()),
+            Operand::Mem(r, ofst) => write!(f, "[{} + {}]", r, ofst),
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: &str = match self {
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::L => "l",
+            Cond::Le => "le",
+            Cond::G => "g",
+            Cond::Ge => "ge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Comment(text) => write!(f, "; --- {} ---", text),
+            Instr::Label(name) => write!(f, "{}:", name),
+            Instr::Mov(dst, src) => write!(f, "    mov {}, {}", dst, src),
+            Instr::Push(src) => write!(f, "    push {}", src),
+            Instr::Pop(dst) => write!(f, "    pop {}", dst),
+            Instr::Add(dst, src) => write!(f, "    add {}, {}", dst, src),
+            Instr::Sub(dst, src) => write!(f, "    sub {}, {}", dst, src),
+            Instr::Imul(dst, src) => write!(f, "    imul {}, {}", dst, src),
+            Instr::Cqo => write!(f, "    cqo"),
+            Instr::Idiv(src) => write!(f, "    idiv {}", src),
+            Instr::Neg(dst) => write!(f, "    neg {}", dst),
+            Instr::Xor(dst, src) => write!(f, "    xor {}, {}", dst, src),
+            Instr::Cmp(lhs, rhs) => write!(f, "    cmp {}, {}", lhs, rhs),
+            Instr::Set(cond, dst) => write!(f, "    set{} {}", cond, dst),
+            Instr::Cmov(cond, dst, src) => write!(f, "    cmov{} {}, {}", cond, dst, src),
+            Instr::Jmp(label) => write!(f, "    jmp {}", label),
+            Instr::Jcc(cond, label) => write!(f, "    j{} {}", cond, label),
+            Instr::Call(label) => write!(f, "    call {}", label),
+            Instr::CallIndirect(target) => write!(f, "    call {}", target),
+            Instr::Ret => write!(f, "    ret"),
+            Instr::Syscall => write!(f, "    syscall"),
+        }
+    }
+}
+
+fn extended(r: Reg) -> bool {
+    matches!(r, Reg::R8 | Reg::R9 | Reg::R10 | Reg::R11 | Reg::R12 | Reg::R13 | Reg::R14 | Reg::R15)
+}
+
+fn fits_i8(i: i64) -> bool {
+    i8::try_from(i).is_ok()
+}
+
+// Bytes of the ModRM byte, SIB byte and displacement addressing an operand.
+fn modrm_size(op: &Operand) -> usize {
+    match op {
+        Operand::Mem(base, ofst) => {
+            let sib: usize = matches!(base, Reg::Rsp | Reg::R12) as usize;
+            // `rbp` and `r13` have no encoding without a displacement, so they take a zero byte one.
+            let disp: usize = match ofst {
+                0 if !matches!(base, Reg::Rbp | Reg::R13) => 0,
+                o if fits_i8(*o) => 1,
+                _ => 4,
+            };
+            1 + sib + disp
+        },
+        _ => 1,
+    }
+}
+
+// Size of `add`, `sub`, `cmp` or `xor` with a 64 bit destination.
+fn alu_size(dst: &Operand, src: &Operand) -> usize {
+    match (dst, src) {
+        (_, Operand::Imm(i)) if fits_i8(*i) => 2 + modrm_size(dst) + 1,
+        (Operand::Reg(Reg::Rax), Operand::Imm(_)) => 6,
+        (_, Operand::Imm(_)) => 2 + modrm_size(dst) + 4,
+        (Operand::Mem(_, _), _) => 2 + modrm_size(dst),
+        _ => 2 + modrm_size(src),
+    }
+}
+
+// Bytes NASM encodes an instruction in, with jumps taking their short form if `short` is set.
+// NOTE: NASM shortens `mov r64, imm` to the 32 bit move when the value fits, which zero extends to the same result.
+pub fn encoded_size(instr: &Instr, short: bool) -> usize {
+    let rex = |op: &Operand| matches!(op, Operand::Reg(r) | Operand::Mem(r, _) if extended(*r)) as usize;
+    match instr {
+        Instr::Comment(_) | Instr::Label(_) => 0,
+        Instr::Mov(Operand::Reg(r), Operand::Imm(i)) if u32::try_from(*i).is_ok() => 5 + extended(*r) as usize,
+        Instr::Mov(Operand::Reg(_), Operand::Imm(i)) if i32::try_from(*i).is_ok() => 7,
+        Instr::Mov(Operand::Reg(_), Operand::Imm(_) | Operand::Label(_)) => 10,
+        Instr::Mov(dst, Operand::Imm(_)) => 2 + modrm_size(dst) + 4,
+        Instr::Mov(dst @ Operand::Mem(_, _), _) => 2 + modrm_size(dst),
+        Instr::Mov(_, src) => 2 + modrm_size(src),
+        Instr::Push(Operand::Imm(i)) | Instr::Pop(Operand::Imm(i)) => if fits_i8(*i) { 2 } else { 5 },
+        Instr::Push(op) | Instr::Pop(op) => match op {
+            Operand::Reg(_) => 1 + rex(op),
+            _ => 1 + rex(op) + modrm_size(op),
+        },
+        Instr::Add(dst, src) | Instr::Sub(dst, src) | Instr::Cmp(dst, src) | Instr::Xor(dst, src) => alu_size(dst, src),
+        Instr::Imul(_, Operand::Imm(i)) => if fits_i8(*i) { 4 } else { 7 },
+        Instr::Imul(_, src) | Instr::Cmov(_, _, src) => 3 + modrm_size(src),
+        Instr::Cqo => 2,
+        Instr::Idiv(op) | Instr::Neg(op) => 2 + modrm_size(op),
+        Instr::Set(_, op) => 2 + modrm_size(op),
+        Instr::Jmp(_) => if short { 2 } else { 5 },
+        Instr::Jcc(_, _) => if short { 2 } else { 6 },
+        Instr::Call(_) => 5,
+        Instr::CallIndirect(op) => 1 + rex(op) + modrm_size(op),
+        Instr::Ret => 1,
+        Instr::Syscall => 2,
+    }
+}
+
+// Bytes a function encodes in. Jumps start out short and are lengthened until every one reaches its target.
+pub fn function_size(func: &AsmFunction) -> usize {
+    let mut short: Vec<bool> = func.body.iter().map(|instr| matches!(instr, Instr::Jmp(_) | Instr::Jcc(_, _))).collect();
+    loop {
+        // Local labels, starting with `.`, belong to the last label before them that does not.
+        let mut scope: &str = &func.name;
+        let mut offsets: Vec<usize> = Vec::new();
+        let mut labels: HashMap<String, usize> = HashMap::new();
+        let mut end: usize = 0;
+        for (instr, short) in func.body.iter().zip(&short) {
+            if let Instr::Label(name) = instr {
+                if !name.starts_with('.') {
+                    scope = name;
+                }
+                labels.insert(format!("{}{}", if name.starts_with('.') { scope } else { "" }, name), end);
+            }
+            offsets.push(end);
+            end += encoded_size(instr, *short);
+        }
+        let mut changed: bool = false;
+        let mut scope: &str = &func.name;
+        for (ix, instr) in func.body.iter().enumerate() {
+            let target: &String = match instr {
+                Instr::Label(name) if !name.starts_with('.') => {
+                    scope = name;
+                    continue;
+                },
+                Instr::Jmp(target) | Instr::Jcc(_, target) if short[ix] => target,
+                _ => continue,
+            };
+            let key: String = format!("{}{}", if target.starts_with('.') { scope } else { "" }, target);
+            let from: usize = offsets[ix] + encoded_size(instr, true);
+            let to: usize = labels.get(&key).copied().unwrap_or(usize::MAX);
+            if !fits_i8(to as i64 - from as i64) {
+                short[ix] = false;
+                changed = true;
+            }
+        }
+        if !changed {
+            return end;
+        }
+    }
+}
+
+// Whether any routine of the program calls the given label.
+fn calls(prog: &AsmProgram, label: &str) -> bool {
+    let call: Instr = Instr::Call(label.to_string());
+    prog.funcs.iter().chain([&prog.entry]).any(|func| func.body.contains(&call))
+}
+
+// Every label of a program in the order it is emitted, with the bytes up to the next one.
+pub fn symbol_map(prog: &AsmProgram) -> Vec<(String, usize)> {
+    let calls = |label: &str| calls(prog, label);
+    let mut res: Vec<(String, usize)> = Vec::new();
+    let item: bool = calls("dump_item");
+    if item && !prog.dump_same_line {
+        res.push(("dump_item".to_string(), 0));
+    }
+    if calls("dump") || item {
+        res.push(("dump".to_string(), DUMP_SIZE));
+    }
+    if item && prog.dump_same_line {
+        res.push(("dump_item".to_string(), DUMP_SIZE));
+    }
+    for (label, _, _, size) in ON_DEMAND {
+        if calls(label) {
+            res.push((label.to_string(), size));
+        }
+    }
+    for func in prog.funcs.iter().chain([&prog.entry]) {
+        res.push((func.name.clone(), function_size(func)));
+    }
+    res
+}
+
+pub fn print_function(f: &mut impl Write, func: &AsmFunction) -> std::io::Result<()> {
+    writeln!(f, "{}:", func.name)?;
+    for instr in &func.body {
+        writeln!(f, "{}", instr)?;
+    }
+    Ok(())
+}
+
+// Prints a program in NASM syntax for x86_64 Linux.
+pub fn print_program(f: &mut impl Write, prog: &AsmProgram) -> std::io::Result<()> {
+    let calls = |label: &str| calls(prog, label);
+    writeln!(f, "; --- Header ---")?;
+    writeln!(f, "global {}", prog.entry.name)?;
+    for name in &prog.exports {
+        writeln!(f, "global {}", name)?;
+    }
+    writeln!(f, "section .text")?;
+    // `dump_item` prints all but the last value of a `dump`. Values on separate lines end like any other, so then it
+    // is just another name for `dump`.
+    let item: bool = calls("dump_item");
+    if calls("dump") || item {
+        writeln!(f, "; --- Debug Dump ---")?;
+        if item && !prog.dump_same_line {
+            writeln!(f, "dump_item:")?;
+        }
+        for line in DUMP_ROUTINE {
+            writeln!(f, "{}", line)?;
+        }
+    }
+    if item && prog.dump_same_line {
+        writeln!(f, "; --- Debug Dump Item ---")?;
+        for line in DUMP_ROUTINE {
+            writeln!(f, "{}", line.replace("dump:", "dump_item:").replace("[rsp + 31], 10", "[rsp + 31], 32"))?;
+        }
+    }
+    for (label, header, routine, _) in ON_DEMAND {
+        if calls(label) {
+            writeln!(f, "; --- {} ---", header)?;
+            for line in routine {
+                writeln!(f, "{}", line)?;
+            }
+        }
+    }
+
+    for func in &prog.funcs {
+        writeln!(f, "; --- FuncDecl {} ---", func.name)?;
+        print_function(f, func)?;
+    }
+
+    writeln!(f, "; --- Footer ---")?;
+    print_function(f, &prog.entry)?;
+
+    if !prog.rodata.is_empty() {
+        writeln!(f, "section .rodata")?;
+        writeln!(f, "; --- Constant Dumps ---")?;
+        for (label, values) in &prog.rodata {
+            writeln!(f, "{}:", label)?;
+            writeln!(f, "    db {}", dump_bytes(values, prog.dump_same_line))?;
+        }
+    }
+
+    // An ELF note is the size of its owner and text, its type, then both ending in a zero byte and padded to 4 bytes.
+    // NOTE: The section is allocated, so the linker gives it a `PT_NOTE` segment that stripping leaves in place.
+    if let Some(text) = &prog.note {
+        writeln!(f, "section .note.language note alloc noexec nowrite align=4")?;
+        writeln!(f, "; --- Build Note ---")?;
+        writeln!(f, "    dd {}, {}, {}", NOTE_OWNER.len() + 1, text.len() + 1, NOTE_TYPE)?;
+        writeln!(f, "    db {}", note_bytes(NOTE_OWNER))?;
+        writeln!(f, "    db {}", note_bytes(text))?;
+    }
+    Ok(())
+}
+
+// A string of a note as NASM data, ending in a zero byte and padded to 4 bytes. NASM strings cannot hold their own
+// quote, so it and other bytes that are not printable are given as numbers.
+fn note_bytes(text: &str) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut run: String = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_graphic() && byte != b'"' || byte == b' ' {
+            run.push(byte as char);
+            continue;
+        }
+        if !run.is_empty() {
+            parts.push(format!("\"{}\"", std::mem::take(&mut run)));
+        }
+        parts.push(byte.to_string());
+    }
+    if !run.is_empty() {
+        parts.push(format!("\"{}\"", run));
+    }
+    let padding: usize = 4 - text.len() % 4;
+    parts.extend(std::iter::repeat_n("0".to_string(), padding));
+    parts.join(", ")
+}
+
+// The bytes `dump` prints for the values, as NASM data. Digits are quoted, separators are character codes.
+fn dump_bytes(values: &[i64], same_line: bool) -> String {
+    let sep: u8 = if same_line { b' ' } else { b'\n' };
+    let parts: Vec<String> = values.iter().enumerate().map(|(ix, value)| {
+        let end: u8 = if ix + 1 == values.len() { b'\n' } else { sep };
+        format!("\"{}\", {}", *value as u64, end)
+    }).collect();
+    parts.join(", ")
+}
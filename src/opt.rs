@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use crate::consteval::eval_const;
+use crate::instr::AsmFunction;
+use crate::instr::Instr;
+use crate::instr::Operand;
+use crate::instr::Reg;
+use crate::instr::reg;
+use crate::lexer::TokenType;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+use crate::symbols::SymbolTable;
+use crate::symbols::mangle;
+
+// How much work codegen puts into the output, chosen with `-O<n>`. `-Os` optimises like `-O2` but never makes the code
+// bigger to make it faster, and picks the shortest encodings.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(PartialOrd)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+    Os,
+}
+impl OptLevel {
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            "-O2" => Some(OptLevel::O2),
+            "-Os" => Some(OptLevel::Os),
+            _ => None,
+        }
+    }
+}
+
+// Spelling of a pure expression, equal for two subtrees exactly when they compute the same value from the same variables.
+fn expr_key(node: &ParseNode) -> Option<String> {
+    match node.kind {
+        NodeType::Literal => node.tok.value.map(|value| value.to_string()),
+        NodeType::Var => Some(node.tok.val_str()),
+        NodeType::UnOp => Some(format!("({}{})", node.tok.val_str(), expr_key(node.children.first()?)?)),
+        NodeType::BinOp => Some(format!("({}{}{})", expr_key(node.children.first()?)?, node.tok.val_str(), expr_key(node.children.get(1)?)?)),
+        // Every `rand()` and `time_ns()` gives a new value, so it is never the same as another.
+        NodeType::Intrinsic if matches!(node.tok.val.as_slice(), b"rand" | b"time_ns") => None,
+        NodeType::Intrinsic => {
+            let args: Vec<String> = node.children.iter().map(expr_key).collect::<Option<_>>()?;
+            Some(format!("{}({})", node.tok.val_str(), args.join(",")))
+        },
+        _ => None,
+    }
+}
+
+// Operations worth computing once, intrinsics other than `rand` and `time_ns` are as pure as operators.
+fn is_operation(node: &ParseNode) -> bool {
+    matches!(node.kind, NodeType::BinOp | NodeType::Intrinsic)
+}
+
+// Collects the keys of every non-constant operation in an expression, largest first.
+// NOTE: The rhs of `&&` and `||` may not run, so computing what is in it ahead of time could fault where the program would not.
+fn collect_exprs(node: &ParseNode, out: &mut Vec<String>) {
+    if is_operation(node) && eval_const(node).is_none() && let Some(key) = expr_key(node) {
+        out.push(key);
+    }
+    let always: usize = if matches!(node.tok.kind, TokenType::OpLogicalAnd | TokenType::OpLogicalOr) { 1 } else { node.children.len() };
+    for child in node.children.iter().take(always) {
+        collect_exprs(child, out);
+    }
+}
+
+// The variables an expression reads.
+fn collect_vars(node: &ParseNode, out: &mut Vec<Vec<u8>>) {
+    if node.kind == NodeType::Var {
+        out.push(node.tok.val.clone());
+    }
+    for child in &node.children {
+        collect_vars(child, out);
+    }
+}
+
+fn replace_expr(node: &mut ParseNode, key: &str, temp: &[u8]) {
+    if is_operation(node) && expr_key(node).as_deref() == Some(key) {
+        node.kind = NodeType::Var;
+        node.tok.kind = TokenType::Identifier;
+        node.tok.val = temp.to_vec();
+        node.children.clear();
+        return;
+    }
+    for child in &mut node.children {
+        replace_expr(child, key, temp);
+    }
+}
+
+// The items an expression is used in, from the first that could compute it ahead of time to the last before one of its
+// variables changes.
+struct Repeat {
+    first: usize,
+    rank: usize, // Where the expression is among those of the first item, which are tried largest first
+    last: usize,
+    uses: usize,
+}
+
+// Keeps the repeated expression that is computed first, as the items before it are left alone afterwards.
+fn keep_first(best: &mut Option<(String, Repeat)>, key: String, repeat: Repeat) {
+    if repeat.uses >= 2 && best.as_ref().is_none_or(|(_, b)| (repeat.first, repeat.rank) < (b.first, b.rank)) {
+        *best = Some((key, repeat));
+    }
+}
+
+// Shares the first repeated expression in a run of straight-line items from `from`, returning the item it is computed
+// before or None once there is none left.
+// NOTE: Only assignments change variables, calls cannot see the caller's locals, so only they end an expression's life.
+// The shared value is computed before the item it first appears in, so an item that calls a function keeps its own,
+// which may fault only after the call has printed.
+fn share_one(items: &mut Vec<ParseNode>, from: usize, end: usize, counter: &mut usize) -> Option<usize> {
+    let mut live: HashMap<String, Repeat> = HashMap::new();
+    let mut readers: HashMap<Vec<u8>, Vec<String>> = HashMap::new(); // Expressions that may be live, by variable read
+    let mut best: Option<(String, Repeat)> = None;
+    for (ix, item) in items.iter().enumerate().take(end).skip(from) {
+        let starts: bool = item.kind == NodeType::FuncCall || !calls_any(item);
+        let mut keys: Vec<String> = Vec::new();
+        for child in &item.children {
+            collect_exprs(child, &mut keys);
+        }
+        for (rank, key) in keys.into_iter().enumerate() {
+            if let Some(repeat) = live.get_mut(&key) {
+                repeat.uses += 1;
+                continue;
+            }
+            if !starts {
+                continue;
+            }
+            let mut vars: Vec<Vec<u8>> = Vec::new();
+            collect_vars(item.children.iter().find_map(|child| find_expr(child, &key)).expect("Error: Expression vanished from its item"), &mut vars);
+            for var in vars {
+                readers.entry(var).or_default().push(key.clone());
+            }
+            live.insert(key, Repeat { first: ix, rank, last: ix, uses: 1 });
+        }
+        // The assigned value is computed before the write, so this item still sees the old value.
+        if item.kind == NodeType::Assign && let Some(keys) = readers.remove(&item.tok.val) {
+            for key in keys {
+                if let Some(repeat) = live.remove(&key) {
+                    keep_first(&mut best, key, Repeat { last: ix, ..repeat });
+                }
+            }
+        }
+    }
+    for (key, repeat) in live {
+        keep_first(&mut best, key, Repeat { last: end - 1, ..repeat });
+    }
+
+    let (key, Repeat { first, last, .. }) = best?;
+    let expr: ParseNode = items[first].children.iter().find_map(|child| find_expr(child, &key)).cloned()
+        .expect("Error: Shared expression vanished from its item");
+    let temp: Vec<u8> = format!("cse.{}", counter).into_bytes();
+    *counter += 1;
+    for item in &mut items[first..=last] {
+        for child in &mut item.children {
+            replace_expr(child, &key, &temp);
+        }
+    }
+    let mut tok = expr.tok.clone();
+    tok.kind = TokenType::Identifier;
+    tok.val = temp;
+    items.insert(first, ParseNode { kind: NodeType::VarDecl, tok, children: vec![expr] });
+    Some(first)
+}
+
+fn find_expr<'a>(node: &'a ParseNode, key: &str) -> Option<&'a ParseNode> {
+    if is_operation(node) && expr_key(node).as_deref() == Some(key) {
+        return Some(node);
+    }
+    node.children.iter().find_map(|child| find_expr(child, key))
+}
+
+fn cse_block(block: &mut ParseNode, counter: &mut usize) {
+    let mut start: usize = 0;
+    while start < block.children.len() {
+        // Branches, loops and nested blocks end the straight-line run, their bodies are runs of their own.
+        // NOTE: A loop guard runs again after the body, so nothing is shared with it.
+        match block.children[start].kind {
+            NodeType::Conditional => {
+                for body in block.children[start].children.iter_mut().skip(1) {
+                    cse_block(body, counter);
+                }
+                start += 1;
+                continue;
+            },
+            NodeType::While => {
+                cse_block(&mut block.children[start].children[1], counter);
+                start += 1;
+                continue;
+            },
+            NodeType::Block => {
+                cse_block(&mut block.children[start], counter);
+                start += 1;
+                continue;
+            },
+            _ => {},
+        }
+        let mut end: usize = start;
+        while end < block.children.len() && !matches!(block.children[end].kind, NodeType::Conditional | NodeType::While | NodeType::Block) {
+            end += 1;
+        }
+        // NOTE: Sharing only changes the items from where the shared value is computed, those before have nothing to share.
+        let mut from: usize = start;
+        while let Some(first) = share_one(&mut block.children, from, end, counter) {
+            from = first;
+            end += 1;
+        }
+        start = end;
+    }
+}
+
+// Removes the functions no call chain from `entry` reaches, unless other object files may call them.
+pub fn eliminate_dead_functions(tree: &ParseTree, entry: &str) -> ParseTree {
+    let table: SymbolTable = SymbolTable::build(tree);
+    let Some(roots) = table.roots(entry) else {
+        return tree.clone();
+    };
+    let reachable: Vec<bool> = table.reachable(&roots);
+    let live: HashSet<String> = tree.functions().into_iter().zip(reachable).filter(|(_, reached)| *reached).map(|((name, _), _)| name).collect();
+    let mut res: ParseTree = tree.clone();
+    res.root.children.retain_mut(|item| match item.kind {
+        NodeType::Module => {
+            let module: String = item.tok.val_str();
+            item.children.retain(|func| live.contains(&format!("{}.{}", module, func.tok.val_str())));
+            true
+        },
+        _ => live.contains(&item.tok.val_str()),
+    });
+    res
+}
+
+// Computes every pure expression repeated within straight-line code once, into a hidden local.
+// NOTE: Temporaries are named `cse.<n>`, which no identifier can spell, so they never clash with user variables.
+pub fn eliminate_common_subexpressions(tree: &ParseTree) -> ParseTree {
+    let mut res: ParseTree = tree.clone();
+    for func in &mut res.root.children {
+        let mut counter: usize = 0;
+        for block in &mut func.children {
+            cse_block(block, &mut counter);
+        }
+    }
+    res
+}
+
+// Functions with at most this many nodes are inlined at -O2.
+pub const INLINE_MAX_NODES: usize = 24;
+
+fn calls_any(node: &ParseNode) -> bool {
+    node.kind == NodeType::FuncCall || node.children.iter().any(calls_any)
+}
+
+fn returns_any(node: &ParseNode) -> bool {
+    node.kind == NodeType::Return || node.children.iter().any(returns_any)
+}
+
+// Prefixes every local of an inlined body with the callee name, so they cannot clash with the caller's.
+// NOTE: The `.` cannot appear in an identifier, and the body stays in its own block so its locals are freed after it.
+fn rename_locals(node: &mut ParseNode, callee: &str) {
+    if matches!(node.kind, NodeType::Var | NodeType::Assign | NodeType::VarDecl) {
+        node.tok.val = format!("{}.{}", callee, node.tok.val_str()).into_bytes();
+    }
+    for child in &mut node.children {
+        rename_locals(child, callee);
+    }
+}
+
+// Only calls made as statements are inlined, the value of one in an expression comes from a `return`.
+fn inline_calls(node: &mut ParseNode, bodies: &[(String, ParseNode)]) -> bool {
+    let mut changed: bool = false;
+    let block: bool = node.kind == NodeType::Block;
+    for child in &mut node.children {
+        if block && child.kind == NodeType::FuncCall {
+            let name: String = child.tok.val_str();
+            if let Some((_, body)) = bodies.iter().find(|(callee, _)| *callee == name) {
+                let mut block: ParseNode = body.clone();
+                rename_locals(&mut block, &name);
+                *child = block;
+                changed = true;
+            }
+        } else {
+            changed |= inline_calls(child, bodies);
+        }
+    }
+    changed
+}
+
+// Replaces calls to small functions that make no calls themselves with a copy of their body.
+// NOTE: Callers that become small leaves this way are inlined on the next round, recursive functions never are.
+pub fn inline_functions(tree: &ParseTree) -> ParseTree {
+    let mut res: ParseTree = tree.clone();
+    loop {
+        let bodies: Vec<(String, ParseNode)> = res.root.children.iter()
+            .filter_map(|func| func.children.first().map(|body| (func.tok.val_str(), body)))
+            // `main` exits at its end rather than returning, which an inlined copy would not. A `return` would leave the
+            // caller rather than the copy.
+            .filter(|(name, body)| *name != mangle("main") && !calls_any(body) && !returns_any(body) && body.post_order().len() <= INLINE_MAX_NODES)
+            .map(|(name, body)| (name, body.clone()))
+            .collect();
+        let mut changed: bool = false;
+        for func in &mut res.root.children {
+            changed |= inline_calls(func, &bodies);
+        }
+        if !changed {
+            return res;
+        }
+    }
+}
+
+// `al` is the low byte of `rax`, so the two count as the same register.
+fn same_reg(a: Reg, b: Reg) -> bool {
+    let full = |r: Reg| if r == Reg::Al { Reg::Rax } else { r };
+    full(a) == full(b)
+}
+
+fn reads_reg(op: &Operand, r: Reg) -> bool {
+    match op {
+        Operand::Reg(x) | Operand::Mem(x, _) => same_reg(*x, r),
+        Operand::Imm(_) | Operand::Label(_) => false,
+    }
+}
+
+// Rewrites a pattern of two real instructions, returning false if they do not match one.
+fn propagate_two(body: &mut Vec<Instr>, i: usize, j: usize) -> bool {
+    match (&body[i], &body[j]) {
+        // push a; pop b  ->  mov b, a
+        (Instr::Push(Operand::Reg(a)), Instr::Pop(Operand::Reg(b))) => {
+            let (a, b) = (*a, *b);
+            body.remove(j);
+            if a == b {
+                body.remove(i);
+            } else {
+                body[i] = Instr::Mov(reg(b), reg(a));
+            }
+            true
+        },
+        // mov [m], r; mov r, [m]  ->  mov [m], r
+        (Instr::Mov(dst @ Operand::Mem(..), Operand::Reg(r)), Instr::Mov(Operand::Reg(r2), src)) if r == r2 && dst == src => {
+            body.remove(j);
+            true
+        },
+        _ => false,
+    }
+}
+
+// Rewrites a pattern of three real instructions, returning false if they do not match one.
+fn propagate_three(body: &mut Vec<Instr>, i: usize, j: usize, k: usize) -> bool {
+    match (&body[i], &body[j], &body[k]) {
+        // push a; mov c, x; pop b  ->  mov b, a; mov c, x
+        (Instr::Push(Operand::Reg(a)), Instr::Mov(Operand::Reg(c), src), Instr::Pop(Operand::Reg(b)))
+            if !same_reg(*c, *b) && !reads_reg(src, *b) && !reads_reg(src, Reg::Rsp) && *c != Reg::Rsp => {
+            body[i] = Instr::Mov(reg(*b), reg(*a));
+            body.remove(k);
+            true
+        },
+        // mov a, x; mov b, a; mov a, y  ->  mov b, x; mov a, y
+        (Instr::Mov(Operand::Reg(a), x), Instr::Mov(Operand::Reg(b), Operand::Reg(a2)), Instr::Mov(Operand::Reg(a3), y))
+            if a == a2 && a == a3 && !reads_reg(y, *a) && *b != Reg::Al => {
+            body[j] = Instr::Mov(reg(*b), x.clone());
+            body.remove(i);
+            true
+        },
+        _ => false,
+    }
+}
+
+// Removes the stack round trips and reloads of just stored values that per-node codegen emits.
+// Instructions are moved over one at a time, matching patterns that end at the newest one. After a rewrite, everything from
+// the start of the pattern is moved back, so patterns the rewrite made are found too. Every rewrite drops an instruction,
+// so this stays linear.
+// NOTE: Comments are skipped but labels are not, so nothing is moved across a jump target.
+pub fn propagate_copies(func: &mut AsmFunction) {
+    let mut pending: Vec<Instr> = std::mem::take(&mut func.body);
+    pending.reverse();
+    let mut out: Vec<Instr> = Vec::with_capacity(pending.len());
+    while let Some(instr) = pending.pop() {
+        let is_comment: bool = matches!(instr, Instr::Comment(_));
+        out.push(instr);
+        if is_comment {
+            continue;
+        }
+        let real: Vec<usize> = (0..out.len()).rev().filter(|i| !matches!(out[*i], Instr::Comment(_))).take(3).collect();
+        let start: Option<usize> = match real[..] {
+            [k, j, ..] if propagate_two(&mut out, j, k) => Some(j),
+            [k, j, i] if propagate_three(&mut out, i, j, k) => Some(i),
+            _ => None,
+        };
+        if let Some(start) = start {
+            pending.extend(out.drain(start..).rev());
+        }
+    }
+    func.body = out;
+}
+
+// NASM scopes labels starting with `.` to the previous plain label, so only plain labels mean the same thing everywhere.
+fn is_local_label(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+// The first real instruction at or after `ix`, looking through comments and labels.
+fn next_real(body: &[Instr], ix: usize) -> Option<usize> {
+    (ix..body.len()).find(|i| !matches!(body[*i], Instr::Comment(_) | Instr::Label(_)))
+}
+
+// Follows a chain of labels that only jump on, stopping at a cycle or a local label.
+fn final_target(body: &[Instr], labels: &HashMap<String, usize>, name: &str) -> String {
+    let mut target: &str = name;
+    let mut seen: HashSet<&str> = HashSet::new();
+    while seen.insert(target) {
+        let Some(next) = labels.get(target).and_then(|ix| next_real(body, *ix)) else {
+            break;
+        };
+        match &body[next] {
+            Instr::Jmp(l) if !is_local_label(l) => target = l,
+            _ => break,
+        }
+    }
+    target.to_string()
+}
+
+// Points jumps straight at their final destination and drops jumps and labels that only fall through.
+pub fn thread_jumps(func: &mut AsmFunction) {
+    // Freeing no block locals does nothing.
+    func.body.retain(|instr| !matches!(instr, Instr::Add(Operand::Reg(Reg::Rsp), Operand::Imm(0))));
+
+    let labels: HashMap<String, usize> = func.body.iter().enumerate()
+        .filter_map(|(ix, instr)| match instr {
+            Instr::Label(l) => Some((l.clone(), ix)),
+            _ => None,
+        })
+        .collect();
+    let targets: Vec<Option<String>> = func.body.iter()
+        .map(|instr| match instr {
+            Instr::Jmp(l) | Instr::Jcc(_, l) if !is_local_label(l) => Some(final_target(&func.body, &labels, l)),
+            _ => None,
+        })
+        .collect();
+    for (instr, target) in func.body.iter_mut().zip(targets) {
+        if let (Instr::Jmp(l) | Instr::Jcc(_, l), Some(target)) = (instr, target) {
+            *l = target;
+        }
+    }
+
+    // A jump to the label that follows anyway only falls through. Going backwards, the jumps dropped after a jump are
+    // already gone when it is looked at.
+    for ix in (0..func.body.len()).rev() {
+        let (Instr::Jmp(l) | Instr::Jcc(_, l)) = &func.body[ix] else {
+            continue;
+        };
+        let falls_through: bool = !is_local_label(l) && func.body[ix + 1..].iter()
+            .take_while(|instr| matches!(instr, Instr::Comment(_) | Instr::Label(_)))
+            .any(|instr| matches!(instr, Instr::Label(n) if n == l));
+        if falls_through {
+            func.body.remove(ix);
+        }
+    }
+
+    // Unused labels go too, unless local labels after them rely on them for their scope.
+    let used: HashSet<String> = func.body.iter()
+        .filter_map(|instr| match instr {
+            Instr::Jmp(l) | Instr::Jcc(_, l) => Some(l.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut scopes_locals: bool = false;
+    let mut keep: Vec<bool> = vec![true; func.body.len()];
+    for ix in (0..func.body.len()).rev() {
+        match &func.body[ix] {
+            Instr::Label(l) if is_local_label(l) => scopes_locals = true,
+            Instr::Label(l) => {
+                keep[ix] = used.contains(l) || scopes_locals;
+                scopes_locals = false;
+            },
+            _ => {},
+        }
+    }
+    let mut keep = keep.into_iter();
+    func.body.retain(|_| keep.next().unwrap_or(true));
+}
+
+// Moves every `else` body after the end of the function, so the `if` body falls straight through to the code after it.
+// NOTE: An `else` body starts with a plain label and only exits by falling through to the end label, so it can live anywhere as long as it jumps back.
+pub fn reorder_blocks(func: &mut AsmFunction) {
+    let mut tail: Vec<Instr> = Vec::new();
+    let mut ix: usize = 0;
+    while ix < func.body.len() {
+        let Some(start) = (ix + 1..func.body.len()).find(|i| !matches!(func.body[*i], Instr::Comment(_))) else {
+            break;
+        };
+        let (Instr::Jmp(end), Instr::Label(label)) = (&func.body[ix], &func.body[start]) else {
+            ix += 1;
+            continue;
+        };
+        if is_local_label(end) || is_local_label(label) {
+            ix += 1;
+            continue;
+        }
+        let end: String = end.clone();
+        let Some(len) = func.body[start..].iter().position(|instr| matches!(instr, Instr::Label(l) if *l == end)) else {
+            ix += 1;
+            continue;
+        };
+        let mut block: Vec<Instr> = func.body.drain(start..start + len).collect();
+        block.push(Instr::Jmp(end));
+        tail.append(&mut block);
+        // The jump left behind now targets the very next label.
+        func.body.remove(ix);
+    }
+    func.body.append(&mut tail);
+}
+
+// Whether the flags at `ix` may be read before anything sets them again. Labels and jumps lead to code that may, so they
+// count as reading them.
+// NOTE: `idiv` leaves the flags undefined, so nothing correct reads them after it.
+fn flags_live(body: &[Instr], ix: usize) -> bool {
+    for instr in &body[ix..] {
+        match instr {
+            Instr::Set(..) | Instr::Cmov(..) | Instr::Jcc(..) | Instr::Jmp(_) | Instr::Label(_) => return true,
+            Instr::Add(..) | Instr::Sub(..) | Instr::Imul(..) | Instr::Idiv(_) | Instr::Neg(_) | Instr::Xor(..) | Instr::Cmp(..)
+                | Instr::Call(_) | Instr::CallIndirect(_) | Instr::Ret => return false,
+            _ => {},
+        }
+    }
+    false
+}
+
+// Swaps moves of small constants into registers for shorter instructions that do the same, for `-Os`.
+// mov r, 0  ->  xor r, r (3 bytes rather than 5), where nothing reads the flags it sets
+// mov r, n  ->  push n; pop r (3 bytes rather than 5, or 7 for negative values), for values that fit in a byte
+pub fn shrink_encodings(func: &mut AsmFunction) {
+    let mut ix: usize = 0;
+    while ix < func.body.len() {
+        if let Instr::Mov(Operand::Reg(r), Operand::Imm(i)) = func.body[ix] && !matches!(r, Reg::Al | Reg::Rsp) {
+            if i == 0 && !flags_live(&func.body, ix + 1) {
+                func.body[ix] = Instr::Xor(reg(r), reg(r));
+            } else if i8::try_from(i).is_ok() {
+                func.body[ix] = Instr::Push(Operand::Imm(i));
+                func.body.insert(ix + 1, Instr::Pop(reg(r)));
+                ix += 1;
+            }
+        }
+        ix += 1;
+    }
+}
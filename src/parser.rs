@@ -1,4 +1,7 @@
+use crate::diagnostic::Diagnostic;
+use crate::json::Json;
 use crate::lexer::Lexer;
+use crate::lexer::unexpected;
 use crate::lexer::Token;
 use crate::lexer::TokenType;
 use crate::lexer::Pos;
@@ -6,51 +9,114 @@ use crate::lexer::Pos;
 #[derive(Debug)]
 #[derive(Clone)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 pub enum NodeType {
     Program,
     Block,
     Exit,
+    Return,
     FuncDecl,
+    PubFuncDecl,
+    ExportFuncDecl,
+    TestFuncDecl,
     FuncCall,
     VarDecl,
     Var,
     Assign,
+    CompoundAssign, // Surface syntax, desugared into `Assign`
+    Assert, // Surface syntax, desugared into `Conditional`
+    For, // Surface syntax, desugared into `While`
+    While,
     DebugDump,
     BinOp,
     UnOp,
     Conditional,
     Literal,
+    Intrinsic,
+    FuncAddr,
+    Module,
 }
 
+// Functions built into the compiler and the number of arguments each takes. Calls of other functions take none.
+pub const INTRINSICS: [(&str, usize); 5] = [
+    ("min", 2),
+    ("max", 2),
+    ("abs", 1),
+    ("rand", 0),
+    ("time_ns", 0),
+];
+
+#[derive(Debug)]
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 pub struct ParseNode {
     pub kind: NodeType,
     pub tok: Token,
     pub children: Vec<ParseNode>,
 }
 impl ParseNode {
-    pub fn dump(&self, _depth: usize) {
-        let tok_str: String = String::from_utf8(self.tok.val.clone()).expect("Error: Failed to convert token value to string");
-        eprintln!("{:padding$}\x1b[94m* {:?}\x1b[0m (\x1b[92m{:?}\x1b[0m: `{}`)", "", self.kind, self.tok.kind, tok_str, padding = _depth);
-        for child in &self.children {
-            child.dump(_depth + 4);
+    // Draws the tree below this node, one node per line with its token and where it starts.
+    // Nodes more than `max_depth` levels down are counted rather than drawn, so long expressions stay readable.
+    pub fn tree_string(&self, max_depth: Option<usize>) -> String {
+        let mut out: String = String::new();
+        self.draw(&mut out, "", "", max_depth);
+        out
+    }
+
+    fn draw(&self, out: &mut String, lead: &str, indent: &str, levels: Option<usize>) {
+        out.push_str(lead);
+        out.push_str(&format!("{:?}", self.kind));
+        if !self.tok.val.is_empty() {
+            out.push_str(&format!(" `{}`", self.tok.val_str()));
+        }
+        // Nodes the parser makes up, like implicit `0`s, are positioned nowhere.
+        if self.tok.pos.row != usize::MAX - 1 {
+            out.push_str(&format!(" {}", self.tok.pos));
+        }
+        out.push('\n');
+        if levels == Some(0) && !self.children.is_empty() {
+            out.push_str(&format!("{}└─ ... {} more\n", indent, self.size() - 1));
+            return;
         }
+        for (ix, child) in self.children.iter().enumerate() {
+            let (branch, rest) = if ix + 1 == self.children.len() { ("└─ ", "   ") } else { ("├─ ", "│  ") };
+            child.draw(out, &format!("{}{}", indent, branch), &format!("{}{}", indent, rest), levels.map(|n| n - 1));
+        }
+    }
+
+    // Number of nodes in the tree below this node, counting itself.
+    fn size(&self) -> usize {
+        1 + self.children.iter().map(|child| child.size()).sum::<usize>()
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("kind", Json::Str(format!("{:?}", self.kind))),
+            ("tok", self.tok.to_json()),
+            ("children", Json::Array(self.children.iter().map(|c| c.to_json()).collect())),
+        ])
     }
 
     pub fn exclusive_post_order(&self) -> Vec<ParseNode> {
-        let mut res: Vec<ParseNode> = Vec::new();
-        for node in &self.children {
-            res.append(&mut node.post_order());
-        }
+        let mut res: Vec<ParseNode> = self.post_order();
+        res.pop();
         res
     }
 
+    // Walks the tree with a worklist rather than by recursion, so a deeply nested tree cannot overflow the stack.
     pub fn post_order(&self) -> Vec<ParseNode> {
         let mut res: Vec<ParseNode> = Vec::new();
-        for node in &self.children {
-            res.append(&mut node.post_order());
+        let mut work: Vec<(&ParseNode, bool)> = vec![(self, false)]; // Each node, and whether its children are in `res` yet
+        while let Some((node, ready)) = work.pop() {
+            if ready {
+                res.push(node.clone());
+                continue;
+            }
+            work.push((node, true));
+            work.extend(node.children.iter().rev().map(|child| (child, false)));
         }
-        res.push(self.clone());
         res
     }
 
@@ -60,29 +126,41 @@ impl ParseNode {
             tok: Token {
                 kind: TokenType::None,
                 val: prog_name.into_bytes(),
-                pos: Pos { row: usize::MAX - 1, col: usize::MAX - 1 },
+                pos: Pos { row: usize::MAX - 1, col: usize::MAX - 1, offset: usize::MAX - 1 },
+                value: None,
             },
             children: prog
         }
     }
 
-    fn new_exit(tok: Token, rhs: ParseNode) -> Self {
+    // A bare `exit;` exits with 0.
+    pub(crate) fn new_exit(tok: Token, rhs: Option<ParseNode>) -> Self {
         ParseNode {
             kind: NodeType::Exit,
             tok,
-            children: vec![rhs],
+            children: vec![rhs.unwrap_or_else(ParseNode::new_implicit_zero)],
+        }
+    }
+
+    // A bare `return;` returns 0.
+    fn new_return(tok: Token, rhs: Option<ParseNode>) -> Self {
+        ParseNode {
+            kind: NodeType::Return,
+            tok,
+            children: vec![rhs.unwrap_or_else(ParseNode::new_implicit_zero)],
         }
     }
 
-    fn new_debug_dump(tok: Token, rhs: ParseNode) -> Self {
+    // One child per value, dumped in order.
+    fn new_debug_dump(tok: Token, values: Vec<ParseNode>) -> Self {
         ParseNode {
             kind: NodeType::DebugDump,
             tok,
-            children: vec![rhs],
+            children: values,
         }
     }
 
-    fn new_bin_op(tok: Token, lhs: ParseNode, rhs: ParseNode) -> Self {
+    pub(crate) fn new_bin_op(tok: Token, lhs: ParseNode, rhs: ParseNode) -> Self {
         ParseNode {
             kind: NodeType::BinOp,
             tok,
@@ -98,7 +176,15 @@ impl ParseNode {
         }
     }
 
-    fn new_literal(tok: Token) -> Self {
+    fn new_intrinsic(tok: Token, args: Vec<ParseNode>) -> Self {
+        ParseNode {
+            kind: NodeType::Intrinsic,
+            tok,
+            children: args,
+        }
+    }
+
+    pub(crate) fn new_literal(tok: Token) -> Self {
         ParseNode {
             kind: NodeType::Literal,
             tok,
@@ -106,11 +192,47 @@ impl ParseNode {
         }
     }
 
-    fn new_func_decl(ident_tok: Token, body: Vec<ParseNode>) -> Self {
+    fn new_func_decl(ident_tok: Token, body: ParseNode) -> Self {
         ParseNode {
             kind: NodeType::FuncDecl,
             tok: ident_tok,
-            children: body,
+            children: vec![body],
+        }
+    }
+
+    // A function marked `pub`, which can be called from outside of its module and is exported from the object file.
+    fn new_pub_func_decl(ident_tok: Token, body: ParseNode) -> Self {
+        ParseNode {
+            kind: NodeType::PubFuncDecl,
+            tok: ident_tok,
+            children: vec![body],
+        }
+    }
+
+    // A `pub` function whose label is its plain name rather than a mangled one, for linking with code in other languages.
+    fn new_export_func_decl(ident_tok: Token, body: ParseNode) -> Self {
+        ParseNode {
+            kind: NodeType::ExportFuncDecl,
+            tok: ident_tok,
+            children: vec![body],
+        }
+    }
+
+    // A function marked `test`, which the `test` subcommand runs in place of `main` and is otherwise left out.
+    fn new_test_func_decl(ident_tok: Token, body: ParseNode) -> Self {
+        ParseNode {
+            kind: NodeType::TestFuncDecl,
+            tok: ident_tok,
+            children: vec![body],
+        }
+    }
+
+    // Functions of a module, which are called by the module name, a `.` and their own name from outside of it.
+    fn new_module(ident_tok: Token, funcs: Vec<ParseNode>) -> Self {
+        ParseNode {
+            kind: NodeType::Module,
+            tok: ident_tok,
+            children: funcs,
         }
     }
 
@@ -122,7 +244,16 @@ impl ParseNode {
         }
     }
 
-    fn new_assign(ident_tok: Token, rhs: ParseNode) -> Self {
+    // Positioned at the function name rather than the `&`.
+    fn new_func_addr(ident_tok: Token) -> Self {
+        ParseNode {
+            kind: NodeType::FuncAddr,
+            tok: ident_tok,
+            children: vec![],
+        }
+    }
+
+    pub(crate) fn new_assign(ident_tok: Token, rhs: ParseNode) -> Self {
         ParseNode {
             kind: NodeType::Assign,
             tok: ident_tok,
@@ -130,7 +261,50 @@ impl ParseNode {
         }
     }
 
-    fn new_var(ident_tok: Token) -> Self {
+    // `a += b` holds the `a + b` it assigns, with the operator positioned at the `+=`.
+    fn new_compound_assign(ident_tok: Token, op_tok: Token, rhs: ParseNode) -> Self {
+        let kind: TokenType = match op_tok.kind {
+            TokenType::OpPlusAssign => TokenType::OpPlus,
+            TokenType::OpMinusAssign => TokenType::OpMinus,
+            TokenType::OpMulAssign => TokenType::OpMul,
+            _ => TokenType::OpDiv,
+        };
+        let op: Token = Token { kind, val: op_tok.val[..1].to_vec(), pos: op_tok.pos, value: None };
+        ParseNode {
+            kind: NodeType::CompoundAssign,
+            tok: ident_tok.clone(),
+            children: vec![ParseNode::new_bin_op(op, ParseNode::new_var(ident_tok), rhs)],
+        }
+    }
+
+    // Positioned at the loop variable, with the first and last value it takes and the body.
+    fn new_for(ident_tok: Token, first: ParseNode, last: ParseNode, body: ParseNode) -> Self {
+        ParseNode {
+            kind: NodeType::For,
+            tok: ident_tok,
+            children: vec![first, last, body],
+        }
+    }
+
+    // Runs the body for as long as the guard holds. There is no syntax for it, `for` loops are made into one.
+    pub(crate) fn new_while(tok: Token, guard: ParseNode, body: ParseNode) -> Self {
+        ParseNode {
+            kind: NodeType::While,
+            tok,
+            children: vec![guard, body],
+        }
+    }
+
+    // Positioned at the `assert`.
+    fn new_assert(tok: Token, cond: ParseNode) -> Self {
+        ParseNode {
+            kind: NodeType::Assert,
+            tok,
+            children: vec![cond],
+        }
+    }
+
+    pub(crate) fn new_var(ident_tok: Token) -> Self {
         ParseNode {
             kind: NodeType::Var,
             tok: ident_tok,
@@ -138,43 +312,39 @@ impl ParseNode {
         }
     }
 
-    fn new_var_decl(ident_tok: Token, rhs: Option<ParseNode>) -> Self {
-        match rhs {
-            None => {
-                let init_tok: Token = Token {
-                    kind: TokenType::LiteralInt,
-                    val: vec![b'0'],
-                    pos: Pos { col: usize::MAX - 1, row: usize::MAX - 1 },
-                };
-                ParseNode {
-                    kind: NodeType::VarDecl,
-                    tok: ident_tok,
-                    children: vec![ParseNode::new_literal(init_tok)],
-                }
-            },
-            Some(rhs) => {
-                ParseNode {
-                    kind: NodeType::VarDecl,
-                    tok: ident_tok,
-                    children: vec![rhs],
-                }
-            }
+    // The `0` standing in for an expression left out of the source, positioned nowhere.
+    fn new_implicit_zero() -> Self {
+        let tok: Token = Token {
+            kind: TokenType::LiteralInt,
+            val: vec![b'0'],
+            pos: Pos { col: usize::MAX - 1, row: usize::MAX - 1, offset: usize::MAX - 1 },
+            value: Some(0),
+        };
+        ParseNode::new_literal(tok)
+    }
+
+    pub(crate) fn new_var_decl(ident_tok: Token, rhs: Option<ParseNode>) -> Self {
+        ParseNode {
+            kind: NodeType::VarDecl,
+            tok: ident_tok,
+            children: vec![rhs.unwrap_or_else(ParseNode::new_implicit_zero)],
         }
     }
 
-    fn new_block(body: Vec<ParseNode>) -> Self {
+    pub(crate) fn new_block(body: Vec<ParseNode>) -> Self {
         ParseNode {
             kind: NodeType::Block,
             tok: Token {
                 kind: TokenType::None,
                 val: vec![],
-                pos: Pos { col: usize::MAX - 1, row: usize::MAX - 1 },
+                pos: Pos { col: usize::MAX - 1, row: usize::MAX - 1, offset: usize::MAX - 1 },
+                value: None,
             },
             children: body,
         }
     }
 
-    fn new_conditional(tok: Token, cond: ParseNode, if_block: ParseNode, else_block: Option<ParseNode>) -> Self {
+    pub(crate) fn new_conditional(tok: Token, cond: ParseNode, if_block: ParseNode, else_block: Option<ParseNode>) -> Self {
         match else_block {
             None => {
                 ParseNode {
@@ -194,24 +364,84 @@ impl ParseNode {
     }
 }
 
+// How many parentheses, operators and blocks may be open at once. Each operator of a chain like `a + b + c` nests the
+// ones before it a level deeper.
+// NOTE: The parser recurses for each parenthesis, so without a limit a long enough run of `(` overflows the stack.
+// A debug build takes about 15KB of stack per parenthesis, so 128 levels still fit the 2MB of a spawned thread. Later
+// passes recurse once per level of the tree, which a long chain of operators makes as deep as its length.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+fn default_max_depth() -> usize {
+    DEFAULT_MAX_DEPTH
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
 pub struct ParseTree {
-    pub root: ParseNode
+    pub root: ParseNode,
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_max_depth"))]
+    pub max_depth: usize,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    depth: usize, // Nesting levels open at the current token
+    // Comparison operators with a comparison written without parentheses as an operand, like the second `<` of `a < b < c`.
+    // NOTE: Parentheses leave no trace in the tree, so the parser records these for the semantic checks to warn about.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub chained: Vec<Token>,
+    // Statements outside of functions make up the body of `main`, which is then not declared.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub script: bool,
 }
 impl ParseTree {
     pub fn new(prog_name: String) -> Self {
-        ParseTree { root: ParseNode::new_program(prog_name, Vec::new()) }
+        ParseTree { root: ParseNode::new_program(prog_name, Vec::new()), max_depth: default_max_depth(), depth: 0, chained: Vec::new(), script: false }
     }
 
-    pub fn construct(&mut self, lexer: &mut Lexer) {
+    pub fn construct(&mut self, lexer: &mut Lexer) -> Result<(), Diagnostic> {
+        self.depth = 0;
+        self.chained.clear();
         let mut children: Vec<ParseNode> = Vec::new();
+        let mut script: Vec<ParseNode> = Vec::new();
+        let mut script_start: Option<Token> = None;
         while lexer.has_token() {
-            children.push(self.parse_function(lexer));
+            if lexer.peek_token().kind == TokenType::KeywordModule {
+                children.push(self.parse_module(lexer)?);
+                continue;
+            }
+            if !self.script || matches!(lexer.peek_token().kind, TokenType::KeywordFunctionDecl | TokenType::KeywordPub | TokenType::KeywordExport | TokenType::KeywordTest) {
+                children.push(self.parse_function(lexer)?);
+                continue;
+            }
+            script_start.get_or_insert_with(|| lexer.peek_token());
+            script.push(self.parse_block_item(lexer)?);
+        }
+        if let Some(start) = script_start {
+            if let Some(main) = children.iter().find(|func| func.tok.val == b"main") {
+                return Err(Diagnostic::error(main.tok.pos.clone(), "Scripts cannot declare `main`, the statements outside of functions are its body".to_string()));
+            }
+            // Named after `main` but positioned at the first statement, so diagnostics about it point into the script.
+            let tok: Token = Token { kind: TokenType::Identifier, val: b"main".to_vec(), pos: start.pos, value: None };
+            children.push(ParseNode::new_func_decl(tok, ParseNode::new_block(script)));
         }
         self.root.children = children;
+        Ok(())
     }
 
     pub fn dump(&self) {
-        self.root.dump(0);
+        eprint!("{}", self.root.tree_string(None));
+    }
+
+    // Every function along with the name it is called by from outside of its module, like `math.sq`.
+    pub fn functions(&self) -> Vec<(String, &ParseNode)> {
+        let mut res: Vec<(String, &ParseNode)> = Vec::new();
+        for item in &self.root.children {
+            match item.kind {
+                NodeType::Module => res.extend(item.children.iter().map(|func| (format!("{}.{}", item.tok.val_str(), func.tok.val_str()), func))),
+                _ => res.push((item.tok.val_str(), item)),
+            }
+        }
+        res
     }
 
     pub fn post_order(&self) -> Vec<ParseNode> {
@@ -220,13 +450,19 @@ impl ParseTree {
 
     /* Production Rules:
      *
-     * <program>   ::= { <function> }
-     * <function>  ::= "func" <id> "{" { <block_item> } "}"
-     * <statement> ::= "dump" <add_expr> ";" 
-     *               | "exit" <add_expr> ";" 
-     *               | <id> "(" ")" ";"
+     * <program>   ::= { <function> | <module> }
+     * <script>    ::= { <function> | <module> | <block_item> }
+     * <module>    ::= "module" <id> "{" { <function> } "}"
+     * <function>  ::= [ "pub" | "export" | "test" ] "func" <id> <block>
+     * <block>     ::= "{" { <block_item> } "}"
+     * <statement> ::= "dump" <add_expr> { "," <add_expr> } ";" 
+     *               | "exit" [ <add_expr> ] ";" 
+     *               | "return" [ <or_expr> ] ";"
+     *               | <func_name> "(" ")" ";"
      *               | <id> "=" <add_expr> ";"
-     *               | "if" <or_expr> "{" { <statement> } "}" [ "else" "{" { <statement> } "}"
+     *               | "if" <or_expr> <block> [ "else" <block> ]
+     *               | "assert" <or_expr> ";"
+     *               | "for" <id> "=" <or_expr> "to" <or_expr> <block>
      * <decl>      ::= "let" <id> [ "=" <add_expr> ] ";"
      * <block_item>::= <statement> | <declaration>
      * <or_expr>   ::= <and_expr> { "||" <and_expr> }
@@ -234,251 +470,396 @@ impl ParseTree {
      * <equ_expr>  ::= <rel_expr> { ("==" | "~=") <rel_expr> }
      * <rel_expr>  ::= <add_expr> { ("<" | ">" | "<=" | ">=") <add_expr> }
      * <add_expr>  ::= <term> { ("+" | "-") <term> }
-     * <term>      ::= <factor> { ("*" | "/") <factor> }
-     * <factor>    ::= "(" <or_expr> ")" | <unary_op> <factor> | <int> | <id>
+     * <term>      ::= <factor> { ("*" | "/" | "%") <factor> }
+     * <factor>    ::= "(" <or_expr> ")" | <intrinsic> | <func_name> "(" ")" | <unary_op> <factor> | "&" <func_name> | <int> | <id>
+     * <intrinsic> ::= ("min" | "max" | "abs" | "rand" | "time_ns") "(" [ <or_expr> { "," <or_expr> } ] ")"
      * <unary_op>  ::= "-"
+     * <func_name> ::= <id> [ "." <id> ]
      */
 
-    fn parse_factor(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut tok: Token = lexer.consume_token();
+    // Opens a nesting level at `tok`, which the caller closes again with `self.depth -= 1` once parsed.
+    fn enter(&mut self, tok: &Token, what: &str) -> Result<(), Diagnostic> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(self.too_deep(tok, what));
+        }
+        Ok(())
+    }
+
+    // NOTE: Kept out of line so the frames of the expression rules, which every parenthesis adds, stay small.
+    #[inline(never)]
+    fn too_deep(&self, tok: &Token, what: &str) -> Diagnostic {
+        Diagnostic::error(tok.pos.clone(), format!("{} too deeply nested (more than {} levels)", what, self.max_depth))
+    }
+
+    fn parse_factor(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let tok: Token = lexer.consume_token();
         match tok.kind {
-            TokenType::LiteralInt => ParseNode::new_literal(tok),
-            TokenType::Identifier => ParseNode::new_var(tok),
             TokenType::OpMinus => { // Unary minus
-                let factor: ParseNode = self.parse_factor(lexer);
-                ParseNode::new_un_op(tok, factor)
+                self.enter(&tok, "Expression")?;
+                let factor: ParseNode = self.parse_factor(lexer)?;
+                self.depth -= 1;
+                Ok(ParseNode::new_un_op(tok, factor))
             },
             TokenType::OpenParen => {
-                let expression: ParseNode = self.parse_or_expr(lexer);
-                tok = lexer.consume_token();
-                if tok.kind != TokenType::CloseParen {
-                    panic!("{} Error: Expected `)` but got `{}`", tok.pos, tok.val_str());
-                }
-                expression
+                self.enter(&tok, "Expression")?;
+                let expression: ParseNode = self.parse_or_expr(lexer)?;
+                lexer.expect(TokenType::CloseParen)?;
+                self.depth -= 1;
+                Ok(expression)
             },
-            _ => panic!("{} Error: Invalid factor `{}`", tok.pos, tok.val_str())
+            _ => self.parse_operand(lexer, tok),
+        }
+    }
+
+    // NOTE: Kept apart from `parse_factor` so its frame, which every level of parentheses adds, stays small.
+    #[inline(never)]
+    fn parse_operand(&mut self, lexer: &mut Lexer, tok: Token) -> Result<ParseNode, Diagnostic> {
+        match tok.kind {
+            TokenType::LiteralInt => Ok(ParseNode::new_literal(tok)),
+            TokenType::Identifier if lexer.peek_token().kind == TokenType::OpenParen
+                && let Some((_, arity)) = INTRINSICS.iter().find(|(name, _)| name.as_bytes() == tok.val.as_slice()) => self.parse_intrinsic(lexer, tok, *arity),
+            TokenType::Identifier if matches!(lexer.peek_token().kind, TokenType::OpenParen | TokenType::Dot) => parse_call_expr(lexer, tok),
+            TokenType::Identifier => Ok(ParseNode::new_var(tok)),
+            TokenType::OpAddressOf => Ok(ParseNode::new_func_addr(parse_func_name(lexer)?)),
+            TokenType::Eof => Err(unexpected("expression", &tok)),
+            _ => Err(Diagnostic::error(tok.pos.clone(), format!("Invalid factor `{}`", tok.val_str())))
+        }
+    }
+
+    fn parse_intrinsic(&mut self, lexer: &mut Lexer, tok: Token, arity: usize) -> Result<ParseNode, Diagnostic> {
+        let open: Token = lexer.expect(TokenType::OpenParen)?;
+        self.enter(&open, "Expression")?;
+        let mut args: Vec<ParseNode> = Vec::new();
+        if lexer.peek_token().kind != TokenType::CloseParen {
+            args.push(self.parse_or_expr(lexer)?);
+            while lexer.eat_if(TokenType::Comma) {
+                args.push(self.parse_or_expr(lexer)?);
+            }
+        }
+        lexer.expect(TokenType::CloseParen)?;
+        self.depth -= 1;
+        if args.len() != arity {
+            return Err(Diagnostic::error(tok.pos.clone(), format!("`{}` takes {} argument{} but got {}", tok.val_str(), arity, if arity == 1 { "" } else { "s" }, args.len())));
         }
+        Ok(ParseNode::new_intrinsic(tok, args))
     }
 
-    fn parse_term(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut factor: ParseNode = self.parse_factor(lexer);
+    fn parse_term(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let depth: usize = self.depth;
+        let mut factor: ParseNode = self.parse_factor(lexer)?;
         let mut tok: Token = lexer.peek_token();
-        while matches!(tok.kind, TokenType::OpMul | TokenType::OpDiv) {
+        while matches!(tok.kind, TokenType::OpMul | TokenType::OpDiv | TokenType::OpMod) {
             lexer.consume_token();
-            let next_factor: ParseNode = self.parse_factor(lexer);
+            self.depth += 1;
+            if self.depth > self.max_depth {
+                return Err(self.too_deep(&tok, "Expression"));
+            }
+            let next_factor: ParseNode = self.parse_factor(lexer)?;
             factor = ParseNode::new_bin_op(tok, factor, next_factor);
             tok = lexer.peek_token();
         }
 
-        factor
+        self.depth = depth;
+        Ok(factor)
     }
 
-    fn parse_add_expr(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut term: ParseNode = self.parse_term(lexer);
+    fn parse_add_expr(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let depth: usize = self.depth;
+        let mut term: ParseNode = self.parse_term(lexer)?;
         let mut tok: Token = lexer.peek_token();
         while matches!(tok.kind, TokenType::OpPlus | TokenType::OpMinus) {
             lexer.consume_token();
-            let next_term: ParseNode = self.parse_term(lexer);
+            self.depth += 1;
+            if self.depth > self.max_depth {
+                return Err(self.too_deep(&tok, "Expression"));
+            }
+            let next_term: ParseNode = self.parse_term(lexer)?;
             term = ParseNode::new_bin_op(tok, term, next_term);
             tok = lexer.peek_token();
         }
 
-        term
+        self.depth = depth;
+        Ok(term)
     }
 
-    fn parse_or_expr(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut and: ParseNode = self.parse_and_expr(lexer);
+    fn parse_or_expr(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let depth: usize = self.depth;
+        let mut and: ParseNode = self.parse_and_expr(lexer)?;
         let mut tok: Token = lexer.peek_token();
         while matches!(tok.kind, TokenType::OpLogicalOr) {
             lexer.consume_token();
-            let next_and: ParseNode = self.parse_and_expr(lexer);
+            self.depth += 1;
+            if self.depth > self.max_depth {
+                return Err(self.too_deep(&tok, "Expression"));
+            }
+            let next_and: ParseNode = self.parse_and_expr(lexer)?;
             and = ParseNode::new_bin_op(tok, and, next_and);
             tok = lexer.peek_token();
         }
 
-        and
+        self.depth = depth;
+        Ok(and)
     }
 
-    fn parse_and_expr(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut equ: ParseNode = self.parse_equ_expr(lexer);
+    fn parse_and_expr(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let depth: usize = self.depth;
+        let mut equ: ParseNode = self.parse_equ_expr(lexer)?;
         let mut tok: Token = lexer.peek_token();
-        while matches!(tok.kind, TokenType::OpLogicalAnd) {
+        while matches!(tok.kind, TokenType::OpLogicalAnd | TokenType::OpAddressOf) {
+            // NOTE: There are no bitwise operators, so `&` after an operand is a mistyped `&&`.
+            if tok.kind == TokenType::OpAddressOf {
+                return Err(Diagnostic::error(tok.pos.clone(), "`&` takes the address of a function, did you mean `&&`?".to_string()));
+            }
             lexer.consume_token();
-            let next_equ: ParseNode = self.parse_equ_expr(lexer);
+            self.depth += 1;
+            if self.depth > self.max_depth {
+                return Err(self.too_deep(&tok, "Expression"));
+            }
+            let next_equ: ParseNode = self.parse_equ_expr(lexer)?;
             equ = ParseNode::new_bin_op(tok, equ, next_equ);
             tok = lexer.peek_token();
         }
 
-        equ
+        self.depth = depth;
+        Ok(equ)
     }
 
-    fn parse_equ_expr(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut rel: ParseNode = self.parse_rel_expr(lexer);
+    fn parse_equ_expr(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let depth: usize = self.depth;
+        let (mut rel, mut bare) = self.parse_rel_expr(lexer)?;
         let mut tok: Token = lexer.peek_token();
         while matches!(tok.kind, TokenType::OpEqual | TokenType::OpNotEqual) {
             lexer.consume_token();
-            let next_rel: ParseNode = self.parse_rel_expr(lexer);
+            self.depth += 1;
+            if self.depth > self.max_depth {
+                return Err(self.too_deep(&tok, "Expression"));
+            }
+            let (next_rel, next_bare) = self.parse_rel_expr(lexer)?;
+            if bare || next_bare {
+                self.chained.push(tok.clone());
+            }
             rel = ParseNode::new_bin_op(tok, rel, next_rel);
+            bare = true;
             tok = lexer.peek_token();
         }
 
-        rel
+        self.depth = depth;
+        Ok(rel)
     }
 
-    fn parse_rel_expr(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut add: ParseNode = self.parse_add_expr(lexer);
+    // Also tells whether the expression is a comparison written without parentheses.
+    fn parse_rel_expr(&mut self, lexer: &mut Lexer) -> Result<(ParseNode, bool), Diagnostic> {
+        let depth: usize = self.depth;
+        let mut add: ParseNode = self.parse_add_expr(lexer)?;
+        let mut bare: bool = false;
         let mut tok: Token = lexer.peek_token();
         while matches!(tok.kind, TokenType::OpGreaterThan | TokenType::OpGreaterEqual | TokenType::OpLessThan | TokenType::OpLessEqual) {
             lexer.consume_token();
-            let next_add: ParseNode = self.parse_add_expr(lexer);
+            self.depth += 1;
+            if self.depth > self.max_depth {
+                return Err(self.too_deep(&tok, "Expression"));
+            }
+            let next_add: ParseNode = self.parse_add_expr(lexer)?;
+            if bare {
+                self.chained.push(tok.clone());
+            }
             add = ParseNode::new_bin_op(tok, add, next_add);
+            bare = true;
             tok = lexer.peek_token();
         }
 
-        add
+        self.depth = depth;
+        Ok((add, bare))
     }
 
-    fn parse_function(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let mut tok: Token = lexer.consume_token();
-        if tok.kind != TokenType::KeywordFunctionDecl {
-            panic!("{} Error: Expected function declaration but got `{}`", tok.pos, tok.val_str());
+    fn parse_function(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let modifier: TokenType = lexer.peek_token().kind;
+        if matches!(modifier, TokenType::KeywordPub | TokenType::KeywordExport | TokenType::KeywordTest) {
+            lexer.consume_token();
         }
-        tok = lexer.consume_token();
-        if tok.kind != TokenType::Identifier {
-            panic!("{} Error: Expected identifier but got `{}`", tok.pos, tok.val_str());
+        lexer.expect(TokenType::KeywordFunctionDecl)?;
+        let tok: Token = lexer.expect(TokenType::Identifier)?;
+        if INTRINSICS.iter().any(|(name, _)| name.as_bytes() == tok.val.as_slice()) {
+            return Err(Diagnostic::error(tok.pos.clone(), format!("`{}` is an intrinsic and cannot name a function", tok.val_str())));
         }
-        let mut next_tok: Token = lexer.consume_token();
-        if next_tok.kind != TokenType::OpenScope {
-            panic!("{} Error: Expected `{{` but got `{}`", next_tok.pos, next_tok.val_str());
+        let body: ParseNode = self.parse_block(lexer)?;
+        match modifier {
+            TokenType::KeywordPub => Ok(ParseNode::new_pub_func_decl(tok, body)),
+            TokenType::KeywordExport => Ok(ParseNode::new_export_func_decl(tok, body)),
+            TokenType::KeywordTest => Ok(ParseNode::new_test_func_decl(tok, body)),
+            _ => Ok(ParseNode::new_func_decl(tok, body)),
         }
-        let mut body: Vec<ParseNode> = Vec::new();
-        while next_tok.kind != TokenType::CloseScope {
-            body.push(self.parse_block_item(lexer));
-            next_tok = lexer.peek_token();
+    }
+
+    fn parse_module(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        lexer.expect(TokenType::KeywordModule)?;
+        let tok: Token = lexer.expect(TokenType::Identifier)?;
+        lexer.expect(TokenType::OpenScope)?;
+        let mut funcs: Vec<ParseNode> = Vec::new();
+        while !lexer.eat_if(TokenType::CloseScope) {
+            funcs.push(self.parse_function(lexer)?);
         }
+        Ok(ParseNode::new_module(tok, funcs))
+    }
 
+    // The body of a function, `if` or `else`, each of which opens a new scope.
+    fn parse_block(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let tok: Token = lexer.expect(TokenType::OpenScope)?;
+        self.enter(&tok, "Block")?;
+        let mut body: Vec<ParseNode> = Vec::new();
+        while lexer.peek_token().kind != TokenType::CloseScope {
+            body.push(self.parse_block_item(lexer)?);
+        }
         lexer.consume_token();
-        ParseNode::new_func_decl(tok, body)
+        self.depth -= 1;
+        Ok(ParseNode::new_block(body))
     }
 
-    fn parse_block_item(&mut self, lexer: &mut Lexer) -> ParseNode {
+    fn parse_block_item(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
         let tok: Token = lexer.peek_token();
         match tok.kind {
             TokenType::KeywordVariableDecl => self.parse_decl(lexer),
-            TokenType::KeywordIf | TokenType::KeywordExit | TokenType::KeywordDebugDump | TokenType::Identifier => self.parse_statement(lexer),
-            _ => panic!("{} Error: Expected block item but got `{}`", tok.pos, tok.val_str())
+            TokenType::KeywordIf
+            | TokenType::KeywordExit
+            | TokenType::KeywordReturn
+            | TokenType::KeywordDebugDump
+            | TokenType::KeywordAssert
+            | TokenType::KeywordFor
+            | TokenType::Identifier => self.parse_statement(lexer),
+            TokenType::Eof => Err(unexpected("`}`", &tok)), // Block items are parsed until the closing brace
+            _ => Err(unexpected("block item", &tok))
         }
     }
 
-    fn parse_decl(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let tok: Token = lexer.consume_token();
-        if tok.kind != TokenType::KeywordVariableDecl {
-            panic!("{} Error: Expected `let` but got `{}`", tok.pos, tok.val_str());
-        }
-        let ident_tok: Token = lexer.consume_token();
-        if ident_tok.kind != TokenType::Identifier {
-            panic!("{} Error: Expected identifier but got `{}`", ident_tok.pos, ident_tok.val_str());
-        }
-        let mut next_tok: Token = lexer.consume_token();
-        if next_tok.kind == TokenType::End {
-            return ParseNode::new_var_decl(ident_tok, None);
+    fn parse_decl(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        lexer.expect(TokenType::KeywordVariableDecl)?;
+        let ident_tok: Token = lexer.expect(TokenType::Identifier)?;
+        if lexer.eat_if(TokenType::End) {
+            return Ok(ParseNode::new_var_decl(ident_tok, None));
         }
 
+        let next_tok: Token = lexer.consume_token();
         if next_tok.kind != TokenType::OpAssign {
-            panic!("{} Error: Expected `=` or `;` but got `{}`", next_tok.pos, next_tok.val_str());
+            return Err(unexpected("`=` or `;`", &next_tok));
         } 
-        let expression: ParseNode = self.parse_add_expr(lexer);
-        next_tok = lexer.consume_token();
-        if next_tok.kind != TokenType::End {
-            panic!("{} Error: Expected `;` but got `{}`", next_tok.pos, next_tok.val_str());
-        }
-        ParseNode::new_var_decl(ident_tok, Some(expression))
+        let expression: ParseNode = self.parse_or_expr(lexer)?;
+        lexer.expect(TokenType::End)?;
+        Ok(ParseNode::new_var_decl(ident_tok, Some(expression)))
     }
 
-    fn parse_statement(&mut self, lexer: &mut Lexer) -> ParseNode {
-        let tok: Token = lexer.consume_token();
-        match tok.kind {
-            TokenType::KeywordIf => {
-                let guard: ParseNode = self.parse_or_expr(lexer);
-
-                let mut next_tok: Token = lexer.consume_token();
-                if next_tok.kind != TokenType::OpenScope {
-                    panic!("{} Error: Expected `{{` but got `{}`", next_tok.pos, next_tok.val_str());
-                }
+    fn parse_call(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let tok: Token = parse_func_name(lexer)?;
+        lexer.expect(TokenType::OpenParen)?;
+        lexer.expect(TokenType::CloseParen)?;
+        lexer.expect(TokenType::End)?;
+        Ok(ParseNode::new_func_call(tok))
+    }
 
-                let mut if_body: Vec<ParseNode> = Vec::new();
-                next_tok = lexer.peek_token();
-                while next_tok.kind != TokenType::CloseScope {
-                    if_body.push(self.parse_block_item(lexer));
-                    next_tok = lexer.peek_token();
-                }
-                lexer.consume_token();
+    fn parse_assign(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        let tok: Token = lexer.expect(TokenType::Identifier)?;
+        let op_tok: Token = lexer.consume_token();
+        let expression: ParseNode = self.parse_or_expr(lexer)?;
+        lexer.expect(TokenType::End)?;
+        if op_tok.kind == TokenType::OpAssign {
+            return Ok(ParseNode::new_assign(tok, expression));
+        }
+        Ok(ParseNode::new_compound_assign(tok, op_tok, expression))
+    }
 
-                next_tok = lexer.peek_token();
-                if next_tok.kind != TokenType::KeywordElse {
-                    return ParseNode::new_conditional(tok, guard, ParseNode::new_block(if_body), None);
-                }
-                lexer.consume_token();
+    // The value of an `exit` or `return` up to its `;`, None if it has none.
+    fn parse_optional_value(&mut self, lexer: &mut Lexer) -> Result<Option<ParseNode>, Diagnostic> {
+        if lexer.eat_if(TokenType::End) {
+            return Ok(None);
+        }
+        let expression: ParseNode = self.parse_or_expr(lexer)?;
+        lexer.expect(TokenType::End)?;
+        Ok(Some(expression))
+    }
 
-                next_tok = lexer.consume_token();
-                if next_tok.kind != TokenType::OpenScope {
-                    panic!("{} Error: Expected `{{` but got `{}`", next_tok.pos, next_tok.val_str());
-                }
+    fn parse_statement(&mut self, lexer: &mut Lexer) -> Result<ParseNode, Diagnostic> {
+        // Calls and assignments both start with an identifier, the token after it tells them apart.
+        if lexer.peek_token().kind == TokenType::Identifier {
+            let next_tok: Token = lexer.peek_n(1);
+            return match next_tok.kind {
+                TokenType::OpenParen | TokenType::Dot => self.parse_call(lexer),
+                TokenType::OpAssign
+                | TokenType::OpPlusAssign
+                | TokenType::OpMinusAssign
+                | TokenType::OpMulAssign
+                | TokenType::OpDivAssign => self.parse_assign(lexer),
+                _ => Err(unexpected("`(` or `=`", &next_tok)),
+            };
+        }
 
-                let mut else_body: Vec<ParseNode> = Vec::new();
-                next_tok = lexer.peek_token();
-                while next_tok.kind != TokenType::CloseScope {
-                    else_body.push(self.parse_block_item(lexer));
-                    next_tok = lexer.peek_token();
+        let tok: Token = lexer.consume_token();
+        match tok.kind {
+            TokenType::KeywordIf => {
+                let guard: ParseNode = self.parse_or_expr(lexer)?;
+                let if_block: ParseNode = self.parse_block(lexer)?;
+                if !lexer.eat_if(TokenType::KeywordElse) {
+                    return Ok(ParseNode::new_conditional(tok, guard, if_block, None));
                 }
-                lexer.consume_token();
-
-                ParseNode::new_conditional(tok, guard, ParseNode::new_block(if_body), Some(ParseNode::new_block(else_body)))
-            },
-            TokenType::KeywordExit => {
-                let expression: ParseNode = self.parse_add_expr(lexer);
-                let next_tok: Token = lexer.consume_token();
-                if next_tok.kind != TokenType::End {
-                    panic!("{} Error: Expected `;` but got `{}`", next_tok.pos, next_tok.val_str());
+                // An `else if` is kept as a conditional in place of the `else` block, and counts as a nested block.
+                if lexer.peek_token().kind == TokenType::KeywordIf {
+                    self.enter(&lexer.peek_token(), "Block")?;
+                    let else_if: ParseNode = self.parse_statement(lexer)?;
+                    self.depth -= 1;
+                    return Ok(ParseNode::new_conditional(tok, guard, if_block, Some(else_if)));
                 }
-                ParseNode::new_exit(tok, expression)
+                let else_block: ParseNode = self.parse_block(lexer)?;
+                Ok(ParseNode::new_conditional(tok, guard, if_block, Some(else_block)))
             },
+            TokenType::KeywordExit => Ok(ParseNode::new_exit(tok, self.parse_optional_value(lexer)?)),
+            TokenType::KeywordReturn => Ok(ParseNode::new_return(tok, self.parse_optional_value(lexer)?)),
             TokenType::KeywordDebugDump => {
-                let expression: ParseNode = self.parse_add_expr(lexer);
-                let next_tok: Token = lexer.consume_token();
-                if next_tok.kind != TokenType::End {
-                    panic!("{} Error: Expected `;` but got `{}`", next_tok.pos, next_tok.val_str());
+                let mut values: Vec<ParseNode> = vec![self.parse_or_expr(lexer)?];
+                while lexer.eat_if(TokenType::Comma) {
+                    values.push(self.parse_or_expr(lexer)?);
                 }
-                ParseNode::new_debug_dump(tok, expression)
+                lexer.expect(TokenType::End)?;
+                Ok(ParseNode::new_debug_dump(tok, values))
             },
-            TokenType::Identifier => {
-                let mut next_tok: Token = lexer.consume_token();
-                match next_tok.kind {
-                    // Function Call
-                    TokenType::OpenParen => {
-                        next_tok = lexer.consume_token();
-                        if next_tok.kind != TokenType::CloseParen {
-                            panic!("{} Error: Expected `)` but got `{}`", next_tok.pos, next_tok.val_str());
-                        }
-                        next_tok = lexer.consume_token();
-                        if next_tok.kind != TokenType::End {
-                            panic!("{} Error: Expected `;` but got `{}`", next_tok.pos, next_tok.val_str());
-                        }
-                        ParseNode::new_func_call(tok)
-                    },
-                    // Variable Assignment
-                    TokenType::OpAssign => {
-                        let expression: ParseNode = self.parse_add_expr(lexer);
-                        next_tok = lexer.consume_token();
-                        if next_tok.kind != TokenType::End {
-                            panic!("{} Error: Expected `;` but got `{}`", next_tok.pos, next_tok.val_str());
-                        }
-                        ParseNode::new_assign(tok, expression)
-                    },
-                    _ => panic!("{} Error: Expected `(` or `=` but got `{}`", next_tok.pos, next_tok.val_str()),
-                }
+            TokenType::KeywordFor => {
+                let ident_tok: Token = lexer.expect(TokenType::Identifier)?;
+                lexer.expect(TokenType::OpAssign)?;
+                let first: ParseNode = self.parse_or_expr(lexer)?;
+                lexer.expect(TokenType::KeywordTo)?;
+                let last: ParseNode = self.parse_or_expr(lexer)?;
+                let body: ParseNode = self.parse_block(lexer)?;
+                Ok(ParseNode::new_for(ident_tok, first, last, body))
+            },
+            TokenType::KeywordAssert => {
+                let cond: ParseNode = self.parse_or_expr(lexer)?;
+                lexer.expect(TokenType::End)?;
+                Ok(ParseNode::new_assert(tok, cond))
             },
-            _ => panic!("{} Error: Expected statement but got `{}`", tok.pos, tok.val_str()),
+            _ => Err(unexpected("statement", &tok)),
         }
     }
 }
+
+// A function name, qualified with the name of its module when it is called from outside of it. The parts are joined
+// into one token positioned at the first, as `.` cannot appear in an identifier.
+fn parse_func_name(lexer: &mut Lexer) -> Result<Token, Diagnostic> {
+    let tok: Token = lexer.expect(TokenType::Identifier)?;
+    finish_func_name(lexer, tok)
+}
+
+// A call whose value is used, once the first part of the function name is consumed.
+fn parse_call_expr(lexer: &mut Lexer, tok: Token) -> Result<ParseNode, Diagnostic> {
+    let tok: Token = finish_func_name(lexer, tok)?;
+    lexer.expect(TokenType::OpenParen)?;
+    lexer.expect(TokenType::CloseParen)?;
+    Ok(ParseNode::new_func_call(tok))
+}
+
+// Like `parse_func_name`, once its first part is consumed.
+fn finish_func_name(lexer: &mut Lexer, mut tok: Token) -> Result<Token, Diagnostic> {
+    if lexer.eat_if(TokenType::Dot) {
+        let name: Token = lexer.expect(TokenType::Identifier)?;
+        tok.val.push(b'.');
+        tok.val.extend(name.val);
+    }
+    Ok(tok)
+}
@@ -0,0 +1,157 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use language::diagnostic;
+use language::diagnostic::CollectorSink;
+use language::diagnostic::Diagnostic;
+use language::json::Json;
+use language::limits::RunLimits;
+use language::Inspection;
+use language::session::Session;
+use language::source::FileId;
+use language::trace;
+
+// Runs every stage that does not need external tools and reports as far as compilation got.
+// NOTE: The tokens and tree shown are the ones written, the assembly comes from the whole pipeline of a `Session`.
+fn compile_to_json(src: String) -> Json {
+    let mut res: Inspection = language::inspect(src.clone(), "playground".to_string());
+    let mut asm: Option<String> = None;
+    if res.ast.is_some() {
+        let (text, diags) = generate_asm(src);
+        asm = text;
+        res.diagnostics = diags;
+    }
+
+    let mut json: Json = res.to_json();
+    if let (Some(asm), Json::Object(fields)) = (asm, &mut json) {
+        fields.push(("asm".to_string(), Json::Str(asm)));
+    }
+    json
+}
+
+// Anyone can send a program to `/run`, so it is stopped long before it could tie up the server.
+const RUN_LIMITS: RunLimits = RunLimits {
+    max_steps: Some(10_000_000),
+    max_memory: Some(1 << 20),
+    timeout: Some(Duration::from_secs(2)),
+    max_call_depth: Some(1 << 14),
+};
+
+// Runs a source in the interpreter, answering with what it dumped and its exit status, or null if it did not finish.
+fn run_to_json(src: String) -> Json {
+    let mut out: Vec<u8> = Vec::new();
+    let (status, diags): (Json, Vec<Diagnostic>) = match language::run_source_limited(&src, &mut out, RUN_LIMITS) {
+        Ok(status) => (Json::Int(status as i64), Vec::new()),
+        Err(diags) => (Json::Null, diags),
+    };
+    Json::object(vec![
+        ("output", Json::Str(String::from_utf8_lossy(&out).to_string())),
+        ("status", status),
+        ("diagnostics", Json::Array(diags.iter().map(|d| d.to_json()).collect())),
+    ])
+}
+
+// Gives the assembly if the source compiles, along with every error and warning.
+fn generate_asm(src: String) -> (Option<String>, Vec<Diagnostic>) {
+    let compiled = diagnostic::catch(AssertUnwindSafe(move || {
+        let mut session: Session = Session::new();
+        let file: FileId = session.add("playground", src.into_bytes());
+        let mut sink: CollectorSink = CollectorSink::default();
+        let asm: Option<String> = session.compile(file, &mut sink);
+        Ok((asm, sink.diagnostics))
+    }));
+    compiled.unwrap_or_else(|diag| (None, vec![diag]))
+}
+
+// Requests are read into memory whole, so any larger than this are turned away before their body is read.
+const MAX_BODY: usize = 1 << 20;
+// Bytes the request line and headers may take, beyond which the request is cut off.
+const MAX_HEAD: usize = 16 << 10;
+// How long a connection may sit without sending or taking anything before it is dropped, freeing its thread.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\n", status)?;
+    write!(stream, "Content-Type: {}\r\n", content_type)?;
+    write!(stream, "Content-Length: {}\r\n", body.len())?;
+    write!(stream, "Access-Control-Allow-Origin: *\r\n")?;
+    write!(stream, "Access-Control-Allow-Methods: POST, OPTIONS\r\n")?;
+    write!(stream, "Access-Control-Allow-Headers: Content-Type\r\n")?;
+    write!(stream, "Connection: close\r\n\r\n{}", body)?;
+    stream.flush()
+}
+
+fn handle(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader: BufReader<std::io::Take<TcpStream>> = BufReader::new(stream.try_clone()?.take((MAX_HEAD + MAX_BODY) as u64));
+    let mut request_line: String = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method: String = parts.next().unwrap_or("").to_string();
+    let target: String = parts.next().unwrap_or("").to_string();
+
+    let mut len: usize = 0;
+    loop {
+        let mut line: String = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line: &str = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, val)) = line.split_once(':') && key.eq_ignore_ascii_case("content-length") {
+            len = val.trim().parse::<usize>().unwrap_or(0);
+        }
+    }
+    if len > MAX_BODY {
+        return respond(&mut stream, "413 Payload Too Large", "text/plain", "Payload Too Large");
+    }
+    let mut body: Vec<u8> = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    match (method.as_str(), target.as_str()) {
+        ("OPTIONS", _) => respond(&mut stream, "204 No Content", "text/plain", ""),
+        ("POST", "/compile") => {
+            let res: Json = compile_to_json(String::from_utf8_lossy(&body).to_string());
+            respond(&mut stream, "200 OK", "application/json", &res.to_string())
+        },
+        ("POST", "/run") => {
+            let res: Json = run_to_json(String::from_utf8_lossy(&body).to_string());
+            respond(&mut stream, "200 OK", "application/json", &res.to_string())
+        },
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not Found"),
+    }
+}
+
+// Serves `POST /compile`, taking source text as the body and answering with tokens, AST, assembly and diagnostics as JSON,
+// and `POST /run`, answering with the output and exit status of running it in the interpreter.
+pub fn run(addr: &str) -> std::io::Result<()> {
+    // NOTE: Compiler errors are reported in the response, so keep them off stderr.
+    panic::set_hook(Box::new(|_| {}));
+
+    let listener: TcpListener = TcpListener::bind(addr)?;
+    trace::info(&format!("Serving playground on http://{}", listener.local_addr()?));
+    serve(listener)
+}
+
+// Answers every connection to `listener` on a thread of its own.
+pub fn serve(listener: TcpListener) -> std::io::Result<()> {
+    for stream in listener.incoming() {
+        let stream: TcpStream = stream?;
+        std::thread::spawn(move || {
+            if let Err(e) = handle(stream) {
+                eprintln!("Error: Playground request failed: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
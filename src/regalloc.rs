@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use crate::hir::Block;
+use crate::hir::Callee;
+use crate::hir::Expr;
+use crate::hir::ExprKind;
+use crate::hir::Function;
+use crate::hir::LocalId;
+use crate::hir::Stmt;
+use crate::instr::Reg;
+
+// Registers locals may live in. Neither the dump routine nor `syscall` touch them, and functions save the ones they use.
+pub const ALLOCATABLE: [Reg; 4] = [Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+// Where each local of a function lives.
+// NOTE: Locals missing from `homes` were spilled and keep their stack slot.
+#[derive(Default)]
+pub struct Allocation {
+    pub homes: HashMap<LocalId, Reg>,
+    pub used: Vec<Reg>,
+}
+impl Allocation {
+    pub fn get(&self, local: LocalId) -> Option<Reg> {
+        self.homes.get(&local).copied()
+    }
+}
+
+// The span of a local, in the order nodes are visited, from its declaration to its last use.
+struct Interval {
+    local: LocalId,
+    start: usize,
+    end: usize,
+}
+
+// Numbers every statement and expression in evaluation order and extends each local's interval to its last use.
+// NOTE: Control flow only jumps back to the start of a loop, so a local used in a loop but declared before it lives until
+// the loop ends. Otherwise any path between two nodes stays inside the span between their numbers.
+struct Liveness {
+    pos: usize,
+    intervals: Vec<Interval>,
+    of_local: HashMap<LocalId, usize>, // Index of each local's interval
+}
+impl Liveness {
+    fn used(&mut self, local: LocalId) {
+        if let Some(ix) = self.of_local.get(&local) {
+            self.intervals[*ix].end = self.pos;
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.pos += 1;
+        for stmt in block {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        self.pos += 1;
+        match stmt {
+            Stmt::Let(local, init) => {
+                self.expr(init);
+                self.of_local.insert(*local, self.intervals.len());
+                self.intervals.push(Interval { local: *local, start: self.pos, end: self.pos });
+            },
+            Stmt::Assign(local, value) => {
+                self.expr(value);
+                self.used(*local);
+            },
+            Stmt::Dump { values, .. } => values.iter().for_each(|value| self.expr(value)),
+            Stmt::Exit(value) | Stmt::Return(value) => self.expr(value),
+            // A call of a local goes through the function address it holds.
+            Stmt::Call(Callee::Local(local)) => self.used(*local),
+            Stmt::Call(Callee::Label(_)) => {},
+            Stmt::If { guard, then, els, .. } => {
+                self.expr(guard);
+                self.block(then);
+                if let Some(els) = els {
+                    self.block(els);
+                }
+            },
+            Stmt::While { guard, body } => {
+                let start: usize = self.pos;
+                self.expr(guard);
+                self.block(body);
+                // The next time round reads what the last one left.
+                for interval in self.intervals.iter_mut().filter(|i| i.start < start && i.end >= start) {
+                    interval.end = self.pos;
+                }
+            },
+            Stmt::Block(block) => self.block(block),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        self.pos += 1;
+        match &expr.kind {
+            ExprKind::Local(local) => self.used(*local),
+            ExprKind::Unary(_, operand) => self.expr(operand),
+            ExprKind::Binary(_, lhs, rhs) => {
+                self.expr(lhs);
+                self.expr(rhs);
+            },
+            ExprKind::Intrinsic(_, args) => args.iter().for_each(|arg| self.expr(arg)),
+            ExprKind::Call(Callee::Local(local)) => self.used(*local),
+            ExprKind::Literal(_) | ExprKind::FuncAddr(_) | ExprKind::Call(Callee::Label(_)) => {},
+        }
+    }
+}
+
+// Linear scan over the live intervals of one function's locals.
+// When every register is taken, whichever of the candidates lives the longest is spilled to the stack.
+pub fn allocate(func: &Function) -> Allocation {
+    let mut liveness: Liveness = Liveness { pos: 0, intervals: Vec::new(), of_local: HashMap::new() };
+    liveness.block(&func.body);
+    let mut intervals: Vec<Interval> = liveness.intervals;
+    intervals.sort_by_key(|i| i.start);
+
+    let mut assigned: Vec<Option<Reg>> = vec![None; intervals.len()];
+    let mut free: Vec<Reg> = ALLOCATABLE.iter().rev().copied().collect();
+    let mut active: Vec<usize> = Vec::new();
+    for (ix, cur) in intervals.iter().enumerate() {
+        // Registers of intervals that ended before this one starts can be reused.
+        active.retain(|other| {
+            let expired: bool = intervals[*other].end < cur.start;
+            if expired {
+                free.push(assigned[*other].expect("Error: Active interval has no register"));
+            }
+            !expired
+        });
+        if let Some(r) = free.pop() {
+            assigned[ix] = Some(r);
+            active.push(ix);
+            continue;
+        }
+        let victim: usize = (0..active.len()).max_by_key(|a| intervals[active[*a]].end).expect("Error: No register is free or active");
+        if intervals[active[victim]].end > cur.end {
+            let spilled: usize = active.swap_remove(victim);
+            assigned[ix] = assigned[spilled].take();
+            active.push(ix);
+        }
+    }
+
+    let mut alloc: Allocation = Allocation::default();
+    for (interval, r) in intervals.iter().zip(assigned) {
+        if let Some(r) = r {
+            alloc.homes.insert(interval.local, r);
+            if !alloc.used.contains(&r) {
+                alloc.used.push(r);
+            }
+        }
+    }
+    alloc.used.sort_by_key(|r| ALLOCATABLE.iter().position(|a| a == r));
+    alloc
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use crate::consteval::eval_const;
+use crate::consteval::evaluate;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Pos;
+use crate::lexer::TokenType;
+use crate::nasm::RESERVED_LABELS;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+use crate::symbols::MANGLE_PREFIX;
+use crate::symbols::RefKind;
+use crate::symbols::Reference;
+use crate::symbols::SymbolKind;
+use crate::symbols::SymbolTable;
+
+// Checks that every name is declared before it is used and only inside the block that declares it, reporting every problem rather than stopping at the first.
+// Also warns about `if` guards that are constant, since one of their branches can never run, about constant
+// arithmetic that overflows or divides by zero, about chained comparisons and about functions that are never called.
+pub fn check(tree: &ParseTree) -> Vec<Diagnostic> {
+    check_entry(tree, "main")
+}
+
+// Like `check`, for a program starting at the function with the qualified name `entry`.
+pub fn check_entry(tree: &ParseTree, entry: &str) -> Vec<Diagnostic> {
+    let table: SymbolTable = SymbolTable::build(tree);
+    let mut diags: Vec<Diagnostic> = check_symbols(&table);
+    check_reachable(tree, &table, entry, &mut diags);
+    check_conditions(&tree.root, &mut diags);
+    check_constants(&tree.root, &mut diags);
+    check_exports(tree, &mut diags);
+    check_local_names(tree, &table, &mut diags);
+    check_chained(tree, &mut diags);
+    diags
+}
+
+// `a < b < c` compares the 0 or 1 of `a < b` with `c`, which is almost never what was meant.
+fn check_chained(tree: &ParseTree, diags: &mut Vec<Diagnostic>) {
+    for tok in &tree.chained {
+        let msg: String = format!("Comparisons do not chain, `{}` compares the 0 or 1 result of the comparison before it. Use `&&` or add parentheses", tok.val_str());
+        diags.push(Diagnostic::warning(tok.pos.clone(), msg));
+    }
+}
+
+fn check_conditions(node: &ParseNode, diags: &mut Vec<Diagnostic>) {
+    // An `assert` becomes an `if` on its condition being false, so a constant one only matters when it always fails.
+    if node.kind == NodeType::Conditional && node.tok.kind == TokenType::KeywordAssert {
+        if eval_const(&node.children[0]).is_some_and(|val| val != 0) {
+            diags.push(Diagnostic::warning(node.tok.pos.clone(), "Assertion always fails".to_string()));
+        }
+    } else if node.kind == NodeType::Conditional {
+        let guard: &ParseNode = node.children.first().unwrap_or_else(|| panic!("{} Error: Failed to get condition in `if`", node.tok.pos));
+        if let Some(val) = eval_const(guard) {
+            diags.push(Diagnostic::warning(node.tok.pos.clone(), format!("Condition is always {}", val != 0)));
+        }
+    }
+    for child in &node.children {
+        check_conditions(child, diags);
+    }
+}
+
+// Functions no call chain from the entry point reaches are left out of the program, which cannot be built without one.
+// NOTE: Test functions are only ever reached by the `test` subcommand, so they are not warned about.
+fn check_reachable(tree: &ParseTree, table: &SymbolTable, entry: &str, diags: &mut Vec<Diagnostic>) {
+    let Some(roots) = table.roots(entry) else {
+        let start: Pos = Pos { row: 0, col: 0, offset: 0 };
+        diags.push(Diagnostic::error(start, format!("No function `{}` to use as the entry point", entry)));
+        return;
+    };
+    let tests: Vec<String> = tree.functions().into_iter().filter(|(_, func)| func.kind == NodeType::TestFuncDecl).map(|(name, _)| name).collect();
+    let reachable: Vec<bool> = table.reachable(&roots);
+    for (ix, (sym, _)) in table.symbols.iter().zip(reachable).enumerate().filter(|(_, (sym, reached))| sym.kind == SymbolKind::Function && !reached) {
+        // Calls go to the first definition of a name, the others are reported as errors already.
+        if tests.contains(&sym.qualified_name()) || table.duplicates.iter().any(|(dup, _)| *dup == ix) {
+            continue;
+        }
+        diags.push(Diagnostic::warning(sym.pos.clone(), format!("Function `{}` is never called and is left out of the program", sym.qualified_name())));
+    }
+}
+
+// `export` functions are labelled by their plain names, which must not be taken by anything else in the object file.
+fn check_exports(tree: &ParseTree, diags: &mut Vec<Diagnostic>) {
+    let mut exported: HashMap<String, String> = HashMap::new();
+    for (name, func) in tree.functions().into_iter().filter(|(_, func)| func.kind == NodeType::ExportFuncDecl) {
+        let label: String = func.tok.val_str();
+        let msg: String = if name == "main" {
+            "`main` is called by the entry point and cannot be exported".to_string()
+        } else if RESERVED_LABELS.contains(&label.as_str()) {
+            format!("`{}` is reserved by the compiler or the assembler and cannot be exported", label)
+        } else if label.starts_with(MANGLE_PREFIX) {
+            format!("`{}` cannot be exported, labels starting with `{}` belong to the other functions", label, MANGLE_PREFIX)
+        } else if let Some(other) = exported.get(&label) {
+            format!("`{}` is exported by `{}` already", label, other)
+        } else {
+            exported.insert(label, name);
+            continue;
+        };
+        diags.push(Diagnostic::error(func.tok.pos.clone(), msg));
+    }
+}
+
+// Calls are resolved by name once functions are named by their labels, so a local sharing a label would take its calls.
+fn check_local_names(tree: &ParseTree, table: &SymbolTable, diags: &mut Vec<Diagnostic>) {
+    let exported: HashMap<String, String> = tree.functions().into_iter()
+        .filter(|(_, func)| func.kind == NodeType::ExportFuncDecl)
+        .map(|(name, func)| (func.tok.val_str(), name))
+        .collect();
+    for sym in table.symbols.iter().filter(|sym| sym.kind == SymbolKind::Variable) {
+        let msg: String = if sym.name.starts_with(MANGLE_PREFIX) {
+            format!("`{}` cannot name a variable, names starting with `{}` belong to the labels of functions", sym.name, MANGLE_PREFIX)
+        } else if let Some(func) = exported.get(&sym.name) {
+            format!("`{}` cannot name a variable, it is the label of the exported function `{}`", sym.name, func)
+        } else {
+            continue;
+        };
+        diags.push(Diagnostic::error(sym.pos.clone(), msg));
+    }
+}
+
+// Overflowing arithmetic wraps and division by zero faults at runtime, neither is likely meant when the operands are
+// known at compile time.
+fn check_constants(node: &ParseNode, diags: &mut Vec<Diagnostic>) {
+    if matches!(node.kind, NodeType::BinOp | NodeType::UnOp | NodeType::Intrinsic) {
+        if let Err(err) = evaluate(node) && let (Some(pos), Some(msg)) = (err.pos(), err.message()) {
+            diags.push(Diagnostic::warning(pos.clone(), msg));
+        }
+        return;
+    }
+    for child in &node.children {
+        check_constants(child, diags);
+    }
+}
+
+pub fn check_symbols(table: &SymbolTable) -> Vec<Diagnostic> {
+    let mut diags: Vec<Diagnostic> = table.unresolved().map(|r| Diagnostic::error(r.pos.clone(), unresolved_message(table, r))).collect();
+    check_duplicates(table, &mut diags);
+    check_indirect_calls(table, &mut diags);
+    check_private_calls(table, &mut diags);
+    diags
+}
+
+// Every function needs a name of its own, as calls and labels could only ever refer to one of them.
+fn check_duplicates(table: &SymbolTable, diags: &mut Vec<Diagnostic>) {
+    for (dup, first) in &table.duplicates {
+        let (dup, first) = (&table.symbols[*dup], &table.symbols[*first]);
+        diags.push(Diagnostic::error(dup.pos.clone(), format!("Function `{}` is already defined (first defined at {})", dup.qualified_name(), first.pos)));
+    }
+}
+
+// Functions of a module that are not marked `pub` can only be used by the other functions of that module.
+fn check_private_calls(table: &SymbolTable, diags: &mut Vec<Diagnostic>) {
+    for r in table.refs.iter().filter(|r| r.kind == RefKind::Call) {
+        let Some(sym) = r.def.map(|ix| &table.symbols[ix]) else {
+            continue;
+        };
+        let Some(module) = &sym.module else {
+            continue;
+        };
+        if sym.kind == SymbolKind::Function && !sym.public && table.symbols[r.func].module.as_ref() != Some(module) {
+            diags.push(Diagnostic::error(r.pos.clone(), format!("Function `{}` is private to module `{}`, mark it `pub` to use it here", r.name, module)));
+        }
+    }
+}
+
+// Calls through a variable need it to only ever hold function addresses, and to not hide a function of the same name.
+fn check_indirect_calls(table: &SymbolTable, diags: &mut Vec<Diagnostic>) {
+    for r in table.refs.iter().filter(|r| r.kind == RefKind::Call) {
+        let Some(sym) = r.def.map(|ix| &table.symbols[ix]) else {
+            continue;
+        };
+        if sym.kind != SymbolKind::Variable {
+            continue;
+        }
+        if table.function(&r.name, r.func).is_some() {
+            diags.push(Diagnostic::error(r.pos.clone(), format!("Variable `{}` hides the function of the same name, rename one of them to call it", r.name)));
+        } else if !sym.callable {
+            diags.push(Diagnostic::error(r.pos.clone(), format!("Variable `{}` is given values other than function addresses, so it cannot be called", r.name)));
+        }
+    }
+}
+
+fn unresolved_message(table: &SymbolTable, r: &Reference) -> String {
+    let declared_later: bool = table.symbols.iter()
+        .any(|sym| sym.kind == SymbolKind::Variable && sym.name == r.name && sym.parent == Some(r.func) && sym.pos.offset > r.pos.offset);
+    // Every earlier declaration in the same function is in scope unless the block it was declared in has ended.
+    let declared_in_block: bool = table.symbols.iter()
+        .any(|sym| sym.kind == SymbolKind::Variable && sym.name == r.name && sym.parent == Some(r.func) && sym.pos.offset < r.pos.offset);
+    match r.kind {
+        RefKind::Call => format!("No such function `{}`", r.name),
+        _ if declared_later => format!("Variable `{}` is used before its declaration", r.name),
+        _ if declared_in_block => format!("Variable `{}` is declared inside a block and is not in scope here", r.name),
+        RefKind::Write => format!("Cannot assign to undeclared variable `{}`", r.name),
+        RefKind::Read => format!("No such variable `{}` in local scope", r.name),
+    }
+}
@@ -0,0 +1,107 @@
+use crate::lexer::Lexer;
+use crate::lexer::Pos;
+use crate::lexer::TokenType;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum SemanticKind {
+    Keyword,
+    Identifier,
+    Literal,
+    Operator,
+    Comment,
+}
+impl SemanticKind {
+    // Order matches the indices reported by `index` and is used as the LSP legend.
+    pub const ALL: [SemanticKind; 5] = [
+        SemanticKind::Keyword,
+        SemanticKind::Identifier,
+        SemanticKind::Literal,
+        SemanticKind::Operator,
+        SemanticKind::Comment,
+    ];
+
+    pub fn index(&self) -> usize {
+        SemanticKind::ALL.iter().position(|k| k == self).expect("Error: Semantic kind missing from legend")
+    }
+
+    // Name of the equivalent standard LSP semantic token type.
+    pub fn lsp_name(&self) -> &'static str {
+        match self {
+            SemanticKind::Keyword => "keyword",
+            SemanticKind::Identifier => "variable",
+            SemanticKind::Literal => "number",
+            SemanticKind::Operator => "operator",
+            SemanticKind::Comment => "comment",
+        }
+    }
+
+    pub fn of(kind: &TokenType) -> Option<SemanticKind> {
+        match kind {
+            TokenType::KeywordFunctionDecl
+            | TokenType::KeywordExit
+            | TokenType::KeywordDebugDump
+            | TokenType::KeywordVariableDecl
+            | TokenType::KeywordIf
+            | TokenType::KeywordElse
+            | TokenType::KeywordModule
+            | TokenType::KeywordPub
+            | TokenType::KeywordExport
+            | TokenType::KeywordTest
+            | TokenType::KeywordAssert
+            | TokenType::KeywordFor
+            | TokenType::KeywordTo
+            | TokenType::KeywordReturn => Some(SemanticKind::Keyword),
+            TokenType::Identifier => Some(SemanticKind::Identifier),
+            TokenType::LiteralInt => Some(SemanticKind::Literal),
+            TokenType::OpPlus
+            | TokenType::OpMinus
+            | TokenType::OpMul
+            | TokenType::OpDiv
+            | TokenType::OpMod
+            | TokenType::OpAssign
+            | TokenType::OpPlusAssign
+            | TokenType::OpMinusAssign
+            | TokenType::OpMulAssign
+            | TokenType::OpDivAssign
+            | TokenType::OpEqual
+            | TokenType::OpNotEqual
+            | TokenType::OpGreaterThan
+            | TokenType::OpLessThan
+            | TokenType::OpGreaterEqual
+            | TokenType::OpLessEqual
+            | TokenType::OpLogicalOr
+            | TokenType::OpLogicalAnd
+            | TokenType::OpAddressOf => Some(SemanticKind::Operator),
+            TokenType::Comment => Some(SemanticKind::Comment),
+            TokenType::None
+            | TokenType::End
+            | TokenType::OpenParen
+            | TokenType::CloseParen
+            | TokenType::OpenScope
+            | TokenType::CloseScope
+            | TokenType::Comma
+            | TokenType::Dot
+            | TokenType::Eof => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SemanticToken {
+    pub pos: Pos,
+    pub len: usize,
+    pub kind: SemanticKind,
+}
+
+// Classifies every highlightable span of a lexed source, including comments, in source order.
+pub fn semantic_tokens(lexer: &Lexer) -> Vec<SemanticToken> {
+    let mut res: Vec<SemanticToken> = lexer.toks.iter()
+        .chain(lexer.comments.iter())
+        .filter_map(|tok| SemanticKind::of(&tok.kind).map(|kind| SemanticToken { pos: tok.pos.clone(), len: tok.val.len(), kind }))
+        .collect();
+    res.sort_by_key(|tok| tok.pos.offset);
+    res
+}
@@ -0,0 +1,231 @@
+use std::io::Write;
+use std::path::PathBuf;
+use crate::codegen::entry_function;
+use crate::codegen::find_label;
+use crate::codegen::generate_nasm_x86_string;
+use crate::codegen::generate_program;
+use crate::codegen::label_functions;
+use crate::diagnostic;
+use crate::diagnostic::Diagnostic;
+use crate::diagnostic::DiagnosticSink;
+use crate::desugar::desugar;
+use crate::hir;
+use crate::hir::Function;
+use crate::instr::AsmProgram;
+use crate::interp::Interpreter;
+use crate::interp::TraceFormat;
+use crate::lexer::Lexer;
+use crate::lexer::Pos;
+use crate::limits::Limits;
+use crate::limits::RunLimits;
+use crate::opt::OptLevel;
+use crate::opt::eliminate_dead_functions;
+use crate::opt::reorder_blocks;
+use crate::opt::shrink_encodings;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+use crate::sema;
+use crate::source::FileId;
+use crate::source::SourceMap;
+use crate::target::Target;
+use crate::trace;
+use crate::validate;
+
+// A custom pass over the parse tree, run after parsing. Returning an error stops the compilation like a parse error.
+pub type AstPass = Box<dyn Fn(&mut ParseTree) -> Result<(), Diagnostic> + Send + Sync>;
+// A custom pass over the instructions, run once the program is lowered and optimised, before it is printed.
+pub type IrPass = Box<dyn Fn(&mut AsmProgram) -> Result<(), Diagnostic> + Send + Sync>;
+
+// The sources of one compilation and the stages run over them.
+// NOTE: Sessions hold no global or shared mutable state, so a server can run one per request on any thread.
+#[derive(Default)]
+pub struct Session {
+    pub sources: SourceMap,
+    pub opt_level: OptLevel,
+    pub no_reorder_blocks: bool, // Keeps blocks in source order, for reading the output side by side with the source
+    pub limits: Limits,
+    pub run_limits: RunLimits, // What a program may do in the interpreter before it is stopped
+    pub script: bool, // Wraps statements outside of functions in an implicit `main`
+    pub dump_same_line: bool, // Prints the values of one `dump` on one line rather than one per line
+    pub entry: Option<String>, // Qualified name of the function `_start` calls in place of `main`
+    pub no_prelude: bool, // Leaves out the routines behind `dump`, `rand` and `time_ns`, for freestanding programs
+    pub ast_validate: bool, // Checks the tree after every stage that builds or changes it, always on in debug builds
+    pub reproducible: bool, // Keeps the directories of the source and output out of everything emitted, so builds match anywhere
+    pub target: Target,
+    pub sysroot: Option<PathBuf>, // Where the assembler and linker for `target` are looked for before `PATH`
+    pub provenance: Option<String>, // Recorded in the output so it can be traced back to how it was built
+    ast_passes: Vec<AstPass>,
+    ir_passes: Vec<IrPass>,
+}
+
+// Fails to build if a field ever makes sessions unusable across threads.
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    let _ = assert_send_sync::<Session>;
+};
+
+impl Session {
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    pub fn add(&mut self, name: &str, src: Vec<u8>) -> FileId {
+        self.sources.add(name, src)
+    }
+
+    // Passes run in the order they were added.
+    pub fn after_parse(&mut self, pass: impl Fn(&mut ParseTree) -> Result<(), Diagnostic> + Send + Sync + 'static) {
+        self.ast_passes.push(Box::new(pass));
+    }
+
+    pub fn after_lower(&mut self, pass: impl Fn(&mut AsmProgram) -> Result<(), Diagnostic> + Send + Sync + 'static) {
+        self.ir_passes.push(Box::new(pass));
+    }
+
+    pub fn lex(&self, file: FileId) -> Result<Lexer, Diagnostic> {
+        trace::stage("lex", || {
+            let mut lexer: Lexer = Lexer::new(self.sources.get(file).src.clone());
+            lexer.tokenize();
+            lexer.lex().map_err(|d| d.in_file(file))?;
+            self.limits.check_tokens(&lexer.toks).map_err(|d| d.in_file(file))?;
+            Ok(lexer)
+        })
+    }
+
+    // The tree as written, with shorthand like `+=` and `else if` kept, for tools that print the source back.
+    // NOTE: The program is named after the source, which is only its file name in a reproducible build.
+    pub fn parse_surface(&self, file: FileId, lexer: &mut Lexer) -> Result<ParseTree, Diagnostic> {
+        let name: &str = &self.sources.get(file).name;
+        let name: &str = if self.reproducible { name.rsplit('/').next().unwrap_or(name) } else { name };
+        let mut ast: ParseTree = ParseTree::new(name.to_string());
+        ast.max_depth = self.limits.max_depth;
+        ast.script = self.script;
+        ast.construct(lexer).map_err(|d| d.in_file(file))?;
+        self.validate("parsing", &ast, true);
+        Ok(ast)
+    }
+
+    // The tree in core nodes only, which the checks and the backends take.
+    pub fn parse(&self, file: FileId, lexer: &mut Lexer) -> Result<ParseTree, Diagnostic> {
+        trace::stage("parse", || {
+            let mut ast: ParseTree = self.parse_surface(file, lexer)?;
+            desugar(&mut ast);
+            self.validate("desugaring", &ast, false);
+            self.limits.check_tree(&ast).map_err(|d| d.in_file(file))?;
+            for pass in &self.ast_passes {
+                pass(&mut ast).map_err(|d| d.in_file(file))?;
+                self.validate("a custom pass", &ast, false);
+            }
+            Ok(ast)
+        })
+    }
+
+    // Runs the semantic checks, returning every error and warning found.
+    pub fn check(&self, file: FileId, ast: &ParseTree) -> Vec<Diagnostic> {
+        trace::stage("check", || sema::check_entry(ast, self.entry.as_deref().unwrap_or("main")).into_iter().map(|d| d.in_file(file)).collect())
+    }
+
+    pub fn lower(&self, file: FileId, ast: &ParseTree) -> Result<AsmProgram, Diagnostic> {
+        trace::stage("lower", || self.lower_program(file, ast))
+    }
+
+    // NOTE: Functions that are never called are dropped first, so only uses of the prelude that can run count.
+    fn lower_program(&self, file: FileId, ast: &ParseTree) -> Result<AsmProgram, Diagnostic> {
+        let live: ParseTree = eliminate_dead_functions(ast, self.entry.as_deref().unwrap_or("main"));
+        if self.no_prelude && let Some(node) = prelude_use(&live.root) {
+            let name: String = if node.kind == NodeType::DebugDump { "dump".to_string() } else { node.tok.val_str() };
+            return Err(Diagnostic::error(node.tok.pos.clone(), format!("`{}` needs the prelude, which `--no-prelude` leaves out", name)).in_file(file));
+        }
+        let mut prog: AsmProgram = generate_program(&live, self.opt_level).map_err(|d| d.in_file(file))?;
+        if let Some(name) = &self.entry {
+            let Some(label) = find_label(ast, name) else {
+                let start: Pos = Pos { row: 0, col: 0, offset: 0 };
+                return Err(Diagnostic::error(start, format!("No function `{}` to use as the entry point", name)).in_file(file));
+            };
+            prog.entry = entry_function(&label);
+        }
+        if self.opt_level >= OptLevel::O1 && !self.no_reorder_blocks {
+            prog.funcs.iter_mut().for_each(reorder_blocks);
+        }
+        if self.opt_level == OptLevel::Os {
+            prog.funcs.iter_mut().for_each(shrink_encodings);
+        }
+        prog.dump_same_line = self.dump_same_line;
+        prog.note = self.provenance.clone();
+        for pass in &self.ir_passes {
+            pass(&mut prog).map_err(|d| d.in_file(file))?;
+        }
+        Ok(prog)
+    }
+
+    // A tree that breaks an invariant is a bug in whatever built it, so it is reported like any other internal error.
+    fn validate(&self, after: &str, ast: &ParseTree, surface: bool) {
+        if !self.ast_validate && !cfg!(debug_assertions) {
+            return;
+        }
+        if let Err(d) = validate::validate(&ast.root, surface) {
+            panic!("{} Error: Invalid tree after {}: {}", d.pos, after, d.msg);
+        }
+    }
+
+    // Lexes, parses and checks a source, reporting errors and warnings into the sink. None if any of them failed.
+    pub fn front_end(&self, file: FileId, sink: &mut dyn DiagnosticSink) -> Option<ParseTree> {
+        let mut lexer: Lexer = diagnostic::report(self.lex(file), file, sink)?;
+        let ast: ParseTree = diagnostic::report(self.parse(file, &mut lexer), file, sink)?;
+        let diags: Vec<Diagnostic> = self.check(file, &ast);
+        let failed: bool = diags.iter().any(|d| d.is_error());
+        diags.into_iter().for_each(|d| sink.emit(d));
+        if failed {
+            return None;
+        }
+        Some(ast)
+    }
+
+    // Runs every in-memory stage and returns the assembly, reporting errors into the sink.
+    pub fn compile(&self, file: FileId, sink: &mut dyn DiagnosticSink) -> Option<String> {
+        let ast: ParseTree = self.front_end(file, sink)?;
+        let prog: AsmProgram = diagnostic::report(self.lower(file, &ast), file, sink)?;
+        Some(trace::stage("emit", || generate_nasm_x86_string(&prog)))
+    }
+
+    // Runs a source with the interpreter rather than compiling it, returning its exit status. Errors while running are
+    // reported into the sink like compile errors.
+    pub fn run(&self, file: FileId, out: &mut dyn Write, sink: &mut dyn DiagnosticSink) -> Option<u8> {
+        self.run_with(file, out, None, sink)
+    }
+
+    // Like `run`, logging every statement to `trace` as it runs.
+    pub fn run_traced(&self, file: FileId, out: &mut dyn Write, format: TraceFormat, trace: &mut dyn Write, sink: &mut dyn DiagnosticSink) -> Option<u8> {
+        self.run_with(file, out, Some((format, trace)), sink)
+    }
+
+    fn run_with(&self, file: FileId, out: &mut dyn Write, trace: Option<(TraceFormat, &mut dyn Write)>, sink: &mut dyn DiagnosticSink) -> Option<u8> {
+        let ast: ParseTree = self.front_end(file, sink)?;
+        let funcs: Vec<Function> = diagnostic::report(hir::lower(&label_functions(&ast)), file, sink)?;
+        let entry: String = self.entry.clone().unwrap_or_else(|| "main".to_string());
+        let Some(label) = find_label(&ast, &entry) else {
+            let start: Pos = Pos { row: 0, col: 0, offset: 0 };
+            sink.emit(Diagnostic::error(start, format!("No function `{}` to use as the entry point", entry)).in_file(file));
+            return None;
+        };
+        let mut interp: Interpreter = Interpreter::new(&funcs, out).dump_same_line(self.dump_same_line).limits(self.run_limits.clone());
+        if let Some((format, trace)) = trace {
+            interp = interp.trace(format, trace);
+        }
+        diagnostic::report(trace::stage("interpret", || interp.run(&label)), file, sink)
+    }
+}
+
+// The first statement or expression that calls a routine of the prelude.
+fn prelude_use(node: &ParseNode) -> Option<&ParseNode> {
+    let uses: bool = match node.kind {
+        NodeType::DebugDump => true,
+        NodeType::Intrinsic => matches!(node.tok.val.as_slice(), b"rand" | b"time_ns"),
+        _ => false,
+    };
+    if uses {
+        return Some(node);
+    }
+    node.children.iter().find_map(prelude_use)
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+
+// Index of a source in a `SourceMap`, carried by diagnostics instead of a path.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct FileId(pub usize);
+
+pub const STDIN_PATH: &str = "-";
+
+// Where source text comes from, so the compiler does not care whether a buffer is saved.
+pub trait FileProvider {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+}
+
+// Reads from the file system, with `-` meaning standard input.
+pub struct DiskProvider;
+impl FileProvider for DiskProvider {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        if path == STDIN_PATH {
+            let mut buf: Vec<u8> = Vec::new();
+            io::stdin().read_to_end(&mut buf)?;
+            return Ok(buf);
+        }
+        fs::read(path)
+    }
+}
+
+// Serves in-memory buffers, e.g. unsaved editor contents, falling back to disk for anything else.
+#[derive(Default)]
+pub struct MemoryProvider {
+    files: HashMap<String, Vec<u8>>,
+}
+impl MemoryProvider {
+    pub fn insert(&mut self, path: &str, src: Vec<u8>) {
+        self.files.insert(path.to_string(), src);
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.files.remove(path);
+    }
+}
+impl FileProvider for MemoryProvider {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        match self.files.get(path) {
+            Some(src) => Ok(src.clone()),
+            None => DiskProvider.read(path),
+        }
+    }
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub src: Vec<u8>,
+}
+impl SourceFile {
+    // NOTE: Rows count from 0, like `Pos`.
+    pub fn line(&self, row: usize) -> Option<String> {
+        self.src.split(|c| *c == b'\n').nth(row).map(|l| String::from_utf8_lossy(l).to_string())
+    }
+}
+
+// Owns every source of one compilation.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+impl SourceMap {
+    pub fn add(&mut self, name: &str, src: Vec<u8>) -> FileId {
+        self.files.push(SourceFile { name: name.to_string(), src });
+        FileId(self.files.len() - 1)
+    }
+
+    pub fn load(&mut self, provider: &dyn FileProvider, path: &str) -> io::Result<FileId> {
+        let src: Vec<u8> = provider.read(path)?;
+        let name: &str = if path == STDIN_PATH { "<stdin>" } else { path };
+        Ok(self.add(name, src))
+    }
+
+    pub fn get(&self, id: FileId) -> &SourceFile {
+        self.files.get(id.0).expect("Error: File id does not belong to this source map")
+    }
+}
@@ -0,0 +1,168 @@
+// Synthesizes large valid programs for benchmarks and for shaking out recursion depth and label collision bugs.
+// The same seed always gives the same program, so a failure can be reproduced from the command line.
+
+pub struct StressConfig {
+    pub stmts: usize, // Statements in the whole program, not counting the calls in `main`
+    pub depth: usize, // How deep every function nests its conditionals
+    pub seed: u64,
+}
+impl Default for StressConfig {
+    fn default() -> Self {
+        StressConfig { stmts: 1000, depth: 8, seed: 1 }
+    }
+}
+
+// Statements per generated function, so that label numbering runs across many functions.
+const FUNC_STMTS: usize = 100;
+// Operands in the occasional long expression chain.
+const LONG_CHAIN: usize = 48;
+
+pub fn generate(config: &StressConfig) -> String {
+    let mut g: Generator = Generator { rng: config.seed.max(1), out: String::new(), scopes: Vec::new(), next_var: 0 };
+    // Functions get enough statements to nest all the way down.
+    let func_stmts: usize = FUNC_STMTS.max(config.depth + 1);
+    let funcs: usize = config.stmts.div_ceil(func_stmts).max(1);
+    for f in 0..funcs {
+        let budget: usize = config.stmts.saturating_sub(f * func_stmts).min(func_stmts);
+        g.out.push_str(&format!("func f{} {{\n", f));
+        g.next_var = 0;
+        g.scopes.push(Vec::new());
+        g.block(1, config.depth, budget, f);
+        g.scopes.pop();
+        g.out.push_str("}\n\n");
+    }
+    g.out.push_str("func main {\n");
+    for f in 0..funcs {
+        g.out.push_str(&format!("    f{}();\n", f));
+    }
+    g.out.push_str("}\n");
+    g.out
+}
+
+struct Generator {
+    rng: u64,
+    out: String,
+    scopes: Vec<Vec<String>>, // Variables visible in each open block
+    next_var: usize,
+}
+impl Generator {
+    // xorshift64, good enough to vary the shape of programs without a dependency.
+    fn next(&mut self, below: usize) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng % below as u64) as usize
+    }
+
+    // Any variable in scope, or None if there are none.
+    fn variable(&mut self) -> Option<String> {
+        let visible: usize = self.scopes.iter().map(|s| s.len()).sum();
+        if visible == 0 {
+            return None;
+        }
+        let ix: usize = self.next(visible);
+        self.scopes.iter().flatten().nth(ix).cloned()
+    }
+
+    fn operand(&mut self) -> String {
+        match self.variable() {
+            Some(name) if self.next(3) > 0 => name,
+            _ => self.next(100).to_string(),
+        }
+    }
+
+    // An additive expression of `len` operands, with some of them grouped in parentheses.
+    // NOTE: Only ever divides by a non-zero literal, so generated programs can also be run.
+    fn expr(&mut self, len: usize) -> String {
+        let mut res: String = self.operand();
+        let mut i: usize = 1;
+        while i < len {
+            match self.next(6) {
+                0 if len - i > 2 => {
+                    let group: usize = 2 + self.next(len - i - 1);
+                    res = format!("{} - ({})", res, self.expr(group));
+                    i += group;
+                    continue;
+                },
+                0 | 1 => res = format!("{} + {}", res, self.operand()),
+                2 | 3 => res = format!("{} - {}", res, self.operand()),
+                4 => res = format!("{} * {}", res, self.operand()),
+                _ => res = format!("{} / {}", res, 1 + self.next(9)),
+            }
+            i += 1;
+        }
+        res
+    }
+
+    fn expr_len(&mut self) -> usize {
+        if self.next(16) == 0 { LONG_CHAIN } else { 1 + self.next(6) }
+    }
+
+    fn condition(&mut self) -> String {
+        let ops: [&str; 6] = ["<", ">", "<=", ">=", "==", "~="];
+        let mut res: String = String::new();
+        for i in 0..1 + self.next(3) {
+            if i > 0 {
+                res.push_str(if self.next(2) == 0 { " && " } else { " || " });
+            }
+            let (len_l, len_r) = (self.expr_len(), self.expr_len());
+            let op: &str = ops[self.next(ops.len())];
+            res.push_str(&format!("{} {} {}", self.expr(len_l), op, self.expr(len_r)));
+        }
+        res
+    }
+
+    // Writes `budget` statements at nesting level `level`, spending the rest of them on a conditional that nests down to `depth`.
+    fn block(&mut self, level: usize, depth: usize, budget: usize, func: usize) {
+        let indent: String = "    ".repeat(level);
+        let here: usize = if level > depth { budget } else { budget.div_ceil(depth + 2 - level) };
+        for _ in 0..here {
+            self.statement(&indent, func);
+        }
+        let rest: usize = budget - here;
+        if rest == 0 {
+            return;
+        }
+        let cond: String = self.condition();
+        self.out.push_str(&format!("{}if {} {{\n", indent, cond));
+        let else_stmts: usize = if rest > 2 { self.next(rest / 2) } else { 0 };
+        self.scopes.push(Vec::new());
+        self.block(level + 1, depth, rest - else_stmts, func);
+        self.scopes.pop();
+        if else_stmts > 0 {
+            self.out.push_str(&format!("{}}} else {{\n", indent));
+            self.scopes.push(Vec::new());
+            self.block(level + 1, depth, else_stmts, func);
+            self.scopes.pop();
+        }
+        self.out.push_str(&format!("{}}}\n", indent));
+    }
+
+    fn statement(&mut self, indent: &str, func: usize) {
+        let len: usize = self.expr_len();
+        let kind: usize = self.next(8);
+        let target: Option<String> = if matches!(kind, 1 | 2) { self.variable() } else { None };
+        match (kind, target) {
+            // Only odd functions call, and only earlier even ones, so nothing recurses and running stays linear.
+            (0, _) if func % 2 == 1 => {
+                let callee: usize = 2 * self.next(func.div_ceil(2));
+                self.out.push_str(&format!("{}f{}();\n", indent, callee));
+            },
+            (1 | 2, Some(name)) => {
+                let rhs: String = self.expr(len);
+                self.out.push_str(&format!("{}{} = {};\n", indent, name, rhs));
+            },
+            (3, _) => {
+                let rhs: String = self.expr(len);
+                self.out.push_str(&format!("{}dump {};\n", indent, rhs));
+            },
+            _ => {
+                let name: String = format!("v{}", self.next_var);
+                self.next_var += 1;
+                let rhs: String = self.expr(len);
+                self.out.push_str(&format!("{}let {} = {};\n", indent, name, rhs));
+                self.scopes.last_mut().expect("Error: Stress generator has no open scope").push(name);
+            },
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use crate::lexer::Pos;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+use crate::parser::ParseTree;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Variable,
+}
+
+#[derive(Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub pos: Pos,
+    pub parent: Option<usize>, // Index of the enclosing function symbol
+    pub callable: bool, // Variables only ever given function addresses, which can be called through
+    pub module: Option<String>, // Module declaring a function
+    pub public: bool, // Functions marked `pub`, which can be used from outside of their module
+}
+impl Symbol {
+    // The name of a function as it is called from outside of its module.
+    pub fn qualified_name(&self) -> String {
+        match &self.module {
+            Some(module) => format!("{}.{}", module, self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum RefKind {
+    Call,
+    Read,
+    Write,
+}
+
+#[derive(Clone)]
+pub struct Reference {
+    pub name: String,
+    pub kind: RefKind,
+    pub pos: Pos,
+    pub func: usize, // Index of the enclosing function symbol
+    pub def: Option<usize>, // Index of the referenced symbol, None if unresolved
+}
+
+// The function a call from inside `module` refers to. Functions of the caller's own module hide the ones outside of it.
+pub fn qualify(name: &str, module: Option<&str>, declared: impl Fn(&str) -> bool) -> String {
+    match module {
+        Some(module) if !name.contains('.') && declared(&format!("{}.{}", module, name)) => format!("{}.{}", module, name),
+        _ => name.to_string(),
+    }
+}
+
+// The label of a function with the given qualified name. The prefix keeps labels apart from the compiler's own
+// routines, from names the assembler reads as registers or keywords and from symbols of other object files.
+pub fn mangle(name: &str) -> String {
+    format!("{}{}", MANGLE_PREFIX, name)
+}
+
+pub const MANGLE_PREFIX: &str = "lang_";
+
+// Every declaration in a program along with every use of a name and the declaration it resolves to.
+pub struct SymbolTable {
+    pub symbols: Vec<Symbol>,
+    pub refs: Vec<Reference>,
+    pub funcs: HashMap<String, usize>, // Function symbols by qualified name
+    pub duplicates: Vec<(usize, usize)>, // Functions defined again under a name already taken, with the first definition
+}
+impl SymbolTable {
+    pub fn build(tree: &ParseTree) -> Self {
+        let mut table: SymbolTable = SymbolTable { symbols: Vec::new(), refs: Vec::new(), funcs: HashMap::new(), duplicates: Vec::new() };
+
+        // Functions are visible from anywhere in the program, so collect them all first.
+        let funcs: Vec<(String, &ParseNode)> = tree.functions();
+        for (name, func) in &funcs {
+            let module: Option<String> = name.rsplit_once('.').map(|(module, _)| module.to_string());
+            let public: bool = matches!(func.kind, NodeType::PubFuncDecl | NodeType::ExportFuncDecl);
+            table.symbols.push(Symbol { name: func.tok.val_str(), kind: SymbolKind::Function, pos: func.tok.pos.clone(), parent: None, callable: true, module, public });
+            let ix: usize = table.symbols.len() - 1;
+            match table.funcs.get(name) {
+                Some(&first) => table.duplicates.push((ix, first)),
+                None => {
+                    table.funcs.insert(name.clone(), ix);
+                },
+            }
+        }
+
+        for (ix, (_, func)) in funcs.iter().enumerate() {
+            let mut scopes: Vec<Vec<(String, usize)>> = vec![Vec::new()];
+            for block_item in &func.children {
+                table.visit(block_item, ix, &mut scopes);
+            }
+        }
+
+        table
+    }
+
+    // The function a name refers to when called from the function with symbol index `func_ix`.
+    pub fn function(&self, name: &str, func_ix: usize) -> Option<usize> {
+        let name: String = qualify(name, self.symbols[func_ix].module.as_deref(), |name| self.funcs.contains_key(name));
+        self.funcs.get(&name).copied()
+    }
+
+    fn lookup(scopes: &[Vec<(String, usize)>], name: &str) -> Option<usize> {
+        scopes.iter().rev().flat_map(|scope| scope.iter().rev()).find(|(n, _)| n == name).map(|(_, ix)| *ix)
+    }
+
+    fn visit(&mut self, node: &ParseNode, func_ix: usize, scopes: &mut Vec<Vec<(String, usize)>>) {
+        match node.kind {
+            NodeType::Block => {
+                scopes.push(Vec::new());
+                for child in &node.children {
+                    self.visit(child, func_ix, scopes);
+                }
+                scopes.pop();
+            },
+            NodeType::VarDecl => {
+                // The initialiser is evaluated before the name comes into scope.
+                for child in &node.children {
+                    self.visit(child, func_ix, scopes);
+                }
+                let name: String = node.tok.val_str();
+                let callable: bool = self.holds_address(&node.children[0], scopes);
+                self.symbols.push(Symbol { name: name.clone(), kind: SymbolKind::Variable, pos: node.tok.pos.clone(), parent: Some(func_ix), callable, module: None, public: false });
+                scopes.last_mut().expect("Error: Symbol table has no open scope").push((name, self.symbols.len() - 1));
+            },
+            // The bounds are evaluated before the loop variable comes into scope, which ends with the body. It is counted up
+            // by one each time round, so it never holds a function address.
+            NodeType::For => {
+                for bound in &node.children[..2] {
+                    self.visit(bound, func_ix, scopes);
+                }
+                let name: String = node.tok.val_str();
+                self.symbols.push(Symbol { name: name.clone(), kind: SymbolKind::Variable, pos: node.tok.pos.clone(), parent: Some(func_ix), callable: false, module: None, public: false });
+                scopes.push(vec![(name, self.symbols.len() - 1)]);
+                self.visit(&node.children[2], func_ix, scopes);
+                scopes.pop();
+            },
+            NodeType::Var | NodeType::Assign | NodeType::CompoundAssign => {
+                for child in &node.children {
+                    self.visit(child, func_ix, scopes);
+                }
+                let name: String = node.tok.val_str();
+                let def: Option<usize> = SymbolTable::lookup(scopes, &name);
+                let kind: RefKind = if node.kind == NodeType::Var { RefKind::Read } else { RefKind::Write };
+                // A variable is callable only if nothing but function addresses are ever stored in it.
+                if kind == RefKind::Write && let Some(ix) = def && !self.holds_address(&node.children[0], scopes) {
+                    self.symbols[ix].callable = false;
+                }
+                self.refs.push(Reference { name, kind, pos: node.tok.pos.clone(), func: func_ix, def });
+            },
+            // A variable in scope is called through rather than the function of the same name.
+            NodeType::FuncCall => {
+                let name: String = node.tok.val_str();
+                let def: Option<usize> = SymbolTable::lookup(scopes, &name).or_else(|| self.function(&name, func_ix));
+                self.refs.push(Reference { name, kind: RefKind::Call, pos: node.tok.pos.clone(), func: func_ix, def });
+            },
+            // Taking a function's address counts as a call, as the function may be called through it.
+            NodeType::FuncAddr => {
+                let name: String = node.tok.val_str();
+                let def: Option<usize> = self.function(&name, func_ix);
+                self.refs.push(Reference { name, kind: RefKind::Call, pos: node.tok.pos.clone(), func: func_ix, def });
+            },
+            _ => {
+                for child in &node.children {
+                    self.visit(child, func_ix, scopes);
+                }
+            }
+        }
+    }
+
+    // Whether an expression gives a function address, either directly or from a callable variable.
+    fn holds_address(&self, node: &ParseNode, scopes: &[Vec<(String, usize)>]) -> bool {
+        match node.kind {
+            NodeType::FuncAddr => true,
+            NodeType::Var => SymbolTable::lookup(scopes, &node.tok.val_str()).is_some_and(|ix| self.symbols[ix].callable),
+            _ => false,
+        }
+    }
+
+    // Finds the declaration of the name under the given (0 indexed) position, which may be a use or the declaration itself.
+    pub fn definition_at(&self, row: usize, col: usize) -> Option<&Symbol> {
+        let covers = |pos: &Pos, name: &str| pos.row == row && col >= pos.col && col < pos.col + name.len();
+        if let Some(sym) = self.symbols.iter().find(|sym| covers(&sym.pos, &sym.name)) {
+            return Some(sym);
+        }
+        self.refs.iter()
+            .find(|r| covers(&r.pos, &r.name))
+            .and_then(|r| r.def)
+            .map(|ix| &self.symbols[ix])
+    }
+
+    // Which symbols are functions called, directly, through a variable or by address, from a chain of calls starting
+    // at one of the given functions.
+    pub fn reachable(&self, roots: &[usize]) -> Vec<bool> {
+        let mut res: Vec<bool> = vec![false; self.symbols.len()];
+        let mut todo: Vec<usize> = roots.to_vec();
+        while let Some(ix) = todo.pop() {
+            if res[ix] {
+                continue;
+            }
+            res[ix] = true;
+            for r in self.refs.iter().filter(|r| r.func == ix && r.kind == RefKind::Call) {
+                if let Some(def) = r.def && self.symbols[def].kind == SymbolKind::Function {
+                    todo.push(def);
+                }
+            }
+        }
+        res
+    }
+
+    // The functions a program starts at: its entry point and every `pub` or `export` function, which code in other
+    // object files may call. None if there is no entry point to start from.
+    pub fn roots(&self, entry: &str) -> Option<Vec<usize>> {
+        let entry: usize = *self.funcs.get(entry)?;
+        let public = self.symbols.iter().enumerate().filter(|(_, sym)| sym.kind == SymbolKind::Function && sym.public).map(|(ix, _)| ix);
+        Some([entry].into_iter().chain(public).collect())
+    }
+
+    pub fn unresolved(&self) -> impl Iterator<Item = &Reference> {
+        self.refs.iter().filter(|r| r.def.is_none())
+    }
+}
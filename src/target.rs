@@ -0,0 +1,71 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+// A platform programs are built for, chosen with `--target <triple>`. It decides what the assembler and linker are
+// told and which cross binutils are looked for.
+// NOTE: Every target so far is x86_64 Linux, so they all share the NASM backend and its system calls.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Target {
+    #[default]
+    X86_64Linux,
+}
+impl Target {
+    pub const ALL: [Target; 1] = [Target::X86_64Linux];
+
+    // Accepts the full triple and the shorter spellings toolchains commonly use for it.
+    pub fn parse(triple: &str) -> Option<Self> {
+        match triple {
+            "x86_64-unknown-linux-gnu" | "x86_64-linux-gnu" | "x86_64-pc-linux-gnu" | "x86_64-linux" => Some(Target::X86_64Linux),
+            _ => None,
+        }
+    }
+
+    pub fn triple(self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "x86_64-unknown-linux-gnu",
+        }
+    }
+
+    // Output format given to `nasm -f`.
+    pub fn object_format(self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "elf64",
+        }
+    }
+
+    // Emulation given to `ld -m`.
+    pub fn linker_emulation(self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "elf_x86_64",
+        }
+    }
+
+    // What cross binutils for the target are named with in front, as in `x86_64-linux-gnu-ld`.
+    pub fn tool_prefix(self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "x86_64-linux-gnu-",
+        }
+    }
+
+    // The program to run for a tool. In a sysroot the one named for the target is preferred over a plain one, in `bin`
+    // and then `usr/bin`. Without a sysroot, or if it has neither, the tool is looked up on `PATH` by its plain name.
+    pub fn tool(self, sysroot: Option<&Path>, name: &str) -> PathBuf {
+        let Some(root) = sysroot else {
+            return PathBuf::from(name);
+        };
+        let prefixed: String = format!("{}{}", self.tool_prefix(), name);
+        for file in [prefixed.as_str(), name] {
+            for dir in ["bin", "usr/bin"] {
+                let path: PathBuf = root.join(dir).join(file);
+                if path.is_file() {
+                    return path;
+                }
+            }
+        }
+        PathBuf::from(name)
+    }
+}
@@ -1,37 +1,53 @@
 use super::*;
+use std::fs;
+use std::path::PathBuf;
+use language::diagnostic::CollectorSink;
 
 static TEST_DIR: &str = "./language_tests/";
 static EXPECTED_EXT: &str = ".expected";
 static LANGUAGE_EXT: &str = ".lang";
+static EXIT_CODE_EXT: &str = ".exitcode";
+
+// True if the assembler and linker the executable tests need can be run.
+fn has_toolchain() -> bool {
+    Command::new("nasm").arg("-v").output().is_ok() && Command::new("ld").arg("-v").output().is_ok()
+}
 
 // Attemtps to compile a file at ./language_tests/test_name.lang and runs it.
 // The output of the file is compared to the file at ./language_tests/test_name.expected.
 // If the output matches, the test passes, otherwise the test fails.
+// The exit status must match ./language_tests/test_name.exitcode if it exists, and be 0 otherwise.
+// NOTE: Artifacts go to a directory of their own per test, so tests can run in parallel and leave the tree clean.
 fn run_test(test_name: &str) {
-    let mut src_path: String = String::new();
-    src_path.push_str(TEST_DIR);
-    src_path.push_str(test_name);
-    src_path.push_str(LANGUAGE_EXT);
+    if !has_toolchain() {
+        eprintln!("Info: Skipping `{}`, nasm or ld is not installed", test_name);
+        return;
+    }
+    let src_path: String = format!("{}{}{}", TEST_DIR, test_name, LANGUAGE_EXT);
+    let exp_path: String = format!("{}{}{}", TEST_DIR, test_name, EXPECTED_EXT);
 
-    let mut exp_path: String = String::new();
-    exp_path.push_str(TEST_DIR);
-    exp_path.push_str(test_name);
-    exp_path.push_str(EXPECTED_EXT);
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-{}-{}", test_name, process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its artifact directory");
+    let res_path: String = dir.join(test_name).to_string_lossy().to_string();
 
-    let mut res_path: String = String::new();
-    res_path.push_str("./");
-    res_path.push_str(test_name);
+    let exp: Vec<u8> = fs::read(&exp_path).expect("Error: Test failed to read expected file");
+    let exp_code: i32 = match fs::read_to_string(format!("{}{}{}", TEST_DIR, test_name, EXIT_CODE_EXT)) {
+        Ok(code) => code.trim().parse::<i32>().expect("Error: Test exit code file does not hold an integer"),
+        Err(_) => 0,
+    };
 
-    let src: Vec<u8> = fs::read(src_path.clone()).expect("Error: Test failed to read source file");
-    let exp: Vec<u8> = fs::read(exp_path.clone()).expect("Error: Test failed to read expected file");
-
-    compile(src, src_path.clone(), res_path.clone(), vec![]);
-    let run = Command::new(res_path.clone()).output().expect("Error: Failed to run executable");
+    let mut sink: CollectorSink = CollectorSink::default();
+    let mut session: Session = Session::new();
+    let file: FileId = session.sources.load(&DiskProvider, &src_path).expect("Error: Test failed to read source file");
+    let ok: bool = compile(&session, file, res_path.clone(), vec![], &mut sink);
+    assert!(ok, "{} Error: Compilation failed: {}", src_path, sink.diagnostics.iter().map(|d| d.to_string()).collect::<Vec<String>>().join(", "));
+    let run = Command::new(&res_path).output().expect("Error: Failed to run executable");
     let stdout_str: String = String::from_utf8(run.stdout.clone()).expect("Error: Failed to convert stdout to string");
     let exp_str: String = String::from_utf8(exp.clone()).expect("Error: Failed to convert expected to string");
     assert_eq!(exp, run.stdout, "{} Error: Unexpected Program output.\nExpected:\n{}\n\nGot:\n{}", src_path, exp_str, stdout_str);
+    assert_eq!(Some(exp_code), run.status.code(), "{} Error: Unexpected exit status", src_path);
 
-    let _ = Command::new("rm").arg(res_path.clone()).output().expect("Error: Failed to delete compiled executable");
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test artifacts");
 }
 
 // Run Tests
@@ -46,3 +62,2219 @@ fn test_variable() { run_test("variable"); }
 fn test_function() { run_test("function"); }
 #[test]
 fn test_redeclare() { run_test("redeclare"); }
+#[test]
+fn test_scope() { run_test("scope"); }
+#[test]
+fn test_exit() { run_test("exit"); }
+#[test]
+fn test_implicit_exit() { run_test("implicit_exit"); }
+#[test]
+fn test_intrinsics() { run_test("intrinsics"); }
+#[test]
+fn test_rand() { run_test("rand"); }
+#[test]
+fn test_time() { run_test("time"); }
+#[test]
+fn test_function_pointer() { run_test("function_pointer"); }
+#[test]
+fn test_dump_list() { run_test("dump_list"); }
+#[test]
+fn test_modules() { run_test("modules"); }
+#[test]
+fn test_desugar() { run_test("desugar"); }
+#[test]
+fn test_boolean() { run_test("boolean"); }
+#[test]
+fn test_short_circuit() { run_test("short_circuit"); }
+#[test]
+fn test_for_loop() { run_test("for_loop"); }
+#[test]
+fn test_for_max() { run_test("for_max"); }
+#[test]
+fn test_return() { run_test("return"); }
+#[test]
+fn test_return_main() { run_test("return_main"); }
+#[test]
+fn test_forward_call() { run_test("forward_call"); }
+#[test]
+fn test_modulo() { run_test("modulo"); }
+
+// Every run test gives the same output and exit status in the interpreter, which needs no toolchain.
+#[test]
+fn test_interpreter() {
+    use language::run_source;
+
+    for entry in fs::read_dir(TEST_DIR).expect("Error: Failed to read language_tests") {
+        let path: PathBuf = entry.expect("Error: Failed to read test entry").path();
+        let name: String = path.to_string_lossy().to_string();
+        let Some(base) = name.strip_suffix(LANGUAGE_EXT) else {
+            continue;
+        };
+        let Ok(exp) = fs::read(format!("{}{}", base, EXPECTED_EXT)) else {
+            continue;
+        };
+        let exp_code: u8 = match fs::read_to_string(format!("{}{}", base, EXIT_CODE_EXT)) {
+            Ok(code) => code.trim().parse::<u8>().expect("Error: Test exit code file does not hold an integer"),
+            Err(_) => 0,
+        };
+        let src: String = fs::read_to_string(&path).expect("Error: Failed to read test program");
+        let mut out: Vec<u8> = Vec::new();
+        let code: u8 = run_source(&src, &mut out).unwrap_or_else(|diags| panic!("{} Error: Failed to run: {}", name, diags[0]));
+        assert_eq!(String::from_utf8_lossy(&exp), String::from_utf8_lossy(&out), "{} Error: Unexpected program output", name);
+        assert_eq!(exp_code, code, "{} Error: Unexpected exit status", name);
+    }
+}
+
+// Faults that would kill a native program are reported as errors where they happen.
+#[test]
+fn test_interpreter_errors() {
+    use language::limits::RunLimits;
+    use language::run_source;
+    use language::run_source_limited;
+
+    let mut out: Vec<u8> = Vec::new();
+    let diags: Vec<Diagnostic> = run_source("func main {\n    let a = 0;\n    dump 1;\n    dump 2 / a;\n}\n", &mut out).expect_err("Error: Division by zero ran");
+    assert_eq!((diags[0].pos.row, diags[0].pos.col, diags[0].msg.as_str()), (3, 11, "Division by zero"));
+    assert_eq!(out, b"1\n");
+
+    // NOTE: Far deeper than the host stack of a test thread holds without growing it.
+    let limits: RunLimits = RunLimits { max_call_depth: Some(20_000), ..RunLimits::default() };
+    let diags: Vec<Diagnostic> = run_source_limited("func f {\n    f();\n}\nfunc main {\n    f();\n}\n", &mut out, limits).expect_err("Error: Endless recursion ran");
+    assert_eq!(diags[0].msg, "Call stack overflow (more than 20000 nested calls)");
+
+    let diags: Vec<Diagnostic> = run_source("func main {\n    dump x;\n}\n", &mut out).expect_err("Error: Undeclared variable ran");
+    assert!(diags.iter().any(|d| d.is_error()));
+    assert_eq!(run_source("func main {\n    exit 256 + 7;\n}\n", &mut out).ok(), Some(7));
+}
+
+// Programs that go over their run limits are stopped with an error, those within them run as usual.
+#[test]
+fn test_interpreter_limits() {
+    use std::time::Duration;
+    use language::limits::RunLimits;
+    use language::run_source_limited;
+
+    let endless: &str = "func main {\n    for i = 1 to 9223372036854775806 {\n        dump i;\n    }\n}\n";
+    let mut out: Vec<u8> = Vec::new();
+    let limits: RunLimits = RunLimits { max_steps: Some(100), ..RunLimits::default() };
+    let diags: Vec<Diagnostic> = run_source_limited(endless, &mut out, limits.clone()).expect_err("Error: Endless loop ran to its end");
+    assert_eq!(diags[0].msg, "Step limit exceeded (more than 100 statements run)");
+    assert_eq!(diags[0].pos.row, 2);
+    assert_eq!(run_source_limited("func main {\n    dump 1;\n    exit 3;\n}\n", &mut out, limits).ok(), Some(3));
+
+    let deep: &str = "func f {\n    let a = 1;\n    let b = a;\n    f();\n}\nfunc main {\n    f();\n}\n";
+    let limits: RunLimits = RunLimits { max_memory: Some(64), ..RunLimits::default() };
+    let diags: Vec<Diagnostic> = run_source_limited(deep, &mut out, limits).expect_err("Error: Deep recursion ran");
+    assert_eq!(diags[0].msg, "Memory limit exceeded (more than 64 bytes of locals)");
+
+    let limits: RunLimits = RunLimits { timeout: Some(Duration::ZERO), ..RunLimits::default() };
+    let diags: Vec<Diagnostic> = run_source_limited(endless, &mut io::sink(), limits).expect_err("Error: Endless loop ran past its time");
+    assert_eq!(diags[0].msg, "Time limit exceeded (more than 0 ms)");
+}
+
+// A traced run logs each statement the source holds with the variables in scope, and gives the same log every time.
+#[test]
+fn test_interpreter_trace() {
+    use language::interp::TraceFormat;
+
+    let src: &str = "func f {\n    let x = 2;\n}\nfunc main {\n    let a = 1;\n    for i = 1 to 2 {\n        a += i;\n    }\n    f();\n    dump rand() + time_ns();\n    exit a;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("trace", src.as_bytes().to_vec());
+    let traced = |format: TraceFormat| {
+        let mut out: Vec<u8> = Vec::new();
+        let mut trace: Vec<u8> = Vec::new();
+        let status: Option<u8> = session.run_traced(file, &mut out, format, &mut trace, &mut CollectorSink::default());
+        assert_eq!(status, Some(4));
+        (String::from_utf8(out).unwrap(), String::from_utf8(trace).unwrap())
+    };
+    let (out, trace) = traced(TraceFormat::Text);
+    let expected: &str = "\
+[5:13] main: let a
+[6:13] main: let i | a = 1
+[7:11] main: assign a | a = 1, i = 1
+[7:11] main: assign a | a = 2, i = 2
+main: call f | a = 4
+[2:13] f: let x
+[10:17] main: dump | a = 4
+[11:10] main: exit | a = 4
+";
+    assert_eq!(trace, expected);
+    assert_eq!(traced(TraceFormat::Text).0, out);
+
+    let (_, trace) = traced(TraceFormat::Json);
+    let line: Json = Json::parse(trace.lines().nth(2).unwrap()).expect("Error: Trace is not JSON");
+    assert_eq!(line.get("row").and_then(Json::as_i64), Some(7));
+    assert_eq!(line.get("statement").and_then(Json::as_str), Some("assign a"));
+    assert_eq!(line.get("variables").and_then(|vars| vars.get("i")).and_then(Json::as_i64), Some(1));
+    assert_eq!(Json::parse(trace.lines().nth(4).unwrap()).unwrap().get("row"), Some(&Json::Null));
+}
+
+// `lang_compile` builds through the whole pipeline at the level asked for, and reports failures as JSON diagnostics.
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_compile() {
+    use std::ffi::CStr;
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+    use std::os::raw::c_int;
+    use language::capi::LangOptLevel;
+    use language::capi::LangOptions;
+    use language::capi::LangOutput;
+    use language::capi::lang_compile;
+    use language::capi::lang_free;
+
+    let compile_with = |src: &str, options: LangOptions| -> (Option<String>, Option<String>) {
+        let src: CString = CString::new(src).unwrap();
+        let mut diag: *mut c_char = std::ptr::null_mut();
+        let text: *mut c_char = unsafe { lang_compile(src.as_ptr(), &options, &mut diag) };
+        let read = |ptr: *mut c_char| (!ptr.is_null()).then(|| unsafe { CStr::from_ptr(ptr) }.to_string_lossy().to_string());
+        let res: (Option<String>, Option<String>) = (read(text), read(diag));
+        unsafe {
+            lang_free(text);
+            lang_free(diag);
+        }
+        res
+    };
+    let compile = |src: &str, opt_level: LangOptLevel| compile_with(src, LangOptions { output: LangOutput::Asm as c_int, opt_level: opt_level as c_int });
+
+    // Compound assignment, `for` and `else if` only exist before desugaring.
+    let src: &str = "func main {\n    let x = 1;\n    x += 2;\n    for i = 1 to 2 {\n        x *= i;\n    }\n    if x == 1 {\n        exit 1;\n    } else if x == 6 {\n        exit 6;\n    }\n}\n";
+    let (asm, diag) = compile(src, LangOptLevel::O0);
+    assert!(asm.is_some_and(|asm| asm.contains("lang_main:")), "Error: Failed to compile through the C API: {:?}", diag);
+    assert_eq!(diag, None);
+    let (o0, _) = compile("func main {\n    let x = 2 + 3;\n    exit x;\n}\n", LangOptLevel::O0);
+    let (o2, _) = compile("func main {\n    let x = 2 + 3;\n    exit x;\n}\n", LangOptLevel::O2);
+    assert_ne!(o0, o2);
+
+    let (asm, diag) = compile("func a {\n}\nfunc a {\n}\nfunc main {\n    a();\n}\n", LangOptLevel::O0);
+    assert_eq!(asm, None);
+    let diags: Json = Json::parse(&diag.expect("Error: No diagnostics for a failed compile")).unwrap();
+    let first: &Json = &diags.as_array().unwrap()[0];
+    assert_eq!(first.get("message").and_then(Json::as_str), Some("Function `a` is already defined (first defined at [1:6])"));
+    assert_eq!(first.get("row").and_then(Json::as_i64), Some(3));
+
+    // Options from C may hold any integer.
+    let (asm, diag) = compile_with("func main {\n}\n", LangOptions { output: 9, opt_level: -1 });
+    assert_eq!(asm, None);
+    let diags: Json = Json::parse(&diag.expect("Error: No diagnostics for unknown options")).unwrap();
+    let messages: Vec<&str> = diags.as_array().unwrap().iter().filter_map(|d| d.get("message").and_then(Json::as_str)).collect();
+    assert_eq!(messages, ["Unknown output 9", "Unknown optimisation level -1"]);
+}
+
+// The playground compiles and runs sources through the whole pipeline, and turns away bodies too large to read.
+#[cfg(feature = "serve")]
+#[test]
+fn test_playground() {
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").expect("Error: Failed to bind the playground");
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || playground::serve(listener));
+    let post = |target: &str, body: &str, len: usize| -> (String, String) {
+        let mut stream: TcpStream = TcpStream::connect(addr).expect("Error: Failed to connect to the playground");
+        write!(stream, "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", target, len, body).unwrap();
+        let mut res: String = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        let (head, body) = res.split_once("\r\n\r\n").expect("Error: Response has no body");
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    };
+
+    let src: &str = "func main {\n    let x = 1;\n    x += 2;\n    for i = 1 to 2 {\n        dump i;\n    }\n    exit x;\n}\n";
+    let (status, body) = post("/compile", src, src.len());
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let res: Json = Json::parse(&body).unwrap();
+    assert_eq!(res.get("diagnostics").and_then(Json::as_array).map(Vec::len), Some(0));
+    assert!(res.get("asm").and_then(Json::as_str).is_some_and(|asm| asm.contains("lang_main:")));
+    assert!(res.get("tokens").is_some() && res.get("ast").is_some());
+
+    let (_, body) = post("/run", src, src.len());
+    let res: Json = Json::parse(&body).unwrap();
+    assert_eq!(res.get("output").and_then(Json::as_str), Some("1\n2\n"));
+    assert_eq!(res.get("status").and_then(Json::as_i64), Some(3));
+
+    let dup: &str = "func a {\n}\nfunc a {\n}\nfunc main {\n    a();\n}\n";
+    let (_, body) = post("/compile", dup, dup.len());
+    let res: Json = Json::parse(&body).unwrap();
+    assert_eq!(res.get("asm"), None);
+    assert_eq!(res.get("diagnostics").and_then(Json::as_array).map(Vec::len), Some(1));
+
+    let (status, _) = post("/run", "", usize::MAX);
+    assert_eq!(status, "HTTP/1.1 413 Payload Too Large");
+}
+
+// Sends each `(method, id, params)` to the language server in turn, giving every message it sent back. Requests are
+// the ones given an id, notifications have none.
+fn lsp_session(msgs: &[(&str, Option<usize>, Json)]) -> Vec<Json> {
+    let mut input: Vec<u8> = Vec::new();
+    for (method, id, params) in msgs {
+        let mut fields: Vec<(&str, Json)> = vec![("jsonrpc", Json::Str("2.0".to_string())), ("method", Json::Str(method.to_string())), ("params", params.clone())];
+        if let Some(id) = id {
+            fields.push(("id", Json::Int(*id as i64)));
+        }
+        let body: String = Json::object(fields).to_string();
+        input.extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes());
+    }
+    let mut out: Vec<u8> = Vec::new();
+    lsp::serve(input.as_slice(), &mut out).expect("Error: Language server failed");
+    let out: String = String::from_utf8(out).unwrap();
+    out.split("Content-Length: ").skip(1)
+        .map(|msg| Json::parse(msg.split_once("\r\n\r\n").expect("Error: Message has no body").1).expect("Error: Message is not JSON"))
+        .collect()
+}
+
+// A whole session over stdio, with columns in UTF-16 code units as the protocol counts them.
+#[test]
+fn test_lsp_session() {
+    let doc = |uri: &str, text: &str| Json::object(vec![("textDocument", Json::object(vec![("uri", Json::Str(uri.to_string())), ("text", Json::Str(text.to_string()))]))]);
+    let at = |line: i64, character: i64| Json::object(vec![
+        ("textDocument", Json::object(vec![("uri", Json::Str("file:///a.lang".to_string()))])),
+        ("position", Json::object(vec![("line", Json::Int(line)), ("character", Json::Int(character))])),
+    ]);
+    let src: &str = "func helper {\n    let v = 1;\n    dump v + zz;\n}\nfunc main {\n    helper(); // ünïcödé\n}\n";
+    let msgs: Vec<Json> = lsp_session(&[
+        ("initialize", Some(0), Json::object(vec![])),
+        ("textDocument/didOpen", None, doc("file:///a.lang", src)),
+        ("textDocument/didOpen", None, doc("file:///b.lang", "func main {\n    dump 1; let 😀 = 1;\n}\n")),
+        ("textDocument/definition", Some(3), at(2, 9)),
+        ("textDocument/definition", Some(4), at(5, 5)),
+        ("textDocument/documentSymbol", Some(5), Json::object(vec![("textDocument", Json::object(vec![("uri", Json::Str("file:///a.lang".to_string()))]))])),
+        ("exit", None, Json::Null),
+    ]);
+    assert_eq!(msgs.len(), 6);
+    let point = |range: &Json, end: &str| -> (i64, i64) {
+        let point: &Json = range.get(end).unwrap();
+        (point.get("line").and_then(Json::as_i64).unwrap(), point.get("character").and_then(Json::as_i64).unwrap())
+    };
+
+    let caps: &Json = msgs[0].get("result").and_then(|res| res.get("capabilities")).expect("Error: No capabilities");
+    assert_eq!(caps.get("definitionProvider"), Some(&Json::Bool(true)));
+
+    let diags: &Vec<Json> = msgs[1].get("params").and_then(|p| p.get("diagnostics")).and_then(Json::as_array).unwrap();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].get("message").and_then(Json::as_str), Some("No such variable `zz` in local scope"));
+    assert_eq!((point(diags[0].get("range").unwrap(), "start"), point(diags[0].get("range").unwrap(), "end")), ((2, 13), (2, 15)));
+
+    // The emoji is 4 bytes and 2 UTF-16 code units.
+    let diags: &Vec<Json> = msgs[2].get("params").and_then(|p| p.get("diagnostics")).and_then(Json::as_array).unwrap();
+    assert_eq!((point(diags[0].get("range").unwrap(), "start"), point(diags[0].get("range").unwrap(), "end")), ((1, 16), (1, 18)));
+
+    assert_eq!(msgs[3].get("id").and_then(Json::as_i64), Some(3));
+    assert_eq!(point(msgs[3].get("result").and_then(|res| res.get("range")).unwrap(), "start"), (1, 8));
+    assert_eq!(point(msgs[4].get("result").and_then(|res| res.get("range")).unwrap(), "start"), (0, 5));
+
+    let symbols: &Vec<Json> = msgs[5].get("result").and_then(Json::as_array).unwrap();
+    let names: Vec<&str> = symbols.iter().filter_map(|sym| sym.get("name").and_then(Json::as_str)).collect();
+    assert_eq!(names, vec!["helper", "main"]);
+    let vars: &Vec<Json> = symbols[0].get("children").and_then(Json::as_array).unwrap();
+    assert_eq!(vars[0].get("name").and_then(Json::as_str), Some("v"));
+    assert_eq!(point(vars[0].get("range").unwrap(), "start"), (1, 8));
+}
+
+// Semantic tokens are sent as (line delta, start delta, length, type, modifiers), the start relative to the token
+// before on the same line, the length in UTF-16 code units.
+#[test]
+fn test_semantic_tokens() {
+    let uri = || Json::object(vec![("uri", Json::Str("file:///a.lang".to_string()))]);
+    let src: &str = "func main {\n    dump 10 + x; // é\n}\n";
+    let msgs: Vec<Json> = lsp_session(&[
+        ("textDocument/didOpen", None, Json::object(vec![("textDocument", Json::object(vec![("uri", Json::Str("file:///a.lang".to_string())), ("text", Json::Str(src.to_string()))]))])),
+        ("textDocument/semanticTokens/full", Some(1), Json::object(vec![("textDocument", uri())])),
+    ]);
+    let data: Vec<i64> = msgs[1].get("result").and_then(|res| res.get("data")).and_then(Json::as_array).unwrap()
+        .iter().map(|n| n.as_i64().unwrap()).collect();
+    assert_eq!(data, vec![
+        0, 0, 4, 0, 0, // func
+        0, 5, 4, 1, 0, // main
+        1, 4, 4, 0, 0, // dump
+        0, 5, 2, 2, 0, // 10
+        0, 3, 1, 3, 0, // +
+        0, 2, 1, 1, 0, // x
+        0, 3, 4, 4, 0, // // é
+    ]);
+}
+
+// Tokens and parse trees read back from their serialized form are the ones written, in the documented schema.
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    use language::lexer::Token;
+
+    let src_path: String = format!("{}modules{}", TEST_DIR, LANGUAGE_EXT);
+    let mut session: Session = Session::new();
+    let file: FileId = session.sources.load(&DiskProvider, &src_path).expect("Error: Test failed to read source file");
+    let mut lexer: Lexer = session.lex(file).unwrap_or_else(|d| panic!("{} Error: Lexing failed: {}", src_path, d));
+    let toks: Vec<Token> = lexer.toks.clone();
+    let ast: ParseTree = session.parse(file, &mut lexer).unwrap_or_else(|d| panic!("{} Error: Parsing failed: {}", src_path, d));
+
+    let text: String = serde_json::to_string(&toks).expect("Error: Failed to serialize tokens");
+    let back: Vec<Token> = serde_json::from_str(&text).expect("Error: Failed to deserialize tokens");
+    assert_eq!(format!("{:?}", back), format!("{:?}", toks));
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json[0], serde_json::json!({"kind": "KeywordModule", "val": "module", "pos": {"row": 1, "col": 0, "offset": 44}}));
+    let literal: Option<&serde_json::Value> = json.as_array().unwrap().iter().find(|tok| tok["kind"] == "LiteralInt");
+    assert_eq!(literal.map(|tok| &tok["value"]), Some(&serde_json::json!(49)));
+
+    let text: String = serde_json::to_string(&ast).expect("Error: Failed to serialize parse tree");
+    let back: ParseTree = serde_json::from_str(&text).expect("Error: Failed to deserialize parse tree");
+    assert_eq!(format!("{:?}", back.root), format!("{:?}", ast.root));
+    assert_eq!(serde_json::to_string(&back).unwrap(), text);
+    assert_eq!(back.max_depth, ast.max_depth);
+}
+
+// Every stage a compilation goes through runs in a span named after it.
+#[cfg(feature = "tracing")]
+#[test]
+fn test_stage_spans() {
+    use std::fmt;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use tracing::Event;
+    use tracing::Metadata;
+    use tracing::Subscriber;
+    use tracing::field::Field;
+    use tracing::field::Visit;
+    use tracing::span;
+
+    struct Stages(Arc<Mutex<Vec<String>>>);
+    impl Visit for &Stages {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "name" {
+                self.0.lock().expect("Error: Stages are poisoned").push(format!("{:?}", value).trim_matches('"').to_string());
+            }
+        }
+    }
+    impl Subscriber for Stages {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+            attrs.record(&mut &*self);
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let stages: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", b"func main {\n    dump 1;\n}\n".to_vec());
+    tracing::subscriber::with_default(Stages(stages.clone()), || {
+        session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+        session.run(file, &mut Vec::new(), &mut CollectorSink::default()).expect("Error: Failed to run source");
+    });
+    let stages: Vec<String> = stages.lock().expect("Error: Stages are poisoned").clone();
+    assert_eq!(stages, ["lex", "parse", "check", "lower", "emit", "lex", "parse", "check", "interpret"]);
+}
+
+// The driver names the innermost stage running when it reports an internal compiler error, and a stage that panicked
+// is left while unwinding.
+#[test]
+fn test_current_stage() {
+    use language::trace;
+
+    assert_eq!(trace::current_stage(), None);
+    let inner: Option<&str> = trace::stage("lower", || trace::stage("emit", trace::current_stage));
+    assert_eq!(inner, Some("emit"));
+    assert_eq!(trace::current_stage(), None);
+
+    let res: Result<(), Diagnostic> = diagnostic::catch(|| trace::stage("check", || panic!("Error: Stage panicked")));
+    assert_eq!(res.err().map(|d| d.msg), Some("Stage panicked".to_string()));
+    assert_eq!(trace::current_stage(), None);
+}
+
+// Trees that break an invariant are caught by the validator, and a pass that breaks one is reported as an internal error
+// naming it.
+#[test]
+fn test_ast_validate() {
+    use language::parser::NodeType;
+    use language::parser::ParseTree;
+    use language::validate::validate;
+
+    let src: &str = "func main {\n    let a = 1 + 2;\n    a += 3;\n    if a > 1 {\n        dump a;\n    } else if a < 0 {\n        exit 1;\n    }\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let surface: ParseTree = session.parse_surface(file, &mut lexer).expect("Error: Failed to parse source");
+    assert!(validate(&surface.root, true).is_ok());
+    assert_eq!(validate(&surface.root, false).err().map(|d| d.msg), Some("`CompoundAssign` node is left after desugaring".to_string()));
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast: ParseTree = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    assert!(validate(&ast.root, false).is_ok());
+
+    let mut broken: ParseTree = ast.clone();
+    broken.root.children[0].children[0].children[0].children[0].children.pop();
+    assert_eq!(validate(&broken.root, false).err().map(|d| d.msg), Some("`BinOp` node has 1 children, expected 2".to_string()));
+    let mut broken: ParseTree = ast.clone();
+    broken.root.children[0].children[0].children.reverse();
+    assert_eq!(validate(&broken.root, false).err().map(|d| d.msg), Some("Children of `Block` node are out of source order".to_string()));
+    let mut broken: ParseTree = ast.clone();
+    broken.root.children[0].children[0].children[0].children[0].children.swap(0, 1);
+    assert_eq!(validate(&broken.root, false).err().map(|d| d.msg), Some("`Literal` node lies outside of its `BinOp` node".to_string()));
+    let mut broken: ParseTree = ast.clone();
+    broken.root.children[0].children[0].children[2].children[1].kind = NodeType::Conditional;
+    assert_eq!(validate(&broken.root, false).err().map(|d| d.msg), Some("`Conditional` node cannot hold a `Conditional` node as a body".to_string()));
+
+    session.after_parse(|ast: &mut ParseTree| {
+        ast.root.children[0].children.clear();
+        Ok(())
+    });
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let res = diagnostic::catch(panic::AssertUnwindSafe(|| session.parse(file, &mut lexer)));
+    assert_eq!(res.err().map(|d| d.msg), Some("Invalid tree after a custom pass: `FuncDecl` node has 0 children, expected 1".to_string()));
+}
+
+// Passes added to a session see and change the tree and the instructions, and their errors stop the compilation.
+#[test]
+fn test_custom_passes() {
+    use language::instr::AsmProgram;
+    use language::instr::Instr;
+    use language::parser::NodeType;
+    use language::parser::ParseNode;
+    use language::parser::ParseTree;
+
+    fn double(node: &mut ParseNode) {
+        if node.kind == NodeType::Literal {
+            let value: i64 = node.tok.value.expect("Error: Literal has no value") * 2;
+            node.tok.val = value.to_string().into_bytes();
+            node.tok.value = Some(value);
+        }
+        node.children.iter_mut().for_each(double);
+    }
+
+    let src: &[u8] = b"func main {\n    dump 21;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.to_vec());
+    session.after_parse(|ast: &mut ParseTree| {
+        double(&mut ast.root);
+        Ok(())
+    });
+    session.after_lower(|prog: &mut AsmProgram| {
+        prog.funcs.iter_mut().for_each(|func| func.body.insert(0, Instr::Comment("Seen by a custom pass".to_string())));
+        Ok(())
+    });
+    let mut sink: CollectorSink = CollectorSink::default();
+    let asm: String = session.compile(file, &mut sink).expect("Error: Failed to compile source");
+    assert!(asm.contains("mov rax, 42"), "Error: Tree pass did not run:\n{}", asm);
+    assert!(asm.contains("; --- Seen by a custom pass ---"), "Error: Instruction pass did not run:\n{}", asm);
+
+    session.after_parse(|ast: &mut ParseTree| match ast.root.children.len() {
+        1 => Err(language::diagnostic::Diagnostic::error(ast.root.children[0].tok.pos.clone(), "Only one function".to_string())),
+        _ => Ok(()),
+    });
+    let mut sink: CollectorSink = CollectorSink::default();
+    assert!(session.compile(file, &mut sink).is_none());
+    assert_eq!(sink.diagnostics.iter().map(|d| d.to_string()).collect::<Vec<String>>(), ["[1:6] Error: Only one function"]);
+}
+
+// `--save-temps` keeps the output of every stage under `<name>.d/`, while a plain build leaves only the executable.
+#[test]
+fn test_save_temps() {
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-save-temps-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its artifact directory");
+    let res_path: String = dir.join("app").to_string_lossy().to_string();
+    let saved: PathBuf = dir.join("app.d");
+
+    let mut sink: CollectorSink = CollectorSink::default();
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", b"func main {\n    dump 1 + 2;\n}\n".to_vec());
+    assert!(compile(&session, file, res_path.clone(), vec![Flag::SaveTemps, Flag::Check], &mut sink));
+    for ext in ["tokens", "ast.json", "ir"] {
+        assert!(saved.join(format!("app.{}", ext)).exists(), "Error: `app.{}` was not saved", ext);
+    }
+    let ast: String = fs::read_to_string(saved.join("app.ast.json")).expect("Error: Test failed to read saved parse tree");
+    assert!(Json::parse(&ast).is_ok(), "Error: Saved parse tree is not JSON");
+    let ir: String = fs::read_to_string(saved.join("app.ir")).expect("Error: Test failed to read saved instructions");
+    assert!(ir.contains("lang_main:") && ir.contains("Call(\"dump\")"), "Error: Unexpected saved instructions:\n{}", ir);
+
+    if has_toolchain() {
+        assert!(compile(&session, file, res_path.clone(), vec![Flag::SaveTemps], &mut sink));
+        assert!(saved.join("app.asm").exists() && saved.join("app.o").exists());
+        fs::remove_dir_all(&saved).expect("Error: Failed to delete test artifacts");
+        assert!(compile(&session, file, res_path.clone(), vec![], &mut sink));
+        let left: Vec<String> = fs::read_dir(&dir).expect("Error: Test failed to list its artifact directory")
+            .map(|e| e.expect("Error: Test failed to list its artifact directory").file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(left, ["app"]);
+    }
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test artifacts");
+}
+
+// `test` runs every source with a `.expected` file beside it, and reports what differs for each one that fails.
+#[test]
+fn test_lang_test_runner() {
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-runner-test-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its test directory");
+    let files: [(&str, &str); 7] = [
+        ("pass.lang", "func main {\n    dump 1, 2;\n    exit 3;\n}\n"),
+        ("pass.expected", "1\n2\n"),
+        ("pass.exitcode", "3\n"),
+        ("wrong.lang", "func main {\n    dump 1, 5;\n}\n"),
+        ("wrong.expected", "1\n2\n3\n"),
+        ("broken.lang", "func main {\n    dump x;\n}\n"),
+        ("broken.expected", ""),
+    ];
+    for (name, contents) in files {
+        fs::write(dir.join(name), contents).expect("Error: Test failed to write a test file");
+    }
+    fs::write(dir.join("untested.lang"), "func main {\n}\n").expect("Error: Test failed to write a test file");
+
+    let mut out: Vec<u8> = Vec::new();
+    let res = run_lang_tests(&dir, true, &mut out).expect("Error: Failed to run tests");
+    let out: String = String::from_utf8(out).expect("Error: Runner output is not UTF-8");
+    assert_eq!(res, (1, 2));
+    assert!(out.contains("PASS\x1b[0m pass\n"));
+    assert!(out.contains("FAIL\x1b[0m wrong\n    Line 2: expected `2`, got `5`\n    Line 3: expected `3`, got nothing\n"));
+    assert!(out.contains("FAIL\x1b[0m broken\n    [2:10] Error: "));
+    assert!(!out.contains("untested"));
+    assert!(out.ends_with("\n1 passed, 2 failed\n"));
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test directory");
+}
+
+// Test functions run one by one in place of `main`, failing on an `assert` or any other exit status.
+#[test]
+fn test_test_functions() {
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-functions-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its test directory");
+    let src: &str = "\
+func helper {
+    dump 1;
+}
+test func holds {
+    let x = 3;
+    assert x * x == 9;
+    assert 1 < 2 && 2 < 3;
+}
+test func breaks {
+    let y = 2;
+    assert y == 2;
+    assert y > 5;
+}
+module m {
+    test func exits {
+        exit 7;
+    }
+}
+";
+    fs::write(dir.join("math.lang"), src).expect("Error: Test failed to write a test file");
+
+    // NOTE: Natively only if the toolchain is installed, the interpreter gives the same statuses.
+    for interp in [true, false].into_iter().filter(|interp| *interp || has_toolchain()) {
+        let mut out: Vec<u8> = Vec::new();
+        let res = run_lang_tests(&dir, interp, &mut out).expect("Error: Failed to run tests");
+        let out: String = String::from_utf8(out).expect("Error: Runner output is not UTF-8");
+        assert_eq!(res, (1, 2), "Error: Unexpected results:\n{}", out);
+        assert!(out.contains("PASS\x1b[0m math::holds\n"));
+        assert!(out.contains("FAIL\x1b[0m math::breaks\n    Assertion failed\n"));
+        assert!(out.contains("FAIL\x1b[0m math::m.exits\n    Exit status 7, expected 0\n"));
+    }
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test directory");
+
+    // Outside of the runner, test functions are neither warned about nor compiled.
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", format!("{}func main {{\n    helper();\n    assert 1 == 2;\n}}\n", src).into_bytes());
+    let mut sink: CollectorSink = CollectorSink::default();
+    let asm: String = session.compile(file, &mut sink).expect("Error: Failed to compile test functions");
+    let warnings: Vec<String> = sink.diagnostics.iter().map(|d| d.msg.clone()).collect();
+    assert_eq!(warnings, vec!["Assertion always fails".to_string()]);
+    assert!(!asm.contains("holds") && !asm.contains("exits"));
+    let mut out: Vec<u8> = Vec::new();
+    assert_eq!(session.run(file, &mut out, &mut sink), Some(134));
+}
+
+// `-c` leaves only the object at the output path, which links on its own into the same program.
+#[test]
+fn test_object_only() {
+    if !has_toolchain() {
+        eprintln!("Info: Skipping `-c`, nasm or ld is not installed");
+        return;
+    }
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-object-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its test directory");
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("prog.lang", b"func main {\n    dump 5;\n    exit 4;\n}\n".to_vec());
+    let obj: PathBuf = dir.join("prog.o");
+    let mut sink: CollectorSink = CollectorSink::default();
+    assert!(compile(&session, file, obj.to_string_lossy().to_string(), vec![Flag::ObjectOnly], &mut sink));
+    assert!(fs::read(&obj).expect("Error: `-c` left no object").starts_with(b"\x7fELF"));
+    let mut left: Vec<String> = fs::read_dir(&dir).expect("Error: Failed to list test directory")
+        .map(|entry| entry.expect("Error: Failed to list test directory").file_name().to_string_lossy().to_string()).collect();
+    left.sort();
+    assert_eq!(left, vec!["prog.o"]);
+    let exe: PathBuf = dir.join("prog");
+    assert!(Command::new("ld").arg("-o").arg(&exe).arg(&obj).status().expect("Error: Failed to run ld").success());
+    let run = Command::new(&exe).output().expect("Error: Failed to run linked object");
+    assert_eq!(run.stdout, b"5\n");
+    assert_eq!(run.status.code(), Some(4));
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test directory");
+}
+
+// `--linker-script` lays out the executable and `-Wl,` arguments reach `ld`.
+#[test]
+fn test_linker_options() {
+    if !has_toolchain() {
+        eprintln!("Info: Skipping linker options, nasm or ld is not installed");
+        return;
+    }
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-link-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its test directory");
+    let script: PathBuf = dir.join("layout.ld");
+    fs::write(&script, "ENTRY(_start)\nSECTIONS {\n    . = 0x800000;\n    .text : { *(.text) }\n}\n").expect("Error: Test failed to write its linker script");
+    let map: PathBuf = dir.join("prog.map");
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("prog.lang", b"func main {\n    dump 6;\n}\n".to_vec());
+    let exe: PathBuf = dir.join("prog");
+    let flags: Vec<Flag> = vec![Flag::LinkerScript(script.to_string_lossy().to_string()), Flag::LinkerArgs(vec![format!("-Map={}", map.display())])];
+    let mut sink: CollectorSink = CollectorSink::default();
+    assert!(compile(&session, file, exe.to_string_lossy().to_string(), flags, &mut sink));
+    let elf: Vec<u8> = fs::read(&exe).expect("Error: Build left out the executable");
+    let entry: u64 = u64::from_le_bytes(elf[24..32].try_into().expect("Error: Executable is too short"));
+    assert!((0x800000..0x801000).contains(&entry), "Error: Entry point {:#x} is outside the scripted text section", entry);
+    assert!(fs::read_to_string(&map).expect("Error: `-Wl,-Map` left no map").contains("_start"));
+    assert_eq!(Command::new(&exe).output().expect("Error: Failed to run executable").stdout, b"6\n");
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test directory");
+}
+
+// `--strip` leaves the symbol table out of the executable, which still runs the same.
+#[test]
+fn test_strip() {
+    if !has_toolchain() {
+        eprintln!("Info: Skipping stripping, nasm or ld is not installed");
+        return;
+    }
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-strip-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its test directory");
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("prog.lang", b"func main {\n    dump 6;\n}\n".to_vec());
+    for strip in [false, true] {
+        let exe: PathBuf = dir.join(format!("prog-{}", strip));
+        let flags: Vec<Flag> = if strip { vec![Flag::Strip] } else { vec![] };
+        assert!(compile(&session, file, exe.to_string_lossy().to_string(), flags, &mut CollectorSink::default()));
+        let elf: Vec<u8> = fs::read(&exe).expect("Error: Build left out the executable");
+        assert_eq!(elf.windows(7).any(|name| name == b".symtab"), !strip);
+        assert_eq!(Command::new(&exe).output().expect("Error: Failed to run executable").stdout, b"6\n");
+    }
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test directory");
+}
+
+// The build note records the source, version and options but not the output path, and only the source's file name in
+// a reproducible build. It ends up in the executable as an ELF note owned by `Language`.
+#[test]
+fn test_provenance() {
+    let args: Vec<String> = ["-O2", "prog/main.lang", "-o", "out/main", "--strip"].map(str::to_string).to_vec();
+    let note: String = provenance("prog/main.lang", &args, true);
+    assert_eq!(note, format!("source=main.lang\nversion={}\noptions=-O2 --strip", env!("CARGO_PKG_VERSION")));
+    assert!(provenance("prog/main.lang", &args, false).starts_with(&format!("source={}", env::current_dir().expect("Error: No working directory").join("prog/main.lang").display())));
+    let args: Vec<String> = ["--sysroot", "/opt/cross", "-Wl,-L/opt/lib,-rpath,/usr/lib/x", "--emit", "ast=/tmp/a.ast", "main.lang"].map(str::to_string).to_vec();
+    assert!(provenance("main.lang", &args, true).ends_with("options=--sysroot cross -Wl,-Llib,-rpath,x --emit ast=a.ast"));
+    assert!(provenance("main.lang", &args, false).ends_with("options=--sysroot /opt/cross -Wl,-L/opt/lib,-rpath,/usr/lib/x --emit ast=/tmp/a.ast"));
+    if !has_toolchain() {
+        eprintln!("Info: Skipping the build note, nasm or ld is not installed");
+        return;
+    }
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-note-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its test directory");
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("prog.lang", b"func main {\n    dump 6;\n}\n".to_vec());
+    for recorded in [false, true] {
+        session.provenance = recorded.then(|| note.clone());
+        let exe: PathBuf = dir.join(format!("prog-{}", recorded));
+        assert!(compile(&session, file, exe.to_string_lossy().to_string(), vec![], &mut CollectorSink::default()));
+        let elf: Vec<u8> = fs::read(&exe).expect("Error: Build left out the executable");
+        let text: Vec<u8> = [b"Language\0\0\0\0".as_slice(), note.as_bytes(), b"\0"].concat();
+        assert_eq!(elf.windows(text.len()).any(|bytes| bytes == text), recorded);
+        assert_eq!(Command::new(&exe).output().expect("Error: Failed to run executable").stdout, b"6\n");
+    }
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test directory");
+}
+
+// `bench` reports the fastest, mean and spread of its runs in milliseconds.
+#[test]
+fn test_bench() {
+    assert_eq!(bench_summary(&[0.002, 0.004, 0.006]), "3 runs: min 2.000 ms, mean 4.000 ms, stddev 2.000 ms");
+    assert_eq!(bench_summary(&[0.0015]), "1 runs: min 1.500 ms, mean 1.500 ms, stddev 0.000 ms");
+    if !has_toolchain() {
+        eprintln!("Info: Skipping running `bench`, nasm or ld is not installed");
+        return;
+    }
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-bench-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its test directory");
+    let src: PathBuf = dir.join("loop.lang");
+    fs::write(&src, "func main {\n    dump 1;\n    exit 3;\n}\n").expect("Error: Test failed to write a test file");
+    let (times, code) = bench(&src, 4, &dir).expect("Error: Failed to benchmark");
+    assert_eq!(times.len(), 4);
+    assert_eq!(code, 3);
+    assert!(times.iter().all(|t| *t > 0.0));
+    assert!(bench(&dir.join("missing.lang"), 4, &dir).is_err());
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test directory");
+}
+
+// Triples name their target, and a sysroot's tools named for the target are used over plain ones and those on `PATH`.
+#[test]
+fn test_target() {
+    use language::target::Target;
+    assert_eq!(Target::parse("x86_64-unknown-linux-gnu"), Some(Target::X86_64Linux));
+    assert_eq!(Target::parse("x86_64-linux-gnu"), Some(Target::X86_64Linux));
+    assert_eq!(Target::parse("aarch64-unknown-linux-gnu"), None);
+    for target in Target::ALL {
+        assert_eq!(Target::parse(target.triple()), Some(target));
+    }
+
+    let target: Target = Target::X86_64Linux;
+    let root: PathBuf = env::temp_dir().join(format!("language-test-sysroot-{}", process::id()));
+    fs::create_dir_all(root.join("bin")).expect("Error: Test failed to create its sysroot");
+    fs::create_dir_all(root.join("usr/bin")).expect("Error: Test failed to create its sysroot");
+    assert_eq!(target.tool(None, "ld"), PathBuf::from("ld"));
+    assert_eq!(target.tool(Some(&root), "ld"), PathBuf::from("ld"));
+    fs::write(root.join("usr/bin/ld"), "").expect("Error: Test failed to write a tool");
+    assert_eq!(target.tool(Some(&root), "ld"), root.join("usr/bin/ld"));
+    fs::write(root.join("usr/bin/x86_64-linux-gnu-ld"), "").expect("Error: Test failed to write a tool");
+    assert_eq!(target.tool(Some(&root), "ld"), root.join("usr/bin/x86_64-linux-gnu-ld"));
+    fs::write(root.join("bin/x86_64-linux-gnu-ld"), "").expect("Error: Test failed to write a tool");
+    assert_eq!(target.tool(Some(&root), "ld"), root.join("bin/x86_64-linux-gnu-ld"));
+    assert_eq!(target.tool(Some(&root), "nasm"), PathBuf::from("nasm"));
+    fs::remove_dir_all(&root).expect("Error: Failed to delete test artifacts");
+}
+
+// Reproducible builds of one source from two directories are the same byte for byte, and neither directory shows up in them.
+#[test]
+fn test_reproducible() {
+    let root: PathBuf = env::temp_dir().join(format!("language-test-reproducible-{}", process::id()));
+    let mut builds: Vec<Vec<Vec<u8>>> = Vec::new();
+    for sub in ["one", "two/deeper"] {
+        let dir: PathBuf = root.join(sub);
+        fs::create_dir_all(&dir).expect("Error: Test failed to create its artifact directory");
+        let src_path: PathBuf = dir.join("prog.lang");
+        fs::write(&src_path, "func main {\n    dump 1;\n    exit 2;\n}\n").expect("Error: Test failed to write its source");
+        // The sysroot is empty, so the tools are still found on PATH.
+        let sysroot: PathBuf = dir.join("sysroot");
+        fs::create_dir_all(&sysroot).expect("Error: Test failed to create its sysroot");
+        let mut session: Session = Session::new();
+        session.reproducible = true;
+        session.sysroot = Some(sysroot.clone());
+        let file: FileId = session.sources.load(&DiskProvider, &src_path.to_string_lossy()).expect("Error: Test failed to read its source");
+        let out: PathBuf = dir.join("prog");
+        let args: Vec<String> = vec!["--sysroot".to_string(), sysroot.to_string_lossy().to_string(), src_path.to_string_lossy().to_string(), "-o".to_string(), out.to_string_lossy().to_string()];
+        session.provenance = Some(provenance(&src_path.to_string_lossy(), &args, true));
+        let mut sink: CollectorSink = CollectorSink::default();
+        // Without a toolchain the build stops once it has checked the program.
+        let (flags, exts): (Vec<Flag>, Vec<&str>) = match has_toolchain() {
+            true => (vec![Flag::SaveTemps], vec!["ast.json", "tokens", "ir", "asm", "o"]),
+            false => (vec![Flag::SaveTemps, Flag::Check], vec!["ast.json", "tokens", "ir"]),
+        };
+        assert!(compile(&session, file, out.to_string_lossy().to_string(), flags, &mut sink));
+        let mut files: Vec<Vec<u8>> = exts.iter().map(|ext| fs::read(dir.join("prog.d").join(format!("prog.{}", ext))).expect("Error: Build left out an artifact")).collect();
+        if has_toolchain() {
+            files.push(fs::read(&out).expect("Error: Build left out the executable"));
+        }
+        files.push(session.compile(file, &mut sink).expect("Error: Failed to compile source").into_bytes());
+        let dir_name: String = root.to_string_lossy().to_string();
+        for contents in &files {
+            assert!(!contents.windows(dir_name.len()).any(|window| window == dir_name.as_bytes()), "Error: Build mentions the directory it was made in");
+        }
+        builds.push(files);
+    }
+    assert!(builds[0] == builds[1], "Error: Builds from different directories differ");
+    fs::remove_dir_all(&root).expect("Error: Failed to delete test artifacts");
+}
+
+// `--emit tokens=PATH` and `--emit ast=PATH` write what `-t` and `-pt` would print to files of their own.
+#[test]
+fn test_emit_to_file() {
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-emit-to-file-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its artifact directory");
+    let toks_path: String = dir.join("app.tokens").to_string_lossy().to_string();
+    let ast_path: String = dir.join("app.tree").to_string_lossy().to_string();
+
+    let mut sink: CollectorSink = CollectorSink::default();
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", b"func main {\n    dump 1 + 2;\n}\n".to_vec());
+    let flags: Vec<Flag> = vec![Flag::EmitTokensTo(toks_path.clone()), Flag::EmitParseTreeTo(ast_path.clone()), Flag::ParseTreeDepth(3), Flag::Check];
+    assert!(compile(&session, file, dir.join("app").to_string_lossy().to_string(), flags, &mut sink));
+    let toks: String = fs::read_to_string(&toks_path).expect("Error: Test failed to read written tokens");
+    assert!(toks.starts_with("[1:1]: KeywordFunctionDecl `func`\n[1:6]: Identifier `main`\n"), "Error: Unexpected tokens:\n{}", toks);
+    let ast: String = fs::read_to_string(&ast_path).expect("Error: Test failed to read written parse tree");
+    assert_eq!(ast, "Program `test`\n└─ FuncDecl `main` [1:6]\n   └─ Block\n      └─ DebugDump `dump` [2:5]\n         └─ ... 3 more\n");
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test artifacts");
+}
+
+// An edit to one function re-parses only that function and gives the same tree as a full parse.
+#[test]
+fn test_incremental_reparse() {
+    use language::incremental::IncrementalParser;
+    use language::lexer::Lexer;
+    use language::parser::ParseTree;
+
+    let before: &str = "func one {\n    dump 1;\n}\nfunc two {\n    dump 2;\n}\nfunc main {\n    one();\n}\n";
+    let after: &str = "func one {\n    dump 1;\n}\nfunc two {\n    let x = 4;\n    dump x;\n}\nfunc main {\n    one();\n}\n";
+
+    let mut parser: IncrementalParser = IncrementalParser::new("test".to_string());
+    parser.update(before).expect("Error: Failed to parse original source");
+    let tree: ParseTree = parser.update(after).expect("Error: Failed to parse edited source");
+    assert_eq!(parser.stats.reused_funcs, 2);
+    assert_eq!(parser.stats.parsed_funcs, 1);
+
+    let mut lexer: Lexer = Lexer::new(after.as_bytes().to_vec());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Failed to lex edited source");
+    let mut full: ParseTree = ParseTree::new("test".to_string());
+    full.construct(&mut lexer).expect("Error: Failed to parse edited source");
+    assert_eq!(tree.root.to_json().to_string(), full.root.to_json().to_string());
+
+    // Statements before the first function are reported where a full parse reports them.
+    for leading in ["let x = 1;\n", "dump 5;\n"] {
+        let src: String = format!("{}{}", leading, after);
+        let err: Diagnostic = parser.update(&src).expect_err("Error: Statements outside of a function were parsed");
+        let mut lexer: Lexer = Lexer::new(src.into_bytes());
+        lexer.tokenize();
+        lexer.lex().expect("Error: Failed to lex edited source");
+        let full: Diagnostic = ParseTree::new("test".to_string()).construct(&mut lexer).expect_err("Error: Statements outside of a function were parsed");
+        assert_eq!((err.pos.row, err.pos.col, err.msg), (full.pos.row, full.pos.col, full.msg));
+    }
+}
+
+// Modules keep their functions and comments indented inside them when formatted.
+#[test]
+fn test_format_modules() {
+    use language::format::format_program;
+    use language::lexer::Lexer;
+    use language::parser::ParseTree;
+
+    let src: String = fs::read_to_string("./language_tests/modules.lang").expect("Error: Test failed to read source file");
+    let mut lexer: Lexer = Lexer::new(src.clone().into_bytes());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Failed to lex module source");
+    let mut tree: ParseTree = ParseTree::new("test".to_string());
+    tree.construct(&mut lexer).expect("Error: Failed to parse module source");
+    assert_eq!(format_program(&lexer, &tree), src);
+}
+
+// Every statement takes a full expression, so comparisons and logical operators need no parentheses anywhere.
+#[test]
+fn test_format_boolean() {
+    use language::format::format_program;
+    use language::lexer::Lexer;
+    use language::parser::ParseTree;
+
+    let src: String = fs::read_to_string("./language_tests/boolean.lang").expect("Error: Test failed to read source file");
+    let mut lexer: Lexer = Lexer::new(src.clone().into_bytes());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Failed to lex boolean source");
+    let mut tree: ParseTree = ParseTree::new("test".to_string());
+    tree.construct(&mut lexer).expect("Error: Failed to parse boolean source");
+    assert_eq!(format_program(&lexer, &tree), src);
+}
+
+// The formatter keeps `+=` and `else if` as written, while the tree the compiler checks has only core nodes.
+#[test]
+fn test_desugar_surface() {
+    use language::format::format_program;
+    use language::parser::NodeType;
+    use language::parser::ParseNode;
+    use language::parser::ParseTree;
+
+    fn surface(node: &ParseNode) -> bool {
+        node.kind == NodeType::CompoundAssign
+            || node.kind == NodeType::Conditional && node.children.get(2).is_some_and(|els| els.kind != NodeType::Block)
+            || node.children.iter().any(surface)
+    }
+
+    let src: String = fs::read_to_string("./language_tests/desugar.lang").expect("Error: Test failed to read source file");
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.clone().into_bytes());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let written: ParseTree = session.parse_surface(file, &mut lexer).expect("Error: Failed to parse source");
+    assert!(surface(&written.root));
+    assert_eq!(format_program(&lexer, &written), src);
+
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let core: ParseTree = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    assert!(!surface(&core.root));
+    assert!(session.check(file, &core).is_empty());
+}
+
+// The kinds of the tokens lexed from a source, without the closing `Eof`.
+fn lex_kinds(src: &str) -> Vec<language::lexer::TokenType> {
+    use language::lexer::Lexer;
+    use language::lexer::TokenType;
+
+    let mut lexer: Lexer = Lexer::new(src.as_bytes().to_vec());
+    lexer.tokenize();
+    lexer.lex().unwrap_or_else(|d| panic!("{} Error: Failed to lex `{}`: {}", d.pos, src, d.msg));
+    lexer.toks.iter().map(|tok| tok.kind.clone()).filter(|kind| *kind != TokenType::Eof).collect()
+}
+
+// Parses an expression as the condition of an `if` and writes its tree as nested prefix operations.
+fn parse_shape(expr: &str) -> String {
+    use language::lexer::Lexer;
+    use language::parser::NodeType;
+    use language::parser::ParseNode;
+    use language::parser::ParseTree;
+
+    fn shape(node: &ParseNode) -> String {
+        match node.kind {
+            NodeType::BinOp => format!("({} {} {})", node.tok.val_str(), shape(&node.children[0]), shape(&node.children[1])),
+            NodeType::UnOp => format!("({} {})", node.tok.val_str(), shape(&node.children[0])),
+            NodeType::Intrinsic => format!("({} {})", node.tok.val_str(), node.children.iter().map(shape).collect::<Vec<String>>().join(" ")),
+            _ => node.tok.val_str(),
+        }
+    }
+
+    let src: String = format!("func main {{\n    if {} {{\n    }}\n}}\n", expr);
+    let mut lexer: Lexer = Lexer::new(src.into_bytes());
+    lexer.tokenize();
+    lexer.lex().unwrap_or_else(|d| panic!("{} Error: Failed to lex `{}`: {}", d.pos, expr, d.msg));
+    let mut ast: ParseTree = ParseTree::new("test".to_string());
+    ast.construct(&mut lexer).unwrap_or_else(|d| panic!("{} Error: Failed to parse `{}`: {}", d.pos, expr, d.msg));
+    let cond: &ParseNode = &ast.root.children[0].children[0].children[0].children[0];
+    shape(cond)
+}
+
+// Operators split from their neighbours without whitespace, and a space splits a two character operator.
+#[test]
+fn test_lex_operators() {
+    use language::lexer::TokenType::*;
+
+    assert_eq!(lex_kinds("a>=b"), vec![Identifier, OpGreaterEqual, Identifier]);
+    assert_eq!(lex_kinds("a> =b"), vec![Identifier, OpGreaterThan, OpAssign, Identifier]);
+    assert_eq!(lex_kinds("a<=b"), vec![Identifier, OpLessEqual, Identifier]);
+    assert_eq!(lex_kinds("a==b~=c"), vec![Identifier, OpEqual, Identifier, OpNotEqual, Identifier]);
+    assert_eq!(lex_kinds("a=-1"), vec![Identifier, OpAssign, OpMinus, LiteralInt]);
+    assert_eq!(lex_kinds("1--2"), vec![LiteralInt, OpMinus, OpMinus, LiteralInt]);
+    assert_eq!(lex_kinds("(a)*(b)"), vec![OpenParen, Identifier, CloseParen, OpMul, OpenParen, Identifier, CloseParen]);
+    assert_eq!(lex_kinds("a&&b||c"), vec![Identifier, OpLogicalAnd, Identifier, OpLogicalOr, Identifier]);
+    assert_eq!(lex_kinds("a<-b"), vec![Identifier, OpLessThan, OpMinus, Identifier]);
+    assert_eq!(lex_kinds("a-=-1"), vec![Identifier, OpMinusAssign, OpMinus, LiteralInt]);
+    assert_eq!(lex_kinds("a+ =1"), vec![Identifier, OpPlus, OpAssign, LiteralInt]);
+    assert_eq!(lex_kinds("a*=b/=c"), vec![Identifier, OpMulAssign, Identifier, OpDivAssign, Identifier]);
+}
+
+// Any two operators or punctuation glued between identifiers split as they would with spaces between them, unless
+// together they start a longer spelling, which then wins.
+#[test]
+fn test_lex_adjacency() {
+    use language::lexer::OPERATORS;
+    use language::lexer::PUNCTUATION;
+    use language::lexer::TokenType;
+    use language::lexer::TokenType::*;
+
+    let symbols: Vec<&(&str, TokenType)> = OPERATORS.iter().chain(PUNCTUATION.iter()).collect();
+    for (a, a_kind) in &symbols {
+        assert_eq!(lex_kinds(&format!("x{}y", a)), vec![Identifier, a_kind.clone(), Identifier], "`x{}y`", a);
+        assert_eq!(lex_kinds(&format!("1{}2", a)), vec![LiteralInt, a_kind.clone(), LiteralInt], "`1{}2`", a);
+        for (b, b_kind) in &symbols {
+            let both: String = format!("{}{}", a, b);
+            let joins: bool = symbols.iter().any(|(text, _)| text.len() > a.len() && both.starts_with(text));
+            if joins || both.contains("//") {
+                continue;
+            }
+            assert_eq!(lex_kinds(&format!("x{}y", both)), vec![Identifier, a_kind.clone(), b_kind.clone(), Identifier], "`x{}y`", both);
+        }
+    }
+
+    assert_eq!(lex_kinds("x<=y||z"), vec![Identifier, OpLessEqual, Identifier, OpLogicalOr, Identifier]);
+    assert_eq!(lex_kinds("x===y"), vec![Identifier, OpEqual, OpAssign, Identifier]);
+    assert_eq!(lex_kinds("x>==y"), vec![Identifier, OpGreaterEqual, OpAssign, Identifier]);
+    assert_eq!(lex_kinds("x-==y"), vec![Identifier, OpMinusAssign, OpAssign, Identifier]);
+    assert_eq!(lex_kinds("x+==y"), vec![Identifier, OpPlusAssign, OpAssign, Identifier]);
+    assert_eq!(lex_kinds("x&&&y"), vec![Identifier, OpLogicalAnd, OpAddressOf, Identifier]);
+    assert_eq!(lex_kinds("x&&&&y"), vec![Identifier, OpLogicalAnd, OpLogicalAnd, Identifier]);
+    assert_eq!(lex_kinds("x~==y"), vec![Identifier, OpNotEqual, OpAssign, Identifier]);
+}
+
+// Every token is placed at its first byte, including operators glued to their neighbours and tokens starting a line.
+#[test]
+fn test_lex_spans() {
+    use language::lexer::Lexer;
+    use language::lexer::Token;
+    use std::collections::HashSet;
+
+    let src: &str = "module m {\npub func f {\n}\nexport func g {\n}\n}\nfunc main {\nlet a=1+2-3*4/5;\n+=1;-=a;*=2;/=3;\n==a~=b>=c<=d\n||x&&y>z<w\n&f.g(x),y\nif 1 {} else {}\ndump 0; exit 0 // done\n";
+    let mut lexer: Lexer = Lexer::new(src.as_bytes().to_vec());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Failed to lex every kind of token");
+    let spans: Vec<(String, usize, usize)> = lexer.toks.iter().chain(lexer.comments.iter()).map(|tok| (tok.val_str(), tok.pos.row, tok.pos.col)).collect();
+    let expected: Vec<(&str, usize, usize)> = vec![
+        ("module", 0, 0), ("m", 0, 7), ("{", 0, 9),
+        ("pub", 1, 0), ("func", 1, 4), ("f", 1, 9), ("{", 1, 11),
+        ("}", 2, 0),
+        ("export", 3, 0), ("func", 3, 7), ("g", 3, 12), ("{", 3, 14),
+        ("}", 4, 0),
+        ("}", 5, 0),
+        ("func", 6, 0), ("main", 6, 5), ("{", 6, 10),
+        ("let", 7, 0), ("a", 7, 4), ("=", 7, 5), ("1", 7, 6), ("+", 7, 7), ("2", 7, 8), ("-", 7, 9), ("3", 7, 10), ("*", 7, 11), ("4", 7, 12), ("/", 7, 13), ("5", 7, 14), (";", 7, 15),
+        ("+=", 8, 0), ("1", 8, 2), (";", 8, 3), ("-=", 8, 4), ("a", 8, 6), (";", 8, 7), ("*=", 8, 8), ("2", 8, 10), (";", 8, 11), ("/=", 8, 12), ("3", 8, 14), (";", 8, 15),
+        ("==", 9, 0), ("a", 9, 2), ("~=", 9, 3), ("b", 9, 5), (">=", 9, 6), ("c", 9, 8), ("<=", 9, 9), ("d", 9, 11),
+        ("||", 10, 0), ("x", 10, 2), ("&&", 10, 3), ("y", 10, 5), (">", 10, 6), ("z", 10, 7), ("<", 10, 8), ("w", 10, 9),
+        ("&", 11, 0), ("f", 11, 1), (".", 11, 2), ("g", 11, 3), ("(", 11, 4), ("x", 11, 5), (")", 11, 6), (",", 11, 7), ("y", 11, 8),
+        ("if", 12, 0), ("1", 12, 3), ("{", 12, 5), ("}", 12, 6), ("else", 12, 8), ("{", 12, 13), ("}", 12, 14),
+        ("dump", 13, 0), ("0", 13, 5), (";", 13, 6), ("exit", 13, 8), ("0", 13, 13),
+        ("", 14, 0),
+        ("// done", 13, 15),
+    ];
+    assert_eq!(spans, expected.iter().map(|(text, row, col)| (text.to_string(), *row, *col)).collect::<Vec<_>>());
+
+    // Every kind but `None` shows up above.
+    let kinds: HashSet<String> = lexer.toks.iter().chain(lexer.comments.iter()).map(|tok| format!("{:?}", tok.kind)).collect();
+    assert_eq!(kinds.len(), 38);
+
+    // Offsets agree with rows and columns in every test program.
+    let check = |src: &[u8], tok: &Token| {
+        assert!(src[tok.pos.offset..].starts_with(&tok.val), "{} Error: `{}` is not at offset {}", tok.pos, tok.val_str(), tok.pos.offset);
+        let line: usize = src[..tok.pos.offset].iter().rposition(|c| *c == b'\n').map_or(0, |ix| ix + 1);
+        assert_eq!((tok.pos.row, tok.pos.col), (src[..line].iter().filter(|c| **c == b'\n').count(), tok.pos.offset - line));
+    };
+    for entry in std::fs::read_dir("language_tests").expect("Error: Failed to read language_tests") {
+        let path: std::path::PathBuf = entry.expect("Error: Failed to read test entry").path();
+        if path.extension().is_none_or(|ext| ext != "lang") {
+            continue;
+        }
+        let src: Vec<u8> = std::fs::read(&path).expect("Error: Failed to read test program");
+        let mut lexer: Lexer = Lexer::new(src.clone());
+        lexer.tokenize();
+        lexer.toks.iter().chain(lexer.comments.iter()).for_each(|tok| check(&src, tok));
+    }
+}
+
+// Digits after the first letter belong to the identifier, keywords are only matched whole.
+#[test]
+fn test_lex_identifiers() {
+    use language::lexer::TokenType::*;
+
+    assert_eq!(lex_kinds("x1 = 23;"), vec![Identifier, OpAssign, LiteralInt, End]);
+    assert_eq!(lex_kinds("let let2 = a1b2;"), vec![KeywordVariableDecl, Identifier, OpAssign, Identifier, End]);
+    assert_eq!(lex_kinds("iff exit dumps"), vec![Identifier, KeywordExit, Identifier]);
+    assert_eq!(lex_kinds("f1();"), vec![Identifier, OpenParen, CloseParen, End]);
+    assert_eq!(lex_kinds("x // 1 + 2\n"), vec![Identifier]);
+}
+
+// Bytes the language has no use for are rejected whole, even when they are not valid UTF-8.
+#[test]
+fn test_lex_invalid_bytes() {
+    use language::lexer::Lexer;
+
+    for (src, msg) in [(&b"dump \xc3\xa9;"[..], "Invalid token `\u{e9}`"), (&b"x\xff"[..], "Invalid token `\u{fffd}`")] {
+        let mut lexer: Lexer = Lexer::new(src.to_vec());
+        lexer.tokenize();
+        let err: Diagnostic = lexer.lex().expect_err("Error: Invalid bytes were lexed");
+        assert_eq!(err.msg, msg);
+    }
+}
+
+// Literals are parsed once while lexing, so leading zeros are dropped and out of range ones never reach the parser.
+#[test]
+fn test_lex_literal_values() {
+    use language::lexer::Lexer;
+
+    let mut lexer: Lexer = Lexer::new(b"dump 007, 9223372036854775807;".to_vec());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Literals were not lexed");
+    let values: Vec<Option<i64>> = lexer.toks.iter().map(|tok| tok.value).collect();
+    assert_eq!(values, vec![None, Some(7), None, Some(i64::MAX), None, None]);
+
+    let mut lexer: Lexer = Lexer::new(b"dump 9223372036854775808;".to_vec());
+    lexer.tokenize();
+    let err: Diagnostic = lexer.lex().expect_err("Error: Out of range literal was lexed");
+    assert_eq!(err.msg, "Integer literal `9223372036854775808` is out of range");
+    assert_eq!((err.pos.row, err.pos.col), (0, 5));
+}
+
+// Binary operators bind by the precedence of the grammar and associate to the left.
+#[test]
+fn test_parse_precedence() {
+    assert_eq!(parse_shape("1 + 2 * 3"), "(+ 1 (* 2 3))");
+    assert_eq!(parse_shape("1 * 2 + 3"), "(+ (* 1 2) 3)");
+    assert_eq!(parse_shape("(1 + 2) * 3"), "(* (+ 1 2) 3)");
+    assert_eq!(parse_shape("1 - 2 - 3"), "(- (- 1 2) 3)");
+    assert_eq!(parse_shape("8 / 4 / 2"), "(/ (/ 8 4) 2)");
+    assert_eq!(parse_shape("-a * b"), "(* (- a) b)");
+    assert_eq!(parse_shape("--a"), "(- (- a))");
+    assert_eq!(parse_shape("a + 1 > b * 2"), "(> (+ a 1) (* b 2))");
+    assert_eq!(parse_shape("a < b == c >= d"), "(== (< a b) (>= c d))");
+    assert_eq!(parse_shape("a == b ~= c"), "(~= (== a b) c)");
+    assert_eq!(parse_shape("a || b && c"), "(|| a (&& b c))");
+    assert_eq!(parse_shape("a && b || c && d"), "(|| (&& a b) (&& c d))");
+    assert_eq!(parse_shape("a || b || c"), "(|| (|| a b) c)");
+    assert_eq!(parse_shape("(a || b) && c"), "(&& (|| a b) c)");
+}
+
+// The tree is drawn one node per line with its span, and a depth limit counts what it leaves out.
+#[test]
+fn test_parse_tree_string() {
+    use language::lexer::Lexer;
+    use language::parser::ParseTree;
+
+    let mut lexer: Lexer = Lexer::new(b"func main {\n    let x = 1 + -abs(2);\n    let y;\n}\n".to_vec());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Failed to lex tree source");
+    let mut tree: ParseTree = ParseTree::new("test".to_string());
+    tree.construct(&mut lexer).expect("Error: Failed to parse tree source");
+    let func = &tree.root.children[0];
+    assert_eq!(func.tree_string(None), "\
+FuncDecl `main` [1:6]
+└─ Block
+   ├─ VarDecl `x` [2:9]
+   │  └─ BinOp `+` [2:15]
+   │     ├─ Literal `1` [2:13]
+   │     └─ UnOp `-` [2:17]
+   │        └─ Intrinsic `abs` [2:18]
+   │           └─ Literal `2` [2:22]
+   └─ VarDecl `y` [3:9]
+      └─ Literal `0`
+");
+    assert_eq!(func.tree_string(Some(3)), "\
+FuncDecl `main` [1:6]
+└─ Block
+   ├─ VarDecl `x` [2:9]
+   │  └─ BinOp `+` [2:15]
+   │     └─ ... 4 more
+   └─ VarDecl `y` [3:9]
+      └─ Literal `0`
+");
+}
+
+// Nesting past the limit is a diagnostic rather than a stack overflow, and the limit can be changed.
+#[test]
+fn test_parse_depth_limit() {
+    use language::lexer::Lexer;
+    use language::parser::DEFAULT_MAX_DEPTH;
+
+    let nested = |n: usize| format!("func main {{\n    dump {}1{};\n}}\n", "(".repeat(n), ")".repeat(n));
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("deep", nested(100_000).into_bytes());
+    let mut lexer: Lexer = session.lex(file).expect("Error: Failed to lex nested parentheses");
+    let err: Diagnostic = session.parse(file, &mut lexer).expect_err("Error: 100k nested parentheses were parsed");
+    assert_eq!(err.msg, format!("Expression too deeply nested (more than {} levels)", DEFAULT_MAX_DEPTH));
+    assert_eq!((err.pos.row, err.pos.col), (1, 8 + DEFAULT_MAX_DEPTH));
+
+    // The body of `main` is one level already.
+    let file: FileId = session.add("limit", nested(DEFAULT_MAX_DEPTH - 1).into_bytes());
+    let mut lexer: Lexer = session.lex(file).expect("Error: Failed to lex nested parentheses");
+    assert!(session.parse(file, &mut lexer).is_ok());
+
+    let blocks: String = format!("func main {{\n{}{}}}\n", "if 1 {\n".repeat(DEFAULT_MAX_DEPTH), "}\n".repeat(DEFAULT_MAX_DEPTH));
+    let file: FileId = session.add("blocks", blocks.into_bytes());
+    let mut lexer: Lexer = session.lex(file).expect("Error: Failed to lex nested blocks");
+    let err: Diagnostic = session.parse(file, &mut lexer).expect_err("Error: Blocks nested past the limit were parsed");
+    assert_eq!(err.msg, format!("Block too deeply nested (more than {} levels)", DEFAULT_MAX_DEPTH));
+
+    session.limits.max_depth = 4;
+    let file: FileId = session.add("lowered", nested(4).into_bytes());
+    let mut lexer: Lexer = session.lex(file).expect("Error: Failed to lex nested parentheses");
+    let err: Diagnostic = session.parse(file, &mut lexer).expect_err("Error: Parentheses nested past a lowered limit were parsed");
+    assert_eq!(err.msg, "Expression too deeply nested (more than 4 levels)");
+}
+
+// Each operator of a flat chain nests the ones before it, so a long chain is a diagnostic rather than overflowing the
+// stack in a later pass. One within the limit goes through every pass on the stack of a test thread.
+#[test]
+fn test_flat_chain_limit() {
+    use language::opt::OptLevel;
+    use language::parser::DEFAULT_MAX_DEPTH;
+
+    let chain = |n: usize| format!("func main {{\n    let a = 1;\n    dump {};\n}}\n", vec!["a"; n].join(" + "));
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("long", chain(20_000).into_bytes());
+    let mut sink: CollectorSink = CollectorSink::default();
+    assert!(session.compile(file, &mut sink).is_none(), "Error: A chain of 20k operators was compiled");
+    assert!(session.run(file, &mut Vec::new(), &mut sink).is_none(), "Error: A chain of 20k operators was run");
+    let err: &Diagnostic = &sink.diagnostics[0];
+    assert_eq!(err.msg, format!("Expression too deeply nested (more than {} levels)", DEFAULT_MAX_DEPTH));
+    // NOTE: The body of `main` is one level already, so the operator after the 128th `a` is one too many.
+    assert_eq!((err.pos.row, err.pos.col), (2, 4 * DEFAULT_MAX_DEPTH + 7));
+
+    let file: FileId = session.add("limit", chain(DEFAULT_MAX_DEPTH).into_bytes());
+    for opt in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::Os] {
+        session.opt_level = opt;
+        let mut sink: CollectorSink = CollectorSink::default();
+        assert!(session.compile(file, &mut sink).is_some(), "Error: A chain within the limit failed at {:?}: {:?}", opt, sink.diagnostics);
+    }
+    let mut out: Vec<u8> = Vec::new();
+    assert_eq!(session.run(file, &mut out, &mut CollectorSink::default()), Some(0));
+    assert_eq!(out, format!("{}\n", DEFAULT_MAX_DEPTH).into_bytes());
+}
+
+// Programs past a size limit are rejected where the limit is first exceeded, and every limit can be changed.
+#[test]
+fn test_limits() {
+    use language::lexer::Lexer;
+    use language::limits::DEFAULT_MAX_IDENT_LEN;
+
+    let mut session: Session = Session::new();
+    let long = |n: usize| format!("func main {{\n    let {} = 1;\n}}\n", "x".repeat(n));
+    let file: FileId = session.add("fits", long(DEFAULT_MAX_IDENT_LEN).into_bytes());
+    assert!(session.lex(file).is_ok());
+    let file: FileId = session.add("long", long(DEFAULT_MAX_IDENT_LEN + 1).into_bytes());
+    let err: Diagnostic = session.lex(file).err().expect("Error: Identifier past the limit was lexed");
+    assert_eq!(err.msg, format!("Identifier too long (more than {} bytes)", DEFAULT_MAX_IDENT_LEN));
+    assert_eq!((err.pos.row, err.pos.col), (1, 8));
+
+    session.limits.max_functions = 2;
+    session.limits.max_locals = 2;
+    let mut parse = |src: &str| -> Result<(), Diagnostic> {
+        let file: FileId = session.add("test", src.as_bytes().to_vec());
+        let mut lexer: Lexer = session.lex(file)?;
+        session.parse(file, &mut lexer).map(|_| ())
+    };
+    assert!(parse("func f {\n}\nfunc main {\n    let a = 1;\n    let b = 2;\n}\n").is_ok());
+    let err: Diagnostic = parse("func f {\n}\nmodule m {\n    func g {\n    }\n}\nfunc main {\n}\n").expect_err("Error: Too many functions were parsed");
+    assert_eq!(err.msg, "Too many functions (more than 2)");
+    assert_eq!(err.pos.row, 6);
+    let err: Diagnostic = parse("func main {\n    let a = 1;\n    if a {\n        let b = 2;\n        let c = 3;\n    }\n}\n").expect_err("Error: Too many locals were parsed");
+    assert_eq!(err.msg, "Too many locals in `main` (more than 2)");
+    assert_eq!((err.pos.row, err.pos.col), (4, 12));
+}
+
+// A large generated program compiles at every level without name errors or clashing labels, and if it can be run,
+// optimising does not change what it prints.
+#[test]
+fn test_stress_program() {
+    use language::opt::OptLevel;
+    use language::stress;
+    use language::stress::StressConfig;
+
+    let src: String = stress::generate(&StressConfig { stmts: 150, depth: 10, seed: 7 });
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("stress", src.into_bytes());
+    let dir: PathBuf = env::temp_dir().join(format!("language-test-stress-{}", process::id()));
+    fs::create_dir_all(&dir).expect("Error: Test failed to create its artifact directory");
+
+    let mut outputs: Vec<Vec<u8>> = Vec::new();
+    for opt in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+        session.opt_level = opt;
+        let mut sink: CollectorSink = CollectorSink::default();
+        let asm: String = session.compile(file, &mut sink).expect("Error: Failed to compile generated program");
+        assert!(!sink.has_errors());
+        let mut labels: Vec<&str> = asm.lines().filter(|l| l.ends_with(':') && !l.starts_with('.')).collect();
+        let count: usize = labels.len();
+        labels.sort();
+        labels.dedup();
+        assert_eq!(labels.len(), count, "Error: Labels are defined twice at {:?}", opt);
+
+        if has_toolchain() {
+            let res_path: String = dir.join(format!("stress{:?}", opt)).to_string_lossy().to_string();
+            assert!(compile(&session, file, res_path.clone(), vec![], &mut sink));
+            let run = Command::new(&res_path).output().expect("Error: Failed to run executable");
+            assert_eq!(run.status.code(), Some(0));
+            outputs.push(run.stdout);
+        }
+    }
+    outputs.dedup();
+    assert!(outputs.len() <= 1, "Error: Optimised program prints something else");
+    fs::remove_dir_all(&dir).expect("Error: Failed to delete test artifacts");
+}
+
+// Every name error in a file is reported by the check, not just the first.
+#[test]
+fn test_check_reports_all() {
+    let src: &str = "func main {\n    x = 1;\n    dump y;\n    f();\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let errors: Vec<Diagnostic> = session.check(file, &ast);
+    let msgs: Vec<String> = errors.iter().map(|d| d.msg.clone()).collect();
+    assert_eq!(msgs, vec![
+        "Cannot assign to undeclared variable `x`".to_string(),
+        "No such variable `y` in local scope".to_string(),
+        "No such function `f`".to_string(),
+    ]);
+}
+
+// Calls are checked against every function in the file, wherever they are and whichever comes first.
+#[test]
+fn test_check_calls() {
+    let src: &str = "func main {\n    dump g() + later();\n    m.f();\n}\nfunc later {\n    return later();\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let errors: Vec<(usize, usize, String)> = session.check(file, &ast).into_iter().map(|d| (d.pos.row, d.pos.col, d.msg)).collect();
+    assert_eq!(errors, vec![
+        (1, 9, "No such function `g`".to_string()),
+        (2, 4, "No such function `m.f`".to_string()),
+    ]);
+}
+
+// A variable declared inside a block cannot be used once the block has ended.
+#[test]
+fn test_check_block_scope() {
+    let src: &str = "func main {\n    let a = 1;\n    if a > 0 {\n        let x = 1;\n    }\n    dump x;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let errors: Vec<Diagnostic> = session.check(file, &ast);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].msg, "Variable `x` is declared inside a block and is not in scope here");
+    assert_eq!((errors[0].pos.row, errors[0].pos.col), (5, 9));
+}
+
+// In script mode statements outside of functions become `main`, and a leading `#!` line is skipped as a comment.
+#[test]
+fn test_script_mode() {
+    let src: &str = "#!/usr/bin/env -S language --script -r\nlet a = 2;\nfunc twice {\n    dump 2;\n}\ntwice();\ndump a;\n";
+    let mut session: Session = Session::new();
+    session.script = true;
+    let file: FileId = session.add("script", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex script");
+    assert_eq!(lexer.comments.len(), 1);
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse script");
+    let names: Vec<String> = ast.root.children.iter().map(|func| func.tok.val_str()).collect();
+    assert_eq!(names, vec!["twice", "main"]);
+    assert_eq!(ast.root.children[1].children[0].children.len(), 3);
+    assert_eq!(ast.root.children[1].tok.pos.row, 1);
+    assert!(session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile script").contains("main:"));
+
+    // Without the flag the same file is an error, and a script may not also declare `main`.
+    session.script = false;
+    let mut lexer = session.lex(file).expect("Error: Failed to lex script");
+    assert!(session.parse(file, &mut lexer).is_err());
+    session.script = true;
+    let file: FileId = session.add("both", b"dump 1;\nfunc main {\n    dump 2;\n}\n".to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex script");
+    let err: Diagnostic = session.parse(file, &mut lexer).expect_err("Error: Script declaring `main` was parsed");
+    assert_eq!(err.msg, "Scripts cannot declare `main`, the statements outside of functions are its body");
+}
+
+// A constant guard is warned about, and from -O1 only the branch it selects is generated.
+#[test]
+fn test_constant_condition() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func main {\n    if 2 > 1 {\n        dump 1;\n    } else {\n        dump 2;\n    }\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let diags: Vec<Diagnostic> = session.check(file, &ast);
+    assert_eq!(diags.len(), 1);
+    assert!(!diags[0].is_error());
+    assert_eq!(diags[0].msg, "Condition is always true");
+
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("_false_"));
+    session.opt_level = OptLevel::O1;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("_false_"));
+    assert_eq!(asm.matches("call dump").count(), 1);
+}
+
+// Constant arithmetic that overflows or divides by zero is warned about at its operator, even inside an expression
+// reading variables, and is left for runtime rather than folded.
+#[test]
+fn test_constant_faults() {
+    use language::consteval::ConstError;
+    use language::consteval::evaluate;
+
+    let src: &str = "func main {\n    let x = 3;\n    dump 9223372036854775807 + 1;\n    dump x + 1 / 0;\n    dump abs(-9223372036854775807 - 1);\n    dump -(2 * 3);\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let diags: Vec<String> = session.check(file, &ast).iter().map(|d| format!("{} {}", d.pos, d.msg)).collect();
+    assert_eq!(diags, ["[3:30] `+` overflows a 64 bit integer", "[4:16] Division by zero", "[5:10] `abs` overflows a 64 bit integer"]);
+
+    let body = &ast.root.children[0].children[0].children;
+    assert!(matches!(evaluate(&body[1].children[0]), Err(ConstError::Overflow(_, _))));
+    assert!(matches!(evaluate(&body[4].children[0]), Ok(-6)));
+    assert!(matches!(evaluate(&body[0].children[0]), Ok(3)));
+}
+
+// Intrinsics take a fixed number of arguments, other functions take none and cannot share their names, and constant
+// arguments are folded.
+#[test]
+fn test_intrinsic_calls() {
+    use language::consteval::eval_const;
+
+    assert_eq!(parse_shape("min(a, b + 1) < abs(-c) * 2"), "(< (min a (+ b 1)) (* (abs (- c)) 2))");
+    let errors = |expr: &str| -> String {
+        let src: String = format!("func main {{\n    dump {};\n}}\n", expr);
+        let mut session: Session = Session::new();
+        let file: FileId = session.add("test", src.into_bytes());
+        let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+        session.parse(file, &mut lexer).expect_err("Error: Invalid intrinsic call was parsed").msg
+    };
+    assert_eq!(errors("min(1)"), "`min` takes 2 arguments but got 1");
+    assert_eq!(errors("abs(1, 2)"), "`abs` takes 1 argument but got 2");
+    assert_eq!(errors("foo(1)"), "Expected `)` but got `1`");
+    assert_eq!(errors("max(1 2)"), "Expected `)` but got `2`");
+    assert_eq!(errors("rand(1)"), "`rand` takes 0 arguments but got 1");
+    assert_eq!(errors("time_ns(0)"), "`time_ns` takes 0 arguments but got 1");
+
+    let src: &str = "func main {\n    if max(2, -5) + abs(-3) + min(1, 4) == 6 {\n    }\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    assert_eq!(eval_const(&ast.root.children[0].children[0].children[0].children[0]), Some(1));
+
+    let file: FileId = session.add("named", b"func rand {\n}\n".to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    assert_eq!(session.parse(file, &mut lexer).expect_err("Error: Function named after an intrinsic was parsed").msg, "`rand` is an intrinsic and cannot name a function");
+}
+
+// All but the last value of a `dump` go through `dump_item`, which only ends values with a space on `--dump-same-line`.
+#[test]
+fn test_dump_same_line() {
+    let src: &str = "func main {\n    dump 1, 2, 3;\n    dump 4;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert_eq!(asm.matches("call dump_item").count(), 2);
+    assert_eq!(asm.matches("call dump\n").count(), 2);
+    assert!(asm.contains("dump_item:\ndump:"));
+
+    session.dump_same_line = true;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("dump_item:\n    sub rsp, 40"));
+    assert!(asm.contains("mov byte [rsp + 31], 32"));
+}
+
+// On -O2 a `dump` of values known at compile time is printed ahead of time into `.rodata`, values that are not are
+// still dumped at run time.
+#[test]
+fn test_constant_dump() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func main {\n    let a = rand();\n    dump 1, 2 * 3, -1;\n    dump a;\n}\n";
+    let mut session: Session = Session::new();
+    session.opt_level = OptLevel::O2;
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("    mov rsi, _dump_0\n    mov rdx, 25\n    syscall\n"));
+    assert!(asm.contains("section .rodata\n; --- Constant Dumps ---\n_dump_0:\n    db \"1\", 10, \"6\", 10, \"18446744073709551615\", 10\n"));
+    assert_eq!(asm.matches("call dump").count(), 1);
+
+    session.dump_same_line = true;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("    db \"1\", 32, \"6\", 32, \"18446744073709551615\", 10\n"));
+
+    session.opt_level = OptLevel::O1;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("section .rodata"));
+}
+
+// The convenience API compiles in memory, handing back the assembly or every diagnostic.
+#[test]
+fn test_compile_to_asm() {
+    use language::compile_to_asm;
+    use language::opt::OptLevel;
+
+    let asm: String = compile_to_asm("func main {\n    dump 1;\n}\n", OptLevel::O0).expect("Error: Failed to compile source");
+    assert!(asm.contains("_start:\n    call lang_main\n"));
+    let asm: String = compile_to_asm("func main {\n    dump 1;\n}\n", OptLevel::O2).expect("Error: Failed to compile source");
+    assert!(asm.contains("section .rodata"));
+
+    let diags: Vec<Diagnostic> = compile_to_asm("func main {\n    dump x;\n}\n", OptLevel::O0).expect_err("Error: Undeclared variable was compiled");
+    assert!(diags.iter().any(|d| d.is_error()));
+    let diags: Vec<Diagnostic> = compile_to_asm("func main {\n    dump 1\n}\n", OptLevel::O0).expect_err("Error: Missing semicolon was compiled");
+    assert_eq!(diags.len(), 1);
+}
+
+// Only functions marked `pub` or `export` are given `global` directives, whether or not they are in a module. Labels
+// are mangled, other than those of `export` functions.
+#[test]
+fn test_exports() {
+    let src: &str = "module m {\n    pub func a {\n    }\n    func b {\n    }\n    export func d {\n        b();\n    }\n}\npub func c {\n}\nfunc main {\n    m.a();\n    m.d();\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("global _start\nglobal lang_m.a\nglobal d\nglobal lang_c\nsection .text"));
+    assert!(asm.contains("\nd:\n"));
+    assert!(asm.contains("call lang_m.b\n"));
+    assert!(asm.contains("call d\n"));
+    assert!(asm.contains("_start:\n    call lang_main\n"));
+}
+
+// Functions no call chain from `main` reaches are warned about and not emitted, unless they are `pub`. Taking a
+// function's address counts as calling it.
+#[test]
+fn test_dead_functions() {
+    let src: &str = "module m {\n    func unused {\n    }\n    func helper {\n    }\n    pub func api {\n        helper();\n    }\n}\nfunc target {\n}\nfunc orphan {\n    target();\n}\nfunc main {\n    let f = &target;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut sink: CollectorSink = CollectorSink::default();
+    let asm: String = session.compile(file, &mut sink).expect("Error: Failed to compile source");
+    let warnings: Vec<String> = sink.diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(warnings, [
+        "[2:10] Warning: Function `m.unused` is never called and is left out of the program",
+        "[12:6] Warning: Function `orphan` is never called and is left out of the program",
+    ]);
+    assert!(!asm.contains("lang_m.unused:") && !asm.contains("lang_orphan:"));
+    assert!(asm.contains("lang_m.helper:") && asm.contains("lang_m.api:") && asm.contains("lang_target:"));
+}
+
+// The prelude is only emitted when used and can be left out, and `_start` can call another function than `main`.
+#[test]
+fn test_entry_and_prelude() {
+    let src: &str = "module app {\n    pub func run {\n        exit 2;\n    }\n}\nfunc main {\n    dump 1;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("\ndump:\n"));
+
+    session.no_prelude = true;
+    let mut sink: CollectorSink = CollectorSink::default();
+    assert!(session.compile(file, &mut sink).is_none());
+    assert_eq!(sink.diagnostics[0].msg, "`dump` needs the prelude, which `--no-prelude` leaves out");
+
+    // `main` is never called from `app.run`, so its `dump` is left out along with it.
+    session.entry = Some("app.run".to_string());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("_start:\n    call lang_app.run\n    mov rdi, 0\n    mov rax, 60\n    syscall\n"));
+    assert!(!asm.contains("dump"));
+    assert!(!asm.contains("lang_main"));
+
+    let file: FileId = session.add("bare", b"func main {\n    exit 1;\n}\n".to_vec());
+    session.entry = None;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("dump"));
+
+    session.entry = Some("start".to_string());
+    let mut sink: CollectorSink = CollectorSink::default();
+    assert!(session.compile(file, &mut sink).is_none());
+    assert_eq!(sink.diagnostics[0].msg, "No function `start` to use as the entry point");
+}
+
+// A comparison taking another comparison as an operand is warned about, unless parentheses show it is meant.
+#[test]
+fn test_chained_comparison() {
+    let warnings = |cond: &str| -> Vec<String> {
+        let src: String = format!("func main {{\n    let a = 1;\n    let b = 2;\n    let c = 3;\n    if {} {{\n        dump a;\n    }}\n}}\n", cond);
+        let mut session: Session = Session::new();
+        let file: FileId = session.add("test", src.into_bytes());
+        let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+        let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+        session.check(file, &ast).iter().map(|d| format!("[{}:{}] {}", d.pos.row + 1, d.pos.col + 1, d.msg)).collect()
+    };
+
+    assert_eq!(warnings("a < b < c"), vec![
+        "[5:14] Comparisons do not chain, `<` compares the 0 or 1 result of the comparison before it. Use `&&` or add parentheses",
+    ]);
+    assert_eq!(warnings("a == b == c").len(), 1);
+    assert_eq!(warnings("a < b == c").len(), 1);
+    assert_eq!(warnings("a == b < c").len(), 1);
+    assert_eq!(warnings("a <= b >= c > 0").len(), 2);
+    assert!(warnings("(a < b) < c").is_empty());
+    assert!(warnings("(a < b) == (b < c)").is_empty());
+    assert!(warnings("a < b && b < c").is_empty());
+    assert!(warnings("a + b < c * 2 || a == (b < c)").is_empty());
+}
+
+// At -O2 an expression repeated in straight-line code is computed once, until a variable it reads is assigned.
+#[test]
+fn test_common_subexpressions() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func main {\n    let a = 3;\n    let b = 4;\n    dump a*b + 1;\n    dump a*b + 2;\n    a = 1;\n    dump a*b;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert_eq!(asm.matches("imul").count(), 3);
+    session.opt_level = OptLevel::O2;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert_eq!(asm.matches("imul").count(), 2);
+}
+
+// At -O2 calls to small leaf functions are replaced by their bodies, locals and branches included.
+#[test]
+fn test_inline_functions() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func f {\n    let x = 5;\n    if x > 3 {\n        dump x;\n    }\n}\nfunc main {\n    let x = 1;\n    f();\n    f();\n    dump x;\n}\n";
+    let mut session: Session = Session::new();
+    session.opt_level = OptLevel::O2;
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut sink: CollectorSink = CollectorSink::default();
+    let asm: String = session.compile(file, &mut sink).expect("Error: Failed to compile source");
+    assert!(!sink.has_errors());
+    assert!(!asm.contains("call f"));
+    assert_eq!(asm.matches("jle _end_").count(), 3);
+}
+
+// From -O1 values move between registers directly, and a variable is not reloaded right after it is stored.
+#[test]
+fn test_propagate_copies() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func main {\n    let a = 1;\n    a = 2;\n    dump a;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("mov rax, [rbp - 8]"));
+    assert!(asm.contains("pop rdi"));
+    session.opt_level = OptLevel::O1;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("mov rax, [rbp - 8]"));
+    assert!(!asm.contains("pop rdi"));
+}
+
+// -Os keeps small functions as calls and zeroes or loads small negative values with shorter instructions.
+#[test]
+fn test_size_optimisation() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func f {\n    dump 1;\n}\nfunc main {\n    let a = 0;\n    let b = 3;\n    f();\n    f();\n    dump a + b;\n    if a == 0 {\n        exit;\n    }\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    session.opt_level = OptLevel::O2;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("call lang_f"));
+    session.opt_level = OptLevel::Os;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert_eq!(asm.matches("call lang_f").count(), 2);
+    assert!(asm.contains("push 3\n    pop rax\n"));
+    assert!(asm.contains("xor r12, r12"));
+    // The 0 compared against is made before `cmp` sets the flags, but the `exit` status comes right before a label,
+    // where code that may read them continues.
+    assert!(asm.contains("xor rax, rax\n    mov rbx, rax\n"));
+    assert!(asm.contains("push 0\n    pop rdi\n"));
+}
+
+// From -O1 the jump out of a nested `if` goes straight to the outer end, and unused labels are dropped.
+#[test]
+fn test_thread_jumps() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func main {\n    let a = 2;\n    if a > 1 {\n        if a > 5 {\n            dump 1;\n        } else {\n            dump 2;\n        }\n    } else {\n        dump 3;\n    }\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("jmp _end_1"));
+    assert!(asm.contains("_if_0:"));
+    session.opt_level = OptLevel::O1;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("jmp _end_1"));
+    assert_eq!(asm.matches("jmp _end_0").count(), 2);
+    assert!(!asm.contains("_if_0:"));
+    assert!(!asm.contains("add rsp, 0"));
+}
+
+// From -O1 a comparison guarding an `if` jumps on the flags it sets, rather than making 0 or 1 to test again.
+#[test]
+fn test_branch_on_comparison() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func main {\n    let a = 2;\n    if a < 5 {\n        dump 1;\n    }\n    if a == 3 || a > 1 {\n        dump 2;\n    }\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("setl al") && !asm.contains("jge"));
+    session.opt_level = OptLevel::O1;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("setl al"));
+    assert!(asm.contains("cmp rax, rbx\n    jge _end_0"));
+    // Only the root of the guard is compared directly, `||` still makes 0 or 1.
+    assert!(asm.contains("sete al") && asm.contains("setg al"));
+}
+
+// From -O1 `else` bodies are moved out of line after the function, unless block reordering is turned off.
+#[test]
+fn test_reorder_blocks() {
+    use language::opt::OptLevel;
+
+    let src: &str = "func pick {\n    let a = 2;\n    if a > 1 {\n        dump 1;\n    } else {\n        dump 2;\n    }\n    dump 3;\n}\nfunc main {\n    pick();\n}\n";
+    let mut session: Session = Session::new();
+    session.opt_level = OptLevel::O1;
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    let ret: usize = asm.find("pick:").and_then(|pick| asm[pick..].find("ret").map(|ret| pick + ret)).expect("Error: Function has no return");
+    assert!(asm.find("_false_0:").expect("Error: Else body is missing") > ret);
+    session.no_reorder_blocks = true;
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    let ret: usize = asm.find("pick:").and_then(|pick| asm[pick..].find("ret").map(|ret| pick + ret)).expect("Error: Function has no return");
+    assert!(asm.find("_false_0:").expect("Error: Else body is missing") < ret);
+}
+
+// Lowering resolves every name to its declaration and types every expression, so locals of the same name in sibling
+// blocks stay apart and a call through a local is told from a call of a function.
+#[test]
+fn test_hir() {
+    use language::hir::Callee;
+    use language::hir::ExprKind;
+    use language::hir::Function;
+    use language::hir::Stmt;
+    use language::hir::Type;
+    use language::lexer::Lexer;
+    use language::parser::ParseTree;
+
+    let src: &str = "func f {\n}\nfunc main {\n    let g = &f;\n    g();\n    if 1 {\n        let x = 2;\n        dump x;\n    } else {\n        let x = 3;\n        x = g;\n    }\n}\n";
+    let mut lexer: Lexer = Lexer::new(src.as_bytes().to_vec());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Failed to lex source");
+    let mut ast: ParseTree = ParseTree::new("test".to_string());
+    ast.construct(&mut lexer).expect("Error: Failed to parse source");
+    let funcs: Vec<Function> = language::hir::lower(&ast).expect("Error: Failed to lower source");
+    let main: &Function = &funcs[1];
+
+    let names: Vec<(&str, Type)> = main.locals.iter().map(|local| (local.name.as_str(), local.ty)).collect();
+    assert_eq!(names, [("g", Type::Func), ("x", Type::Int), ("x", Type::Int)]);
+    assert!(matches!(&main.body[1], Stmt::Call(Callee::Local(0))));
+    let Stmt::If { known, then, els: Some(els), .. } = &main.body[2] else {
+        panic!("Error: Expected an `if` with an `else`");
+    };
+    assert_eq!(*known, Some(1));
+    assert!(matches!(&then[1], Stmt::Dump { values, .. } if matches!(values[0].kind, ExprKind::Local(1))));
+    assert!(matches!(&els[1], Stmt::Assign(2, value) if value.ty == Type::Func));
+}
+
+// Code generation walks expressions and blocks with a worklist, so a tree nested far deeper than the parser allows is
+// generated on a small stack.
+#[test]
+fn test_deep_codegen() {
+    use std::thread;
+    use language::codegen::generate_function;
+    use language::hir::BinOp;
+    use language::hir::Expr;
+    use language::hir::ExprKind;
+    use language::hir::Function;
+    use language::hir::Stmt;
+    use language::instr::AsmFunction;
+    use language::instr::Instr;
+    use language::lexer::Lexer;
+    use language::opt::OptLevel;
+    use language::parser::ParseTree;
+
+    let mut lexer: Lexer = Lexer::new(b"func main {\n    dump 1;\n}\n".to_vec());
+    lexer.tokenize();
+    lexer.lex().expect("Error: Failed to lex source");
+    let mut ast: ParseTree = ParseTree::new("test".to_string());
+    ast.construct(&mut lexer).expect("Error: Failed to parse source");
+    let mut func: Function = language::hir::lower(&ast).expect("Error: Failed to lower source").remove(0);
+
+    const DEPTH: usize = 100000;
+    let res = thread::Builder::new().stack_size(256 * 1024).spawn(move || {
+        let Stmt::Dump { values, .. } = &mut func.body[0] else {
+            panic!("Error: Expected a `dump`");
+        };
+        let one: Expr = values[0].clone();
+        for _ in 0..DEPTH {
+            let lhs: Expr = values.pop().expect("Error: `dump` has no value");
+            values.push(Expr { kind: ExprKind::Binary(BinOp::Add, Box::new(lhs), Box::new(one.clone())), ..one.clone() });
+        }
+        for _ in 0..DEPTH {
+            func.body = vec![Stmt::Block(std::mem::take(&mut func.body))];
+        }
+        let asm: AsmFunction = generate_function(&func, &mut 0, OptLevel::O0, &mut Vec::new());
+        // NOTE: Dropping the tree recurses, which only trees deeper than the parser allows have no room for.
+        std::mem::forget(func);
+        asm
+    }).expect("Error: Failed to spawn thread").join();
+    let asm: AsmFunction = res.expect("Error: Code generation overflowed the stack");
+    assert_eq!(asm.body.iter().filter(|instr| matches!(instr, Instr::Add(..))).count(), 2 * DEPTH + 1);
+}
+
+// At -O2 locals live in registers, and only spill to the stack once every register is taken.
+#[test]
+fn test_register_allocation() {
+    use language::opt::OptLevel;
+    use language::regalloc::ALLOCATABLE;
+
+    // `main` exits rather than returning, so the registers are saved and restored in a function of their own, which
+    // calls itself to not be inlined.
+    let few: &str = "func f {\n    let a = 1;\n    let b = 2;\n    a = a + b;\n    dump a * b;\n    if a > 9 {\n        f();\n    }\n}\nfunc main {\n    f();\n}\n";
+    let mut session: Session = Session::new();
+    session.opt_level = OptLevel::O2;
+    let file: FileId = session.add("test", few.as_bytes().to_vec());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(!asm.contains("[rbp -"));
+    assert!(asm.contains("push r12") && asm.contains("pop r12"));
+
+    let decls: String = (0..ALLOCATABLE.len() + 2).map(|i| format!("    let v{} = {};\n", i, i)).collect();
+    let uses: String = (0..ALLOCATABLE.len() + 2).map(|i| format!("    dump v{};\n", i)).collect();
+    let many: String = format!("func main {{\n{}{}}}\n", decls, uses);
+    let file: FileId = session.add("test", many.into_bytes());
+    let asm: String = session.compile(file, &mut CollectorSink::default()).expect("Error: Failed to compile source");
+    assert!(asm.contains("[rbp -"));
+}
+
+// A `for` loop jumps back to its guard, so at -O2 a local it reads keeps its register until the loop is over rather than
+// until the read, and the loop variable, its last value and whether it goes round again are locals of a block around the loop.
+#[test]
+fn test_loop_registers() {
+    use language::hir::Stmt;
+    use language::opt::OptLevel;
+
+    let mut session: Session = Session::new();
+    session.opt_level = OptLevel::O2;
+    let src: &str = "func main {\n    let step = 7;\n    let sum = 0;\n    for k = 1 to 3 {\n        let twice = step * 2;\n        sum += twice + k;\n    }\n    dump sum;\n}\n";
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let ast: ParseTree = session.front_end(file, &mut CollectorSink::default()).expect("Error: Failed to parse source");
+    let funcs: Vec<language::hir::Function> = language::hir::lower(&ast).expect("Error: Failed to lower source");
+    let Stmt::Block(scope) = &funcs[0].body[2] else {
+        panic!("Error: Loop is not in a block of its own: {:?}", funcs[0].body[2]);
+    };
+    assert!(matches!(scope[..], [Stmt::Let(..), Stmt::Let(..), Stmt::Let(..), Stmt::While { .. }]));
+    let names: Vec<&str> = funcs[0].locals.iter().map(|local| local.name.as_str()).collect();
+    assert_eq!(names, vec!["step", "sum", "k", "for.k", "for.k.more", "twice"]);
+
+    let alloc: language::regalloc::Allocation = language::regalloc::allocate(&funcs[0]);
+    assert!(alloc.get(0).is_some() && alloc.get(5).is_some());
+    assert_ne!(alloc.get(0), alloc.get(5), "Error: `twice` took the register of `step`, which the next time round reads");
+
+    let mut out: Vec<u8> = Vec::new();
+    assert_eq!(session.run(file, &mut out, &mut CollectorSink::default()), Some(0));
+    assert_eq!(String::from_utf8_lossy(&out), "48\n");
+}
+
+// Sizes follow the encodings NASM picks, and a jump is only lengthened when its target is out of reach of a short one.
+#[test]
+fn test_symbol_map() {
+    use language::instr::AsmFunction;
+    use language::instr::AsmProgram;
+    use language::instr::Instr;
+    use language::instr::Operand;
+    use language::instr::Reg;
+    use language::nasm::encoded_size;
+    use language::nasm::function_size;
+    use language::nasm::symbol_map;
+
+    assert_eq!(encoded_size(&Instr::Mov(Operand::Reg(Reg::Rax), Operand::Imm(1)), true), 5);
+    assert_eq!(encoded_size(&Instr::Mov(Operand::Reg(Reg::R12), Operand::Imm(-1)), true), 7);
+    assert_eq!(encoded_size(&Instr::Mov(Operand::Reg(Reg::Rax), Operand::Mem(Reg::Rbp, -8)), true), 4);
+    assert_eq!(encoded_size(&Instr::Mov(Operand::Reg(Reg::Rax), Operand::Mem(Reg::Rsp, 0)), true), 4);
+    assert_eq!(encoded_size(&Instr::Push(Operand::Reg(Reg::R13)), true), 2);
+    assert_eq!(encoded_size(&Instr::Cmp(Operand::Reg(Reg::Rax), Operand::Imm(0)), true), 4);
+    assert_eq!(encoded_size(&Instr::Add(Operand::Reg(Reg::Rsp), Operand::Imm(200)), true), 7);
+    assert_eq!(encoded_size(&Instr::Jcc(language::instr::Cond::E, "x".to_string()), false), 6);
+
+    // 40 bytes of `syscall` between a jump and its target still fit a short jump, 80 do not.
+    let func = |n: usize| AsmFunction {
+        name: "f".to_string(),
+        body: [Instr::Jmp(".end".to_string())].into_iter().chain(vec![Instr::Syscall; n]).chain([Instr::Label(".end".to_string())]).collect(),
+        frame_size: 0,
+        spills: 0,
+    };
+    assert_eq!(function_size(&func(40)), 2 + 80);
+    assert_eq!(function_size(&func(80)), 5 + 160);
+
+    let src: &str = "func helper {\n    dump 1;\n}\nfunc main {\n    helper();\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let prog: AsmProgram = session.lower(file, &ast).expect("Error: Failed to lower source");
+    let labels: Vec<String> = symbol_map(&prog).into_iter().map(|(label, _)| label).collect();
+    assert_eq!(labels, ["dump", "lang_helper", "lang_main", "_start"]);
+}
+
+// Stats count stack slots of locals, including the deepest nested block, and how many locals missed a register.
+#[test]
+fn test_function_stats() {
+    use language::instr::AsmProgram;
+    use language::opt::OptLevel;
+
+    let src: &str = "func main {\n    let a = 1;\n    if a > 0 {\n        let b = 2;\n        let c = 3;\n        dump b + c;\n    }\n    let d = 4;\n    dump d;\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let prog: AsmProgram = session.lower(file, &ast).expect("Error: Failed to lower source");
+    assert_eq!((prog.funcs[0].frame_size, prog.funcs[0].spills), (24, 4));
+    assert!(prog.funcs[0].instr_count() > 0);
+    session.opt_level = OptLevel::O2;
+    let prog: AsmProgram = session.lower(file, &ast).expect("Error: Failed to lower source");
+    assert_eq!(prog.funcs[0].spills, 0);
+}
+
+// `--emit asm --only-func` prints one function by its qualified name, and says why a function has no assembly to print.
+#[test]
+fn test_asm_listing() {
+    use language::instr::AsmProgram;
+
+    let src: &str = "module m {\n    pub func g {\n        dump 2;\n    }\n}\nfunc unused {\n    dump 1;\n}\nfunc main {\n    m.g();\n}\n";
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("test", src.as_bytes().to_vec());
+    let mut lexer = session.lex(file).expect("Error: Failed to lex source");
+    let ast = session.parse(file, &mut lexer).expect("Error: Failed to parse source");
+    let prog: AsmProgram = session.lower(file, &ast).expect("Error: Failed to lower source");
+    let all: String = asm_listing(&prog, &ast, None).expect("Error: Failed to print program");
+    assert!(all.contains("lang_main:") && all.contains("lang_m.g:"));
+    let one: String = asm_listing(&prog, &ast, Some("m.g")).expect("Error: Failed to print function");
+    assert!(one.starts_with("lang_m.g:\n") && one.ends_with("    ret\n"));
+    assert!(!one.contains("lang_main:") && !one.contains("Header"));
+    assert_eq!(asm_listing(&prog, &ast, Some("g")).err().map(|d| d.msg), Some("No function `g` to emit".to_string()));
+    assert_eq!(asm_listing(&prog, &ast, Some("unused")).err().map(|d| d.msg), Some("`unused` is never called, so it has no assembly".to_string()));
+}
+
+// Compiles ./language_tests/test_name.lang in memory and compares the assembly to ./language_tests/test_name<suffix>.asm.expected.
+// Run with UPDATE_EXPECT=1 to write the current output as the new expectation instead.
+fn run_snapshot_test(test_name: &str, opt: language::opt::OptLevel, suffix: &str) {
+    let src_path: String = format!("{}{}{}", TEST_DIR, test_name, LANGUAGE_EXT);
+    let exp_path: String = format!("{}{}{}.asm{}", TEST_DIR, test_name, suffix, EXPECTED_EXT);
+
+    let mut sink: CollectorSink = CollectorSink::default();
+    let mut session: Session = Session::new();
+    session.opt_level = opt;
+    let file: FileId = session.sources.load(&DiskProvider, &src_path).expect("Error: Test failed to read source file");
+    let asm: String = session.compile(file, &mut sink).unwrap_or_else(|| panic!("{} Error: Compilation failed: {:?}", src_path, sink.diagnostics));
+    compare_snapshot(&exp_path, &asm);
+}
+
+// Compares output to the snapshot at `exp_path`, or writes it there with UPDATE_EXPECT=1.
+fn compare_snapshot(exp_path: &str, got: &str) {
+    if std::env::var("UPDATE_EXPECT").is_ok_and(|v| v == "1") {
+        fs::write(exp_path, got).expect("Error: Test failed to write expected output");
+        return;
+    }
+    let exp: String = fs::read_to_string(exp_path).unwrap_or_else(|_| panic!("{} Error: No expected output, run with UPDATE_EXPECT=1 to create it", exp_path));
+    if let Some((row, (e, g))) = exp.lines().zip(got.lines()).enumerate().find(|(_, (e, g))| e != g) {
+        panic!("{} Error: Output differs at line {}.\nExpected: {}\nGot:      {}\nRun with UPDATE_EXPECT=1 if the change is intended.", exp_path, row + 1, e, g);
+    }
+    assert_eq!(exp.lines().count(), got.lines().count(), "{} Error: Output differs in length. Run with UPDATE_EXPECT=1 if the change is intended.", exp_path);
+}
+
+// Lexes ./language_tests/test_name.lang and compares what `--emit tokens-json` prints to
+// ./language_tests/test_name.tokens.json.expected.
+fn run_tokens_snapshot_test(test_name: &str) {
+    let src_path: String = format!("{}{}{}", TEST_DIR, test_name, LANGUAGE_EXT);
+    let mut session: Session = Session::new();
+    let file: FileId = session.sources.load(&DiskProvider, &src_path).expect("Error: Test failed to read source file");
+    let lexer: Lexer = session.lex(file).unwrap_or_else(|d| panic!("{} Error: Lexing failed: {}", src_path, d));
+    compare_snapshot(&format!("{}{}.tokens.json{}", TEST_DIR, test_name, EXPECTED_EXT), &format!("{}\n", tokens_json(&lexer)));
+}
+
+// Snapshot Tests
+
+#[test]
+fn snapshot_arithmetic() { run_snapshot_test("arithmetic", language::opt::OptLevel::O0, ""); }
+#[test]
+fn snapshot_conditional() { run_snapshot_test("conditional", language::opt::OptLevel::O0, ""); }
+#[test]
+fn snapshot_variable() { run_snapshot_test("variable", language::opt::OptLevel::O0, ""); }
+#[test]
+fn snapshot_function() { run_snapshot_test("function", language::opt::OptLevel::O0, ""); }
+#[test]
+fn snapshot_redeclare() { run_snapshot_test("redeclare", language::opt::OptLevel::O0, ""); }
+#[test]
+fn snapshot_scope() { run_snapshot_test("scope", language::opt::OptLevel::O0, ""); }
+#[test]
+fn snapshot_implicit_exit() { run_snapshot_test("implicit_exit", language::opt::OptLevel::O0, ""); }
+#[test]
+fn snapshot_intrinsics_o2() { run_snapshot_test("intrinsics", language::opt::OptLevel::O2, ".O2"); }
+#[test]
+fn snapshot_rand_o2() { run_snapshot_test("rand", language::opt::OptLevel::O2, ".O2"); }
+#[test]
+fn snapshot_variable_o2() { run_snapshot_test("variable", language::opt::OptLevel::O2, ".O2"); }
+#[test]
+fn snapshot_function_o2() { run_snapshot_test("function", language::opt::OptLevel::O2, ".O2"); }
+#[test]
+fn snapshot_scope_o2() { run_snapshot_test("scope", language::opt::OptLevel::O2, ".O2"); }
+#[test]
+fn snapshot_function_tokens() { run_tokens_snapshot_test("function"); }
+
+// Doc comments are attached to functions, in and out of modules, and rendered in each format.
+#[test]
+fn snapshot_doc() {
+    let src_path: String = format!("{}doc{}", TEST_DIR, LANGUAGE_EXT);
+    let mut session: Session = Session::new();
+    let file: FileId = session.sources.load(&DiskProvider, &src_path).expect("Error: Test failed to read source file");
+    let mut lexer: Lexer = session.lex(file).unwrap_or_else(|d| panic!("{} Error: Lexing failed: {}", src_path, d));
+    let ast: ParseTree = session.parse(file, &mut lexer).unwrap_or_else(|d| panic!("{} Error: Parsing failed: {}", src_path, d));
+    let docs: Vec<doc::FuncDoc> = doc::collect(&lexer, &ast);
+    compare_snapshot(&format!("{}doc.md{}", TEST_DIR, EXPECTED_EXT), &doc::render("doc.lang", &docs, DocFormat::Markdown));
+    compare_snapshot(&format!("{}doc.html{}", TEST_DIR, EXPECTED_EXT), &doc::render("doc.lang", &docs, DocFormat::Html));
+}
+
+// The editor grammars are generated from the lexer's tables, so a change to the language shows up in them here.
+#[test]
+fn snapshot_grammar() {
+    compare_snapshot(&format!("{}grammar.tmlanguage.json{}", TEST_DIR, EXPECTED_EXT), &format!("{}\n", grammar::generate(GrammarFormat::TmLanguage)));
+    compare_snapshot(&format!("{}grammar.tree-sitter.js{}", TEST_DIR, EXPECTED_EXT), &format!("{}\n", grammar::generate(GrammarFormat::TreeSitter)));
+}
+
+static FAIL_DIR: &str = "./language_tests/fail/";
+static ANNOTATION: &str = "//~ ";
+
+// Compiles ./language_tests/fail/test_name.lang, which must fail with exactly the diagnostics its annotations list.
+// An annotation is a comment of the form `//~ [row:col] Error: message`, matching how the diagnostic is displayed.
+fn run_fail_test(test_name: &str) {
+    let src_path: String = format!("{}{}{}", FAIL_DIR, test_name, LANGUAGE_EXT);
+    let src: String = fs::read_to_string(&src_path).expect("Error: Test failed to read source file");
+    let mut expected: Vec<String> = src.lines()
+        .filter_map(|line| line.split_once(ANNOTATION).map(|(_, note)| note.trim().to_string()))
+        .collect();
+
+    let mut sink: CollectorSink = CollectorSink::default();
+    let mut session: Session = Session::new();
+    let file: FileId = session.add(&src_path, src.into_bytes());
+    assert!(session.compile(file, &mut sink).is_none(), "{} Error: Compilation succeeded but was expected to fail", src_path);
+    let mut got: Vec<String> = sink.diagnostics.iter().map(|d| d.to_string()).collect();
+
+    expected.sort();
+    got.sort();
+    assert_eq!(expected, got, "{} Error: Diagnostics differ from the annotations", src_path);
+}
+
+// Compile-Fail Tests
+
+#[test]
+fn fail_undeclared() { run_fail_test("undeclared"); }
+#[test]
+fn fail_block_scope() { run_fail_test("block_scope"); }
+#[test]
+fn fail_missing_semicolon() { run_fail_test("missing_semicolon"); }
+#[test]
+fn fail_unexpected_eof() { run_fail_test("unexpected_eof"); }
+#[test]
+fn fail_redeclare() { run_fail_test("redeclare"); }
+#[test]
+fn fail_literal_range() { run_fail_test("literal_range"); }
+#[test]
+fn fail_function_pointer() { run_fail_test("function_pointer"); }
+#[test]
+fn fail_modules() { run_fail_test("modules"); }
+#[test]
+fn fail_export() { run_fail_test("export"); }
+#[test]
+fn fail_single_and() { run_fail_test("single_and"); }
+#[test]
+fn fail_single_or() { run_fail_test("single_or"); }
+#[test]
+fn fail_duplicate_function() { run_fail_test("duplicate_function"); }
+#[test]
+fn fail_no_main() { run_fail_test("no_main"); }
+
+// A local named like a label would take the calls of that function, so it is rejected by the interpreter and at every
+// optimisation level rather than running the wrong function.
+#[test]
+fn fail_label_local() {
+    use language::opt::OptLevel;
+
+    run_fail_test("label_local");
+    let src: String = fs::read_to_string(format!("{}label_local{}", FAIL_DIR, LANGUAGE_EXT)).expect("Error: Test failed to read source file");
+    let mut session: Session = Session::new();
+    let file: FileId = session.add("label_local", src.into_bytes());
+    for opt in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::Os] {
+        session.opt_level = opt;
+        assert!(session.compile(file, &mut CollectorSink::default()).is_none(), "Error: Compiled at {:?}", opt);
+    }
+    assert!(session.run(file, &mut Vec::new(), &mut CollectorSink::default()).is_none(), "Error: Interpreter ran the program");
+}
+
+// Random programs for the formatter round trip. Every token is positioned at the start of the file,
+// except implicit initialisers, which are positioned nowhere like the ones the parser makes.
+fn arb_node(kind: language::parser::NodeType, tok_kind: language::lexer::TokenType, val: &str, children: Vec<language::parser::ParseNode>) -> language::parser::ParseNode {
+    use language::lexer::Pos;
+    use language::lexer::Token;
+
+    let value: Option<i64> = if tok_kind == language::lexer::TokenType::LiteralInt { val.parse().ok() } else { None };
+    language::parser::ParseNode { kind, tok: Token { kind: tok_kind, val: val.as_bytes().to_vec(), pos: Pos { row: 0, col: 0, offset: 0 }, value }, children }
+}
+
+fn arb_ident() -> impl proptest::strategy::Strategy<Value = String> {
+    use proptest::prelude::*;
+
+    "[a-z][a-z0-9]{0,4}".prop_filter("Identifiers cannot be keywords", |name| !language::lexer::KEYWORDS.iter().any(|(kw, _)| kw == name))
+}
+
+fn arb_expr() -> impl proptest::strategy::Strategy<Value = language::parser::ParseNode> {
+    use language::lexer::TokenType;
+    use language::parser::NodeType;
+    use proptest::prelude::*;
+
+    let leaf = prop_oneof![
+        (0..1000u32).prop_map(|n| arb_node(NodeType::Literal, TokenType::LiteralInt, &n.to_string(), Vec::new())),
+        arb_ident().prop_map(|name| arb_node(NodeType::Var, TokenType::Identifier, &name, Vec::new())),
+        arb_ident().prop_map(|name| arb_node(NodeType::FuncAddr, TokenType::Identifier, &name, Vec::new())),
+    ];
+    let ops: Vec<(&str, TokenType)> = language::lexer::OPERATORS.iter().filter(|(_, kind)| !matches!(kind, TokenType::OpAssign | TokenType::OpPlusAssign | TokenType::OpMinusAssign | TokenType::OpMulAssign | TokenType::OpDivAssign | TokenType::OpAddressOf)).cloned().collect();
+    leaf.prop_recursive(4, 32, 2, move |inner| prop_oneof![
+        (proptest::sample::select(ops.clone()), inner.clone(), inner.clone())
+            .prop_map(|((text, kind), lhs, rhs)| arb_node(NodeType::BinOp, kind, text, vec![lhs, rhs])),
+        inner.clone().prop_map(|rhs| arb_node(NodeType::UnOp, TokenType::OpMinus, "-", vec![rhs])),
+        (proptest::sample::select(language::parser::INTRINSICS.to_vec()), inner.clone(), inner)
+            .prop_map(|((name, arity), a, b)| arb_node(NodeType::Intrinsic, TokenType::Identifier, name, [a, b].into_iter().take(arity).collect())),
+    ])
+}
+
+fn arb_block_item() -> impl proptest::strategy::Strategy<Value = language::parser::ParseNode> {
+    use language::lexer::Pos;
+    use language::lexer::TokenType;
+    use language::parser::NodeType;
+    use proptest::prelude::*;
+
+    // Left out expressions are a `0` positioned nowhere, as the parser makes them.
+    let or_zero = |expr: Option<language::parser::ParseNode>| expr.unwrap_or_else(|| {
+        let mut zero = arb_node(NodeType::Literal, TokenType::LiteralInt, "0", Vec::new());
+        zero.tok.pos = Pos { row: usize::MAX - 1, col: usize::MAX - 1, offset: usize::MAX - 1 };
+        zero
+    });
+    let statement = prop_oneof![
+        (arb_ident(), proptest::option::of(arb_expr())).prop_map(move |(name, init)| arb_node(NodeType::VarDecl, TokenType::Identifier, &name, vec![or_zero(init)])),
+        (arb_ident(), arb_expr()).prop_map(|(name, rhs)| arb_node(NodeType::Assign, TokenType::Identifier, &name, vec![rhs])),
+        (arb_ident(), proptest::sample::select(vec![("+", TokenType::OpPlus), ("-", TokenType::OpMinus), ("*", TokenType::OpMul), ("/", TokenType::OpDiv)]), arb_expr())
+            .prop_map(|(name, (text, kind), rhs)| {
+                let var = arb_node(NodeType::Var, TokenType::Identifier, &name, Vec::new());
+                arb_node(NodeType::CompoundAssign, TokenType::Identifier, &name, vec![arb_node(NodeType::BinOp, kind, text, vec![var, rhs])])
+            }),
+        proptest::collection::vec(arb_expr(), 1..4).prop_map(|values| arb_node(NodeType::DebugDump, TokenType::KeywordDebugDump, "dump", values)),
+        proptest::option::of(arb_expr()).prop_map(move |rhs| arb_node(NodeType::Exit, TokenType::KeywordExit, "exit", vec![or_zero(rhs)])),
+        arb_ident().prop_map(|name| arb_node(NodeType::FuncCall, TokenType::Identifier, &name, Vec::new())),
+    ];
+    statement.prop_recursive(3, 16, 3, |inner| {
+        let block = proptest::collection::vec(inner, 0..3).prop_map(|items| arb_node(NodeType::Block, TokenType::None, "", items));
+        let else_if = proptest::option::of((arb_expr(), block.clone()));
+        (arb_expr(), block.clone(), else_if, proptest::option::of(block)).prop_map(|(cond, if_block, else_if, else_block)| {
+            let mut else_children: Vec<language::parser::ParseNode> = else_block.into_iter().collect();
+            if let Some((cond, block)) = else_if {
+                let mut children = vec![cond, block];
+                children.append(&mut else_children);
+                else_children = vec![arb_node(NodeType::Conditional, TokenType::KeywordIf, "if", children)];
+            }
+            let mut children = vec![cond, if_block];
+            children.extend(else_children);
+            arb_node(NodeType::Conditional, TokenType::KeywordIf, "if", children)
+        })
+    })
+}
+
+fn arb_tree() -> impl proptest::strategy::Strategy<Value = language::parser::ParseTree> {
+    use language::lexer::TokenType;
+    use language::parser::NodeType;
+    use language::parser::ParseTree;
+    use proptest::prelude::*;
+
+    let name = arb_ident().prop_filter("Functions cannot be named after intrinsics", |name| !language::parser::INTRINSICS.iter().any(|(intrinsic, _)| intrinsic == name));
+    let func = (name, any::<bool>(), proptest::collection::vec(arb_block_item(), 0..5)).prop_map(|(name, public, items)| {
+        let body = arb_node(NodeType::Block, TokenType::None, "", items);
+        let kind: NodeType = if public { NodeType::PubFuncDecl } else { NodeType::FuncDecl };
+        arb_node(kind, TokenType::Identifier, &name, vec![body])
+    });
+    proptest::collection::vec(func, 1..4).prop_map(|funcs| {
+        let mut tree: ParseTree = ParseTree::new("test".to_string());
+        tree.root.children = funcs;
+        tree
+    })
+}
+
+// Trees are the same if they match in everything but positions.
+fn same_shape(a: &language::parser::ParseNode, b: &language::parser::ParseNode) -> bool {
+    a.kind == b.kind && a.tok.kind == b.tok.kind && a.tok.val == b.tok.val && a.children.len() == b.children.len()
+        && a.children.iter().zip(&b.children).all(|(a, b)| same_shape(a, b))
+}
+
+proptest::proptest! {
+    // Formatting a tree and parsing the result gives the same tree back, and formatting that again changes nothing.
+    #[test]
+    fn test_format_roundtrip(tree in arb_tree()) {
+        use language::format::format_program;
+        use language::lexer::Lexer;
+        use language::parser::ParseTree;
+
+        let src: String = format_program(&Lexer::new(Vec::new()), &tree);
+        let mut lexer: Lexer = Lexer::new(src.clone().into_bytes());
+        lexer.tokenize();
+        lexer.lex().unwrap_or_else(|d| panic!("{} Error: Failed to lex formatted source: {}\n{}", d.pos, d.msg, src));
+        let mut reparsed: ParseTree = ParseTree::new("test".to_string());
+        reparsed.construct(&mut lexer).unwrap_or_else(|d| panic!("{} Error: Failed to parse formatted source: {}\n{}", d.pos, d.msg, src));
+        proptest::prop_assert!(same_shape(&tree.root, &reparsed.root), "Error: Formatted source parsed to a different tree:\n{}", src);
+        proptest::prop_assert_eq!(format_program(&lexer, &reparsed), src);
+    }
+}
@@ -0,0 +1,101 @@
+// Instrumentation shared by the stages and the driver. With the `tracing` feature every stage runs in a span and driver
+// messages are events, so whoever installs a subscriber decides what is shown. Without it messages go straight to stderr.
+use std::cell::RefCell;
+#[cfg(feature = "tracing")]
+pub use stderr::StderrSubscriber;
+
+thread_local! {
+    // Stages running on this thread, innermost last.
+    static STAGES: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+// Leaves a stage when dropped, so a stage unwinding from a panic is left too.
+struct Entered;
+impl Drop for Entered {
+    fn drop(&mut self) {
+        STAGES.with(|stages| stages.borrow_mut().pop());
+    }
+}
+
+// Runs a stage of the compiler, e.g. `lex` or `lower`, inside a span named after it.
+pub fn stage<T>(name: &'static str, run: impl FnOnce() -> T) -> T {
+    STAGES.with(|stages| stages.borrow_mut().push(name));
+    let _entered: Entered = Entered;
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!("stage", name).in_scope(run);
+    #[cfg(not(feature = "tracing"))]
+    run()
+}
+
+// The innermost stage running on this thread, which a panic hook can use to say where the compiler failed.
+pub fn current_stage() -> Option<&'static str> {
+    STAGES.with(|stages| stages.borrow().last().copied())
+}
+
+// Says what the driver is doing, like the tools it calls and the files it deletes.
+pub fn info(msg: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::info!("{}", msg);
+    #[cfg(not(feature = "tracing"))]
+    eprintln!("Info: {}", msg);
+}
+
+#[cfg(feature = "tracing")]
+mod stderr {
+    use std::fmt;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering;
+    use tracing::Event;
+    use tracing::Level;
+    use tracing::Metadata;
+    use tracing::Subscriber;
+    use tracing::field::Field;
+    use tracing::field::Visit;
+    use tracing::span;
+
+    // Prints events to stderr the way the driver does without the feature, for when nothing else is installed.
+    // NOTE: Spans are only numbered, this keeps no state about them.
+    #[derive(Default)]
+    pub struct StderrSubscriber {
+        spans: AtomicU64,
+    }
+
+    struct Message(String);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl Subscriber for StderrSubscriber {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            *metadata.level() <= Level::INFO
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(self.spans.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut msg: Message = Message(String::new());
+            event.record(&mut msg);
+            let level: &str = match *event.metadata().level() {
+                Level::ERROR => "Error",
+                Level::WARN => "Warning",
+                _ => "Info",
+            };
+            eprintln!("{}: {}", level, msg.0);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+}
@@ -0,0 +1,169 @@
+// Checks the structural invariants later stages rely on, so a stage that breaks the tree is caught where it did rather
+// than where the broken tree is used. A failure is a bug in the compiler or in a custom pass, never in the program.
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Pos;
+use crate::parser::INTRINSICS;
+use crate::parser::NodeType;
+use crate::parser::ParseNode;
+
+// Statements a block can hold.
+const STATEMENTS: [NodeType; 12] = [
+    NodeType::VarDecl,
+    NodeType::Assign,
+    NodeType::CompoundAssign,
+    NodeType::DebugDump,
+    NodeType::Exit,
+    NodeType::Return,
+    NodeType::FuncCall,
+    NodeType::Conditional,
+    NodeType::Assert,
+    NodeType::For,
+    NodeType::While,
+    NodeType::Block, // The scope desugaring puts around a `for` loop
+];
+
+const EXPRESSIONS: [NodeType; 7] = [
+    NodeType::Literal,
+    NodeType::Var,
+    NodeType::FuncAddr,
+    NodeType::FuncCall,
+    NodeType::UnOp,
+    NodeType::BinOp,
+    NodeType::Intrinsic,
+];
+
+const FUNCTIONS: [NodeType; 4] = [
+    NodeType::FuncDecl,
+    NodeType::PubFuncDecl,
+    NodeType::ExportFuncDecl,
+    NodeType::TestFuncDecl,
+];
+
+// Validates a tree as parsed if `surface` is set, and otherwise as desugared into core nodes.
+pub fn validate(root: &ParseNode, surface: bool) -> Result<(), Diagnostic> {
+    if root.kind != NodeType::Program {
+        return Err(invalid(root, format!("Root is a `{:?}` node rather than `Program`", root.kind)));
+    }
+    node(root, surface)
+}
+
+fn invalid(node: &ParseNode, msg: String) -> Diagnostic {
+    Diagnostic::error(node.tok.pos.clone(), msg)
+}
+
+// Nodes the parser made up, like blocks and implicit `0` initialisers, have no place in the source.
+fn is_synthetic(pos: &Pos) -> bool {
+    pos.row == usize::MAX - 1
+}
+
+// The bytes of source a node and its children were parsed from, None if all of them were made up.
+fn span(node: &ParseNode) -> Option<(usize, usize)> {
+    let own: Option<(usize, usize)> = (!is_synthetic(&node.tok.pos)).then(|| (node.tok.pos.offset, node.tok.pos.offset + node.tok.val.len()));
+    node.children.iter().filter_map(span).chain(own).reduce(|(start, end), (s, e)| (start.min(s), end.max(e)))
+}
+
+fn node(node: &ParseNode, surface: bool) -> Result<(), Diagnostic> {
+    let kinds = |allowed: &[NodeType]| -> Result<(), Diagnostic> {
+        match node.children.iter().find(|child| !allowed.contains(&child.kind)) {
+            Some(child) => Err(invalid(child, format!("`{:?}` node cannot hold a `{:?}` node", node.kind, child.kind))),
+            None => Ok(()),
+        }
+    };
+    let arity = |min: usize, max: usize| -> Result<(), Diagnostic> {
+        let n: usize = node.children.len();
+        if n < min || n > max {
+            let expected: String = if min == max { min.to_string() } else { format!("{} to {}", min, max) };
+            return Err(invalid(node, format!("`{:?}` node has {} children, expected {}", node.kind, n, expected)));
+        }
+        Ok(())
+    };
+
+    match node.kind {
+        NodeType::Program => kinds(&[FUNCTIONS.as_slice(), &[NodeType::Module]].concat())?,
+        NodeType::Module => kinds(&FUNCTIONS)?,
+        NodeType::FuncDecl | NodeType::PubFuncDecl | NodeType::ExportFuncDecl | NodeType::TestFuncDecl => {
+            arity(1, 1)?;
+            kinds(&[NodeType::Block])?;
+        },
+        NodeType::Block => kinds(&STATEMENTS)?,
+        NodeType::CompoundAssign if !surface => return Err(invalid(node, "`CompoundAssign` node is left after desugaring".to_string())),
+        NodeType::Assert if !surface => return Err(invalid(node, "`Assert` node is left after desugaring".to_string())),
+        NodeType::VarDecl | NodeType::Assign | NodeType::CompoundAssign | NodeType::Assert | NodeType::Exit | NodeType::Return | NodeType::UnOp => {
+            arity(1, 1)?;
+            kinds(&EXPRESSIONS)?;
+        },
+        NodeType::DebugDump => {
+            arity(1, usize::MAX)?;
+            kinds(&EXPRESSIONS)?;
+        },
+        NodeType::BinOp => {
+            arity(2, 2)?;
+            kinds(&EXPRESSIONS)?;
+        },
+        NodeType::For if !surface => return Err(invalid(node, "`For` node is left after desugaring".to_string())),
+        NodeType::For => {
+            arity(3, 3)?;
+            for bound in &node.children[..2] {
+                if !EXPRESSIONS.contains(&bound.kind) {
+                    return Err(invalid(bound, format!("`For` node cannot hold a `{:?}` node as a bound", bound.kind)));
+                }
+            }
+            if node.children[2].kind != NodeType::Block {
+                return Err(invalid(&node.children[2], format!("`For` node cannot hold a `{:?}` node as its body", node.children[2].kind)));
+            }
+        },
+        NodeType::While => {
+            arity(2, 2)?;
+            if !EXPRESSIONS.contains(&node.children[0].kind) {
+                return Err(invalid(&node.children[0], format!("`While` node cannot hold a `{:?}` node as its guard", node.children[0].kind)));
+            }
+            if node.children[1].kind != NodeType::Block {
+                return Err(invalid(&node.children[1], format!("`While` node cannot hold a `{:?}` node as its body", node.children[1].kind)));
+            }
+        },
+        NodeType::Conditional => {
+            arity(2, 3)?;
+            if !EXPRESSIONS.contains(&node.children[0].kind) {
+                return Err(invalid(&node.children[0], format!("`Conditional` node cannot hold a `{:?}` node as its guard", node.children[0].kind)));
+            }
+            for body in &node.children[1..] {
+                // An `else if` is only folded into a block by desugaring.
+                if body.kind != NodeType::Block && !(surface && body.kind == NodeType::Conditional) {
+                    return Err(invalid(body, format!("`Conditional` node cannot hold a `{:?}` node as a body", body.kind)));
+                }
+            }
+        },
+        NodeType::Intrinsic => {
+            let Some((_, n)) = INTRINSICS.iter().find(|(name, _)| name.as_bytes() == node.tok.val.as_slice()) else {
+                return Err(invalid(node, format!("No intrinsic `{}`", node.tok.val_str())));
+            };
+            arity(*n, *n)?;
+            kinds(&EXPRESSIONS)?;
+        },
+        NodeType::Literal if node.tok.value.is_none() => return Err(invalid(node, format!("Literal `{}` has no value", node.tok.val_str()))),
+        NodeType::Literal | NodeType::Var | NodeType::FuncAddr | NodeType::FuncCall => arity(0, 0)?,
+    }
+
+    // Children come from the source after the token that starts their parent, an operator sits between its operands,
+    // and siblings are in source order. Functions of a script and its implicit `main` interleave, so the program is not
+    // checked.
+    if node.kind != NodeType::Program {
+        let spans: Vec<Option<(usize, usize)>> = node.children.iter().map(span).collect();
+        if !is_synthetic(&node.tok.pos) {
+            let (start, end) = (node.tok.pos.offset, node.tok.pos.offset + node.tok.val.len());
+            let outside: Option<usize> = match node.kind {
+                NodeType::BinOp => [spans[0].is_some_and(|(_, e)| e > start), spans[1].is_some_and(|(s, _)| s < end)].iter().position(|bad| *bad),
+                _ => spans.iter().position(|span| span.is_some_and(|(s, _)| s < start)),
+            };
+            if let Some(ix) = outside {
+                return Err(invalid(&node.children[ix], format!("`{:?}` node lies outside of its `{:?}` node", node.children[ix].kind, node.kind)));
+            }
+        }
+        let real: Vec<(usize, (usize, usize))> = spans.iter().enumerate().filter_map(|(ix, span)| span.map(|span| (ix, span))).collect();
+        if let Some(pair) = real.windows(2).find(|pair| pair[0].1.1 > pair[1].1.0) {
+            return Err(invalid(&node.children[pair[1].0], format!("Children of `{:?}` node are out of source order", node.kind)));
+        }
+    }
+
+    node.children.iter().try_for_each(|child| self::node(child, surface))
+}
@@ -0,0 +1,23 @@
+use wasm_bindgen::prelude::wasm_bindgen;
+use crate::opt::OptLevel;
+use crate::grammar;
+use crate::grammar::GrammarFormat;
+
+// JavaScript entry points for running the front end client-side, every result is a JSON string.
+// NOTE: wasm32-unknown-unknown aborts on panic, so a compile error currently traps the instance.
+
+#[wasm_bindgen]
+pub fn inspect(src: &str) -> String {
+    crate::inspect(src.to_string(), "playground".to_string()).to_json().to_string()
+}
+
+// Returns the generated NASM assembly, or an empty string if the source does not compile.
+#[wasm_bindgen]
+pub fn asm(src: &str) -> String {
+    crate::compile_to_asm(src, OptLevel::O0).unwrap_or_default()
+}
+
+#[wasm_bindgen]
+pub fn tmlanguage() -> String {
+    grammar::generate(GrammarFormat::TmLanguage)
+}